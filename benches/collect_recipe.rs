@@ -0,0 +1,107 @@
+// Lists every recipe of a database holding a growing number of them. Recipes
+// and their instructions are hydrated with a fixed number of queries, so the
+// time per recipe should stay flat as the count grows
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use lib::db::health::MonitoredConnection;
+use lib::db::migrations::Migrator;
+use lib::db::sqlite::conn::OpenSqliteConnection;
+use lib::db::DbMetaData;
+use lib::db::DbType;
+use lib::gpio::mock::MockGpioDriver;
+use lib::parsers::settings::SqliteConfigurer;
+use lib::pour::Dispenser;
+use lib::rpc_types::recipe_types::DrinkSize;
+use lib::rpc_types::recipe_types::Instruction;
+use lib::rpc_types::recipe_types::Recipe;
+use lib::rpc_types::server::udm_service_server::UdmService;
+use lib::rpc_types::server::DaemonServerContext;
+use lib::rpc_types::service_types::AddInstructionRequest;
+use lib::rpc_types::service_types::AddRecipeRequest;
+use lib::rpc_types::service_types::CollectRecipeRequest;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tonic::Request;
+
+const RECIPE_COUNTS: [usize; 3] = [10, 50, 200];
+const INSTRUCTIONS_PER_RECIPE: i32 = 4;
+
+async fn server(recipes: usize) -> DaemonServerContext {
+    let settings = SqliteConfigurer::new(":memory:");
+    let conn = OpenSqliteConnection::new(settings.clone()).await;
+    Migrator::new(&conn).up(None).await.unwrap();
+    let server = DaemonServerContext::new(
+        Arc::new(MonitoredConnection::new(Box::new(conn))),
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+        DbMetaData::new(Arc::new(DbType::Sqlite(settings))),
+        Arc::new(Dispenser::new(Arc::new(MockGpioDriver::new()))),
+    );
+    for recipe in 0..recipes {
+        let mut instructions = HashMap::new();
+        for position in 1..=INSTRUCTIONS_PER_RECIPE {
+            let id = server
+                .add_instruction(Request::new(AddInstructionRequest {
+                    instruction: Some(Instruction {
+                        instruction_name: format!("Step {} of recipe {}", position, recipe),
+                        instruction_detail: "Stir".to_string(),
+                        ..Default::default()
+                    }),
+                }))
+                .await
+                .unwrap()
+                .into_inner()
+                .instruction_id;
+            instructions.insert(
+                position,
+                Instruction {
+                    id,
+                    ..Default::default()
+                },
+            );
+        }
+        server
+            .add_recipe(Request::new(AddRecipeRequest {
+                recipe: Some(Recipe {
+                    name: format!("Recipe {}", recipe),
+                    description: format!("Recipe number {}", recipe),
+                    size: DrinkSize::Small.into(),
+                    instructions,
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap();
+    }
+    server
+}
+
+fn collect_recipe(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("collect_recipe");
+    for count in RECIPE_COUNTS {
+        let server = runtime.block_on(server(count));
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &server, |b, server| {
+            b.to_async(&runtime).iter(|| async {
+                let recipes = server
+                    .collect_recipe(Request::new(CollectRecipeRequest::default()))
+                    .await
+                    .unwrap()
+                    .into_inner()
+                    .recipes;
+                assert_eq!(recipes.len(), count);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, collect_recipe);
+criterion_main!(benches);
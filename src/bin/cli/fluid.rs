@@ -1,5 +1,7 @@
 use crate::cli::helpers::ensure_removal;
+use crate::cli::helpers::print_query_example;
 use crate::cli::helpers::MainCommandHandler;
+use crate::cli::helpers::PageArgs;
 use crate::cli::helpers::ShowHandler;
 use crate::cli::helpers::UdmGrpcActions;
 use crate::cli::helpers::UdmServerOptions;
@@ -11,11 +13,12 @@ use cli_table::Table;
 use cli_table::TableStruct;
 use lib::db::FluidRegulationSchema;
 use lib::error::UdmError;
+use lib::parsers::filter;
 use lib::rpc_types::fhs_types::FluidRegulator;
 use lib::rpc_types::fhs_types::RegulatorType;
 use lib::rpc_types::service_types::AddFluidRegulatorRequest;
 use lib::rpc_types::service_types::CollectFluidRegulatorsRequest;
-use lib::rpc_types::service_types::FetchData;
+use lib::rpc_types::service_types::FilterExpression;
 use lib::rpc_types::service_types::ModifyFluidRegulatorRequest;
 use lib::rpc_types::service_types::RemoveFluidRegulatorRequest;
 use lib::rpc_types::FieldValidation;
@@ -175,11 +178,14 @@ impl MainCommandHandler for UpdateFluidArgs {
 }
 #[derive(Args, Debug)]
 pub struct ShowFluidArgs {
+    #[arg(long = "query", help = "Filter the results, see --example")]
     query_options: Option<String>,
     #[arg(long, short = 'e', help = "Example queries", default_value = "false")]
     example: bool,
     #[arg(long, short = 's', help = "show_fields", default_value = "false")]
     show_fields: bool,
+    #[command(flatten)]
+    page: PageArgs,
 }
 #[async_trait]
 impl MainCommandHandler for ShowFluidArgs {
@@ -192,20 +198,28 @@ impl MainCommandHandler for ShowFluidArgs {
             Ok(())
         } else {
             let fetched = self.sanatize_input()?;
+            let (order_by, direction) = self.page.sort()?;
             let mut open_connection = options.connect().await?;
             let response = open_connection
                 .collect_fluid_regulators(CollectFluidRegulatorsRequest {
-                    expressions: fetched,
+                    order_by,
+                    direction: direction.into(),
+                    limit: self.page.limit(),
+                    page_token: self.page.page_token(),
+                    filter: fetched,
+                    ..Default::default()
                 })
                 .await
                 .map_err(|e| UdmError::ApiFailure(format!("{}", e)));
             match response {
                 Ok(response) => {
                     tracing::debug!("Got response {:?}", &response);
-                    let fluids = response.into_inner().fluids;
-                    println!("Found {} results", &fluids.len());
+                    let response = response.into_inner();
+                    let fluids = response.fluids;
+                    println!("Found {} results", response.total_count);
                     let table = self.create_tables(fluids);
                     println!("{}", table.display().unwrap());
+                    PageArgs::print_next_page(&response.next_page_token);
                     Ok(())
                 }
                 Err(err) => {
@@ -218,10 +232,7 @@ impl MainCommandHandler for ShowFluidArgs {
 }
 impl ShowHandler<FluidRegulator> for ShowFluidArgs {
     fn show_example() {
-        println!("To build a query it will be <field><operation><values>");
-        println!("fr_id=1");
-        println!("^^ will query fr_id=1");
-        println!("Another example of multiple values, fr_id = 1");
+        print_query_example("gpio_pin IN (4, 17) OR fr_id > 3");
         Self::get_schema_columns();
     }
 
@@ -257,10 +268,8 @@ impl ShowHandler<FluidRegulator> for ShowFluidArgs {
     fn get_schema_columns() {
         println!("{}", FluidRegulationSchema::FrId);
     }
-    fn sanatize_input(&self) -> UdmResult<Vec<FetchData>> {
-        let collected_queries =
-            FetchData::to_fetch_data_vec(self.query_options.clone().unwrap().as_str())?;
-        Ok(collected_queries)
+    fn sanatize_input(&self) -> UdmResult<Option<FilterExpression>> {
+        self.query_options.as_deref().map(filter::parse).transpose()
     }
 }
 
@@ -425,4 +434,18 @@ mod tests {
         let fr: UdmResult<FluidRegulator> = update_fluid.sanatize_input();
         assert!(fr.is_err(), "{}", true)
     }
+    #[test]
+    fn test_sanatize_show_query() {
+        let mut show_fluid = ShowFluidArgs {
+            query_options: None,
+            example: false,
+            show_fields: false,
+            page: PageArgs::default(),
+        };
+        assert!(show_fluid.sanatize_input().unwrap().is_none());
+        show_fluid.query_options = Some("fr_id IN (1, 2) OR gpio_pin = 4".to_string());
+        assert!(show_fluid.sanatize_input().unwrap().is_some());
+        show_fluid.query_options = Some("fr_id IN 1".to_string());
+        assert!(show_fluid.sanatize_input().is_err());
+    }
 }
@@ -1,6 +1,9 @@
+use clap::Args;
 use cli_table::TableStruct;
+use lib::error::UdmError;
 use lib::rpc_types::server::udm_service_client::UdmServiceClient;
-use lib::rpc_types::service_types::FetchData;
+use lib::rpc_types::service_types::FilterExpression;
+use lib::rpc_types::service_types::SortDirection;
 use lib::UdmResult;
 
 pub trait UdmGrpcActions<T> {
@@ -16,8 +19,59 @@ pub(crate) trait ShowHandler<T>: MainCommandHandler {
     fn show_example();
     fn create_tables(&self, data: Vec<T>) -> TableStruct;
     fn get_schema_columns();
-    fn sanatize_input(&self) -> UdmResult<Vec<FetchData>>;
+    fn sanatize_input(&self) -> UdmResult<Option<FilterExpression>>;
 }
+/// Explains the --query syntax with an example on the entity being shown
+pub(crate) fn print_query_example(example: &str) {
+    println!("Filter with --query, every condition is <field> <operation> <value>");
+    println!("Operations are =, !=, <, <=, >, >=, LIKE, NOT LIKE, IN (...), NOT IN (...), IS NULL and IS NOT NULL");
+    println!("Join conditions with AND (or a comma) and OR, group them with parentheses and negate them with NOT");
+    println!("Quote values that hold spaces, commas or keywords, 'Old Fashioned'");
+    println!("Booleans are true or false, enum fields take a name like REGULATOR_TYPE_PUMP");
+    println!("udm <entity> show --query \"{}\"", example);
+}
+#[derive(Args, Debug, Default)]
+pub struct PageArgs {
+    #[arg(long, help = "Show at most this many results")]
+    limit: Option<u32>,
+    #[arg(
+        long,
+        help = "Column to sort on, add :desc to reverse it e.g. name:desc"
+    )]
+    sort: Option<String>,
+    #[arg(long, help = "Page token printed by the previous show")]
+    page: Option<String>,
+}
+
+impl PageArgs {
+    /// The order_by column and direction of the request
+    pub fn sort(&self) -> UdmResult<(String, SortDirection)> {
+        let Some(sort) = &self.sort else {
+            return Ok((String::new(), SortDirection::Unspecified));
+        };
+        match sort.split_once(':') {
+            None => Ok((sort.clone(), SortDirection::Ascending)),
+            Some((column, "asc")) => Ok((column.to_string(), SortDirection::Ascending)),
+            Some((column, "desc")) => Ok((column.to_string(), SortDirection::Descending)),
+            Some((_, direction)) => Err(UdmError::InvalidInput(format!(
+                "Unknown sort direction {}, use asc or desc",
+                direction
+            ))),
+        }
+    }
+    pub fn limit(&self) -> u32 {
+        self.limit.unwrap_or_default()
+    }
+    pub fn page_token(&self) -> String {
+        self.page.clone().unwrap_or_default()
+    }
+    pub fn print_next_page(next_page_token: &str) {
+        if !next_page_token.is_empty() {
+            println!("More results with --page {}", next_page_token);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct UdmServerOptions {
     pub host: String,
@@ -47,3 +101,29 @@ pub fn ensure_removal() -> UdmResult<()> {
         std::process::exit(2)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_args_sort() {
+        let page = |sort: &str| PageArgs {
+            sort: Some(sort.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            PageArgs::default().sort().unwrap(),
+            (String::new(), SortDirection::Unspecified)
+        );
+        assert_eq!(
+            page("name").sort().unwrap(),
+            ("name".to_string(), SortDirection::Ascending)
+        );
+        assert_eq!(
+            page("name:desc").sort().unwrap(),
+            ("name".to_string(), SortDirection::Descending)
+        );
+        assert!(page("name:sideways").sort().is_err());
+    }
+}
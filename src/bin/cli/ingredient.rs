@@ -1,5 +1,7 @@
 use crate::cli::helpers::ensure_removal;
+use crate::cli::helpers::print_query_example;
 use crate::cli::helpers::MainCommandHandler;
+use crate::cli::helpers::PageArgs;
 use crate::cli::helpers::ShowHandler;
 use crate::cli::helpers::UdmGrpcActions;
 use crate::cli::helpers::UdmServerOptions;
@@ -12,13 +14,14 @@ use cli_table::Table;
 use cli_table::TableStruct;
 use lib::db::IngredientSchema;
 use lib::error::UdmError;
+use lib::parsers::filter;
 use lib::rpc_types::fhs_types::FluidRegulator;
 use lib::rpc_types::recipe_types::Ingredient;
 use lib::rpc_types::recipe_types::IngredientType;
 use lib::rpc_types::recipe_types::Instruction;
 use lib::rpc_types::service_types::AddIngredientRequest;
 use lib::rpc_types::service_types::CollectIngredientRequest;
-use lib::rpc_types::service_types::FetchData;
+use lib::rpc_types::service_types::FilterExpression;
 use lib::rpc_types::service_types::ModifyIngredientRequest;
 use lib::rpc_types::service_types::RemoveIngredientRequest;
 use lib::rpc_types::FieldValidation;
@@ -319,11 +322,14 @@ impl TryFrom<&UpdateIngredientArgs> for Ingredient {
 
 #[derive(Args, Debug)]
 pub struct ShowIngredientArgs {
+    #[arg(long = "query", help = "Filter the results, see --example")]
     query_options: Option<String>,
     #[arg(long, short = 'e', help = "Example queries", default_value = "false")]
     example: bool,
     #[arg(long, short = 's', help = "show_fields", default_value = "false")]
     show_fields: bool,
+    #[command(flatten)]
+    page: PageArgs,
 }
 #[async_trait]
 impl MainCommandHandler for ShowIngredientArgs {
@@ -336,20 +342,28 @@ impl MainCommandHandler for ShowIngredientArgs {
             Ok(())
         } else {
             let fetched = self.sanatize_input()?;
+            let (order_by, direction) = self.page.sort()?;
             let mut open_connection = options.connect().await?;
             let response = open_connection
                 .collect_ingredients(CollectIngredientRequest {
-                    expressions: fetched,
+                    order_by,
+                    direction: direction.into(),
+                    limit: self.page.limit(),
+                    page_token: self.page.page_token(),
+                    filter: fetched,
+                    ..Default::default()
                 })
                 .await
                 .map_err(|e| UdmError::ApiFailure(format!("{}", e)));
             match response {
                 Ok(response) => {
                     tracing::debug!("Got response {:?}", &response);
-                    let fluids = response.into_inner().ingredients;
-                    println!("Found {} results", &fluids.len());
+                    let response = response.into_inner();
+                    let fluids = response.ingredients;
+                    println!("Found {} results", response.total_count);
                     let table = self.create_tables(fluids);
                     println!("{}", table.display().unwrap());
+                    PageArgs::print_next_page(&response.next_page_token);
                     Ok(())
                 }
                 Err(err) => {
@@ -362,10 +376,9 @@ impl MainCommandHandler for ShowIngredientArgs {
 }
 impl ShowHandler<Ingredient> for ShowIngredientArgs {
     fn show_example() {
-        println!("To build a query it will be <field><operation><values>");
-        println!("fr_id=1");
-        println!("^^ will query fr_id=1");
-        println!("Another example of multiple values, fr_id = 1");
+        print_query_example(
+            "name LIKE 'Lime%' OR (fr_id IN (1, 2) AND instruction_id IS NOT NULL)",
+        );
         Self::get_schema_columns();
     }
 
@@ -413,15 +426,8 @@ impl ShowHandler<Ingredient> for ShowIngredientArgs {
     fn get_schema_columns() {
         println!("{}", IngredientSchema::IngredientId);
     }
-    fn sanatize_input(&self) -> UdmResult<Vec<FetchData>> {
-        if self.query_options.is_none() {
-            return Err(UdmError::InvalidInput(
-                "Error while parsing query".to_string(),
-            ));
-        }
-        let collected_queries =
-            FetchData::to_fetch_data_vec(self.query_options.clone().unwrap().as_str())?;
-        Ok(collected_queries)
+    fn sanatize_input(&self) -> UdmResult<Option<FilterExpression>> {
+        self.query_options.as_deref().map(filter::parse).transpose()
     }
 }
 #[derive(Args, Debug)]
@@ -8,16 +8,19 @@ use cli_table::Table;
 use cli_table::TableStruct;
 use lib::db::InstructionSchema;
 use lib::error::UdmError;
+use lib::parsers::filter;
 use lib::rpc_types::recipe_types::Instruction;
 use lib::rpc_types::service_types::AddInstructionRequest;
 use lib::rpc_types::service_types::CollectInstructionRequest;
-use lib::rpc_types::service_types::FetchData;
+use lib::rpc_types::service_types::FilterExpression;
 use lib::rpc_types::service_types::ModifyInstructionRequest;
 use lib::rpc_types::service_types::RemoveInstructionRequest;
 use lib::rpc_types::FieldValidation;
 use lib::UdmResult;
 
+use crate::cli::helpers::print_query_example;
 use crate::cli::helpers::MainCommandHandler;
+use crate::cli::helpers::PageArgs;
 use crate::cli::helpers::ShowHandler;
 use crate::cli::helpers::UdmGrpcActions;
 use crate::cli::helpers::UdmServerOptions;
@@ -106,11 +109,14 @@ impl MainCommandHandler for AddInstructionArgs {
 }
 #[derive(Args, Debug)]
 pub struct ShowInstructionArgs {
+    #[arg(long = "query", help = "Filter the results, see --example")]
     query_options: Option<String>,
     #[arg(long, short = 'e', help = "Example queries", default_value = "false")]
     example: bool,
     #[arg(long, short = 's', help = "show_fields", default_value = "false")]
     show_fields: bool,
+    #[command(flatten)]
+    page: PageArgs,
 }
 #[async_trait]
 impl MainCommandHandler for ShowInstructionArgs {
@@ -129,20 +135,28 @@ impl MainCommandHandler for ShowInstructionArgs {
                     std::process::exit(1)
                 }
             };
+            let (order_by, direction) = self.page.sort()?;
             let mut open_connection = options.connect().await?;
             let response = open_connection
                 .collect_instructions(CollectInstructionRequest {
-                    expressions: fetched,
+                    order_by,
+                    direction: direction.into(),
+                    limit: self.page.limit(),
+                    page_token: self.page.page_token(),
+                    filter: fetched,
+                    ..Default::default()
                 })
                 .await
                 .map_err(|e| UdmError::ApiFailure(format!("{}", e)));
             match response {
                 Ok(response) => {
                     tracing::debug!("Got response {:?}", &response);
-                    let instructions = response.into_inner().instructions;
-                    println!("Found {} results", &instructions.len());
+                    let response = response.into_inner();
+                    let instructions = response.instructions;
+                    println!("Found {} results", response.total_count);
                     let table = self.create_tables(instructions);
                     println!("{}", table.display().unwrap());
+                    PageArgs::print_next_page(&response.next_page_token);
                     Ok(())
                 }
                 Err(err) => {
@@ -155,10 +169,7 @@ impl MainCommandHandler for ShowInstructionArgs {
 }
 impl ShowHandler<Instruction> for ShowInstructionArgs {
     fn show_example() {
-        println!("To build a query it will be <field><operation><values>");
-        println!("fr_id=1");
-        println!("^^ will query fr_id=1");
-        println!("Another example of multiple values, fr_id = 1");
+        print_query_example("instruction_name LIKE 'Shake%' OR instruction_id IN (1, 2)");
         Self::get_schema_columns();
     }
 
@@ -184,15 +195,8 @@ impl ShowHandler<Instruction> for ShowInstructionArgs {
     fn get_schema_columns() {
         println!("{}", InstructionSchema::InstructionId);
     }
-    fn sanatize_input(&self) -> UdmResult<Vec<FetchData>> {
-        if self.query_options.is_none() {
-            return Err(UdmError::InvalidInput(
-                "Error while parsing query".to_string(),
-            ));
-        }
-        let collected_queries =
-            FetchData::to_fetch_data_vec(self.query_options.clone().unwrap().as_str())?;
-        Ok(collected_queries)
+    fn sanatize_input(&self) -> UdmResult<Option<FilterExpression>> {
+        self.query_options.as_deref().map(filter::parse).transpose()
     }
 }
 #[derive(Args, Debug)]
@@ -0,0 +1,162 @@
+use crate::cli::helpers::MainCommandHandler;
+use crate::cli::helpers::UdmServerOptions;
+use clap::Args;
+use clap::Subcommand;
+use cli_table::Cell;
+use cli_table::Style;
+use cli_table::Table;
+use cli_table::TableStruct;
+use lib::error::UdmError;
+use lib::rpc_types::fhs_types::Bottle;
+use lib::rpc_types::service_types::GetInventoryRequest;
+use lib::rpc_types::service_types::SetBottleLevelRequest;
+use lib::UdmResult;
+use tonic::async_trait;
+
+#[derive(Subcommand, Debug)]
+pub enum InventoryCommands {
+    #[command(about = "Show the bottles loaded on the fluid regulators")]
+    Show(ShowInventoryArgs),
+    #[command(about = "Set the bottle loaded on a fluid regulator")]
+    Set(SetBottleArgs),
+    #[command(about = "Mark the bottle on a fluid regulator as full")]
+    Refill(RefillBottleArgs),
+}
+#[async_trait]
+impl MainCommandHandler for InventoryCommands {
+    async fn handle_command(&self, options: UdmServerOptions) -> UdmResult<()> {
+        match self {
+            InventoryCommands::Show(user_input) => user_input.handle_command(options).await,
+            InventoryCommands::Set(user_input) => user_input.handle_command(options).await,
+            InventoryCommands::Refill(user_input) => user_input.handle_command(options).await,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ShowInventoryArgs {
+    #[arg(short, long, help = "Only show these fluid regulators")]
+    fr_id: Vec<i32>,
+}
+#[async_trait]
+impl MainCommandHandler for ShowInventoryArgs {
+    async fn handle_command(&self, options: UdmServerOptions) -> UdmResult<()> {
+        let mut open_connection = options.connect().await?;
+        let response = open_connection
+            .get_inventory(GetInventoryRequest {
+                fr_ids: self.fr_id.clone(),
+            })
+            .await
+            .map_err(|e| UdmError::ApiFailure(format!("{}", e)));
+        match response {
+            Ok(response) => {
+                tracing::debug!("Got response {:?}", &response);
+                let bottles = response.into_inner().bottles;
+                println!("Found {} results", &bottles.len());
+                println!("{}", create_table(bottles).display().unwrap());
+                Ok(())
+            }
+            Err(err) => {
+                println!("Error: Could not show inventory due to: {}", err);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct SetBottleArgs {
+    #[arg(
+        short,
+        long,
+        help = "The fluid regulator the bottle is on",
+        required = true
+    )]
+    fr_id: i32,
+    #[arg(short, long, help = "Volume left in ml, defaults to a full bottle")]
+    remaining: Option<f32>,
+    #[arg(short, long, help = "Size of the bottle in ml")]
+    capacity: Option<f32>,
+    #[arg(short, long, help = "The ingredient in the bottle")]
+    ingredient_id: Option<i32>,
+}
+#[async_trait]
+impl MainCommandHandler for SetBottleArgs {
+    async fn handle_command(&self, options: UdmServerOptions) -> UdmResult<()> {
+        let req = SetBottleLevelRequest {
+            fr_id: self.fr_id,
+            remaining_ml: self.remaining,
+            capacity_ml: self.capacity,
+            ingredient_id: self.ingredient_id,
+        };
+        set_bottle_level(options, req).await
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct RefillBottleArgs {
+    #[arg(
+        short,
+        long,
+        help = "The fluid regulator the bottle is on",
+        required = true
+    )]
+    fr_id: i32,
+}
+#[async_trait]
+impl MainCommandHandler for RefillBottleArgs {
+    async fn handle_command(&self, options: UdmServerOptions) -> UdmResult<()> {
+        let req = SetBottleLevelRequest {
+            fr_id: self.fr_id,
+            ..Default::default()
+        };
+        set_bottle_level(options, req).await
+    }
+}
+
+async fn set_bottle_level(options: UdmServerOptions, req: SetBottleLevelRequest) -> UdmResult<()> {
+    let mut open_connection = options.connect().await?;
+    let response = open_connection
+        .set_bottle_level(req)
+        .await
+        .map_err(|e| UdmError::ApiFailure(format!("{}", e)));
+    match response {
+        Ok(response) => {
+            let bottles = response.into_inner().bottle.into_iter().collect();
+            println!("{}", create_table(bottles).display().unwrap());
+            Ok(())
+        }
+        Err(err) => {
+            println!("Error: Could not set the bottle due to: {}", err);
+            Err(err)
+        }
+    }
+}
+
+fn create_table(data: Vec<Bottle>) -> TableStruct {
+    let mut table = Vec::new();
+    for bottle in data {
+        let ingredient = match bottle.ingredient_id {
+            Some(id) => format!("{}", id),
+            None => "Not Set".to_string(),
+        };
+        let percent = 100.0 * bottle.remaining_ml / bottle.capacity_ml;
+        table.push(vec![
+            bottle.fr_id.cell(),
+            ingredient.cell(),
+            format!("{:.1}", bottle.capacity_ml).cell(),
+            format!("{:.1}", bottle.remaining_ml).cell(),
+            format!("{:.0}%", percent).cell(),
+        ]);
+    }
+    table
+        .table()
+        .title(vec![
+            "Fluid Regulator".cell().bold(true),
+            "Ingredient ID".cell().bold(true),
+            "Capacity (ml)".cell().bold(true),
+            "Remaining (ml)".cell().bold(true),
+            "Level".cell().bold(true),
+        ])
+        .bold(true)
+}
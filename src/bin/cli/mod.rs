@@ -5,6 +5,7 @@ pub mod fluid;
 pub mod helpers;
 pub mod ingredient;
 pub mod instruction;
+pub mod inventory;
 pub mod recipe;
 pub mod recipetoinstruction;
 use self::helpers::MainCommandHandler;
@@ -12,9 +13,14 @@ use crate::cli::helpers::UdmServerOptions;
 use async_trait::async_trait;
 use clap_verbosity_flag::Verbosity;
 use lib::error::UdmError;
+use lib::rpc_types::service_types::DaemonStatusRequest;
+use lib::rpc_types::service_types::EmergencyStopRequest;
 use lib::rpc_types::service_types::EntityType;
+use lib::rpc_types::service_types::GetHealthRequest;
 use lib::rpc_types::service_types::ResetRequest;
 use lib::rpc_types::service_types::ResetType;
+use lib::rpc_types::service_types::ResumeRequest;
+use lib::rpc_types::MultipleValues;
 use lib::UdmResult;
 
 #[derive(Parser, Debug)]
@@ -74,21 +80,40 @@ pub enum UdmCommand {
     Fluid(fluid::FluidCommands),
     #[command(about = "To interact with RecipeToInstruction Order", subcommand)]
     RecipeToInstruction(recipetoinstruction::RecipeToInstructionCommands),
-    #[command(about = "Reset all tables in the database")]
+    #[command(about = "To interact with the bottle inventory", subcommand)]
+    Inventory(inventory::InventoryCommands),
+    #[command(about = "Reset all tables in the database or a single entity")]
     Reset(ResetCommands),
+    #[command(about = "Emergency stop, closes every regulator and halts the dispenser")]
+    Stop(StopCommands),
+    #[command(about = "Resume the dispenser after an emergency stop")]
+    Resume(ResumeCommands),
+    #[command(about = "Show the dispenser status")]
+    Status(StatusCommands),
+    #[command(about = "Show whether the daemon can reach its database")]
+    Health(HealthCommands),
 }
 
 #[derive(Args, Debug)]
+#[group(required = true, multiple = false)]
 pub struct ResetCommands {
-    #[arg(short, long, help = "reset all databases", default_value = "true")]
+    #[arg(short, long, help = "reset all databases")]
     all: bool,
+    #[arg(short, long, help = "reset a single entity and the rows that depend on it", value_parser=EntityType::get_possible_values())]
+    entity: Option<String>,
 }
 
 #[async_trait]
 impl MainCommandHandler for ResetCommands {
     async fn handle_command(&self, options: UdmServerOptions) -> UdmResult<()> {
+        let entity = self
+            .entity
+            .as_ref()
+            .map_or(EntityType::Unspecified, |entity| {
+                EntityType::from_str_name(entity.as_str()).unwrap_or(EntityType::Unspecified)
+            });
         let req = ResetRequest {
-            entity: EntityType::Unspecified.into(),
+            entity: entity.into(),
             reset_type: {
                 if self.all {
                     ResetType::All.into()
@@ -114,3 +139,107 @@ impl MainCommandHandler for ResetCommands {
         }
     }
 }
+
+#[derive(Args, Debug)]
+pub struct StopCommands {
+    #[arg(short, long, help = "Why the dispenser is being stopped")]
+    reason: Option<String>,
+}
+
+#[async_trait]
+impl MainCommandHandler for StopCommands {
+    async fn handle_command(&self, options: UdmServerOptions) -> UdmResult<()> {
+        let req = EmergencyStopRequest {
+            reason: self.reason.clone().unwrap_or_default(),
+        };
+        let mut connection = options.connect().await?;
+        match connection.emergency_stop(req).await {
+            Ok(response) => {
+                let stopped = response.into_inner();
+                println!("Dispenser halted, run `udm resume` to pour again");
+                println!("Cancelled pours: {:?}", stopped.cancelled_pours);
+                println!("Closed pins: {:?}", stopped.closed_pins);
+                if !stopped.regulator_error.is_empty() {
+                    println!(
+                        "Warning: could not read the regulators, only pins already used were closed: {}",
+                        stopped.regulator_error
+                    );
+                }
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!("Failed to stop the dispenser: {}", e.to_string());
+                println!("Error: {}", e.message());
+                Err(UdmError::ApiFailure(format!("{}", e)))
+            }
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ResumeCommands {}
+
+#[async_trait]
+impl MainCommandHandler for ResumeCommands {
+    async fn handle_command(&self, options: UdmServerOptions) -> UdmResult<()> {
+        let mut connection = options.connect().await?;
+        let resumed = connection
+            .resume(ResumeRequest {})
+            .await
+            .map_err(|e| UdmError::ApiFailure(format!("{}", e)));
+        match resumed {
+            Ok(_) => {
+                println!("Dispenser resumed");
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!("Failed to resume the dispenser: {}", e.to_string());
+                Err(e)
+            }
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct StatusCommands {}
+
+#[async_trait]
+impl MainCommandHandler for StatusCommands {
+    async fn handle_command(&self, options: UdmServerOptions) -> UdmResult<()> {
+        let mut connection = options.connect().await?;
+        let status = connection
+            .get_daemon_status(DaemonStatusRequest {})
+            .await
+            .map_err(|e| UdmError::ApiFailure(format!("{}", e)))?
+            .into_inner();
+        if status.halted {
+            println!("Dispenser is halted: {}", status.halt_reason);
+        } else {
+            println!("Dispenser is running");
+        }
+        println!("Active pours: {:?}", status.active_pours);
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct HealthCommands {}
+
+#[async_trait]
+impl MainCommandHandler for HealthCommands {
+    async fn handle_command(&self, options: UdmServerOptions) -> UdmResult<()> {
+        let mut connection = options.connect().await?;
+        let health = connection
+            .get_health(GetHealthRequest {})
+            .await
+            .map_err(|e| UdmError::ApiFailure(format!("{}", e)))?
+            .into_inner();
+        if health.database_available {
+            println!("Database is available");
+        } else {
+            println!("Database is unavailable: {}", health.database_message);
+            println!("Failed probes: {}", health.failed_probes);
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
 use crate::cli::helpers::ensure_removal;
+use crate::cli::helpers::print_query_example;
 use crate::cli::helpers::MainCommandHandler;
+use crate::cli::helpers::PageArgs;
 use crate::cli::helpers::ShowHandler;
 use crate::cli::helpers::UdmServerOptions;
 use clap::Args;
@@ -10,11 +12,12 @@ use cli_table::Table;
 use cli_table::TableStruct;
 use lib::db::RecipeSchema;
 use lib::error::UdmError;
+use lib::parsers::filter;
 use lib::rpc_types::recipe_types::DrinkSize;
 use lib::rpc_types::recipe_types::Recipe;
 use lib::rpc_types::service_types::AddRecipeRequest;
 use lib::rpc_types::service_types::CollectRecipeRequest;
-use lib::rpc_types::service_types::FetchData;
+use lib::rpc_types::service_types::FilterExpression;
 use lib::rpc_types::service_types::ModifyRecipeRequest;
 use lib::rpc_types::service_types::RemoveRecipeRequest;
 use lib::rpc_types::FieldValidation;
@@ -130,11 +133,14 @@ impl MainCommandHandler for AddRecipeArgs {
 }
 #[derive(Args, Debug)]
 pub struct ShowRecipeArgs {
+    #[arg(long = "query", help = "Filter the results, see --example")]
     query_options: Option<String>,
     #[arg(long, short = 'e', help = "Example queries", default_value = "false")]
     example: bool,
     #[arg(long, short = 's', help = "show_fields", default_value = "false")]
     show_fields: bool,
+    #[command(flatten)]
+    page: PageArgs,
 }
 
 #[async_trait]
@@ -154,20 +160,27 @@ impl MainCommandHandler for ShowRecipeArgs {
                     std::process::exit(1)
                 }
             };
+            let (order_by, direction) = self.page.sort()?;
             let mut open_connection = options.connect().await?;
             let response = open_connection
                 .collect_recipe(CollectRecipeRequest {
-                    expressions: fetched,
+                    order_by,
+                    direction: direction.into(),
+                    limit: self.page.limit(),
+                    page_token: self.page.page_token(),
+                    filter: fetched,
+                    ..Default::default()
                 })
                 .await
                 .map_err(|e| UdmError::ApiFailure(format!("{}", e)));
             match response {
                 Ok(response) => {
                     tracing::debug!("Got response {:?}", &response);
-                    let recipes = &response.get_ref().recipes;
-                    println!("Found {} results", &recipes.len());
-                    let table = self.create_tables(recipes.to_vec());
+                    let response = response.into_inner();
+                    println!("Found {} results", response.total_count);
+                    let table = self.create_tables(response.recipes);
                     println!("{}", table.display().unwrap());
+                    PageArgs::print_next_page(&response.next_page_token);
                     Ok(())
                 }
                 Err(err) => {
@@ -180,10 +193,9 @@ impl MainCommandHandler for ShowRecipeArgs {
 }
 impl ShowHandler<Recipe> for ShowRecipeArgs {
     fn show_example() {
-        println!("To build a query it will be <field><operation><values>");
-        println!("fr_id=1");
-        println!("^^ will query fr_id=1");
-        println!("Another example of multiple values, fr_id = 1");
+        print_query_example(
+            "name = 'Old Fashioned' OR (recipe_id IN (1, 2) AND description IS NOT NULL)",
+        );
         Self::get_schema_columns();
     }
 
@@ -215,15 +227,8 @@ impl ShowHandler<Recipe> for ShowRecipeArgs {
     fn get_schema_columns() {
         println!("{}", RecipeSchema::RecipeId);
     }
-    fn sanatize_input(&self) -> UdmResult<Vec<FetchData>> {
-        if self.query_options.is_none() {
-            return Err(UdmError::InvalidInput(
-                "Error while parsing query".to_string(),
-            ));
-        }
-        let collected_queries =
-            FetchData::to_fetch_data_vec(self.query_options.clone().unwrap().as_str())?;
-        Ok(collected_queries)
+    fn sanatize_input(&self) -> UdmResult<Option<FilterExpression>> {
+        self.query_options.as_deref().map(filter::parse).transpose()
     }
 }
 #[derive(Args, Debug)]
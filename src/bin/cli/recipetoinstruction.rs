@@ -1,5 +1,7 @@
 use crate::cli::helpers::ensure_removal;
+use crate::cli::helpers::print_query_example;
 use crate::cli::helpers::MainCommandHandler;
+use crate::cli::helpers::PageArgs;
 use crate::cli::helpers::ShowHandler;
 use crate::cli::helpers::UdmGrpcActions;
 use crate::cli::helpers::UdmServerOptions;
@@ -12,9 +14,10 @@ use cli_table::Table;
 use cli_table::TableStruct;
 use lib::db::InstructionToRecipeSchema;
 use lib::error::UdmError;
+use lib::parsers::filter;
 use lib::rpc_types::service_types::AddRecipeInstOrderRequest;
 use lib::rpc_types::service_types::CollectRecipeInstOrderRequest;
-use lib::rpc_types::service_types::FetchData;
+use lib::rpc_types::service_types::FilterExpression;
 use lib::rpc_types::service_types::RecipeInstructionOrder;
 use lib::rpc_types::service_types::RemoveRecipeInstOrderRequest;
 use lib::rpc_types::service_types::UpdateRecipeInstOrderRequest;
@@ -260,11 +263,14 @@ impl FieldValidation for AddInstructionOrderArgs {
 }
 #[derive(Args, Debug)]
 pub struct ShowInstructionOrderArgs {
+    #[arg(long = "query", help = "Filter the results, see --example")]
     query_options: Option<String>,
     #[arg(long, short = 'e', help = "Example queries", default_value = "false")]
     example: bool,
     #[arg(long, short = 's', help = "show_fields", default_value = "false")]
     show_fields: bool,
+    #[command(flatten)]
+    page: PageArgs,
 }
 #[async_trait]
 impl MainCommandHandler for ShowInstructionOrderArgs {
@@ -277,20 +283,28 @@ impl MainCommandHandler for ShowInstructionOrderArgs {
             Ok(())
         } else {
             let fetched = self.sanatize_input()?;
+            let (order_by, direction) = self.page.sort()?;
             let mut open_connection = options.connect().await?;
             let response = open_connection
                 .collect_recipe_instruction_order(CollectRecipeInstOrderRequest {
-                    expressions: fetched,
+                    order_by,
+                    direction: direction.into(),
+                    limit: self.page.limit(),
+                    page_token: self.page.page_token(),
+                    filter: fetched,
+                    ..Default::default()
                 })
                 .await
                 .map_err(|e| UdmError::ApiFailure(format!("{}", e)));
             match response {
                 Ok(response) => {
                     tracing::debug!("Got response {:?}", &response);
-                    let recipe_orders = response.into_inner().recipe_to_instructions;
-                    println!("Found {} results", &recipe_orders.len());
+                    let response = response.into_inner();
+                    let recipe_orders = response.recipe_to_instructions;
+                    println!("Found {} results", response.total_count);
                     let table = self.create_tables(recipe_orders);
                     println!("{}", table.display().unwrap());
+                    PageArgs::print_next_page(&response.next_page_token);
                     Ok(())
                 }
                 Err(err) => {
@@ -303,10 +317,7 @@ impl MainCommandHandler for ShowInstructionOrderArgs {
 }
 impl ShowHandler<RecipeInstructionOrder> for ShowInstructionOrderArgs {
     fn show_example() {
-        println!("To build a query it will be <field><operation><values>");
-        println!("fr_id=1");
-        println!("^^ will query fr_id=1");
-        println!("Another example of multiple values, fr_id = 1");
+        print_query_example("recipe_id = 1 AND NOT instruction_order > 3");
         Self::get_schema_columns();
     }
 
@@ -334,15 +345,8 @@ impl ShowHandler<RecipeInstructionOrder> for ShowInstructionOrderArgs {
     fn get_schema_columns() {
         println!("{}", InstructionToRecipeSchema::Id);
     }
-    fn sanatize_input(&self) -> UdmResult<Vec<FetchData>> {
-        if self.query_options.is_none() {
-            return Err(UdmError::InvalidInput(
-                "Error while parsing query".to_string(),
-            ));
-        }
-        let collected_queries =
-            FetchData::to_fetch_data_vec(self.query_options.clone().unwrap().as_str())?;
-        Ok(collected_queries)
+    fn sanatize_input(&self) -> UdmResult<Option<FilterExpression>> {
+        self.query_options.as_deref().map(filter::parse).transpose()
     }
 }
 #[derive(Args, Debug)]
@@ -36,9 +36,24 @@ async fn main() -> Result<(), Box<dyn Error>> {
             cli::UdmCommand::RecipeToInstruction(user_input) => {
                 let _ = user_input.handle_command(server_options).await;
             }
+            cli::UdmCommand::Inventory(user_input) => {
+                let _ = user_input.handle_command(server_options).await;
+            }
             cli::UdmCommand::Reset(user_input) => {
                 let _ = user_input.handle_command(server_options).await;
             }
+            cli::UdmCommand::Stop(user_input) => {
+                let _ = user_input.handle_command(server_options).await;
+            }
+            cli::UdmCommand::Resume(user_input) => {
+                let _ = user_input.handle_command(server_options).await;
+            }
+            cli::UdmCommand::Status(user_input) => {
+                let _ = user_input.handle_command(server_options).await;
+            }
+            cli::UdmCommand::Health(user_input) => {
+                let _ = user_input.handle_command(server_options).await;
+            }
         }
     }
     Ok(())
@@ -22,7 +22,7 @@ pub struct DaemonCli {
     pub test: bool,
 
     #[command(subcommand)]
-    command: Option<UdmCommands>,
+    pub command: Option<UdmCommands>,
 }
 impl Default for DaemonCli {
     fn default() -> Self {
@@ -47,4 +47,23 @@ impl DaemonCli {
 }
 
 #[derive(Subcommand, Debug)]
-enum UdmCommands {}
+pub enum UdmCommands {
+    #[command(subcommand, about = "Manage the database schema")]
+    Migrate(MigrateCommands),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MigrateCommands {
+    #[command(about = "Apply pending migrations")]
+    Up {
+        #[arg(long, help = "Stop after applying this version")]
+        to: Option<i64>,
+    },
+    #[command(about = "Revert applied migrations, the latest one by default")]
+    Down {
+        #[arg(long, help = "Revert every migration newer than this version")]
+        to: Option<i64>,
+    },
+    #[command(about = "Show which migrations are applied")]
+    Status,
+}
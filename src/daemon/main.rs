@@ -1,9 +1,16 @@
 use clap::Parser;
+use itertools::Itertools;
 use lib::db;
+use lib::db::health::MonitoredConnection;
+use lib::db::migrations::Migrator;
+use lib::db::DbConnection;
 use lib::db::DbMetaData;
+use lib::gpio;
 use lib::logger::UdmLogger;
 use lib::logger::UdmLoggerType;
 use lib::parsers;
+use lib::pour::inventory::BottleLedger;
+use lib::pour::Dispenser;
 use lib::rpc_types::server;
 use lib::Retrieval;
 use std::error::Error;
@@ -12,6 +19,7 @@ use std::net::Ipv4Addr;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tracing::debug;
+use tracing::error;
 use tracing::info;
 pub mod cli;
 
@@ -36,12 +44,22 @@ async fn main() -> Result<(), Box<dyn Error>> {
     lib::parsers::validate_configurer(Arc::clone(&configeror)).unwrap_or_else(|e| panic!("{}", e));
     // Load in the Correct Db Settings and establish connection
     let db_type = Arc::new(db::DbType::load_db(Arc::clone(&configeror)));
-    let mut connection = db_type.establish_connection().await;
-    info!("Initializing database");
-    let _ = connection
-        .gen_schmea()
-        .await
-        .map_err(|e| format!("Failed to create database schema {}", e));
+    let connection = Arc::new(MonitoredConnection::new(
+        db_type.establish_connection().await,
+    ));
+    let migrator = Migrator::new(connection.as_ref());
+    if let Some(cli::UdmCommands::Migrate(command)) = &cli_opts.command {
+        return migrate(&migrator, command).await;
+    }
+    let pending = migrator.pending().await?;
+    if !pending.is_empty() {
+        let versions = pending.iter().map(|m| m.version.to_string()).join(", ");
+        error!(
+            "Database is missing migrations {}, run `daemon migrate up` first",
+            versions
+        );
+        std::process::exit(20)
+    }
 
     let addr = SocketAddr::new(
         IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
@@ -49,8 +67,53 @@ async fn main() -> Result<(), Box<dyn Error>> {
     );
     info!("Attempting to start server on {}", &addr);
     let db_metadata = DbMetaData::new(Arc::clone(&db_type));
-    let daemon_server = server::DaemonServerContext::new(connection, addr, db_metadata);
-    let udm_service = server::udm_service_server::UdmServiceServer::new(daemon_server);
-    server::start_server(udm_service, addr).await?;
+    let driver = gpio::load_driver(&configeror.daemon.hardware)?;
+    let ledger = Arc::new(BottleLedger::new(
+        Arc::clone(&connection) as Arc<dyn DbConnection>
+    ));
+    let dispenser = Arc::new(Dispenser::new(driver).with_ledger(ledger));
+    tokio::spawn(Arc::clone(&connection).run_probe());
+    let daemon_server = Arc::new(server::DaemonServerContext::new(
+        Arc::clone(&connection),
+        addr,
+        db_metadata,
+        dispenser,
+    ));
+    tokio::spawn(Arc::clone(&daemon_server).run_order_queue());
+    let udm_service = server::udm_service_server::UdmServiceServer::from_arc(daemon_server);
+    server::start_server(udm_service, addr, connection).await?;
+    Ok(())
+}
+
+async fn migrate(
+    migrator: &Migrator<'_>,
+    command: &cli::MigrateCommands,
+) -> Result<(), Box<dyn Error>> {
+    match command {
+        cli::MigrateCommands::Up { to } => {
+            let applied = migrator.up(*to).await?;
+            if applied.is_empty() {
+                println!("Database is up to date");
+            }
+            for migration in applied {
+                println!("Applied {} {}", migration.version, migration.name);
+            }
+        }
+        cli::MigrateCommands::Down { to } => {
+            for migration in migrator.down(*to).await? {
+                println!("Reverted {} {}", migration.version, migration.name);
+            }
+        }
+        cli::MigrateCommands::Status => {
+            for migration in migrator.status().await? {
+                let state = match (migration.applied, migration.unknown) {
+                    (true, true) => "applied (unknown to this release)",
+                    (true, false) => "applied",
+                    _ => "pending",
+                };
+                println!("{:>4} {:<30} {}", migration.version, migration.name, state);
+            }
+        }
+    }
     Ok(())
 }
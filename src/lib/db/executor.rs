@@ -1,5 +1,9 @@
 use sea_query::Asterisk;
+use sea_query::Condition;
+use sea_query::DynIden;
+use sea_query::Expr;
 use sea_query::Iden;
+use sea_query::Order;
 // This will generate all the queries
 // This manipluates the data itself
 use async_trait::async_trait;
@@ -10,6 +14,28 @@ use sea_query::SelectStatement;
 use sea_query::SimpleExpr;
 use sea_query::UpdateStatement;
 
+/// The window of rows a paged select returns
+#[derive(Debug, Clone)]
+pub struct Page {
+    // Sorted on in order, the last one should be unique so pages don't overlap
+    pub order_by: Vec<DynIden>,
+    pub order: Order,
+    pub limit: Option<u64>,
+    pub offset: u64,
+}
+
+impl Page {
+    /// Empty once the page reaches the last row
+    pub fn next_page_token(&self, returned: usize, total_count: i64) -> String {
+        let next = self.offset + returned as u64;
+        if self.limit.is_some() && returned > 0 && (next as i64) < total_count {
+            next.to_string()
+        } else {
+            String::new()
+        }
+    }
+}
+
 #[async_trait]
 pub trait GenQueries {
     fn gen_insert_query(&self) -> InsertStatement;
@@ -25,6 +51,42 @@ pub trait GenQueries {
         }
         query.to_owned()
     }
+    fn gen_paged_select_query<T: Iden + 'static>(
+        table: T,
+        condition: Condition,
+        page: &Page,
+    ) -> SelectStatement {
+        let mut query = Query::select()
+            .column(Asterisk)
+            .from(table)
+            .cond_where(condition)
+            .to_owned();
+        for column in &page.order_by {
+            query.order_by(column.clone(), page.order.clone());
+        }
+        match page.limit {
+            Some(limit) => {
+                query.limit(limit);
+            }
+            // Sqlite only takes an offset after a limit
+            None if page.offset > 0 => {
+                query.limit(i64::MAX as u64);
+            }
+            None => {}
+        }
+        if page.offset > 0 {
+            query.offset(page.offset);
+        }
+        query
+    }
+    /// Counts the rows matching `condition` on every page
+    fn gen_count_query<T: Iden + 'static>(table: T, condition: Condition) -> SelectStatement {
+        Query::select()
+            .expr(Expr::col(Asterisk).count())
+            .from(table)
+            .cond_where(condition)
+            .to_owned()
+    }
     fn gen_remove_query(id: i32) -> DeleteStatement;
     fn gen_custom_remove_query(&self) -> DeleteStatement {
         unimplemented!()
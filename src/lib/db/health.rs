@@ -0,0 +1,330 @@
+// Wraps the connection the daemon hands out and keeps track of whether the
+// database answers. While it doesn't, statements fail fast instead of each
+// waiting out a connect timeout. A background probe pings the database on an
+// interval and backs off while it is down, every ping goes through the pool
+// so the first one that succeeds is also the reconnect
+use crate::db::row::DbRow;
+use crate::db::DatabaseTransactionsFactory;
+use crate::db::DbConnection;
+use crate::db::DbTransaction;
+use crate::db::SchemaStatement;
+use crate::error::UdmError;
+use crate::UdmResult;
+use async_trait::async_trait;
+use sea_query::DeleteStatement;
+use sea_query::InsertStatement;
+use sea_query::SelectStatement;
+use sea_query::UpdateStatement;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::sync::watch;
+use tokio::sync::Notify;
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DbHealth {
+    pub available: bool,
+    // Why the database was taken offline
+    pub message: String,
+    // Probes that failed in a row
+    pub failed_probes: u32,
+    // Seconds since the epoch
+    pub last_checked: i64,
+    pub unavailable_since: Option<i64>,
+}
+
+pub struct MonitoredConnection {
+    connection: Box<dyn DbConnection>,
+    health: watch::Sender<DbHealth>,
+    // Wakes the probe early once a statement lost the connection
+    recheck: Notify,
+}
+
+impl MonitoredConnection {
+    pub fn new(connection: Box<dyn DbConnection>) -> Self {
+        let health = DbHealth {
+            available: true,
+            last_checked: now(),
+            ..Default::default()
+        };
+        Self {
+            connection,
+            health: watch::Sender::new(health),
+            recheck: Notify::new(),
+        }
+    }
+    pub fn health(&self) -> DbHealth {
+        self.health.borrow().clone()
+    }
+    pub fn is_available(&self) -> bool {
+        self.health.borrow().available
+    }
+    /// Pings the database once and records the outcome
+    pub async fn probe(&self) -> DbHealth {
+        let result = self.connection.ping().await;
+        self.health.send_modify(|health| {
+            health.last_checked = now();
+            match result {
+                Ok(_) => {
+                    if !health.available {
+                        tracing::info!("Database is available again");
+                    }
+                    health.available = true;
+                    health.message.clear();
+                    health.failed_probes = 0;
+                    health.unavailable_since = None;
+                }
+                Err(e) => {
+                    Self::mark_unavailable(health, e.to_string());
+                    health.failed_probes += 1;
+                }
+            }
+        });
+        self.health()
+    }
+    /// Probes the database until the daemon exits
+    pub async fn run_probe(self: Arc<Self>) {
+        loop {
+            let health = self.probe().await;
+            let delay = if health.available {
+                PROBE_INTERVAL
+            } else {
+                backoff(health.failed_probes)
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = self.recheck.notified() => {}
+            }
+        }
+    }
+    fn ensure_available(&self) -> UdmResult<()> {
+        let health = self.health.borrow();
+        if health.available {
+            Ok(())
+        } else {
+            Err(UdmError::DatabaseUnavailable(health.message.clone()))
+        }
+    }
+    // Statements that lost the connection take the database offline until
+    // the next successful probe
+    fn observe<T>(&self, result: UdmResult<T>) -> UdmResult<T> {
+        if let Err(UdmError::DatabaseUnavailable(message)) = &result {
+            self.health
+                .send_modify(|health| Self::mark_unavailable(health, message.clone()));
+            self.recheck.notify_one();
+        }
+        result
+    }
+    fn mark_unavailable(health: &mut DbHealth, message: String) {
+        if health.available {
+            tracing::error!("Database is unavailable: {}", &message);
+            health.available = false;
+            health.unavailable_since = Some(now());
+        }
+        health.message = message;
+    }
+}
+
+#[async_trait]
+impl DbConnection for MonitoredConnection {
+    async fn insert(&self, stmt: InsertStatement) -> UdmResult<i32> {
+        self.ensure_available()?;
+        self.observe(self.connection.insert(stmt).await)
+    }
+    async fn delete(&self, stmt: DeleteStatement) -> UdmResult<()> {
+        self.ensure_available()?;
+        self.observe(self.connection.delete(stmt).await)
+    }
+    async fn update(&self, stmt: UpdateStatement) -> UdmResult<i32> {
+        self.ensure_available()?;
+        self.observe(self.connection.update(stmt).await)
+    }
+    async fn select(&self, stmt: SelectStatement) -> UdmResult<Vec<DbRow>> {
+        self.ensure_available()?;
+        self.observe(self.connection.select(stmt).await)
+    }
+    async fn begin<'a>(&'a self) -> UdmResult<Box<dyn DbTransaction + 'a>> {
+        self.ensure_available()?;
+        let transaction = self.observe(self.connection.begin().await)?;
+        Ok(Box::new(MonitoredTransaction {
+            monitor: self,
+            transaction,
+        }))
+    }
+    async fn ping(&self) -> UdmResult<()> {
+        self.connection.ping().await
+    }
+}
+
+#[async_trait]
+impl DatabaseTransactionsFactory for MonitoredConnection {
+    async fn collect_all_current_tables(&mut self) -> UdmResult<Vec<String>> {
+        self.ensure_available()?;
+        let result = self.connection.collect_all_current_tables().await;
+        self.observe(result)
+    }
+    async fn execute_schema(&self, statements: Vec<SchemaStatement>) -> UdmResult<()> {
+        self.ensure_available()?;
+        self.observe(self.connection.execute_schema(statements).await)
+    }
+    async fn truncate_schema(&self) -> UdmResult<()> {
+        self.ensure_available()?;
+        self.observe(self.connection.truncate_schema().await)
+    }
+}
+
+// Observes every statement of a transaction and its commit, so a connection
+// lost halfway through takes the database offline like any other statement
+struct MonitoredTransaction<'a> {
+    monitor: &'a MonitoredConnection,
+    transaction: Box<dyn DbTransaction + 'a>,
+}
+
+#[async_trait]
+impl DbTransaction for MonitoredTransaction<'_> {
+    async fn insert(&mut self, stmt: InsertStatement) -> UdmResult<i32> {
+        let result = self.transaction.insert(stmt).await;
+        self.monitor.observe(result)
+    }
+    async fn delete(&mut self, stmt: DeleteStatement) -> UdmResult<()> {
+        let result = self.transaction.delete(stmt).await;
+        self.monitor.observe(result)
+    }
+    async fn update(&mut self, stmt: UpdateStatement) -> UdmResult<i32> {
+        let result = self.transaction.update(stmt).await;
+        self.monitor.observe(result)
+    }
+    async fn select(&mut self, stmt: SelectStatement) -> UdmResult<Vec<DbRow>> {
+        let result = self.transaction.select(stmt).await;
+        self.monitor.observe(result)
+    }
+    async fn commit(self: Box<Self>) -> UdmResult<()> {
+        let result = self.transaction.commit().await;
+        self.monitor.observe(result)
+    }
+    async fn rollback(self: Box<Self>) -> UdmResult<()> {
+        let result = self.transaction.rollback().await;
+        self.monitor.observe(result)
+    }
+}
+
+// Doubles with every failed probe up to MAX_BACKOFF
+fn backoff(failed_probes: u32) -> Duration {
+    let doublings = failed_probes.saturating_sub(1).min(16);
+    MIN_BACKOFF.saturating_mul(1 << doublings).min(MAX_BACKOFF)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::sqlite::conn::OpenSqliteConnection;
+    use crate::parsers::settings::SqliteConfigurer;
+    use sea_query::Expr;
+    use sea_query::Query;
+
+    async fn open() -> MonitoredConnection {
+        let settings = SqliteConfigurer {
+            db_path: ":memory:".to_string(),
+        };
+        MonitoredConnection::new(Box::new(OpenSqliteConnection::new(settings).await))
+    }
+
+    #[test]
+    fn test_backoff_grows_until_capped() {
+        assert_eq!(backoff(1), MIN_BACKOFF);
+        assert_eq!(backoff(2), MIN_BACKOFF * 2);
+        assert_eq!(backoff(4), MIN_BACKOFF * 8);
+        assert_eq!(backoff(20), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_lost_connection_fails_fast_until_probed() {
+        let conn = open().await;
+        assert!(conn.probe().await.available);
+        let lost: UdmResult<()> = Err(UdmError::DatabaseUnavailable("closed".to_string()));
+        assert!(conn.observe(lost).is_err());
+        let health = conn.health();
+        assert!(!health.available);
+        assert_eq!(health.message, "closed");
+        assert!(health.unavailable_since.is_some());
+
+        let query = Query::select().expr(Expr::val(1)).to_owned();
+        assert!(matches!(
+            conn.select(query.clone()).await,
+            Err(UdmError::DatabaseUnavailable(_))
+        ));
+        assert!(conn.probe().await.available);
+        assert!(conn.select(query).await.is_ok());
+    }
+
+    // A transaction whose connection dropped after it began
+    struct LostTransaction;
+
+    #[async_trait]
+    impl DbTransaction for LostTransaction {
+        async fn insert(&mut self, _stmt: InsertStatement) -> UdmResult<i32> {
+            Err(UdmError::DatabaseUnavailable("closed".to_string()))
+        }
+        async fn delete(&mut self, _stmt: DeleteStatement) -> UdmResult<()> {
+            Err(UdmError::DatabaseUnavailable("closed".to_string()))
+        }
+        async fn update(&mut self, _stmt: UpdateStatement) -> UdmResult<i32> {
+            Err(UdmError::DatabaseUnavailable("closed".to_string()))
+        }
+        async fn select(&mut self, _stmt: SelectStatement) -> UdmResult<Vec<DbRow>> {
+            Err(UdmError::DatabaseUnavailable("closed".to_string()))
+        }
+        async fn commit(self: Box<Self>) -> UdmResult<()> {
+            Err(UdmError::DatabaseUnavailable("closed".to_string()))
+        }
+        async fn rollback(self: Box<Self>) -> UdmResult<()> {
+            Err(UdmError::DatabaseUnavailable("closed".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transactions_are_observed() {
+        let conn = open().await;
+        let mut transaction = conn.begin().await.unwrap();
+        let query = Query::select().expr(Expr::val(1)).to_owned();
+        assert!(transaction.select(query).await.is_ok());
+        transaction.commit().await.unwrap();
+        assert!(conn.is_available());
+
+        let mut lost = MonitoredTransaction {
+            monitor: &conn,
+            transaction: Box::new(LostTransaction),
+        };
+        let query = Query::select().expr(Expr::val(1)).to_owned();
+        assert!(lost.select(query).await.is_err());
+        assert!(!conn.is_available());
+        assert!(conn.probe().await.available);
+
+        let lost = Box::new(MonitoredTransaction {
+            monitor: &conn,
+            transaction: Box::new(LostTransaction),
+        });
+        assert!(lost.commit().await.is_err());
+        assert!(!conn.is_available());
+    }
+
+    #[tokio::test]
+    async fn test_other_errors_keep_the_database_available() {
+        let conn = open().await;
+        let failed: UdmResult<()> = Err(UdmError::ApiFailure("syntax error".to_string()));
+        assert!(conn.observe(failed).is_err());
+        assert!(conn.is_available());
+    }
+}
@@ -0,0 +1,595 @@
+// Ordered history of the database schema. Any change to a *Schema enum needs
+// a new migration appended to MIGRATIONS, usually an ALTER TABLE, migrations
+// that have been released are never edited. Each migration spells out its own
+// table and column names instead of going through the *Schema enums, so
+// editing an enum can't rewrite what a released migration runs. Applied
+// versions are recorded in schema_migrations
+use crate::db::DbConnection;
+use crate::db::SchemaStatement;
+use crate::error::UdmError;
+use crate::UdmResult;
+use sea_query::Alias;
+use sea_query::Asterisk;
+use sea_query::ColumnDef;
+use sea_query::Expr;
+use sea_query::ForeignKey;
+use sea_query::ForeignKeyAction;
+use sea_query::ForeignKeyCreateStatement;
+use sea_query::Iden;
+use sea_query::Query;
+use sea_query::Table;
+use sea_query::TableStatement;
+use sea_query::Value;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+#[derive(Iden)]
+#[iden = "schema_migrations"]
+enum SchemaMigrationsSchema {
+    Table,
+    Version,
+    Name,
+    AppliedAt,
+}
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    up: fn() -> Vec<TableStatement>,
+    down: fn() -> Vec<TableStatement>,
+}
+
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_core_tables",
+        up: create_core_tables,
+        down: || {
+            vec![
+                drop_table("InstructionToRecipe"),
+                drop_table("Ingredient"),
+                drop_table("Recipe"),
+                drop_table("Instruction"),
+                drop_table("FluidRegulation"),
+            ]
+        },
+    },
+    Migration {
+        version: 2,
+        name: "create_flow_calibration",
+        up: create_flow_calibration,
+        down: || vec![drop_table("FlowCalibration")],
+    },
+    Migration {
+        version: 3,
+        name: "create_drink_order",
+        up: create_drink_order,
+        down: || vec![drop_table("DrinkOrder")],
+    },
+    Migration {
+        version: 4,
+        name: "create_bottle",
+        up: create_bottle,
+        down: || vec![drop_table("Bottle")],
+    },
+];
+
+// Tables are created if they don't exist so databases set up before
+// migrations existed are adopted by running `migrate up`
+fn create_core_tables() -> Vec<TableStatement> {
+    let fluid_regulation = Table::create()
+        .table(Alias::new("FluidRegulation"))
+        .if_not_exists()
+        .col(&mut serial("fr_id"))
+        .col(column("regulator_type").integer().not_null())
+        .col(column("gpio_pin").integer())
+        .to_owned();
+    let instruction = Table::create()
+        .table(Alias::new("Instruction"))
+        .if_not_exists()
+        .col(&mut serial("instruction_id"))
+        .col(column("instruction_detail").text())
+        .col(column("instruction_name").text().not_null())
+        .to_owned();
+    let recipe = Table::create()
+        .table(Alias::new("Recipe"))
+        .if_not_exists()
+        .col(&mut serial("recipe_id"))
+        .col(column("name").text().not_null().unique_key())
+        .col(
+            column("user_input")
+                .boolean()
+                .not_null()
+                .default(Value::Bool(Some(false))),
+        )
+        .col(
+            column("drink_size")
+                .integer()
+                .not_null()
+                .default(Value::Int(Some(0))),
+        )
+        .col(column("description").text().not_null().unique_key())
+        .to_owned();
+    let ingredient = Table::create()
+        .table(Alias::new("Ingredient"))
+        .if_not_exists()
+        .col(&mut serial("ingredient_id"))
+        .col(column("name").text().not_null())
+        .col(
+            column("alcoholic")
+                .boolean()
+                .not_null()
+                .default(Value::Bool(Some(false))),
+        )
+        .col(column("description").text())
+        .col(
+            column("is_active")
+                .boolean()
+                .not_null()
+                .default(Value::Bool(Some(false))),
+        )
+        .col(column("amount").float())
+        .col(column("ingredient_type").integer().not_null())
+        .col(column("fr_id").integer())
+        .col(column("instruction_id").integer())
+        .foreign_key(&mut foreign_key(
+            "fk_fluidregulation",
+            ("Ingredient", "fr_id"),
+            ("FluidRegulation", "fr_id"),
+            ForeignKeyAction::SetNull,
+        ))
+        .foreign_key(&mut foreign_key(
+            "fk_instruction",
+            ("Ingredient", "instruction_id"),
+            ("Instruction", "instruction_id"),
+            ForeignKeyAction::SetNull,
+        ))
+        .to_owned();
+    let instruction_to_recipe = Table::create()
+        .table(Alias::new("InstructionToRecipe"))
+        .if_not_exists()
+        .col(&mut serial("id"))
+        .col(column("recipe_id").integer())
+        .col(column("instruction_id").integer())
+        .col(column("instruction_order").integer().not_null())
+        .foreign_key(&mut foreign_key(
+            "fk_recipe",
+            ("InstructionToRecipe", "recipe_id"),
+            ("Recipe", "recipe_id"),
+            ForeignKeyAction::SetNull,
+        ))
+        .foreign_key(&mut foreign_key(
+            "fk_instruction",
+            ("InstructionToRecipe", "instruction_id"),
+            ("Instruction", "instruction_id"),
+            ForeignKeyAction::SetNull,
+        ))
+        .to_owned();
+    vec![
+        TableStatement::Create(fluid_regulation),
+        TableStatement::Create(instruction),
+        TableStatement::Create(recipe),
+        TableStatement::Create(ingredient),
+        TableStatement::Create(instruction_to_recipe),
+    ]
+}
+
+fn create_flow_calibration() -> Vec<TableStatement> {
+    let flow_calibration = Table::create()
+        .table(Alias::new("FlowCalibration"))
+        .if_not_exists()
+        .col(column("fr_id").integer().not_null().primary_key())
+        .col(column("ml_per_second").float().not_null())
+        .col(
+            column("startup_lag_ms")
+                .integer()
+                .not_null()
+                .default(Value::Int(Some(0))),
+        )
+        .col(
+            column("drip_compensation_ml")
+                .float()
+                .not_null()
+                .default(Value::Float(Some(0.0))),
+        )
+        .foreign_key(&mut foreign_key(
+            "fk_calibration_fluidregulation",
+            ("FlowCalibration", "fr_id"),
+            ("FluidRegulation", "fr_id"),
+            ForeignKeyAction::Cascade,
+        ))
+        .to_owned();
+    vec![TableStatement::Create(flow_calibration)]
+}
+
+fn create_drink_order() -> Vec<TableStatement> {
+    let drink_order = Table::create()
+        .table(Alias::new("DrinkOrder"))
+        .if_not_exists()
+        .col(&mut serial("order_id"))
+        .col(column("recipe_id").integer().not_null())
+        .col(
+            column("drink_size")
+                .integer()
+                .not_null()
+                .default(Value::Int(Some(0))),
+        )
+        .col(column("requester").text().not_null())
+        .col(
+            column("priority")
+                .integer()
+                .not_null()
+                .default(Value::Int(Some(0))),
+        )
+        .col(column("position").integer().not_null())
+        .col(column("status").integer().not_null())
+        .col(column("pour_id").big_integer())
+        .col(column("message").text().not_null().default(""))
+        .foreign_key(&mut foreign_key(
+            "fk_drinkorder_recipe",
+            ("DrinkOrder", "recipe_id"),
+            ("Recipe", "recipe_id"),
+            ForeignKeyAction::Cascade,
+        ))
+        .to_owned();
+    vec![TableStatement::Create(drink_order)]
+}
+
+fn create_bottle() -> Vec<TableStatement> {
+    let bottle = Table::create()
+        .table(Alias::new("Bottle"))
+        .if_not_exists()
+        .col(column("fr_id").integer().not_null().primary_key())
+        .col(column("ingredient_id").integer())
+        .col(column("capacity_ml").float().not_null())
+        .col(column("remaining_ml").float().not_null())
+        .foreign_key(&mut foreign_key(
+            "fk_bottle_fluidregulation",
+            ("Bottle", "fr_id"),
+            ("FluidRegulation", "fr_id"),
+            ForeignKeyAction::Cascade,
+        ))
+        .foreign_key(&mut foreign_key(
+            "fk_bottle_ingredient",
+            ("Bottle", "ingredient_id"),
+            ("Ingredient", "ingredient_id"),
+            ForeignKeyAction::SetNull,
+        ))
+        .to_owned();
+    vec![TableStatement::Create(bottle)]
+}
+
+fn column(name: &'static str) -> ColumnDef {
+    ColumnDef::new(Alias::new(name))
+}
+
+fn serial(name: &'static str) -> ColumnDef {
+    column(name)
+        .integer()
+        .auto_increment()
+        .not_null()
+        .primary_key()
+        .to_owned()
+}
+
+// Both on delete and on update
+fn foreign_key(
+    name: &'static str,
+    (table, column): (&'static str, &'static str),
+    (to_table, to_column): (&'static str, &'static str),
+    action: ForeignKeyAction,
+) -> ForeignKeyCreateStatement {
+    ForeignKey::create()
+        .name(name)
+        .from(Alias::new(table), Alias::new(column))
+        .to(Alias::new(to_table), Alias::new(to_column))
+        .on_delete(action)
+        .on_update(action)
+        .to_owned()
+}
+
+fn drop_table(table: &'static str) -> TableStatement {
+    TableStatement::Drop(
+        Table::drop()
+            .table(Alias::new(table))
+            .if_exists()
+            .to_owned(),
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: String,
+    pub applied: bool,
+    // Applied by a newer release than this one
+    pub unknown: bool,
+}
+
+pub struct Migrator<'a> {
+    connection: &'a dyn DbConnection,
+}
+
+impl<'a> Migrator<'a> {
+    pub fn new(connection: &'a dyn DbConnection) -> Self {
+        Self { connection }
+    }
+    /// Every known migration plus any applied version this release doesn't
+    /// know about, ordered by version
+    pub async fn status(&self) -> UdmResult<Vec<MigrationStatus>> {
+        let mut applied = self.applied().await?;
+        let mut status: Vec<MigrationStatus> = MIGRATIONS
+            .iter()
+            .map(|migration| MigrationStatus {
+                version: migration.version,
+                name: migration.name.to_string(),
+                applied: applied.remove(&migration.version).is_some(),
+                unknown: false,
+            })
+            .collect();
+        status.extend(applied.into_iter().map(|(version, name)| MigrationStatus {
+            version,
+            name,
+            applied: true,
+            unknown: true,
+        }));
+        status.sort_by_key(|migration| migration.version);
+        Ok(status)
+    }
+    pub async fn pending(&self) -> UdmResult<Vec<&'static Migration>> {
+        let applied = self.applied().await?;
+        Ok(MIGRATIONS
+            .iter()
+            .filter(|migration| !applied.contains_key(&migration.version))
+            .collect())
+    }
+    /// Applies pending migrations in order, up to and including `target`
+    pub async fn up(&self, target: Option<i64>) -> UdmResult<Vec<&'static Migration>> {
+        let pending = self
+            .pending()
+            .await?
+            .into_iter()
+            .filter(|migration| target.is_none_or(|target| migration.version <= target))
+            .collect::<Vec<&'static Migration>>();
+        for migration in &pending {
+            tracing::info!(
+                "Applying migration {} {}",
+                migration.version,
+                migration.name
+            );
+            let mut statements = (migration.up)()
+                .into_iter()
+                .map(|statement| SchemaStatement::Table(Box::new(statement)))
+                .collect::<Vec<SchemaStatement>>();
+            statements.push(SchemaStatement::Insert(
+                Query::insert()
+                    .into_table(SchemaMigrationsSchema::Table)
+                    .columns([
+                        SchemaMigrationsSchema::Version,
+                        SchemaMigrationsSchema::Name,
+                        SchemaMigrationsSchema::AppliedAt,
+                    ])
+                    .values_panic([
+                        migration.version.into(),
+                        migration.name.into(),
+                        Self::now().into(),
+                    ])
+                    .to_owned(),
+            ));
+            self.connection.execute_schema(statements).await?;
+        }
+        Ok(pending)
+    }
+    /// Reverts applied migrations newer than `target`, newest first. Without
+    /// a target only the latest migration is reverted
+    pub async fn down(&self, target: Option<i64>) -> UdmResult<Vec<&'static Migration>> {
+        let applied = self.applied().await?;
+        let versions: Vec<i64> = match target {
+            Some(target) => applied.range(target + 1..).map(|(v, _)| *v).collect(),
+            None => applied.keys().next_back().copied().into_iter().collect(),
+        };
+        let mut reverted = Vec::new();
+        for version in versions.into_iter().rev() {
+            let migration = MIGRATIONS
+                .iter()
+                .find(|migration| migration.version == version)
+                .ok_or_else(|| {
+                    UdmError::InvalidInput(format!(
+                        "Migration {} was applied by a newer release and can't be reverted",
+                        version
+                    ))
+                })?;
+            tracing::info!(
+                "Reverting migration {} {}",
+                migration.version,
+                migration.name
+            );
+            let mut statements = (migration.down)()
+                .into_iter()
+                .map(|statement| SchemaStatement::Table(Box::new(statement)))
+                .collect::<Vec<SchemaStatement>>();
+            statements.push(SchemaStatement::Delete(
+                Query::delete()
+                    .from_table(SchemaMigrationsSchema::Table)
+                    .and_where(Expr::col(SchemaMigrationsSchema::Version).eq(version))
+                    .to_owned(),
+            ));
+            self.connection.execute_schema(statements).await?;
+            reverted.push(migration);
+        }
+        Ok(reverted)
+    }
+    // Applied versions and their names
+    async fn applied(&self) -> UdmResult<BTreeMap<i64, String>> {
+        let table = Table::create()
+            .table(SchemaMigrationsSchema::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(SchemaMigrationsSchema::Version)
+                    .big_integer()
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(
+                ColumnDef::new(SchemaMigrationsSchema::Name)
+                    .text()
+                    .not_null(),
+            )
+            .col(
+                ColumnDef::new(SchemaMigrationsSchema::AppliedAt)
+                    .big_integer()
+                    .not_null(),
+            )
+            .to_owned();
+        self.connection
+            .execute_schema(vec![SchemaStatement::Table(Box::new(
+                TableStatement::Create(table),
+            ))])
+            .await?;
+        let query = Query::select()
+            .column(Asterisk)
+            .from(SchemaMigrationsSchema::Table)
+            .to_owned();
+        self.connection
+            .select(query)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let version = row.try_get(SchemaMigrationsSchema::Version.to_string().as_str())?;
+                let name = row.try_get(SchemaMigrationsSchema::Name.to_string().as_str())?;
+                Ok((version, name))
+            })
+            .collect()
+    }
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::sqlite::conn::OpenSqliteConnection;
+    use crate::db::DatabaseTransactionsFactory;
+    use crate::parsers::settings::SqliteConfigurer;
+    use sea_query::PostgresQueryBuilder;
+    use sea_query::SqliteQueryBuilder;
+
+    async fn open() -> OpenSqliteConnection {
+        let settings = SqliteConfigurer {
+            db_path: ":memory:".to_string(),
+        };
+        OpenSqliteConnection::new(settings).await
+    }
+    fn versions(migrations: &[&Migration]) -> Vec<i64> {
+        migrations
+            .iter()
+            .map(|migration| migration.version)
+            .collect()
+    }
+
+    #[test]
+    fn test_versions_are_increasing() {
+        assert!(MIGRATIONS
+            .windows(2)
+            .all(|pair| pair[0].version < pair[1].version));
+    }
+
+    #[tokio::test]
+    async fn test_up_applies_pending_once() {
+        let conn = open().await;
+        let migrator = Migrator::new(&conn);
+        assert_eq!(migrator.pending().await.unwrap().len(), MIGRATIONS.len());
+        assert_eq!(versions(&migrator.up(Some(2)).await.unwrap()), vec![1, 2]);
+        assert_eq!(versions(&migrator.up(None).await.unwrap()), vec![3, 4]);
+        assert!(migrator.up(None).await.unwrap().is_empty());
+        assert!(migrator.pending().await.unwrap().is_empty());
+        assert!(migrator
+            .status()
+            .await
+            .unwrap()
+            .iter()
+            .all(|migration| migration.applied));
+    }
+
+    #[tokio::test]
+    async fn test_down_reverts_newest_first() {
+        let mut conn = open().await;
+        Migrator::new(&conn).up(None).await.unwrap();
+        let migrator = Migrator::new(&conn);
+        assert_eq!(versions(&migrator.down(None).await.unwrap()), vec![4]);
+        assert_eq!(versions(&migrator.pending().await.unwrap()), vec![4]);
+        assert_eq!(
+            versions(&migrator.down(Some(0)).await.unwrap()),
+            vec![3, 2, 1]
+        );
+        let tables = conn.collect_all_current_tables().await.unwrap();
+        // sqlite_sequence is sqlite's own bookkeeping for autoincrement
+        assert_eq!(
+            tables,
+            vec![
+                "schema_migrations".to_string(),
+                "sqlite_sequence".to_string()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_versions_block_down() {
+        let conn = open().await;
+        let migrator = Migrator::new(&conn);
+        migrator.up(None).await.unwrap();
+        let newer = Query::insert()
+            .into_table(SchemaMigrationsSchema::Table)
+            .columns([
+                SchemaMigrationsSchema::Version,
+                SchemaMigrationsSchema::Name,
+                SchemaMigrationsSchema::AppliedAt,
+            ])
+            .values_panic([99.into(), "from_the_future".into(), 0.into()])
+            .to_owned();
+        conn.execute_schema(vec![SchemaStatement::Insert(newer)])
+            .await
+            .unwrap();
+        let status = migrator.status().await.unwrap();
+        assert!(status.last().unwrap().unknown);
+        assert!(migrator.down(None).await.is_err());
+    }
+
+    // Released migrations must keep running the same statements whatever
+    // happens to the *Schema enums later on
+    #[test]
+    fn test_released_migrations_are_frozen() {
+        let sql = |version: i64, builder: &dyn Fn(&TableStatement) -> String| {
+            let migration = MIGRATIONS
+                .iter()
+                .find(|migration| migration.version == version)
+                .unwrap();
+            (migration.up)()
+                .iter()
+                .map(builder)
+                .collect::<Vec<String>>()
+        };
+        let sqlite = sql(1, &|statement| statement.to_string(SqliteQueryBuilder));
+        let expected = [
+            r#"CREATE TABLE IF NOT EXISTS "FluidRegulation" ( "fr_id" integer NOT NULL PRIMARY KEY AUTOINCREMENT, "regulator_type" integer NOT NULL, "gpio_pin" integer )"#,
+            r#"CREATE TABLE IF NOT EXISTS "Instruction" ( "instruction_id" integer NOT NULL PRIMARY KEY AUTOINCREMENT, "instruction_detail" text, "instruction_name" text NOT NULL )"#,
+            r#"CREATE TABLE IF NOT EXISTS "Recipe" ( "recipe_id" integer NOT NULL PRIMARY KEY AUTOINCREMENT, "name" text NOT NULL UNIQUE, "user_input" boolean NOT NULL DEFAULT FALSE, "drink_size" integer NOT NULL DEFAULT 0, "description" text NOT NULL UNIQUE )"#,
+            r#"CREATE TABLE IF NOT EXISTS "Ingredient" ( "ingredient_id" integer NOT NULL PRIMARY KEY AUTOINCREMENT, "name" text NOT NULL, "alcoholic" boolean NOT NULL DEFAULT FALSE, "description" text, "is_active" boolean NOT NULL DEFAULT FALSE, "amount" real, "ingredient_type" integer NOT NULL, "fr_id" integer, "instruction_id" integer, FOREIGN KEY ("fr_id") REFERENCES "FluidRegulation" ("fr_id") ON DELETE SET NULL ON UPDATE SET NULL, FOREIGN KEY ("instruction_id") REFERENCES "Instruction" ("instruction_id") ON DELETE SET NULL ON UPDATE SET NULL )"#,
+            r#"CREATE TABLE IF NOT EXISTS "InstructionToRecipe" ( "id" integer NOT NULL PRIMARY KEY AUTOINCREMENT, "recipe_id" integer, "instruction_id" integer, "instruction_order" integer NOT NULL, FOREIGN KEY ("recipe_id") REFERENCES "Recipe" ("recipe_id") ON DELETE SET NULL ON UPDATE SET NULL, FOREIGN KEY ("instruction_id") REFERENCES "Instruction" ("instruction_id") ON DELETE SET NULL ON UPDATE SET NULL )"#,
+        ];
+        assert_eq!(sqlite, expected);
+        let postgres = sql(1, &|statement| statement.to_string(PostgresQueryBuilder));
+        let expected = [
+            r#"CREATE TABLE IF NOT EXISTS "FluidRegulation" ( "fr_id" serial NOT NULL PRIMARY KEY, "regulator_type" integer NOT NULL, "gpio_pin" integer )"#,
+            r#"CREATE TABLE IF NOT EXISTS "Instruction" ( "instruction_id" serial NOT NULL PRIMARY KEY, "instruction_detail" text, "instruction_name" text NOT NULL )"#,
+            r#"CREATE TABLE IF NOT EXISTS "Recipe" ( "recipe_id" serial NOT NULL PRIMARY KEY, "name" text NOT NULL UNIQUE, "user_input" bool NOT NULL DEFAULT FALSE, "drink_size" integer NOT NULL DEFAULT 0, "description" text NOT NULL UNIQUE )"#,
+            r#"CREATE TABLE IF NOT EXISTS "Ingredient" ( "ingredient_id" serial NOT NULL PRIMARY KEY, "name" text NOT NULL, "alcoholic" bool NOT NULL DEFAULT FALSE, "description" text, "is_active" bool NOT NULL DEFAULT FALSE, "amount" real, "ingredient_type" integer NOT NULL, "fr_id" integer, "instruction_id" integer, CONSTRAINT "fk_fluidregulation" FOREIGN KEY ("fr_id") REFERENCES "FluidRegulation" ("fr_id") ON DELETE SET NULL ON UPDATE SET NULL, CONSTRAINT "fk_instruction" FOREIGN KEY ("instruction_id") REFERENCES "Instruction" ("instruction_id") ON DELETE SET NULL ON UPDATE SET NULL )"#,
+            r#"CREATE TABLE IF NOT EXISTS "InstructionToRecipe" ( "id" serial NOT NULL PRIMARY KEY, "recipe_id" integer, "instruction_id" integer, "instruction_order" integer NOT NULL, CONSTRAINT "fk_recipe" FOREIGN KEY ("recipe_id") REFERENCES "Recipe" ("recipe_id") ON DELETE SET NULL ON UPDATE SET NULL, CONSTRAINT "fk_instruction" FOREIGN KEY ("instruction_id") REFERENCES "Instruction" ("instruction_id") ON DELETE SET NULL ON UPDATE SET NULL )"#,
+        ];
+        assert_eq!(postgres, expected);
+    }
+}
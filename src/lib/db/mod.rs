@@ -1,21 +1,35 @@
 use std::fmt::Display;
-use tokio_postgres::Row;
 use tonic::async_trait;
 
+use crate::db::row::DbRow;
 use crate::error::UdmError;
 use crate::parsers::settings;
 use crate::rpc_types::fhs_types::RegulatorType;
+use crate::rpc_types::recipe_types::DrinkSize;
+use crate::rpc_types::recipe_types::IngredientType;
+use crate::rpc_types::service_types::OrderStatus;
 use crate::rpc_types::MultipleValues;
 use crate::UdmResult;
+use sea_query::backend::QueryBuilder;
+use sea_query::backend::SchemaBuilder;
 use sea_query::foreign_key::ForeignKeyAction;
 use sea_query::foreign_key::ForeignKeyCreateStatement;
 use sea_query::value::Value;
 use sea_query::ColumnDef;
+use sea_query::DeleteStatement;
 use sea_query::Iden;
+use sea_query::InsertStatement;
+use sea_query::SelectStatement;
 use sea_query::Table;
+use sea_query::TableCreateStatement;
+use sea_query::TableStatement;
+use sea_query::UpdateStatement;
 use std::sync::Arc;
 pub mod executor;
+pub mod health;
+pub mod migrations;
 pub mod postgres;
+pub mod row;
 pub mod sqlite;
 
 // Build "loadable" different db types with their relevant information
@@ -27,12 +41,73 @@ pub trait SqlTransactionsFactory: Display {
     fn from_str(value: &'static str) -> Option<Self>
     where
         Self: Sized;
+    // None for the Table variant
+    fn column_type(&self) -> Option<ColumnType>;
+}
+
+// What a column holds, filter values arrive as text and are converted to it
+// before they are bound
+#[derive(Debug, Clone, Copy)]
+pub enum ColumnType {
+    Int,
+    Float,
+    Bool,
+    Text,
+    // Stored as the number of a protobuf enum, values can also be given by
+    // their name e.g. REGULATOR_TYPE_PUMP. Resolves either to a number the
+    // enum defines
+    Enum(fn(&str) -> Option<i32>),
+}
+
+// Numbers go through the enum as well so only values it defines get stored
+fn enum_value<E>(value: &str, from_name: fn(&str) -> Option<E>) -> Option<i32>
+where
+    E: TryFrom<i32> + Into<i32>,
+{
+    match value.parse::<i32>() {
+        Ok(number) => E::try_from(number).ok().map(Into::into),
+        Err(_) => from_name(value).map(Into::into),
+    }
+}
+
+impl ColumnType {
+    pub fn to_value(&self, column: &str, value: &str) -> UdmResult<Value> {
+        let invalid = |expected: &str| {
+            UdmError::InvalidInput(format!("{} expects {}, got '{}'", column, expected, value))
+        };
+        match self {
+            ColumnType::Int => value
+                .trim()
+                .parse::<i64>()
+                .map(Value::from)
+                .map_err(|_| invalid("an int")),
+            ColumnType::Float => value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|float| float.is_finite())
+                .map(Value::from)
+                .ok_or_else(|| invalid("a finite float")),
+            ColumnType::Bool => match value.trim().to_lowercase().as_str() {
+                "true" => Ok(Value::from(true)),
+                "false" => Ok(Value::from(false)),
+                _ => Err(invalid("true or false")),
+            },
+            ColumnType::Text => Ok(Value::from(value)),
+            ColumnType::Enum(resolve) => resolve(value.trim())
+                .map(Value::from)
+                .ok_or_else(|| invalid("an enum name or number")),
+        }
+    }
 }
 
 // This generates schemas and manupulates tables outside of the data itself
 // This ipml on each individual table you want to
 pub trait SqlTableTransactionsFactory: SqlTransactionsFactory {
-    fn create_table(builder: impl sea_query::backend::SchemaBuilder) -> String;
+    fn create_table_statement() -> TableCreateStatement;
+    fn create_table(builder: impl sea_query::backend::SchemaBuilder) -> String {
+        Self::create_table_statement().build(builder)
+    }
     fn alter_table(
         builder: impl sea_query::backend::SchemaBuilder,
         column_def: &mut ColumnDef,
@@ -45,21 +120,57 @@ pub trait SqlTableTransactionsFactory: SqlTransactionsFactory {
     }
 }
 
+// Schema changes together with their bookkeeping rows. Only statements
+// built by the daemon itself belong here, values are rendered inline
+pub enum SchemaStatement {
+    Table(Box<TableStatement>),
+    Insert(InsertStatement),
+    Delete(DeleteStatement),
+}
+
+impl SchemaStatement {
+    pub fn to_string<T: SchemaBuilder + QueryBuilder>(&self, builder: T) -> String {
+        match self {
+            Self::Table(stmt) => stmt.to_string(builder),
+            Self::Insert(stmt) => stmt.to_string(builder),
+            Self::Delete(stmt) => stmt.to_string(builder),
+        }
+    }
+}
+
 // This Generates and executes the actual queries
 #[async_trait]
 pub trait DatabaseTransactionsFactory {
     async fn collect_all_current_tables(&mut self) -> UdmResult<Vec<String>>;
-    async fn gen_schmea(&mut self) -> UdmResult<()>;
+    // Runs every statement or none of them
+    async fn execute_schema(&self, statements: Vec<SchemaStatement>) -> UdmResult<()>;
     async fn truncate_schema(&self) -> UdmResult<()>;
 }
 
 #[async_trait]
 pub trait DbConnection: DatabaseTransactionsFactory + Send + Sync {
-    // Documentation for datatypes: https://docs.rs/postgres/0.14.0/postgres/types/trait.FromSql.html#types
-    async fn insert(&self, stmt: String) -> UdmResult<i32>;
-    async fn delete(&self, stmt: String) -> UdmResult<()>;
-    async fn update(&self, stmt: String) -> UdmResult<i32>;
-    async fn select(&self, stmt: String) -> UdmResult<Vec<Row>>;
+    // Statements are rendered in the dialect of the connection
+    async fn insert(&self, stmt: InsertStatement) -> UdmResult<i32>;
+    async fn delete(&self, stmt: DeleteStatement) -> UdmResult<()>;
+    async fn update(&self, stmt: UpdateStatement) -> UdmResult<i32>;
+    async fn select(&self, stmt: SelectStatement) -> UdmResult<Vec<DbRow>>;
+    /// Starts a transaction, other statements on the connection wait until it
+    /// is committed or rolled back
+    async fn begin<'a>(&'a self) -> UdmResult<Box<dyn DbTransaction + 'a>>;
+    /// Cheapest round trip to the database, used by the health probe
+    async fn ping(&self) -> UdmResult<()>;
+}
+
+// Statements run on a transaction only become visible once it is committed.
+// Dropping a transaction without committing rolls it back
+#[async_trait]
+pub trait DbTransaction: Send {
+    async fn insert(&mut self, stmt: InsertStatement) -> UdmResult<i32>;
+    async fn delete(&mut self, stmt: DeleteStatement) -> UdmResult<()>;
+    async fn update(&mut self, stmt: UpdateStatement) -> UdmResult<i32>;
+    async fn select(&mut self, stmt: SelectStatement) -> UdmResult<Vec<DbRow>>;
+    async fn commit(self: Box<Self>) -> UdmResult<()>;
+    async fn rollback(self: Box<Self>) -> UdmResult<()>;
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -76,14 +187,14 @@ impl DbMetaData {
 // A loadable enum depending on the mechanism is chosen
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum DbType {
-    Postgres(settings::PostgresConfigurer),
+    Postgres(Box<settings::PostgresConfigurer>),
     Sqlite(settings::SqliteConfigurer),
 }
 impl DbType {
     pub fn load_db(udm_configurer: Arc<settings::UdmConfigurer>) -> Self {
         if let Some(postgres_configurer) = udm_configurer.daemon.postgres.clone() {
             tracing::info!("Using postgres as the Database");
-            Self::Postgres(postgres_configurer)
+            Self::Postgres(Box::new(postgres_configurer))
         } else if let Some(sqlite_configurer) = udm_configurer.daemon.sqlite.clone() {
             tracing::info!("Using sqlite as the database");
             Self::Sqlite(sqlite_configurer)
@@ -93,9 +204,9 @@ impl DbType {
     }
     pub async fn establish_connection(&self) -> Box<dyn DbConnection> {
         match self {
-            DbType::Postgres(config) => {
-                Box::new(postgres::conn::OpenPostgresConnection::new(config.to_owned()).await)
-            }
+            DbType::Postgres(config) => Box::new(
+                postgres::conn::OpenPostgresConnection::new(config.as_ref().to_owned()).await,
+            ),
             DbType::Sqlite(config) => {
                 Box::new(sqlite::conn::OpenSqliteConnection::new(config.to_owned()).await)
             }
@@ -132,6 +243,15 @@ impl SqlTransactionsFactory for FluidRegulationSchema {
             _ => None,
         }
     }
+    fn column_type(&self) -> Option<ColumnType> {
+        match self {
+            Self::Table => None,
+            Self::FrId | Self::GpioPin => Some(ColumnType::Int),
+            Self::RegulatorType => Some(ColumnType::Enum(|value| {
+                enum_value(value, RegulatorType::from_str_name)
+            })),
+        }
+    }
 }
 
 impl Display for FluidRegulationSchema {
@@ -148,7 +268,7 @@ impl Display for FluidRegulationSchema {
     }
 }
 impl SqlTableTransactionsFactory for FluidRegulationSchema {
-    fn create_table(builder: impl sea_query::backend::SchemaBuilder) -> String {
+    fn create_table_statement() -> TableCreateStatement {
         Table::create()
             .table(Self::Table)
             .if_not_exists()
@@ -161,7 +281,7 @@ impl SqlTableTransactionsFactory for FluidRegulationSchema {
             )
             .col(ColumnDef::new(Self::RegulatorType).integer().not_null())
             .col(ColumnDef::new(Self::GpioPin).integer())
-            .build(builder)
+            .to_owned()
     }
 
     fn alter_table(
@@ -188,6 +308,373 @@ impl TryFrom<String> for FluidRegulationSchema {
     }
 }
 #[derive(Iden, Eq, PartialEq, Debug)]
+#[iden = "FlowCalibration"]
+pub enum FlowCalibrationSchema {
+    Table,
+    FrId, // Primary and Foreign Key
+    MlPerSecond,
+    StartupLagMs,
+    DripCompensationMl,
+}
+impl SqlTransactionsFactory for FlowCalibrationSchema {
+    fn column_to_str(&self) -> &'static str {
+        match self {
+            Self::Table => "FlowCalibration",
+            Self::FrId => "fr_id",
+            Self::MlPerSecond => "ml_per_second",
+            Self::StartupLagMs => "startup_lag_ms",
+            Self::DripCompensationMl => "drip_compensation_ml",
+        }
+    }
+    fn from_str(value: &'static str) -> Option<Self> {
+        match value {
+            "FlowCalibration" => Some(Self::Table),
+            "fr_id" => Some(Self::FrId),
+            "ml_per_second" => Some(Self::MlPerSecond),
+            "startup_lag_ms" => Some(Self::StartupLagMs),
+            "drip_compensation_ml" => Some(Self::DripCompensationMl),
+            _ => None,
+        }
+    }
+    fn column_type(&self) -> Option<ColumnType> {
+        match self {
+            Self::Table => None,
+            Self::FrId | Self::StartupLagMs => Some(ColumnType::Int),
+            Self::MlPerSecond | Self::DripCompensationMl => Some(ColumnType::Float),
+        }
+    }
+}
+impl Display for FlowCalibrationSchema {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Valid Fields are:\n\
+        fr_id: int\n\
+        ml_per_second: float\n\
+        startup_lag_ms: int\n\
+        drip_compensation_ml: float\n\
+        "
+        )
+    }
+}
+impl TryFrom<String> for FlowCalibrationSchema {
+    type Error = UdmError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "FlowCalibration" => Ok(Self::Table),
+            "fr_id" => Ok(Self::FrId),
+            "ml_per_second" => Ok(Self::MlPerSecond),
+            "startup_lag_ms" => Ok(Self::StartupLagMs),
+            "drip_compensation_ml" => Ok(Self::DripCompensationMl),
+            _ => Err(UdmError::ApiFailure(
+                "Failed to collect FlowCalibrationSchema Column".to_string(),
+            )),
+        }
+    }
+}
+impl SqlTableTransactionsFactory for FlowCalibrationSchema {
+    fn create_table_statement() -> TableCreateStatement {
+        Table::create()
+            .table(Self::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(Self::FrId)
+                    .integer()
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new(Self::MlPerSecond).float().not_null())
+            .col(
+                ColumnDef::new(Self::StartupLagMs)
+                    .integer()
+                    .not_null()
+                    .default(Value::Int(Some(0))),
+            )
+            .col(
+                ColumnDef::new(Self::DripCompensationMl)
+                    .float()
+                    .not_null()
+                    .default(Value::Float(Some(0.0))),
+            )
+            .foreign_key(
+                ForeignKeyCreateStatement::new()
+                    .name("fk_calibration_fluidregulation")
+                    .from(Self::Table, Self::FrId)
+                    .to(FluidRegulationSchema::Table, FluidRegulationSchema::FrId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_owned()
+    }
+
+    fn alter_table(
+        builder: impl sea_query::backend::SchemaBuilder,
+        column_def: &mut ColumnDef,
+    ) -> String {
+        Table::alter()
+            .table(Self::Table)
+            .add_column(column_def)
+            .build(builder)
+    }
+}
+#[derive(Iden, Eq, PartialEq, Debug)]
+#[iden = "Bottle"]
+pub enum BottleSchema {
+    Table,
+    FrId,         // Primary and Foreign Key
+    IngredientId, // Foreign Key
+    CapacityMl,
+    RemainingMl,
+}
+impl SqlTransactionsFactory for BottleSchema {
+    fn column_to_str(&self) -> &'static str {
+        match self {
+            Self::Table => "Bottle",
+            Self::FrId => "fr_id",
+            Self::IngredientId => "ingredient_id",
+            Self::CapacityMl => "capacity_ml",
+            Self::RemainingMl => "remaining_ml",
+        }
+    }
+    fn from_str(value: &'static str) -> Option<Self> {
+        match value {
+            "Bottle" => Some(Self::Table),
+            "fr_id" => Some(Self::FrId),
+            "ingredient_id" => Some(Self::IngredientId),
+            "capacity_ml" => Some(Self::CapacityMl),
+            "remaining_ml" => Some(Self::RemainingMl),
+            _ => None,
+        }
+    }
+    fn column_type(&self) -> Option<ColumnType> {
+        match self {
+            Self::Table => None,
+            Self::FrId | Self::IngredientId => Some(ColumnType::Int),
+            Self::CapacityMl | Self::RemainingMl => Some(ColumnType::Float),
+        }
+    }
+}
+impl Display for BottleSchema {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Valid Fields are:\n\
+        fr_id: int\n\
+        ingredient_id: int\n\
+        capacity_ml: float\n\
+        remaining_ml: float\n\
+        "
+        )
+    }
+}
+impl TryFrom<String> for BottleSchema {
+    type Error = UdmError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "Bottle" => Ok(Self::Table),
+            "fr_id" => Ok(Self::FrId),
+            "ingredient_id" => Ok(Self::IngredientId),
+            "capacity_ml" => Ok(Self::CapacityMl),
+            "remaining_ml" => Ok(Self::RemainingMl),
+            _ => Err(UdmError::ApiFailure(
+                "Failed to collect BottleSchema Column".to_string(),
+            )),
+        }
+    }
+}
+impl SqlTableTransactionsFactory for BottleSchema {
+    fn create_table_statement() -> TableCreateStatement {
+        Table::create()
+            .table(Self::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(Self::FrId)
+                    .integer()
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new(Self::IngredientId).integer())
+            .col(ColumnDef::new(Self::CapacityMl).float().not_null())
+            .col(ColumnDef::new(Self::RemainingMl).float().not_null())
+            .foreign_key(
+                ForeignKeyCreateStatement::new()
+                    .name("fk_bottle_fluidregulation")
+                    .from(Self::Table, Self::FrId)
+                    .to(FluidRegulationSchema::Table, FluidRegulationSchema::FrId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .foreign_key(
+                ForeignKeyCreateStatement::new()
+                    .name("fk_bottle_ingredient")
+                    .from(Self::Table, Self::IngredientId)
+                    .to(IngredientSchema::Table, IngredientSchema::IngredientId)
+                    .on_delete(ForeignKeyAction::SetNull)
+                    .on_update(ForeignKeyAction::SetNull),
+            )
+            .to_owned()
+    }
+
+    fn alter_table(
+        builder: impl sea_query::backend::SchemaBuilder,
+        column_def: &mut ColumnDef,
+    ) -> String {
+        Table::alter()
+            .table(Self::Table)
+            .add_column(column_def)
+            .build(builder)
+    }
+}
+#[derive(Iden, Eq, PartialEq, Debug)]
+#[iden = "DrinkOrder"]
+pub enum DrinkOrderSchema {
+    Table,
+    OrderId,
+    RecipeId, // Foreign Key
+    DrinkSize,
+    Requester,
+    Priority,
+    Position,
+    Status,
+    PourId,
+    Message,
+}
+impl SqlTransactionsFactory for DrinkOrderSchema {
+    fn column_to_str(&self) -> &'static str {
+        match self {
+            Self::Table => "DrinkOrder",
+            Self::OrderId => "order_id",
+            Self::RecipeId => "recipe_id",
+            Self::DrinkSize => "drink_size",
+            Self::Requester => "requester",
+            Self::Priority => "priority",
+            Self::Position => "position",
+            Self::Status => "status",
+            Self::PourId => "pour_id",
+            Self::Message => "message",
+        }
+    }
+    fn from_str(value: &'static str) -> Option<Self> {
+        match value {
+            "DrinkOrder" => Some(Self::Table),
+            "order_id" => Some(Self::OrderId),
+            "recipe_id" => Some(Self::RecipeId),
+            "drink_size" => Some(Self::DrinkSize),
+            "requester" => Some(Self::Requester),
+            "priority" => Some(Self::Priority),
+            "position" => Some(Self::Position),
+            "status" => Some(Self::Status),
+            "pour_id" => Some(Self::PourId),
+            "message" => Some(Self::Message),
+            _ => None,
+        }
+    }
+    fn column_type(&self) -> Option<ColumnType> {
+        match self {
+            Self::Table => None,
+            Self::OrderId | Self::RecipeId | Self::Priority | Self::Position | Self::PourId => {
+                Some(ColumnType::Int)
+            }
+            Self::Requester | Self::Message => Some(ColumnType::Text),
+            Self::DrinkSize => Some(ColumnType::Enum(|value| {
+                enum_value(value, DrinkSize::from_str_name)
+            })),
+            Self::Status => Some(ColumnType::Enum(|value| {
+                enum_value(value, OrderStatus::from_str_name)
+            })),
+        }
+    }
+}
+impl Display for DrinkOrderSchema {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Valid Fields are:\n\
+        order_id: int\n\
+        recipe_id: int\n\
+        drink_size: int\n\
+        requester: string\n\
+        priority: int\n\
+        position: int\n\
+        status: int\n\
+        pour_id: int\n\
+        message: string\n\
+        "
+        )
+    }
+}
+impl TryFrom<String> for DrinkOrderSchema {
+    type Error = UdmError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "DrinkOrder" => Ok(Self::Table),
+            "order_id" => Ok(Self::OrderId),
+            "recipe_id" => Ok(Self::RecipeId),
+            "drink_size" => Ok(Self::DrinkSize),
+            "requester" => Ok(Self::Requester),
+            "priority" => Ok(Self::Priority),
+            "position" => Ok(Self::Position),
+            "status" => Ok(Self::Status),
+            "pour_id" => Ok(Self::PourId),
+            "message" => Ok(Self::Message),
+            _ => Err(UdmError::ApiFailure(
+                "Failed to collect DrinkOrderSchema Column".to_string(),
+            )),
+        }
+    }
+}
+impl SqlTableTransactionsFactory for DrinkOrderSchema {
+    fn create_table_statement() -> TableCreateStatement {
+        Table::create()
+            .table(Self::Table)
+            .if_not_exists()
+            .col(
+                ColumnDef::new(Self::OrderId)
+                    .integer()
+                    .auto_increment()
+                    .not_null()
+                    .primary_key(),
+            )
+            .col(ColumnDef::new(Self::RecipeId).integer().not_null())
+            .col(
+                ColumnDef::new(Self::DrinkSize)
+                    .integer()
+                    .not_null()
+                    .default(Value::Int(Some(0))),
+            )
+            .col(ColumnDef::new(Self::Requester).text().not_null())
+            .col(
+                ColumnDef::new(Self::Priority)
+                    .integer()
+                    .not_null()
+                    .default(Value::Int(Some(0))),
+            )
+            .col(ColumnDef::new(Self::Position).integer().not_null())
+            .col(ColumnDef::new(Self::Status).integer().not_null())
+            .col(ColumnDef::new(Self::PourId).big_integer())
+            .col(ColumnDef::new(Self::Message).text().not_null().default(""))
+            .foreign_key(
+                ForeignKeyCreateStatement::new()
+                    .name("fk_drinkorder_recipe")
+                    .from(Self::Table, Self::RecipeId)
+                    .to(RecipeSchema::Table, RecipeSchema::RecipeId)
+                    .on_delete(ForeignKeyAction::Cascade)
+                    .on_update(ForeignKeyAction::Cascade),
+            )
+            .to_owned()
+    }
+
+    fn alter_table(
+        builder: impl sea_query::backend::SchemaBuilder,
+        column_def: &mut ColumnDef,
+    ) -> String {
+        Table::alter()
+            .table(Self::Table)
+            .add_column(column_def)
+            .build(builder)
+    }
+}
+#[derive(Iden, Eq, PartialEq, Debug)]
 #[iden = "Ingredient"]
 pub enum IngredientSchema {
     Table,
@@ -212,7 +699,7 @@ impl SqlTransactionsFactory for IngredientSchema {
             Self::IsActive => "is_active",
             Self::FrId => "fr_id",
             Self::Amount => "amount",
-            Self::IngredientType => "amount",
+            Self::IngredientType => "ingredient_type",
             Self::InstructionId => "instruction_id",
         }
     }
@@ -231,6 +718,18 @@ impl SqlTransactionsFactory for IngredientSchema {
             _ => None,
         }
     }
+    fn column_type(&self) -> Option<ColumnType> {
+        match self {
+            Self::Table => None,
+            Self::IngredientId | Self::FrId | Self::InstructionId => Some(ColumnType::Int),
+            Self::Name | Self::Description => Some(ColumnType::Text),
+            Self::Alcoholic | Self::IsActive => Some(ColumnType::Bool),
+            Self::Amount => Some(ColumnType::Float),
+            Self::IngredientType => Some(ColumnType::Enum(|value| {
+                enum_value(value, IngredientType::from_str_name)
+            })),
+        }
+    }
 }
 impl Display for IngredientSchema {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -272,7 +771,7 @@ impl TryFrom<String> for IngredientSchema {
 }
 
 impl SqlTableTransactionsFactory for IngredientSchema {
-    fn create_table(builder: impl sea_query::backend::SchemaBuilder) -> String {
+    fn create_table_statement() -> TableCreateStatement {
         Table::create()
             .table(Self::Table)
             .if_not_exists()
@@ -317,7 +816,7 @@ impl SqlTableTransactionsFactory for IngredientSchema {
                     .on_delete(ForeignKeyAction::SetNull)
                     .on_update(ForeignKeyAction::SetNull),
             )
-            .build(builder)
+            .to_owned()
     }
 
     fn alter_table(
@@ -361,6 +860,13 @@ impl SqlTransactionsFactory for InstructionSchema {
             _ => None,
         }
     }
+    fn column_type(&self) -> Option<ColumnType> {
+        match self {
+            Self::Table => None,
+            Self::InstructionId => Some(ColumnType::Int),
+            Self::InstructionDetail | Self::InstructionName => Some(ColumnType::Text),
+        }
+    }
 }
 impl Display for InstructionSchema {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -390,7 +896,7 @@ impl TryFrom<String> for InstructionSchema {
 }
 
 impl SqlTableTransactionsFactory for InstructionSchema {
-    fn create_table(builder: impl sea_query::backend::SchemaBuilder) -> String {
+    fn create_table_statement() -> TableCreateStatement {
         Table::create()
             .table(Self::Table)
             .if_not_exists()
@@ -403,7 +909,7 @@ impl SqlTableTransactionsFactory for InstructionSchema {
             )
             .col(ColumnDef::new(Self::InstructionDetail).text())
             .col(ColumnDef::new(Self::InstructionName).text().not_null())
-            .build(builder)
+            .to_owned()
     }
 
     fn alter_table(
@@ -445,6 +951,14 @@ impl SqlTransactionsFactory for InstructionToRecipeSchema {
             _ => None,
         }
     }
+    fn column_type(&self) -> Option<ColumnType> {
+        match self {
+            Self::Table => None,
+            Self::Id | Self::RecipeId | Self::InstructionId | Self::InstructionOrder => {
+                Some(ColumnType::Int)
+            }
+        }
+    }
 }
 impl Display for InstructionToRecipeSchema {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -475,7 +989,7 @@ impl TryFrom<String> for InstructionToRecipeSchema {
     }
 }
 impl SqlTableTransactionsFactory for InstructionToRecipeSchema {
-    fn create_table(builder: impl sea_query::backend::SchemaBuilder) -> String {
+    fn create_table_statement() -> TableCreateStatement {
         Table::create()
             .table(Self::Table)
             .if_not_exists()
@@ -505,7 +1019,7 @@ impl SqlTableTransactionsFactory for InstructionToRecipeSchema {
                     .on_update(ForeignKeyAction::SetNull),
             )
             .col(ColumnDef::new(Self::InstructionOrder).integer().not_null())
-            .build(builder)
+            .to_owned()
     }
 
     fn alter_table(
@@ -550,6 +1064,17 @@ impl SqlTransactionsFactory for RecipeSchema {
             _ => None,
         }
     }
+    fn column_type(&self) -> Option<ColumnType> {
+        match self {
+            Self::Table => None,
+            Self::RecipeId => Some(ColumnType::Int),
+            Self::Name | Self::Description => Some(ColumnType::Text),
+            Self::UserInput => Some(ColumnType::Bool),
+            Self::DrinkSize => Some(ColumnType::Enum(|value| {
+                enum_value(value, DrinkSize::from_str_name)
+            })),
+        }
+    }
 }
 impl Display for RecipeSchema {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -580,7 +1105,7 @@ impl TryFrom<String> for RecipeSchema {
     }
 }
 impl SqlTableTransactionsFactory for RecipeSchema {
-    fn create_table(builder: impl sea_query::backend::SchemaBuilder) -> String {
+    fn create_table_statement() -> TableCreateStatement {
         Table::create()
             .table(Self::Table)
             .if_not_exists()
@@ -610,7 +1135,7 @@ impl SqlTableTransactionsFactory for RecipeSchema {
                     .not_null()
                     .unique_key(),
             )
-            .build(builder)
+            .to_owned()
     }
 
     fn alter_table(
@@ -642,4 +1167,75 @@ mod tests {
             Some(FluidRegulationSchema::GpioPin)
         )
     }
+
+    #[test]
+    fn column_type_to_value() {
+        let column_type = |column: FluidRegulationSchema| column.column_type().unwrap();
+        assert_eq!(
+            column_type(FluidRegulationSchema::GpioPin)
+                .to_value("gpio_pin", "23")
+                .unwrap(),
+            Value::BigInt(Some(23))
+        );
+        let regulator_type = column_type(FluidRegulationSchema::RegulatorType);
+        assert_eq!(
+            regulator_type
+                .to_value("regulator_type", "REGULATOR_TYPE_PUMP")
+                .unwrap(),
+            Value::Int(Some(RegulatorType::Pump.into()))
+        );
+        assert_eq!(
+            regulator_type.to_value("regulator_type", "2").unwrap(),
+            Value::Int(Some(2))
+        );
+        assert_eq!(
+            IngredientSchema::IsActive
+                .column_type()
+                .unwrap()
+                .to_value("is_active", "TRUE")
+                .unwrap(),
+            Value::Bool(Some(true))
+        );
+        assert!(FluidRegulationSchema::Table.column_type().is_none());
+        for (column_type, value, message) in [
+            (
+                ColumnType::Int,
+                "2.5",
+                "Invalid Input x expects an int, got '2.5'",
+            ),
+            (
+                ColumnType::Float,
+                "one",
+                "Invalid Input x expects a finite float, got 'one'",
+            ),
+            (
+                ColumnType::Float,
+                "NaN",
+                "Invalid Input x expects a finite float, got 'NaN'",
+            ),
+            (
+                ColumnType::Float,
+                "-inf",
+                "Invalid Input x expects a finite float, got '-inf'",
+            ),
+            (
+                ColumnType::Bool,
+                "yes",
+                "Invalid Input x expects true or false, got 'yes'",
+            ),
+            (
+                regulator_type,
+                "REGULATOR_TYPE_HOSE",
+                "Invalid Input x expects an enum name or number, got 'REGULATOR_TYPE_HOSE'",
+            ),
+            (
+                regulator_type,
+                "999",
+                "Invalid Input x expects an enum name or number, got '999'",
+            ),
+        ] {
+            let error = column_type.to_value("x", value).unwrap_err();
+            assert_eq!(error.to_string(), message);
+        }
+    }
 }
@@ -1,99 +1,234 @@
+use crate::db::postgres::params;
+use crate::db::postgres::tls;
+use crate::db::row::DbRow;
 use crate::db::DatabaseTransactionsFactory;
 use crate::db::DbConnection;
-use crate::db::FluidRegulationSchema;
-use crate::db::IngredientSchema;
-use crate::db::InstructionSchema;
-use crate::db::InstructionToRecipeSchema;
-use crate::db::RecipeSchema;
-use crate::db::SqlTableTransactionsFactory;
+use crate::db::DbTransaction;
+use crate::db::SchemaStatement;
 use crate::error::UdmError;
 use crate::parsers::settings;
 use crate::UdmResult;
 use async_trait::async_trait;
-use tokio_postgres::Row;
-
+use deadpool_postgres::ClientWrapper;
+use deadpool_postgres::Manager;
+use deadpool_postgres::ManagerConfig;
+use deadpool_postgres::Object;
+use deadpool_postgres::Pool;
+use deadpool_postgres::PoolError;
+use deadpool_postgres::RecyclingMethod;
+use deadpool_postgres::Runtime;
+use deadpool_postgres::TimeoutType;
+use sea_query::DeleteStatement;
+use sea_query::InsertStatement;
+use sea_query::PostgresQueryBuilder;
+use sea_query::SelectStatement;
+use sea_query::UpdateStatement;
+use sea_query::Values;
+use std::error::Error;
+use std::time::Duration;
+use tokio_postgres::types::ToSql;
 use tokio_postgres::Config;
 use tokio_postgres::NoTls;
+use tokio_postgres::Row;
+
+// Per connection, enough for every statement shape the handlers build.
+// Filters with long IN lists are the only ones that keep adding new shapes
+const STATEMENT_CACHE_SIZE: usize = 256;
 
 pub struct OpenPostgresConnection {
-    pub conn: tokio_postgres::Client,
+    pub pool: Pool,
+    // The pool only opens connections on demand, the probe tops it up to this
+    min_size: usize,
 }
 #[async_trait]
 impl DbConnection for OpenPostgresConnection {
-    async fn insert(&self, stmt: String) -> UdmResult<i32> {
-        tracing::info!("Received insert call query: {}", &stmt);
-        let prepared = self.conn.prepare(stmt.as_str()).await.map_err(|e| {
-            tracing::error!("{}", e.to_string());
-            UdmError::ApiFailure(e.to_string())
-        })?;
-        let row = self.conn.query_one(&prepared, &[]).await.map_err(|e| {
-            tracing::error!("{}", e.to_string());
-            UdmError::ApiFailure(e.to_string())
-        })?;
-        let data: UdmResult<i32> = row
-            .try_get(0)
-            .map_err(|e| UdmError::ApiFailure(e.to_string()));
-        tracing::debug!("Result from inserting into db {:?}", &data);
-        data
-    }
-    async fn delete(&self, stmt: String) -> UdmResult<()> {
-        tracing::info!("Received delete call query: {}", &stmt);
-        let prepared = self.conn.prepare(stmt.as_str()).await?;
-        let result = self.conn.query_opt(&prepared, &[]).await.map_err(|e| {
-            tracing::error!("{}", e.to_string());
-            UdmError::ApiFailure(e.to_string())
-        })?;
-        tracing::debug!("Result from deleting from db: {:?}", &result);
-        Ok(())
+    async fn insert(&self, stmt: InsertStatement) -> UdmResult<i32> {
+        let client = self.checkout().await?;
+        run_insert(&client, stmt).await
     }
-    async fn update(&self, stmt: String) -> UdmResult<i32> {
-        tracing::info!("Received update call query: {}", &stmt);
-        let prepared = self.conn.prepare(stmt.as_str()).await?;
-        let row = self.conn.query_one(&prepared, &[]).await.map_err(|e| {
-            tracing::error!("{}", e.to_string());
-            UdmError::ApiFailure(e.to_string())
-        })?;
-        let data: UdmResult<i32> = row
-            .try_get(0)
-            .map_err(|e| UdmError::ApiFailure(e.to_string()));
-        tracing::debug!("Result from inserting into db {:?}", &data);
-        data
-    }
-    async fn select(&self, stmt: String) -> UdmResult<Vec<Row>> {
-        tracing::info!("Received update call query: {}", &stmt);
-        let prepared = self.conn.prepare(stmt.as_str()).await?;
-        let rows = self.conn.query(&prepared, &[]).await.map_err(|e| {
-            tracing::error!("{}", e.to_string());
-            UdmError::ApiFailure(e.to_string())
-        })?;
-        tracing::debug!("Result from inserting into db {:?}", &rows);
-        Ok(rows)
+    async fn delete(&self, stmt: DeleteStatement) -> UdmResult<()> {
+        let client = self.checkout().await?;
+        run_delete(&client, stmt).await
+    }
+    async fn update(&self, stmt: UpdateStatement) -> UdmResult<i32> {
+        let client = self.checkout().await?;
+        run_update(&client, stmt).await
+    }
+    async fn select(&self, stmt: SelectStatement) -> UdmResult<Vec<DbRow>> {
+        let client = self.checkout().await?;
+        run_select(&client, stmt).await
+    }
+    async fn begin<'a>(&'a self) -> UdmResult<Box<dyn DbTransaction + 'a>> {
+        let client = self.checkout().await?;
+        tracing::debug!("Beginning transaction");
+        client.batch_execute("BEGIN").await.map_err(to_udm_error)?;
+        Ok(Box::new(PostgresTransaction {
+            client: Some(client),
+        }))
+    }
+    async fn ping(&self) -> UdmResult<()> {
+        let client = self.checkout().await?;
+        client
+            .simple_query("SELECT 1")
+            .await
+            .map(|_| ())
+            .map_err(to_udm_error)?;
+        drop(client);
+        self.replenish().await
+    }
+}
+
+// Holds on to its pooled connection until it is committed or rolled back
+pub struct PostgresTransaction {
+    client: Option<Object>,
+}
+
+impl PostgresTransaction {
+    fn client(&self) -> UdmResult<&ClientWrapper> {
+        self.client
+            .as_deref()
+            .ok_or_else(|| UdmError::ApiFailure("Transaction has already ended".to_string()))
+    }
+    async fn finish(mut self: Box<Self>, sql: &'static str) -> UdmResult<()> {
+        tracing::debug!("Ending transaction with {}", sql);
+        let client = self
+            .client
+            .take()
+            .ok_or_else(|| UdmError::ApiFailure("Transaction has already ended".to_string()))?;
+        client.batch_execute(sql).await.map_err(to_udm_error)
+    }
+}
+
+#[async_trait]
+impl DbTransaction for PostgresTransaction {
+    async fn insert(&mut self, stmt: InsertStatement) -> UdmResult<i32> {
+        run_insert(self.client()?, stmt).await
+    }
+    async fn delete(&mut self, stmt: DeleteStatement) -> UdmResult<()> {
+        run_delete(self.client()?, stmt).await
+    }
+    async fn update(&mut self, stmt: UpdateStatement) -> UdmResult<i32> {
+        run_update(self.client()?, stmt).await
+    }
+    async fn select(&mut self, stmt: SelectStatement) -> UdmResult<Vec<DbRow>> {
+        run_select(self.client()?, stmt).await
+    }
+    async fn commit(self: Box<Self>) -> UdmResult<()> {
+        self.finish("COMMIT").await
+    }
+    async fn rollback(self: Box<Self>) -> UdmResult<()> {
+        self.finish("ROLLBACK").await
+    }
+}
+
+impl Drop for PostgresTransaction {
+    fn drop(&mut self) {
+        // Dropped mid way, e.g. the rpc was cancelled. The connection only
+        // goes back to the pool once the rollback went through
+        let Some(client) = self.client.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            // Can't roll back, close the connection instead of reusing it
+            drop(Object::take(client));
+            return;
+        };
+        runtime.spawn(async move {
+            if let Err(e) = client.batch_execute("ROLLBACK").await {
+                tracing::error!("Failed to roll back transaction: {}", e);
+                drop(Object::take(client));
+            }
+        });
     }
 }
 
 impl OpenPostgresConnection {
     pub async fn new(settings: settings::PostgresConfigurer) -> Self {
-        let config: Config = settings.into();
-        let (client, connection) = config.connect(NoTls).await.unwrap_or_else(|e| {
+        let min_size = settings.pool_min_size;
+        let max_size = settings.pool_max_size;
+        if max_size == 0 || min_size > max_size {
+            tracing::error!(
+                "Invalid postgres pool size, min {} must be at most max {} and max at least 1",
+                min_size,
+                max_size
+            );
+            std::process::exit(15)
+        }
+        let checkout_timeout = Duration::from_millis(settings.checkout_timeout_ms);
+        let tls = tls::connector(&settings).unwrap_or_else(|e| {
             tracing::error!("{}", e);
             std::process::exit(15)
         });
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                tracing::error!(
-                    "Unable to establish an connection with postgres database {}",
-                    e
-                );
-                std::process::exit(10)
-            }
+        let config: Config = settings.into();
+        let manager_config = ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        };
+        let manager = match tls {
+            Some(tls) => Manager::from_config(config, tls, manager_config),
+            None => Manager::from_config(config, NoTls, manager_config),
+        };
+        let pool = Pool::builder(manager)
+            .max_size(max_size)
+            .wait_timeout(Some(checkout_timeout))
+            .create_timeout(Some(checkout_timeout))
+            .runtime(Runtime::Tokio1)
+            .build()
+            .unwrap_or_else(|e| {
+                tracing::error!("{}", e);
+                std::process::exit(15)
+            });
+        let connection = Self { pool, min_size };
+        connection.replenish().await.unwrap_or_else(|e| {
+            tracing::error!("{}", e);
+            std::process::exit(15)
         });
-        Self { conn: client }
+        tracing::info!(
+            "Opened {} postgres connections",
+            connection.pool.status().size
+        );
+        connection
+    }
+    /// Opens connections until the pool holds at least `pool_min_size`
+    ///
+    /// Connections dropped after an error or a restart of the database are not
+    /// replaced by the pool, so the health probe calls this on every round
+    async fn replenish(&self) -> UdmResult<()> {
+        let status = self.pool.status();
+        if status.size >= self.min_size {
+            return Ok(());
+        }
+        // Checking out every idle connection plus the missing ones at once makes
+        // the pool open the missing ones, busy connections are left alone
+        let busy = status.size.saturating_sub(status.available);
+        let opened =
+            futures::future::try_join_all((busy..self.min_size).map(|_| self.checkout())).await?;
+        tracing::debug!(
+            "Topped up the postgres pool from {} to {} connections",
+            status.size,
+            busy + opened.len()
+        );
+        Ok(())
+    }
+    async fn checkout(&self) -> UdmResult<Object> {
+        self.pool.get().await.map_err(|e| {
+            let error = match e {
+                // Every connection is busy, the database itself is fine
+                PoolError::Timeout(TimeoutType::Wait) => {
+                    UdmError::ApiFailure("Timed out waiting for a database connection".to_string())
+                }
+                PoolError::Backend(e) => to_udm_error(e),
+                e => UdmError::DatabaseUnavailable(e.to_string()),
+            };
+            tracing::error!("{}", error);
+            error
+        })
     }
     pub async fn collect_current_dbs(&mut self) -> UdmResult<Vec<String>> {
         tracing::debug!("Collecting Current databases");
         let sql = "SELECT datname FROM pg_database";
-        let stmt = self.conn.prepare(sql).await?;
-        let collected_db_rows = self.conn.query(&stmt, &[]).await;
+        let client = self.checkout().await?;
+        let stmt = client.prepare(sql).await?;
+        let collected_db_rows = client.query(&stmt, &[]).await;
         Self::from_row_to_vec_string(collected_db_rows?)
     }
     fn from_row_to_vec_string(rows: Vec<tokio_postgres::Row>) -> UdmResult<Vec<String>> {
@@ -111,37 +246,110 @@ impl OpenPostgresConnection {
 impl DatabaseTransactionsFactory for OpenPostgresConnection {
     async fn collect_all_current_tables(&mut self) -> UdmResult<Vec<String>> {
         tracing::debug!("Getting Current tables from protgres database");
-        let stmt = self
-            .conn
-            .prepare("SELECT * FROM pg_catalog.pg_tables")
-            .await?;
-        let table_rows = self.conn.query(&stmt, &[]).await;
+        let client = self.checkout().await?;
+        let stmt = client.prepare("SELECT * FROM pg_catalog.pg_tables").await?;
+        let table_rows = client.query(&stmt, &[]).await;
         Self::from_row_to_vec_string(table_rows?)
     }
-    async fn gen_schmea(&mut self) -> UdmResult<()> {
-        let tables = [
-            FluidRegulationSchema::create_table(sea_query::PostgresQueryBuilder),
-            InstructionSchema::create_table(sea_query::PostgresQueryBuilder),
-            RecipeSchema::create_table(sea_query::PostgresQueryBuilder),
-            IngredientSchema::create_table(sea_query::PostgresQueryBuilder),
-            InstructionToRecipeSchema::create_table(sea_query::PostgresQueryBuilder),
-        ]
-        .join("; ");
-        tracing::debug!("Ensure schmea is defined");
-        if let Err(query_err) = self.conn.batch_execute(tables.as_str()).await {
-            tracing::error!("{}", query_err);
-            std::process::exit(20)
-        }
-        Ok(())
+    async fn execute_schema(&self, statements: Vec<SchemaStatement>) -> UdmResult<()> {
+        let query = statements
+            .iter()
+            .map(|statement| statement.to_string(PostgresQueryBuilder))
+            .collect::<Vec<String>>()
+            .join("; ");
+        tracing::info!("Running query: {}", &query);
+        // A multi statement batch runs as a single implicit transaction
+        self.checkout()
+            .await?
+            .batch_execute(query.as_str())
+            .await
+            .map_err(to_udm_error)
     }
     async fn truncate_schema(&self) -> UdmResult<()> {
-        let tables =
-            r#""InstructionToRecipe", "Ingredient", "Recipe", "Instruction", "FluidRegulation""#;
+        let tables = r#""DrinkOrder", "Bottle", "InstructionToRecipe", "Ingredient", "Recipe", "Instruction", "FlowCalibration", "FluidRegulation""#;
         let query = format!("TRUNCATE TABLE {};", tables);
         tracing::info!("Running query: {}", &query);
-        self.conn
+        self.checkout()
+            .await?
             .batch_execute(query.as_str())
             .await
-            .map_err(|e| UdmError::ApiFailure(e.to_string()))
+            .map_err(to_udm_error)
+    }
+}
+
+async fn run_insert(client: &ClientWrapper, stmt: InsertStatement) -> UdmResult<i32> {
+    let (sql, values) = stmt.build(PostgresQueryBuilder);
+    tracing::info!("Received insert call query: {} with {:?}", &sql, &values);
+    let data = query_id(client, sql, values).await;
+    tracing::debug!("Result from inserting into db {:?}", &data);
+    data
+}
+async fn run_delete(client: &ClientWrapper, stmt: DeleteStatement) -> UdmResult<()> {
+    let (sql, values) = stmt.build(PostgresQueryBuilder);
+    tracing::info!("Received delete call query: {} with {:?}", &sql, &values);
+    let rows = query(client, sql, values).await?;
+    tracing::debug!("Result from deleting from db: {:?}", &rows);
+    Ok(())
+}
+async fn run_update(client: &ClientWrapper, stmt: UpdateStatement) -> UdmResult<i32> {
+    let (sql, values) = stmt.build(PostgresQueryBuilder);
+    tracing::info!("Received update call query: {} with {:?}", &sql, &values);
+    let data = query_id(client, sql, values).await;
+    tracing::debug!("Result from updating db {:?}", &data);
+    data
+}
+async fn run_select(client: &ClientWrapper, stmt: SelectStatement) -> UdmResult<Vec<DbRow>> {
+    let (sql, values) = stmt.build(PostgresQueryBuilder);
+    tracing::info!("Received select call query: {} with {:?}", &sql, &values);
+    let rows = query(client, sql, values)
+        .await?
+        .into_iter()
+        .map(DbRow::try_from)
+        .collect::<UdmResult<Vec<DbRow>>>()?;
+    tracing::debug!("Result from selecting from db {:?}", &rows);
+    Ok(rows)
+}
+async fn query(client: &ClientWrapper, sql: String, values: Values) -> UdmResult<Vec<Row>> {
+    if client.statement_cache.size() >= STATEMENT_CACHE_SIZE {
+        client.statement_cache.clear();
+    }
+    let statement = client
+        .prepare_cached(sql.as_str())
+        .await
+        .map_err(to_udm_error)?;
+    let params = params::bind_params(values, statement.params())?;
+    let params = params
+        .iter()
+        .map(|param| param.as_ref() as &(dyn ToSql + Sync))
+        .collect::<Vec<&(dyn ToSql + Sync)>>();
+    client
+        .query(&statement, &params)
+        .await
+        .map_err(to_udm_error)
+}
+// Runs a statement with a RETURNING clause and reads back the id
+async fn query_id(client: &ClientWrapper, sql: String, values: Values) -> UdmResult<i32> {
+    let rows = query(client, sql, values).await?;
+    match rows.as_slice() {
+        [row] => row
+            .try_get(0)
+            .map_err(|e| UdmError::ApiFailure(e.to_string())),
+        _ => Err(UdmError::ApiFailure(format!(
+            "Expected one row to be returned but got {}",
+            rows.len()
+        ))),
+    }
+}
+// Errors from a connection that went away are told apart so the health
+// monitor can take the database offline
+fn to_udm_error(e: tokio_postgres::Error) -> UdmError {
+    tracing::error!("{}", e);
+    let lost = e.is_closed()
+        || e.source()
+            .is_some_and(|source| source.is::<std::io::Error>());
+    if lost {
+        UdmError::DatabaseUnavailable(e.to_string())
+    } else {
+        UdmError::ApiFailure(e.to_string())
     }
 }
@@ -1 +1,3 @@
 pub mod conn;
+pub mod params;
+pub mod tls;
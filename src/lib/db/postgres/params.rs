@@ -0,0 +1,140 @@
+// Postgres does not coerce bound parameters the way it coerces literals, so
+// each value is converted to the type the server inferred for its placeholder.
+// Filter values arrive from the cli as text and are parsed here
+use crate::db::row::DbValue;
+use crate::error::UdmError;
+use crate::UdmResult;
+use sea_query::Values;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::types::Type;
+
+pub type PostgresParam = Box<dyn ToSql + Sync + Send>;
+
+pub fn bind_params(values: Values, param_types: &[Type]) -> UdmResult<Vec<PostgresParam>> {
+    if values.0.len() != param_types.len() {
+        return Err(UdmError::ApiFailure(format!(
+            "Statement expects {} parameters but {} were given",
+            param_types.len(),
+            values.0.len()
+        )));
+    }
+    values
+        .into_iter()
+        .zip(param_types)
+        .map(|(value, param_type)| to_param(DbValue::try_from(value)?, param_type))
+        .collect()
+}
+
+fn to_param(value: DbValue, param_type: &Type) -> UdmResult<PostgresParam> {
+    let param: PostgresParam = if *param_type == Type::BOOL {
+        Box::new(as_bool(&value, param_type)?)
+    } else if *param_type == Type::INT2 {
+        Box::new(as_int::<i16>(&value, param_type)?)
+    } else if *param_type == Type::INT4 {
+        Box::new(as_int::<i32>(&value, param_type)?)
+    } else if *param_type == Type::INT8 {
+        Box::new(as_int::<i64>(&value, param_type)?)
+    } else if *param_type == Type::FLOAT4 {
+        Box::new(as_float(&value, param_type)?.map(|data| data as f32))
+    } else if *param_type == Type::FLOAT8 {
+        Box::new(as_float(&value, param_type)?)
+    } else if [Type::TEXT, Type::VARCHAR, Type::BPCHAR, Type::NAME].contains(param_type) {
+        Box::new(as_text(&value))
+    } else {
+        return Err(UdmError::ApiFailure(format!(
+            "Parameters of type {} are not supported",
+            param_type
+        )));
+    };
+    Ok(param)
+}
+
+fn mismatch(value: &DbValue, param_type: &Type) -> UdmError {
+    UdmError::InvalidInput(format!("{:?} is not a valid {}", value, param_type))
+}
+
+fn as_bool(value: &DbValue, param_type: &Type) -> UdmResult<Option<bool>> {
+    match value {
+        DbValue::Null => Ok(None),
+        DbValue::Bool(data) => Ok(Some(*data)),
+        DbValue::Int(data) => Ok(Some(*data != 0)),
+        DbValue::Text(data) => data
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| mismatch(value, param_type)),
+        DbValue::Float(_) => Err(mismatch(value, param_type)),
+    }
+}
+
+fn as_int<T: TryFrom<i64>>(value: &DbValue, param_type: &Type) -> UdmResult<Option<T>> {
+    let data = match value {
+        DbValue::Null => return Ok(None),
+        DbValue::Bool(data) => i64::from(*data),
+        DbValue::Int(data) => *data,
+        DbValue::Float(data) if data.fract() == 0.0 => *data as i64,
+        DbValue::Text(data) => data
+            .trim()
+            .parse()
+            .map_err(|_| mismatch(value, param_type))?,
+        DbValue::Float(_) => return Err(mismatch(value, param_type)),
+    };
+    T::try_from(data)
+        .map(Some)
+        .map_err(|_| mismatch(value, param_type))
+}
+
+fn as_float(value: &DbValue, param_type: &Type) -> UdmResult<Option<f64>> {
+    match value {
+        DbValue::Null => Ok(None),
+        DbValue::Int(data) => Ok(Some(*data as f64)),
+        DbValue::Float(data) => Ok(Some(*data)),
+        DbValue::Text(data) => data
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| mismatch(value, param_type)),
+        DbValue::Bool(_) => Err(mismatch(value, param_type)),
+    }
+}
+
+fn as_text(value: &DbValue) -> Option<String> {
+    match value {
+        DbValue::Null => None,
+        DbValue::Bool(data) => Some(data.to_string()),
+        DbValue::Int(data) => Some(data.to_string()),
+        DbValue::Float(data) => Some(data.to_string()),
+        DbValue::Text(data) => Some(data.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_query::Value;
+
+    #[test]
+    fn test_text_is_parsed_into_the_placeholder_type() {
+        let text = |data: &str| DbValue::Text(data.to_string());
+        assert_eq!(as_int::<i32>(&text("12"), &Type::INT4).unwrap(), Some(12));
+        assert_eq!(as_float(&text("1.5"), &Type::FLOAT4).unwrap(), Some(1.5));
+        assert_eq!(as_bool(&text("true"), &Type::BOOL).unwrap(), Some(true));
+        assert!(as_int::<i32>(&text("1; DROP TABLE"), &Type::INT4).is_err());
+        assert!(as_int::<i16>(&DbValue::Int(70000), &Type::INT2).is_err());
+        assert_eq!(as_int::<i32>(&DbValue::Null, &Type::INT4).unwrap(), None);
+        assert_eq!(as_text(&DbValue::Int(3)), Some("3".to_string()));
+    }
+
+    #[test]
+    fn test_bind_params_checks_the_count() {
+        let values = Values(vec![Value::Int(Some(1)), Value::String(None)]);
+        assert_eq!(
+            bind_params(values.clone(), &[Type::INT4, Type::TEXT])
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(bind_params(values.clone(), &[Type::INT4]).is_err());
+        assert!(bind_params(values, &[Type::INT4, Type::BYTEA]).is_err());
+    }
+}
@@ -0,0 +1,393 @@
+// Builds the rustls connector from the ssl settings. The modes follow libpq,
+// require only encrypts unless a CA is given, verify-ca checks the chain and
+// verify-full the host name on top of it
+use crate::error::UdmError;
+use crate::parsers::settings::PostgresConfigurer;
+use crate::parsers::settings::PostgresSslMode;
+use crate::UdmResult;
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::client::danger::ServerCertVerified;
+use rustls::client::danger::ServerCertVerifier;
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::PrivateKeyDer;
+use rustls::pki_types::ServerName;
+use rustls::pki_types::UnixTime;
+use rustls::CertificateError;
+use rustls::ClientConfig;
+use rustls::DigitallySignedStruct;
+use rustls::RootCertStore;
+use rustls::SignatureScheme;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+/// None when TLS is disabled
+pub fn connector(settings: &PostgresConfigurer) -> UdmResult<Option<MakeRustlsConnect>> {
+    if settings.ssl_mode == PostgresSslMode::Disable {
+        return Ok(None);
+    }
+    Ok(Some(MakeRustlsConnect::new(client_config(settings)?)))
+}
+
+pub fn client_config(settings: &PostgresConfigurer) -> UdmResult<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier: Arc<dyn ServerCertVerifier> = match &settings.ssl_root_cert {
+        None if matches!(
+            settings.ssl_mode,
+            PostgresSslMode::VerifyCa | PostgresSslMode::VerifyFull
+        ) =>
+        {
+            return Err(UdmError::InvalidateConfiguration(format!(
+                "ssl_mode {:?} requires ssl_root_cert",
+                settings.ssl_mode
+            )))
+        }
+        None => Arc::new(EncryptOnly {
+            provider: Arc::clone(&provider),
+        }),
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(|e| invalid_file(path, e))?;
+            }
+            let verifier =
+                WebPkiServerVerifier::builder_with_provider(Arc::new(roots), Arc::clone(&provider))
+                    .build()
+                    .map_err(|e| invalid_file(path, e))?;
+            if settings.ssl_mode == PostgresSslMode::VerifyFull {
+                verifier
+            } else {
+                Arc::new(IgnoreHostName { verifier })
+            }
+        }
+    };
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| UdmError::InvalidateConfiguration(e.to_string()))?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier);
+    match (&settings.ssl_cert, &settings.ssl_key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| invalid_file(key, e)),
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err(UdmError::InvalidateConfiguration(
+            "ssl_cert and ssl_key must be set together".to_string(),
+        )),
+    }
+}
+
+fn invalid_file(path: &str, e: impl std::fmt::Display) -> UdmError {
+    UdmError::InvalidateConfiguration(format!("{}: {}", path, e))
+}
+
+fn open(path: &str) -> UdmResult<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| invalid_file(path, e))
+}
+
+fn load_certs(path: &str) -> UdmResult<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid_file(path, e))?;
+    if certs.is_empty() {
+        return Err(invalid_file(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> UdmResult<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut open(path)?)
+        .map_err(|e| invalid_file(path, e))?
+        .ok_or_else(|| invalid_file(path, "no private key found"))
+}
+
+// verify-ca, the chain has to lead to the CA but the name may be anything
+#[derive(Debug)]
+struct IgnoreHostName {
+    verifier: Arc<WebPkiServerVerifier>,
+}
+
+impl ServerCertVerifier for IgnoreHostName {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // Names are only checked once the chain is valid
+        match self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        ) {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) => Ok(ServerCertVerified::assertion()),
+            result => result,
+        }
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
+
+// prefer and require without a CA, the connection is encrypted but the
+// server is taken at its word
+#[derive(Debug)]
+struct EncryptOnly {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for EncryptOnly {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::BasicConstraints;
+    use rcgen::Certificate;
+    use rcgen::CertificateParams;
+    use rcgen::IsCa;
+    use rcgen::KeyPair;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::ServerConfig;
+    use std::path::PathBuf;
+    use tokio_rustls::TlsAcceptor;
+    use tokio_rustls::TlsConnector;
+
+    const HOST: &str = "db.udm.test";
+
+    struct Issued {
+        cert: Certificate,
+        key: KeyPair,
+    }
+
+    fn ca() -> Issued {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        Issued { cert, key }
+    }
+    fn issue(ca: &Issued, name: &str) -> Issued {
+        let params = CertificateParams::new(vec![name.to_string()]).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &ca.cert, &ca.key).unwrap();
+        Issued { cert, key }
+    }
+    fn private_key(issued: &Issued) -> PrivateKeyDer<'static> {
+        PrivatePkcs8KeyDer::from(issued.key.serialize_der()).into()
+    }
+    // Writes the pem files into a directory of its own for each test
+    fn write(dir: &str, name: &str, contents: String) -> String {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("udm-tls-{}-{}", std::process::id(), dir));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path.display().to_string()
+    }
+    fn settings(fields: &str) -> PostgresConfigurer {
+        let json = format!(r#"{{"password": "secret"{}}}"#, fields);
+        serde_json::from_str(&json).unwrap()
+    }
+    fn server(issued: &Issued, client_ca: Option<&Issued>) -> ServerConfig {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let builder = match client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                roots.add(ca.cert.der().clone()).unwrap();
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()
+                        .unwrap();
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        builder
+            .with_single_cert(vec![issued.cert.der().clone()], private_key(issued))
+            .unwrap()
+    }
+    async fn handshake(
+        client: ClientConfig,
+        server: ServerConfig,
+        host: &str,
+    ) -> Result<(), String> {
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let connector = TlsConnector::from(Arc::new(client));
+        let acceptor = TlsAcceptor::from(Arc::new(server));
+        let name = ServerName::try_from(host.to_string()).unwrap();
+        let (client, server) = tokio::join!(
+            connector.connect(name, client_io),
+            acceptor.accept(server_io)
+        );
+        client.map_err(|e| e.to_string())?;
+        server.map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_full_checks_the_host_name() {
+        let ca = ca();
+        let root = write("full", "ca.pem", ca.cert.pem());
+        let settings = settings(&format!(
+            r#", "ssl_mode": "verify-full", "ssl_root_cert": "{}""#,
+            root
+        ));
+        let server = || server(&issue(&ca, HOST), None);
+        let config = || client_config(&settings).unwrap();
+        assert!(handshake(config(), server(), HOST).await.is_ok());
+        assert!(handshake(config(), server(), "other.udm.test")
+            .await
+            .is_err());
+        let untrusted = server_for_other_ca();
+        assert!(handshake(config(), untrusted, HOST).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_ca_ignores_the_host_name() {
+        let ca = ca();
+        let root = write("ca", "ca.pem", ca.cert.pem());
+        let settings = settings(&format!(
+            r#", "ssl_mode": "verify-ca", "ssl_root_cert": "{}""#,
+            root
+        ));
+        let config = || client_config(&settings).unwrap();
+        let server = server(&issue(&ca, HOST), None);
+        assert!(handshake(config(), server, "other.udm.test").await.is_ok());
+        let untrusted = server_for_other_ca();
+        assert!(handshake(config(), untrusted, HOST).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_require_without_ca_only_encrypts() {
+        let settings = settings(r#", "ssl_mode": "require""#);
+        let config = client_config(&settings).unwrap();
+        assert!(handshake(config, server_for_other_ca(), HOST).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_is_presented() {
+        let ca = ca();
+        let client = issue(&ca, "udm");
+        let root = write("client", "ca.pem", ca.cert.pem());
+        let cert = write("client", "client.pem", client.cert.pem());
+        let key = write("client", "client.key", client.key.serialize_pem());
+        let with_cert = settings(&format!(
+            r#", "ssl_mode": "verify-full", "ssl_root_cert": "{}", "ssl_cert": "{}", "ssl_key": "{}""#,
+            root, cert, key
+        ));
+        let without_cert = settings(&format!(
+            r#", "ssl_mode": "verify-full", "ssl_root_cert": "{}""#,
+            root
+        ));
+        let server = || server(&issue(&ca, HOST), Some(&ca));
+        assert!(
+            handshake(client_config(&with_cert).unwrap(), server(), HOST)
+                .await
+                .is_ok()
+        );
+        assert!(
+            handshake(client_config(&without_cert).unwrap(), server(), HOST)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(connector(&settings("")).unwrap().is_none());
+        assert!(client_config(&settings(r#", "ssl_mode": "verify-full""#)).is_err());
+        let ca = ca();
+        let root = write("invalid", "ca.pem", ca.cert.pem());
+        let cert_only = settings(&format!(
+            r#", "ssl_mode": "require", "ssl_cert": "{}""#,
+            root
+        ));
+        assert!(client_config(&cert_only).is_err());
+        let missing =
+            settings(r#", "ssl_mode": "verify-ca", "ssl_root_cert": "/nonexistent/ca.pem""#);
+        assert!(client_config(&missing).is_err());
+        let not_pem = write("invalid", "empty.pem", String::new());
+        let empty = settings(&format!(
+            r#", "ssl_mode": "verify-ca", "ssl_root_cert": "{}""#,
+            not_pem
+        ));
+        assert!(client_config(&empty).is_err());
+    }
+
+    fn server_for_other_ca() -> ServerConfig {
+        server(&issue(&ca(), HOST), None)
+    }
+}
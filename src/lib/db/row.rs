@@ -0,0 +1,282 @@
+// Rows returned by a DbConnection, independent of the backend that produced
+// them. Each backend converts its own rows into a DbRow so the rpc types only
+// need a single conversion
+use crate::error::UdmError;
+use crate::UdmResult;
+use rusqlite::types::ValueRef;
+use tokio_postgres::types::Type;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DbValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DbRow {
+    columns: Vec<String>,
+    values: Vec<DbValue>,
+}
+
+impl DbRow {
+    pub fn new(columns: Vec<String>, values: Vec<DbValue>) -> Self {
+        Self { columns, values }
+    }
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+    /// Reads a column by its position or its name
+    pub fn try_get<I: ColumnIndex, T: FromDbValue>(&self, index: I) -> UdmResult<T> {
+        let position = index.position(&self.columns).ok_or_else(|| {
+            UdmError::ApiFailure(format!("Column {} does not exist in the row", index))
+        })?;
+        T::from_db_value(&self.values[position])
+            .map_err(|e| UdmError::ApiFailure(format!("Failed to read column {}: {}", index, e)))
+    }
+}
+
+pub trait ColumnIndex: std::fmt::Display {
+    fn position(&self, columns: &[String]) -> Option<usize>;
+}
+
+impl ColumnIndex for usize {
+    fn position(&self, columns: &[String]) -> Option<usize> {
+        (*self < columns.len()).then_some(*self)
+    }
+}
+
+impl ColumnIndex for &str {
+    fn position(&self, columns: &[String]) -> Option<usize> {
+        columns.iter().position(|column| column == self)
+    }
+}
+
+pub trait FromDbValue: Sized {
+    fn from_db_value(value: &DbValue) -> UdmResult<Self>;
+}
+
+fn unexpected<T>(wanted: &str, value: &DbValue) -> UdmResult<T> {
+    Err(UdmError::ApiFailure(format!(
+        "Expected {} but found {:?}",
+        wanted, value
+    )))
+}
+
+impl FromDbValue for i64 {
+    fn from_db_value(value: &DbValue) -> UdmResult<Self> {
+        match value {
+            DbValue::Int(data) => Ok(*data),
+            _ => unexpected("an integer", value),
+        }
+    }
+}
+
+impl FromDbValue for i32 {
+    fn from_db_value(value: &DbValue) -> UdmResult<Self> {
+        let data = i64::from_db_value(value)?;
+        i32::try_from(data).map_err(|e| UdmError::ApiFailure(e.to_string()))
+    }
+}
+
+impl FromDbValue for f64 {
+    fn from_db_value(value: &DbValue) -> UdmResult<Self> {
+        match value {
+            DbValue::Float(data) => Ok(*data),
+            // Sqlite hands back whole numbers in real columns as integers
+            DbValue::Int(data) => Ok(*data as f64),
+            _ => unexpected("a float", value),
+        }
+    }
+}
+
+impl FromDbValue for f32 {
+    fn from_db_value(value: &DbValue) -> UdmResult<Self> {
+        f64::from_db_value(value).map(|data| data as f32)
+    }
+}
+
+impl FromDbValue for bool {
+    fn from_db_value(value: &DbValue) -> UdmResult<Self> {
+        match value {
+            DbValue::Bool(data) => Ok(*data),
+            // Sqlite stores booleans as 0 and 1
+            DbValue::Int(data) => Ok(*data != 0),
+            _ => unexpected("a boolean", value),
+        }
+    }
+}
+
+impl FromDbValue for String {
+    fn from_db_value(value: &DbValue) -> UdmResult<Self> {
+        match value {
+            DbValue::Text(data) => Ok(data.clone()),
+            _ => unexpected("text", value),
+        }
+    }
+}
+
+impl<T: FromDbValue> FromDbValue for Option<T> {
+    fn from_db_value(value: &DbValue) -> UdmResult<Self> {
+        match value {
+            DbValue::Null => Ok(None),
+            _ => T::from_db_value(value).map(Some),
+        }
+    }
+}
+
+// Bound parameters of a built statement go through DbValue as well so both
+// backends bind the same set of types
+impl TryFrom<sea_query::Value> for DbValue {
+    type Error = UdmError;
+
+    fn try_from(value: sea_query::Value) -> Result<Self, Self::Error> {
+        use sea_query::Value;
+        let value = match value {
+            Value::Bool(data) => data.map(DbValue::Bool),
+            Value::TinyInt(data) => data.map(|data| DbValue::Int(data.into())),
+            Value::SmallInt(data) => data.map(|data| DbValue::Int(data.into())),
+            Value::Int(data) => data.map(|data| DbValue::Int(data.into())),
+            Value::BigInt(data) => data.map(DbValue::Int),
+            Value::TinyUnsigned(data) => data.map(|data| DbValue::Int(data.into())),
+            Value::SmallUnsigned(data) => data.map(|data| DbValue::Int(data.into())),
+            Value::Unsigned(data) => data.map(|data| DbValue::Int(data.into())),
+            Value::BigUnsigned(data) => match data {
+                Some(data) => Some(DbValue::Int(
+                    i64::try_from(data).map_err(|e| UdmError::InvalidInput(e.to_string()))?,
+                )),
+                None => None,
+            },
+            Value::Float(data) => data.map(|data| DbValue::Float(data.into())),
+            Value::Double(data) => data.map(DbValue::Float),
+            Value::String(data) => data.map(|data| DbValue::Text(*data)),
+            Value::Char(data) => data.map(|data| DbValue::Text(data.to_string())),
+            Value::Bytes(_) => {
+                return Err(UdmError::InvalidInput(
+                    "Binary values are not supported".to_string(),
+                ))
+            }
+        };
+        Ok(value.unwrap_or(DbValue::Null))
+    }
+}
+
+impl TryFrom<tokio_postgres::Row> for DbRow {
+    type Error = UdmError;
+
+    fn try_from(row: tokio_postgres::Row) -> Result<Self, Self::Error> {
+        let mut columns = Vec::with_capacity(row.len());
+        let mut values = Vec::with_capacity(row.len());
+        for (index, column) in row.columns().iter().enumerate() {
+            let column_type = column.type_();
+            let value = if *column_type == Type::BOOL {
+                row.try_get::<_, Option<bool>>(index)?.map(DbValue::Bool)
+            } else if *column_type == Type::INT2 {
+                row.try_get::<_, Option<i16>>(index)?
+                    .map(|data| DbValue::Int(data.into()))
+            } else if *column_type == Type::INT4 {
+                row.try_get::<_, Option<i32>>(index)?
+                    .map(|data| DbValue::Int(data.into()))
+            } else if *column_type == Type::INT8 {
+                row.try_get::<_, Option<i64>>(index)?.map(DbValue::Int)
+            } else if *column_type == Type::FLOAT4 {
+                row.try_get::<_, Option<f32>>(index)?
+                    .map(|data| DbValue::Float(data.into()))
+            } else if *column_type == Type::FLOAT8 {
+                row.try_get::<_, Option<f64>>(index)?.map(DbValue::Float)
+            } else if [Type::TEXT, Type::VARCHAR, Type::BPCHAR, Type::NAME].contains(column_type) {
+                row.try_get::<_, Option<String>>(index)?.map(DbValue::Text)
+            } else {
+                return Err(UdmError::ApiFailure(format!(
+                    "Column {} has unsupported type {}",
+                    column.name(),
+                    column_type
+                )));
+            };
+            columns.push(column.name().to_string());
+            values.push(value.unwrap_or(DbValue::Null));
+        }
+        Ok(Self { columns, values })
+    }
+}
+
+impl TryFrom<&rusqlite::Row<'_>> for DbRow {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &rusqlite::Row<'_>) -> Result<Self, Self::Error> {
+        let statement = row.as_ref();
+        let mut columns = Vec::with_capacity(statement.column_count());
+        let mut values = Vec::with_capacity(statement.column_count());
+        for index in 0..statement.column_count() {
+            let value = match row.get_ref(index)? {
+                ValueRef::Null => DbValue::Null,
+                ValueRef::Integer(data) => DbValue::Int(data),
+                ValueRef::Real(data) => DbValue::Float(data),
+                ValueRef::Text(data) => DbValue::Text(String::from_utf8_lossy(data).into_owned()),
+                ValueRef::Blob(_) => {
+                    return Err(rusqlite::Error::InvalidColumnType(
+                        index,
+                        statement.column_name(index)?.to_string(),
+                        rusqlite::types::Type::Blob,
+                    ))
+                }
+            };
+            columns.push(statement.column_name(index)?.to_string());
+            values.push(value);
+        }
+        Ok(Self { columns, values })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row() -> DbRow {
+        DbRow::new(
+            vec![
+                "id".to_string(),
+                "name".to_string(),
+                "is_active".to_string(),
+                "amount".to_string(),
+                "fr_id".to_string(),
+            ],
+            vec![
+                DbValue::Int(1),
+                DbValue::Text("Tequila".to_string()),
+                DbValue::Int(1),
+                DbValue::Int(30),
+                DbValue::Null,
+            ],
+        )
+    }
+
+    #[test]
+    fn test_get_by_name_and_position() {
+        let row = row();
+        assert_eq!(row.try_get::<_, i32>("id").unwrap(), 1);
+        assert_eq!(row.try_get::<_, String>(1).unwrap(), "Tequila");
+        assert!(row.try_get::<_, bool>("is_active").unwrap());
+        assert_eq!(row.try_get::<_, f32>("amount").unwrap(), 30.0);
+        assert_eq!(row.try_get::<_, Option<i32>>("fr_id").unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_errors() {
+        let row = row();
+        assert!(row.try_get::<_, i32>("missing").is_err());
+        assert!(row.try_get::<_, i32>(5).is_err());
+        assert!(row.try_get::<_, i32>("name").is_err());
+        assert!(row.try_get::<_, i32>("fr_id").is_err());
+        let too_big = DbRow::new(vec!["id".to_string()], vec![DbValue::Int(i64::MAX)]);
+        assert!(too_big.try_get::<_, i32>("id").is_err());
+    }
+}
@@ -1,30 +1,140 @@
-use crate::db::{DatabaseTransactionsFactory, DbConnection};
+use crate::db::row::DbRow;
+use crate::db::row::DbValue;
+use crate::db::DatabaseTransactionsFactory;
+use crate::db::DbConnection;
+use crate::db::DbTransaction;
+use crate::db::SchemaStatement;
+use crate::error::UdmError;
 use crate::parsers::settings::{self, SqliteConfigurer};
 use crate::UdmResult;
 use async_trait::async_trait;
+use rusqlite::params_from_iter;
+use rusqlite::types::Value as SqliteValue;
+use sea_query::DeleteStatement;
+use sea_query::InsertStatement;
+use sea_query::SelectStatement;
+use sea_query::SqliteQueryBuilder;
+use sea_query::UpdateStatement;
+use sea_query::Values;
 use std::path::Path;
-use tokio_postgres::Row;
+use std::sync::Arc;
+use tokio::sync::OwnedRwLockWriteGuard;
+use tokio::sync::RwLock;
 use tokio_rusqlite::Connection;
 
 pub struct OpenSqliteConnection {
     pub connection: Connection,
     pub settings: SqliteConfigurer,
+    // Statements share one connection, while a transaction holds the write
+    // half nothing else can slip into it
+    transaction_lock: Arc<RwLock<()>>,
 }
 
 #[async_trait]
 impl DbConnection for OpenSqliteConnection {
-    async fn insert(&self, _stmt: String) -> UdmResult<i32> {
-        todo!()
+    async fn insert(&self, stmt: InsertStatement) -> UdmResult<i32> {
+        let _guard = self.transaction_lock.read().await;
+        self.run_insert(stmt).await
     }
+    async fn delete(&self, stmt: DeleteStatement) -> UdmResult<()> {
+        let _guard = self.transaction_lock.read().await;
+        self.run_delete(stmt).await
+    }
+    async fn update(&self, stmt: UpdateStatement) -> UdmResult<i32> {
+        let _guard = self.transaction_lock.read().await;
+        self.run_update(stmt).await
+    }
+    async fn select(&self, stmt: SelectStatement) -> UdmResult<Vec<DbRow>> {
+        let _guard = self.transaction_lock.read().await;
+        self.run_select(stmt).await
+    }
+    async fn begin<'a>(&'a self) -> UdmResult<Box<dyn DbTransaction + 'a>> {
+        let guard = Arc::clone(&self.transaction_lock).write_owned().await;
+        tracing::debug!("Beginning transaction");
+        self.connection
+            .call_unwrap(|conn| conn.execute_batch("BEGIN"))
+            .await?;
+        Ok(Box::new(SqliteTransaction {
+            connection: self,
+            guard: Some(guard),
+        }))
+    }
+    async fn ping(&self) -> UdmResult<()> {
+        let _guard = self.transaction_lock.read().await;
+        self.connection
+            .call_unwrap(|conn| conn.execute_batch("SELECT 1"))
+            .await
+            .map_err(UdmError::from)
+    }
+}
+
+pub struct SqliteTransaction<'a> {
+    connection: &'a OpenSqliteConnection,
+    // Released once the transaction is committed or rolled back
+    guard: Option<OwnedRwLockWriteGuard<()>>,
+}
 
-    async fn delete(&self, _stmt: String) -> UdmResult<()> {
-        todo!()
+impl SqliteTransaction<'_> {
+    async fn finish(mut self: Box<Self>, sql: &'static str) -> UdmResult<()> {
+        tracing::debug!("Ending transaction with {}", sql);
+        let _guard = self.guard.take();
+        self.connection
+            .connection
+            .call_unwrap(move |conn| {
+                let result = conn.execute_batch(sql);
+                // A failed COMMIT leaves the transaction open
+                if result.is_err() && !conn.is_autocommit() {
+                    conn.execute_batch("ROLLBACK")?;
+                }
+                result
+            })
+            .await
+            .map_err(|e| UdmError::ApiFailure(e.to_string()))
     }
-    async fn update(&self, _stmt: String) -> UdmResult<i32> {
-        todo!()
+}
+
+#[async_trait]
+impl DbTransaction for SqliteTransaction<'_> {
+    async fn insert(&mut self, stmt: InsertStatement) -> UdmResult<i32> {
+        self.connection.run_insert(stmt).await
+    }
+    async fn delete(&mut self, stmt: DeleteStatement) -> UdmResult<()> {
+        self.connection.run_delete(stmt).await
+    }
+    async fn update(&mut self, stmt: UpdateStatement) -> UdmResult<i32> {
+        self.connection.run_update(stmt).await
+    }
+    async fn select(&mut self, stmt: SelectStatement) -> UdmResult<Vec<DbRow>> {
+        self.connection.run_select(stmt).await
     }
-    async fn select(&self, _stmt: String) -> UdmResult<Vec<Row>> {
-        todo!()
+    async fn commit(self: Box<Self>) -> UdmResult<()> {
+        self.finish("COMMIT").await
+    }
+    async fn rollback(self: Box<Self>) -> UdmResult<()> {
+        self.finish("ROLLBACK").await
+    }
+}
+
+impl Drop for SqliteTransaction<'_> {
+    fn drop(&mut self) {
+        // Dropped mid way, e.g. the rpc was cancelled. The lock is held until
+        // the rollback went through
+        let Some(guard) = self.guard.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let connection = self.connection.connection.clone();
+        runtime.spawn(async move {
+            if let Err(e) = connection
+                .call_unwrap(|conn| conn.execute_batch("ROLLBACK"))
+                .await
+            {
+                tracing::error!("Failed to roll back transaction: {}", e);
+            }
+            drop(guard);
+        });
     }
 }
 
@@ -35,57 +145,649 @@ impl OpenSqliteConnection {
         let conn = tokio_rusqlite::Connection::open(path)
             .await
             .unwrap_or_else(|e| panic!("Error connection to {} due to: {:?}", path.display(), e));
+        // Sqlite leaves foreign keys off per connection, the schema relies on
+        // them to cascade deletes the same way postgres does
+        conn.call_unwrap(|conn| conn.execute_batch("PRAGMA foreign_keys = ON;"))
+            .await
+            .unwrap_or_else(|e| panic!("Error enabling foreign keys due to: {:?}", e));
         tracing::info!("Established Connection with sqlite file");
         OpenSqliteConnection {
             connection: conn,
             settings,
+            transaction_lock: Arc::new(RwLock::new(())),
         }
     }
+    async fn run_insert(&self, stmt: InsertStatement) -> UdmResult<i32> {
+        let (sql, values) = stmt.build(SqliteQueryBuilder);
+        tracing::info!("Received insert call query: {} with {:?}", &sql, &values);
+        let data = self.query_id(sql, values).await;
+        tracing::debug!("Result from inserting into db {:?}", &data);
+        data
+    }
+    async fn run_delete(&self, stmt: DeleteStatement) -> UdmResult<()> {
+        let (sql, values) = stmt.build(SqliteQueryBuilder);
+        tracing::info!("Received delete call query: {} with {:?}", &sql, &values);
+        let params = Self::bind_params(values)?;
+        let result = self
+            .connection
+            .call_unwrap(move |conn| {
+                conn.prepare_cached(sql.as_str())?
+                    .execute(params_from_iter(params))
+            })
+            .await
+            .map_err(|e| {
+                tracing::error!("{}", e.to_string());
+                UdmError::from(e)
+            })?;
+        tracing::debug!("Deleted {} rows from db", result);
+        Ok(())
+    }
+    async fn run_update(&self, stmt: UpdateStatement) -> UdmResult<i32> {
+        let (sql, values) = stmt.build(SqliteQueryBuilder);
+        tracing::info!("Received update call query: {} with {:?}", &sql, &values);
+        let data = self.query_id(sql, values).await;
+        tracing::debug!("Result from updating db {:?}", &data);
+        data
+    }
+    async fn run_select(&self, stmt: SelectStatement) -> UdmResult<Vec<DbRow>> {
+        let (sql, values) = stmt.build(SqliteQueryBuilder);
+        tracing::info!("Received select call query: {} with {:?}", &sql, &values);
+        let params = Self::bind_params(values)?;
+        let rows = self
+            .connection
+            .call_unwrap(move |conn| {
+                let mut prepared = conn.prepare_cached(sql.as_str())?;
+                let rows =
+                    prepared.query_map(params_from_iter(params), |row| DbRow::try_from(row))?;
+                rows.collect::<rusqlite::Result<Vec<DbRow>>>()
+            })
+            .await
+            .map_err(|e| {
+                tracing::error!("{}", e.to_string());
+                UdmError::from(e)
+            })?;
+        tracing::debug!("Result from selecting from db {:?}", &rows);
+        Ok(rows)
+    }
+    // Runs a statement with a RETURNING clause and reads back the id
+    async fn query_id(&self, sql: String, values: Values) -> UdmResult<i32> {
+        let params = Self::bind_params(values)?;
+        self.connection
+            .call_unwrap(move |conn| {
+                conn.prepare_cached(sql.as_str())?
+                    .query_row(params_from_iter(params), |row| row.get(0))
+            })
+            .await
+            .map_err(|e| {
+                tracing::error!("{}", e.to_string());
+                UdmError::from(e)
+            })
+    }
+    fn bind_params(values: Values) -> UdmResult<Vec<SqliteValue>> {
+        values
+            .into_iter()
+            .map(|value| {
+                Ok(match DbValue::try_from(value)? {
+                    DbValue::Null => SqliteValue::Null,
+                    DbValue::Bool(data) => SqliteValue::Integer(data.into()),
+                    DbValue::Int(data) => SqliteValue::Integer(data),
+                    DbValue::Float(data) => SqliteValue::Real(data),
+                    DbValue::Text(data) => SqliteValue::Text(data),
+                })
+            })
+            .collect()
+    }
 }
 
 #[async_trait]
 impl DatabaseTransactionsFactory for OpenSqliteConnection {
     async fn collect_all_current_tables(&mut self) -> UdmResult<Vec<String>> {
-        todo!()
-        // tracing::debug!("Getting current tables in db");
-        // let mut stmt = self.connection.prepare("SELECT name FROM main.sqlite_master WHERE type='table'")?;
-        // let table_rows = stmt.query_map([], |rows| rows.get(0))?;
-        // let mut tables: Vec<String> = Vec::new();
-        // for row in table_rows {
-        //     tables.push(row?);
-        // }
-        // tracing::trace!("Data: tables {:?}", tables);
-        // Ok(tables)
-    }
-
-    async fn gen_schmea(&mut self) -> UdmResult<()> {
-        todo!()
-        // let tables = [
-        //     db::FluidRegulationSchema::create_table(SqliteQueryBuilder),
-        //     db::InstructionSchema::create_table(SqliteQueryBuilder),
-        //     db::RecipeSchema::create_table(SqliteQueryBuilder),
-        //     db::IngredientSchema::create_table(SqliteQueryBuilder),
-        //     db::InstructionToRecipeSchema::create_table(SqliteQueryBuilder),
-        // ]
-        // .join("; ");
-        // tracing::debug!("Ensure schmea is defined and exists");
-        // let mut batch = rusqlite::Batch::new(&self.connection, &tables);
-        // while let Some(mut stmt) = batch.next().unwrap_or_else(|e| {
-        //     panic!("Failure creating the schema {:?} with query {:?}", e, batch)
-        // }) {
-        //     tracing::trace!("Using Batched query {:?}", stmt);
-        //     stmt.execute([]).unwrap_or_else(|e| {
-        //         panic!(
-        //             "Failure creating the schema {:?} with query {:?}",
-        //             e,
-        //             stmt.expanded_sql()
-        //         )
-        //     });
-        // }
-        // tracing::debug!("Tables created");
-        // Ok(())
+        tracing::debug!("Getting current tables in db");
+        let tables = self
+            .connection
+            .call_unwrap(|conn| {
+                let mut stmt =
+                    conn.prepare("SELECT name FROM main.sqlite_master WHERE type='table'")?;
+                let table_rows = stmt.query_map([], |row| row.get(0))?;
+                table_rows.collect::<rusqlite::Result<Vec<String>>>()
+            })
+            .await?;
+        tracing::trace!("Data: tables {:?}", tables);
+        Ok(tables)
+    }
+
+    async fn execute_schema(&self, statements: Vec<SchemaStatement>) -> UdmResult<()> {
+        let query = statements
+            .iter()
+            .map(|statement| statement.to_string(SqliteQueryBuilder))
+            .collect::<Vec<String>>()
+            .join("; ");
+        tracing::info!("Running query: {}", &query);
+        let _guard = self.transaction_lock.read().await;
+        self.connection
+            .call_unwrap(move |conn| {
+                let transaction = conn.transaction()?;
+                transaction.execute_batch(query.as_str())?;
+                transaction.commit()
+            })
+            .await
+            .map_err(|e| UdmError::ApiFailure(e.to_string()))
     }
     async fn truncate_schema(&self) -> UdmResult<()> {
-        todo!()
+        // Sqlite has no TRUNCATE, children are cleared before their parents
+        let query = [
+            "DrinkOrder",
+            "Bottle",
+            "InstructionToRecipe",
+            "Ingredient",
+            "Recipe",
+            "Instruction",
+            "FlowCalibration",
+            "FluidRegulation",
+        ]
+        .iter()
+        .map(|table| format!(r#"DELETE FROM "{}";"#, table))
+        .collect::<Vec<String>>()
+        .join(" ");
+        tracing::info!("Running query: {}", &query);
+        let _guard = self.transaction_lock.read().await;
+        self.connection
+            .call_unwrap(move |conn| {
+                let transaction = conn.transaction()?;
+                transaction.execute_batch(query.as_str())?;
+                transaction.commit()
+            })
+            .await
+            .map_err(|e| UdmError::ApiFailure(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::executor::GenQueries;
+    use crate::db::migrations::Migrator;
+    use crate::db::FluidRegulationSchema;
+    use crate::db::IngredientSchema;
+    use crate::db::RecipeSchema;
+    use crate::parsers::filter::parse;
+    use crate::rpc_types::fhs_types::Bottle;
+    use crate::rpc_types::fhs_types::FlowCalibration;
+    use crate::rpc_types::fhs_types::FluidRegulator;
+    use crate::rpc_types::fhs_types::RegulatorType;
+    use crate::rpc_types::recipe_types::Ingredient;
+    use crate::rpc_types::recipe_types::IngredientType;
+    use crate::rpc_types::recipe_types::Instruction;
+    use crate::rpc_types::recipe_types::Recipe;
+    use crate::rpc_types::service_types::CollectExpressions;
+    use crate::rpc_types::service_types::CollectFluidRegulatorsRequest;
+    use crate::rpc_types::service_types::CollectIngredientRequest;
+    use crate::rpc_types::service_types::CollectPage;
+    use crate::rpc_types::service_types::CollectRecipeRequest;
+    use crate::rpc_types::service_types::DrinkOrder;
+    use crate::rpc_types::service_types::EntityType;
+    use crate::rpc_types::service_types::InstructionToRecipeMetadata;
+    use crate::rpc_types::service_types::SortDirection;
+    use sea_query::Condition;
+
+    async fn open() -> OpenSqliteConnection {
+        let settings = SqliteConfigurer {
+            db_path: ":memory:".to_string(),
+        };
+        let conn = OpenSqliteConnection::new(settings).await;
+        Migrator::new(&conn).up(None).await.unwrap();
+        conn
+    }
+    async fn count(conn: &OpenSqliteConnection, table: &'static str) -> i64 {
+        conn.connection
+            .call_unwrap(move |conn| {
+                conn.query_row(&format!(r#"SELECT COUNT(*) FROM "{}""#, table), [], |row| {
+                    row.get(0)
+                })
+            })
+            .await
+            .unwrap()
+    }
+
+    async fn reset(conn: &OpenSqliteConnection, entity: EntityType) {
+        let mut transaction = conn.begin().await.unwrap();
+        for statement in entity.reset_statements().unwrap() {
+            transaction.delete(statement).await.unwrap();
+        }
+        transaction.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrations_create_every_table() {
+        let mut conn = open().await;
+        let tables = conn.collect_all_current_tables().await.unwrap();
+        for table in [
+            "FluidRegulation",
+            "FlowCalibration",
+            "Instruction",
+            "Recipe",
+            "Ingredient",
+            "InstructionToRecipe",
+            "DrinkOrder",
+            "Bottle",
+        ] {
+            assert!(tables.contains(&table.to_string()), "missing {}", table);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert_update_delete_every_schema() {
+        let conn = open().await;
+        let mut regulator = FluidRegulator {
+            fr_id: None,
+            gpio_pin: Some(4),
+            regulator_type: Some(1),
+        };
+        let fr_id = conn.insert(regulator.gen_insert_query()).await.unwrap();
+        regulator.fr_id = Some(fr_id);
+        regulator.gpio_pin = Some(5);
+        let query = regulator.gen_update_query();
+        assert_eq!(conn.update(query).await.unwrap(), fr_id);
+
+        let calibration = FlowCalibration {
+            fr_id,
+            ml_per_second: 10.0,
+            ..Default::default()
+        };
+        let query = calibration.gen_insert_query();
+        assert_eq!(conn.insert(query.clone()).await.unwrap(), fr_id);
+        // Calibrating again replaces the row
+        assert_eq!(conn.insert(query).await.unwrap(), fr_id);
+
+        let mut instruction = Instruction {
+            instruction_name: "Shake".to_string(),
+            instruction_detail: "Shake it well".to_string(),
+            ..Default::default()
+        };
+        instruction.id = conn.insert(instruction.gen_insert_query()).await.unwrap();
+        let recipe = Recipe {
+            name: "Margarita".to_string(),
+            ..Default::default()
+        };
+        let recipe_id = conn.insert(recipe.gen_insert_query()).await.unwrap();
+        let ingredient = Ingredient {
+            name: "Tequila".to_string(),
+            amount: 30.0,
+            regulator: Some(regulator.clone()),
+            instruction: Some(instruction.clone()),
+            ..Default::default()
+        };
+        let ingredient_id = conn.insert(ingredient.gen_insert_query()).await.unwrap();
+        let metadata = InstructionToRecipeMetadata {
+            id: None,
+            recipe_id,
+            instruction_id: instruction.id,
+            instruction_order: 1,
+        };
+        conn.insert(metadata.gen_insert_query()).await.unwrap();
+        let order = DrinkOrder {
+            recipe_id,
+            ..Default::default()
+        };
+        conn.insert(order.gen_insert_query()).await.unwrap();
+        let bottle = Bottle {
+            fr_id,
+            ingredient_id: Some(ingredient_id),
+            capacity_ml: 750.0,
+            remaining_ml: 750.0,
+        };
+        conn.insert(bottle.gen_insert_query()).await.unwrap();
+        assert_eq!(count(&conn, "FlowCalibration").await, 1);
+        assert_eq!(count(&conn, "Bottle").await, 1);
+
+        // Removing the regulator cascades to its calibration and bottle
+        conn.delete(FluidRegulator::gen_remove_query(fr_id))
+            .await
+            .unwrap();
+        assert_eq!(count(&conn, "FlowCalibration").await, 0);
+        assert_eq!(count(&conn, "Bottle").await, 0);
+        // And removing the recipe cascades to its orders
+        conn.delete(Recipe::gen_remove_query(recipe_id))
+            .await
+            .unwrap();
+        assert_eq!(count(&conn, "DrinkOrder").await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_select_reads_rows_back() {
+        let conn = open().await;
+        let mut regulator = FluidRegulator {
+            fr_id: None,
+            gpio_pin: Some(4),
+            regulator_type: Some(1),
+        };
+        regulator.fr_id = Some(conn.insert(regulator.gen_insert_query()).await.unwrap());
+        let query =
+            FluidRegulator::gen_select_query_on_fields(FluidRegulationSchema::Table, vec![]);
+        let rows = conn.select(query).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(
+            FluidRegulator::try_from(rows[0].clone()).unwrap(),
+            regulator
+        );
+
+        let ingredient = Ingredient {
+            name: "Tequila".to_string(),
+            is_active: true,
+            amount: 30.5,
+            regulator: Some(regulator.clone()),
+            ..Default::default()
+        };
+        conn.insert(ingredient.gen_insert_query()).await.unwrap();
+        let query = Ingredient::gen_select_query_on_fields(IngredientSchema::Table, vec![]);
+        let row = conn.select(query).await.unwrap().remove(0);
+        let read = Ingredient::try_from(row).unwrap();
+        assert_eq!(read.name, "Tequila");
+        assert!(read.is_active);
+        assert_eq!(read.amount, 30.5);
+        assert_eq!(read.regulator.and_then(|fr| fr.fr_id), regulator.fr_id);
+    }
+
+    #[tokio::test]
+    async fn test_filter_values_are_bound() {
+        let conn = open().await;
+        let recipe = Recipe {
+            name: "Margarita".to_string(),
+            ..Default::default()
+        };
+        conn.insert(recipe.gen_insert_query()).await.unwrap();
+        let select = |value: &str| {
+            Recipe::gen_select_query_on_fields(
+                RecipeSchema::Table,
+                vec![sea_query::Expr::col(RecipeSchema::Name).eq(value)],
+            )
+        };
+        let injected = select(r#"x' OR '1'='1"#);
+        assert!(conn.select(injected).await.unwrap().is_empty());
+        assert_eq!(conn.select(select("Margarita")).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_paged_select() {
+        let conn = open().await;
+        for name in ["Mojito", "Daiquiri", "Margarita", "Negroni", "Gimlet"] {
+            let recipe = Recipe {
+                name: name.to_string(),
+                description: format!("A {}", name),
+                ..Default::default()
+            };
+            conn.insert(recipe.gen_insert_query()).await.unwrap();
+        }
+        let mut request = CollectRecipeRequest {
+            order_by: "name".to_string(),
+            direction: SortDirection::Descending.into(),
+            limit: 2,
+            ..Default::default()
+        };
+        let mut names = Vec::new();
+        loop {
+            let page = request.get_page().unwrap();
+            let query =
+                Recipe::gen_paged_select_query(RecipeSchema::Table, Condition::all(), &page);
+            let rows = conn.select(query).await.unwrap();
+            assert!(rows.len() <= 2);
+            names.extend(
+                rows.iter()
+                    .map(|row| Recipe::try_from(row.clone()).unwrap().name),
+            );
+            request.page_token = page.next_page_token(rows.len(), 5);
+            if request.page_token.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(
+            names,
+            vec!["Negroni", "Mojito", "Margarita", "Gimlet", "Daiquiri"]
+        );
+        let count = Recipe::gen_count_query(
+            RecipeSchema::Table,
+            Condition::all().add(sea_query::Expr::col(RecipeSchema::Name).like("M%")),
+        );
+        let total: i64 = conn.select(count).await.unwrap()[0].try_get(0).unwrap();
+        assert_eq!(total, 2);
+
+        request.order_by = "Recipe".to_string();
+        assert!(request.get_page().is_err());
+        request.order_by = String::new();
+        request.page_token = "two".to_string();
+        assert!(request.get_page().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_filter_condition() {
+        let conn = open().await;
+        for name in ["Mojito", "Daiquiri", "Margarita", "Negroni", "Gimlet"] {
+            let recipe = Recipe {
+                name: name.to_string(),
+                description: format!("A {}", name),
+                ..Default::default()
+            };
+            conn.insert(recipe.gen_insert_query()).await.unwrap();
+        }
+        let mut request = CollectRecipeRequest {
+            order_by: "name".to_string(),
+            ..Default::default()
+        };
+        for (filter, expected) in [
+            ("name in ('Gimlet', 'Negroni')", vec!["Gimlet", "Negroni"]),
+            (
+                "name LIKE 'M%' AND NOT description = 'A Mojito' OR name = Gimlet",
+                vec!["Gimlet", "Margarita"],
+            ),
+            (
+                "not (recipe_id > 1, recipe_id < 5)",
+                vec!["Gimlet", "Mojito"],
+            ),
+            (
+                "name NOT IN (Mojito, Daiquiri), name != Negroni",
+                vec!["Gimlet", "Margarita"],
+            ),
+            ("description IS NULL", vec![]),
+        ] {
+            request.filter = Some(parse(filter).unwrap());
+            let query = Recipe::gen_paged_select_query(
+                RecipeSchema::Table,
+                request.get_condition().unwrap(),
+                &request.get_page().unwrap(),
+            );
+            let names: Vec<String> = conn
+                .select(query)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|row| Recipe::try_from(row).unwrap().name)
+                .collect();
+            assert_eq!(names, expected, "{}", filter);
+        }
+        request.filter = Some(parse("recipe = 1").unwrap());
+        assert!(request.get_condition().is_err());
+        request.filter = Some(parse("recipe_id = one").unwrap());
+        assert!(request.get_condition().is_err());
+        request.filter = Some(parse("recipe_id LIKE '1%'").unwrap());
+        assert!(request.get_condition().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_filter_values_are_typed() {
+        let conn = open().await;
+        let regulator = FluidRegulator {
+            fr_id: None,
+            gpio_pin: Some(23),
+            regulator_type: Some(RegulatorType::Pump.into()),
+        };
+        let fr_id = conn.insert(regulator.gen_insert_query()).await.unwrap();
+        for (name, is_active, fr_id) in [("Lime", true, None), ("Gin", false, Some(fr_id))] {
+            let ingredient = Ingredient {
+                name: name.to_string(),
+                is_active,
+                regulator: fr_id.map(|fr_id| FluidRegulator {
+                    fr_id: Some(fr_id),
+                    ..Default::default()
+                }),
+                ingredient_type: IngredientType::Fluid.into(),
+                ..Default::default()
+            };
+            conn.insert(ingredient.gen_insert_query()).await.unwrap();
+        }
+        let request = CollectIngredientRequest {
+            filter: Some(
+                parse("is_active = true OR ingredient_type != INGREDIENT_TYPE_FLUID").unwrap(),
+            ),
+            ..Default::default()
+        };
+        let query = Ingredient::gen_paged_select_query(
+            IngredientSchema::Table,
+            request.get_condition().unwrap(),
+            &request.get_page().unwrap(),
+        );
+        let rows = conn.select(query).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(Ingredient::try_from(rows[0].clone()).unwrap().name, "Lime");
+
+        let request = CollectFluidRegulatorsRequest {
+            filter: Some(parse("gpio_pin = 23 AND regulator_type = REGULATOR_TYPE_PUMP").unwrap()),
+            ..Default::default()
+        };
+        let query = FluidRegulator::gen_paged_select_query(
+            FluidRegulationSchema::Table,
+            request.get_condition().unwrap(),
+            &request.get_page().unwrap(),
+        );
+        assert_eq!(conn.select(query).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_update_of_missing_row_fails() {
+        let conn = open().await;
+        let regulator = FluidRegulator {
+            fr_id: Some(42),
+            gpio_pin: Some(4),
+            regulator_type: Some(1),
+        };
+        let query = regulator.gen_update_query();
+        assert!(conn.update(query).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_truncate_schema() {
+        let conn = open().await;
+        let recipe = Recipe {
+            name: "Margarita".to_string(),
+            ..Default::default()
+        };
+        let recipe_id = conn.insert(recipe.gen_insert_query()).await.unwrap();
+        let order = DrinkOrder {
+            recipe_id,
+            ..Default::default()
+        };
+        conn.insert(order.gen_insert_query()).await.unwrap();
+        conn.truncate_schema().await.unwrap();
+        assert_eq!(count(&conn, "Recipe").await, 0);
+        assert_eq!(count(&conn, "DrinkOrder").await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_reset_entity_clears_dependent_rows() {
+        let conn = open().await;
+        let regulator = FluidRegulator {
+            fr_id: None,
+            gpio_pin: Some(4),
+            regulator_type: Some(1),
+        };
+        let fr_id = conn.insert(regulator.gen_insert_query()).await.unwrap();
+        let mut instruction = Instruction {
+            instruction_name: "Shake".to_string(),
+            ..Default::default()
+        };
+        instruction.id = conn.insert(instruction.gen_insert_query()).await.unwrap();
+        let recipe = Recipe {
+            name: "Margarita".to_string(),
+            ..Default::default()
+        };
+        let recipe_id = conn.insert(recipe.gen_insert_query()).await.unwrap();
+        let ingredient = Ingredient {
+            name: "Tequila".to_string(),
+            regulator: Some(FluidRegulator {
+                fr_id: Some(fr_id),
+                ..regulator
+            }),
+            instruction: Some(instruction.clone()),
+            ..Default::default()
+        };
+        conn.insert(ingredient.gen_insert_query()).await.unwrap();
+        let metadata = InstructionToRecipeMetadata {
+            id: None,
+            recipe_id,
+            instruction_id: instruction.id,
+            instruction_order: 1,
+        };
+        conn.insert(metadata.gen_insert_query()).await.unwrap();
+        reset(&conn, EntityType::Instruction).await;
+        assert_eq!(count(&conn, "Instruction").await, 0);
+        assert_eq!(count(&conn, "InstructionToRecipe").await, 0);
+        // The ingredient stays but no longer points at the instruction
+        let query = Ingredient::gen_select_query_on_fields(
+            IngredientSchema::Table,
+            vec![sea_query::Expr::col(IngredientSchema::InstructionId).is_null()],
+        );
+        assert_eq!(conn.select(query).await.unwrap().len(), 1);
+        assert_eq!(count(&conn, "Recipe").await, 1);
+
+        reset(&conn, EntityType::Fluid).await;
+        assert_eq!(count(&conn, "FluidRegulation").await, 0);
+        assert_eq!(count(&conn, "Ingredient").await, 1);
+        assert!(EntityType::Unspecified.reset_statements().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_commit_and_rollback() {
+        let conn = open().await;
+        let recipe = Recipe {
+            name: "Margarita".to_string(),
+            ..Default::default()
+        };
+        let mut transaction = conn.begin().await.unwrap();
+        transaction.insert(recipe.gen_insert_query()).await.unwrap();
+        let query = Recipe::gen_select_query_on_fields(RecipeSchema::Table, vec![]);
+        assert_eq!(transaction.select(query).await.unwrap().len(), 1);
+        transaction.rollback().await.unwrap();
+        assert_eq!(count(&conn, "Recipe").await, 0);
+
+        let mut transaction = conn.begin().await.unwrap();
+        transaction.insert(recipe.gen_insert_query()).await.unwrap();
+        transaction.commit().await.unwrap();
+        assert_eq!(count(&conn, "Recipe").await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_transaction_rolls_back() {
+        let conn = open().await;
+        let recipe = Recipe {
+            name: "Margarita".to_string(),
+            ..Default::default()
+        };
+        {
+            let mut transaction = conn.begin().await.unwrap();
+            let recipe_id = transaction.insert(recipe.gen_insert_query()).await.unwrap();
+            // The instruction doesn't exist so the order is rejected
+            let metadata = InstructionToRecipeMetadata {
+                id: None,
+                recipe_id,
+                instruction_id: 42,
+                instruction_order: 1,
+            };
+            assert!(transaction
+                .insert(metadata.gen_insert_query())
+                .await
+                .is_err());
+        }
+        // Waits for the rollback to release the connection
+        let query = Recipe::gen_select_query_on_fields(RecipeSchema::Table, vec![]);
+        assert!(conn.select(query).await.unwrap().is_empty());
     }
 }
@@ -19,6 +19,10 @@ pub enum UdmError {
     ParsingError(#[from] RegexError),
     #[error("Error Setting Up Logger: {0}")]
     LoggerError(String),
+    #[error("Hardware Failure: {0}")]
+    HardwareError(String),
+    #[error("Database Unavailable: {0}")]
+    DatabaseUnavailable(String),
 }
 
 impl From<String> for UdmError {
@@ -0,0 +1,77 @@
+use crate::error::UdmError;
+use crate::gpio::GpioDriver;
+use crate::UdmResult;
+use gpio_cdev::Chip;
+use gpio_cdev::LineHandle;
+use gpio_cdev::LineRequestFlags;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const CONSUMER: &str = "udm";
+
+// Uses the linux gpiochip character device (/dev/gpiochipN)
+// Lines are requested the first time a pin is used and held until the daemon exits
+pub struct CharDevGpioDriver {
+    chip: Mutex<Chip>,
+    lines: Mutex<HashMap<u32, LineHandle>>,
+    flags: LineRequestFlags,
+}
+
+impl CharDevGpioDriver {
+    pub fn new(chip_path: &str, active_low: bool) -> UdmResult<Self> {
+        let chip = Chip::new(chip_path).map_err(|e| {
+            UdmError::HardwareError(format!("Failed to open gpio chip {}: {}", chip_path, e))
+        })?;
+        let mut flags = LineRequestFlags::OUTPUT;
+        if active_low {
+            flags |= LineRequestFlags::ACTIVE_LOW;
+        }
+        Ok(Self {
+            chip: Mutex::new(chip),
+            lines: Mutex::new(HashMap::new()),
+            flags,
+        })
+    }
+    fn set_value(&self, pin: u32, value: u8) -> UdmResult<()> {
+        let mut lines = self
+            .lines
+            .lock()
+            .map_err(|e| UdmError::HardwareError(e.to_string()))?;
+        let handle = match lines.entry(pin) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let mut chip = self
+                    .chip
+                    .lock()
+                    .map_err(|e| UdmError::HardwareError(e.to_string()))?;
+                let handle = chip
+                    .get_line(pin)
+                    .and_then(|line| line.request(self.flags, 0, CONSUMER))
+                    .map_err(|e| {
+                        UdmError::HardwareError(format!("Failed to request pin {}: {}", pin, e))
+                    })?;
+                entry.insert(handle)
+            }
+        };
+        tracing::trace!("Setting pin {} to {}", pin, value);
+        handle
+            .set_value(value)
+            .map_err(|e| UdmError::HardwareError(format!("Failed to set pin {}: {}", pin, e)))
+    }
+}
+
+impl GpioDriver for CharDevGpioDriver {
+    fn open(&self, pin: u32) -> UdmResult<()> {
+        self.set_value(pin, 1)
+    }
+    fn close(&self, pin: u32) -> UdmResult<()> {
+        self.set_value(pin, 0)
+    }
+    fn used_pins(&self) -> Vec<u32> {
+        self.lines
+            .lock()
+            .map(|lines| lines.keys().copied().collect())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,94 @@
+use crate::error::UdmError;
+use crate::gpio::GpioDriver;
+use crate::UdmResult;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinState {
+    Open,
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinEvent {
+    pub pin: u32,
+    pub state: PinState,
+}
+
+// Records every pin change instead of touching hardware.
+// Used for tests and for machines with no gpio
+#[derive(Debug, Default)]
+pub struct MockGpioDriver {
+    events: Mutex<Vec<PinEvent>>,
+    states: Mutex<HashMap<u32, PinState>>,
+}
+
+impl MockGpioDriver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn events(&self) -> Vec<PinEvent> {
+        self.events.lock().map(|e| e.clone()).unwrap_or_default()
+    }
+    pub fn state(&self, pin: u32) -> PinState {
+        self.states
+            .lock()
+            .ok()
+            .and_then(|states| states.get(&pin).copied())
+            .unwrap_or(PinState::Closed)
+    }
+    fn record(&self, pin: u32, state: PinState) -> UdmResult<()> {
+        tracing::debug!("Mock gpio pin {} is now {:?}", pin, state);
+        self.events
+            .lock()
+            .map_err(|e| UdmError::HardwareError(e.to_string()))?
+            .push(PinEvent { pin, state });
+        self.states
+            .lock()
+            .map_err(|e| UdmError::HardwareError(e.to_string()))?
+            .insert(pin, state);
+        Ok(())
+    }
+}
+
+impl GpioDriver for MockGpioDriver {
+    fn open(&self, pin: u32) -> UdmResult<()> {
+        self.record(pin, PinState::Open)
+    }
+    fn close(&self, pin: u32) -> UdmResult<()> {
+        self.record(pin, PinState::Closed)
+    }
+    fn used_pins(&self) -> Vec<u32> {
+        self.states
+            .lock()
+            .map(|states| states.keys().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pulse_records_open_then_close() {
+        let driver = MockGpioDriver::new();
+        driver.pulse(5, Duration::from_secs(3)).await.unwrap();
+        assert_eq!(
+            driver.events(),
+            vec![
+                PinEvent {
+                    pin: 5,
+                    state: PinState::Open
+                },
+                PinEvent {
+                    pin: 5,
+                    state: PinState::Closed
+                },
+            ]
+        );
+        assert_eq!(driver.state(5), PinState::Closed);
+    }
+}
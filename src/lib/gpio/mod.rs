@@ -0,0 +1,70 @@
+use crate::error::UdmError;
+use crate::parsers::settings::GpioDriverType;
+use crate::parsers::settings::HardwareConfigurer;
+use crate::UdmResult;
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(target_os = "linux")]
+pub mod chardev;
+pub mod mock;
+
+// Drives the pins the FluidRegulators are wired to.
+// Open means fluid is flowing, close is always the safe state.
+#[async_trait]
+pub trait GpioDriver: Send + Sync {
+    fn open(&self, pin: u32) -> UdmResult<()>;
+    fn close(&self, pin: u32) -> UdmResult<()>;
+    // Every pin the driver has opened or requested, an emergency stop closes
+    // all of them without asking the database
+    fn used_pins(&self) -> Vec<u32>;
+    async fn pulse(&self, pin: u32, duration: Duration) -> UdmResult<()> {
+        self.open(pin)?;
+        tokio::time::sleep(duration).await;
+        self.close(pin)
+    }
+}
+
+pub fn to_pin(gpio_pin: i32) -> UdmResult<u32> {
+    u32::try_from(gpio_pin)
+        .map_err(|_| UdmError::HardwareError(format!("Invalid gpio pin {}", gpio_pin)))
+}
+
+pub fn load_driver(config: &HardwareConfigurer) -> UdmResult<Arc<dyn GpioDriver>> {
+    match config.driver {
+        GpioDriverType::Mock => {
+            tracing::warn!("Using the mock gpio driver, no pins will be driven");
+            Ok(Arc::new(mock::MockGpioDriver::new()))
+        }
+        #[cfg(target_os = "linux")]
+        GpioDriverType::Chardev => {
+            tracing::info!("Using gpio character device {}", &config.chip_path);
+            Ok(Arc::new(chardev::CharDevGpioDriver::new(
+                config.chip_path.as_str(),
+                config.active_low,
+            )?))
+        }
+        #[cfg(not(target_os = "linux"))]
+        GpioDriverType::Chardev => Err(UdmError::InvalidateConfiguration(String::from(
+            "The chardev gpio driver is only available on linux",
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_pin() {
+        assert_eq!(to_pin(17).unwrap(), 17);
+        assert!(to_pin(-1).is_err());
+    }
+
+    #[test]
+    fn test_load_mock_driver() {
+        let driver = load_driver(&HardwareConfigurer::default()).unwrap();
+        assert!(driver.open(4).is_ok());
+        assert!(driver.close(4).is_ok());
+    }
+}
@@ -5,8 +5,10 @@ use std::path::PathBuf;
 use std::result;
 pub mod db;
 pub mod error;
+pub mod gpio;
 pub mod logger;
 pub mod parsers;
+pub mod pour;
 pub mod rpc_types;
 
 pub type UdmResult<T> = result::Result<T, error::UdmError>;
@@ -0,0 +1,423 @@
+// The filter language behind `udm <entity> show --query`. Conditions are
+// joined with AND (or a comma) and OR, grouped with parentheses and negated
+// with NOT, AND binds tighter than OR. Keywords are case insensitive
+//
+//   name = 'Old Fashioned' OR (recipe_id IN (1, 2, 3) AND NOT user_input = true)
+//   description IS NOT NULL, name LIKE 'M%'
+use crate::error::UdmError;
+use crate::rpc_types::service_types::filter_expression::Node;
+use crate::rpc_types::service_types::FetchData;
+use crate::rpc_types::service_types::FilterExpression;
+use crate::rpc_types::service_types::FilterGroup;
+use crate::rpc_types::service_types::Operation;
+use crate::UdmResult;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Op(Operation),
+    LParen,
+    RParen,
+    Comma,
+}
+
+impl Token {
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Token::Word(word) if word.eq_ignore_ascii_case(keyword))
+    }
+}
+
+/// Parses a filter typed by a user into the tree the server compiles
+pub fn parse(input: &str) -> UdmResult<FilterExpression> {
+    let tokens = tokenize(input)?;
+    if tokens.is_empty() {
+        return Err(UdmError::InvalidInput("The filter is empty".to_string()));
+    }
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        end: input.len(),
+    };
+    let expression = parser.parse_or()?;
+    match parser.tokens.get(parser.position) {
+        Some(_) => Err(parser.unexpected("the end of the filter")),
+        None => Ok(expression),
+    }
+}
+
+// Every token with the byte offset it starts at
+fn tokenize(input: &str) -> UdmResult<Vec<(usize, Token)>> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            ',' => Token::Comma,
+            '=' => Token::Op(Operation::Equal),
+            '!' | '<' | '>' => {
+                let equals = chars.next_if(|(_, next)| *next == '=').is_some();
+                match (c, equals) {
+                    ('!', true) => Token::Op(Operation::NotEqual),
+                    ('<', true) => Token::Op(Operation::LessThanOrEqual),
+                    ('<', false) => Token::Op(Operation::LessThan),
+                    ('>', true) => Token::Op(Operation::GreaterThanOrEqual),
+                    ('>', false) => Token::Op(Operation::GreaterThan),
+                    _ => {
+                        return Err(UdmError::InvalidInput(format!(
+                            "Expected != at position {}",
+                            start
+                        )))
+                    }
+                }
+            }
+            '\'' | '"' => {
+                let mut value = String::new();
+                let mut closed = false;
+                while let Some((_, next)) = chars.next() {
+                    match next {
+                        '\\' => {
+                            if let Some((_, escaped)) = chars.next() {
+                                value.push(escaped);
+                            }
+                        }
+                        quote if quote == c => {
+                            closed = true;
+                            break;
+                        }
+                        other => value.push(other),
+                    }
+                }
+                if !closed {
+                    return Err(UdmError::InvalidInput(format!(
+                        "Unterminated string starting at position {}",
+                        start
+                    )));
+                }
+                Token::Quoted(value)
+            }
+            _ => {
+                let mut word = String::from(c);
+                while let Some((_, next)) = chars
+                    .next_if(|(_, next)| !next.is_whitespace() && !"()=!<>,'\"".contains(*next))
+                {
+                    word.push(next);
+                }
+                Token::Word(word)
+            }
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    // Reported when the filter ends too early
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.peek().is_some_and(|token| token.is_keyword(keyword));
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+    fn expect(&mut self, expected: Token, description: &str) -> UdmResult<()> {
+        if self.peek() == Some(&expected) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.unexpected(description))
+        }
+    }
+    fn unexpected(&self, expected: &str) -> UdmError {
+        match self.tokens.get(self.position) {
+            Some((position, token)) => UdmError::InvalidInput(format!(
+                "Expected {} at position {}, found {}",
+                expected,
+                position,
+                describe(token)
+            )),
+            None => UdmError::InvalidInput(format!(
+                "Expected {} at position {}, found the end of the filter",
+                expected, self.end
+            )),
+        }
+    }
+    fn parse_or(&mut self) -> UdmResult<FilterExpression> {
+        let mut expressions = vec![self.parse_and()?];
+        while self.eat_keyword("or") {
+            expressions.push(self.parse_and()?);
+        }
+        Ok(group(expressions, Node::Any))
+    }
+    fn parse_and(&mut self) -> UdmResult<FilterExpression> {
+        let mut expressions = vec![self.parse_unary()?];
+        loop {
+            if self.peek() == Some(&Token::Comma) {
+                self.position += 1;
+            } else if !self.eat_keyword("and") {
+                break;
+            }
+            expressions.push(self.parse_unary()?);
+        }
+        Ok(group(expressions, Node::All))
+    }
+    fn parse_unary(&mut self) -> UdmResult<FilterExpression> {
+        if self.eat_keyword("not") {
+            let expression = self.parse_unary()?;
+            return Ok(FilterExpression {
+                node: Some(Node::Not(Box::new(expression))),
+            });
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.position += 1;
+            let expression = self.parse_or()?;
+            self.expect(Token::RParen, ")")?;
+            return Ok(expression);
+        }
+        self.parse_comparison()
+    }
+    fn parse_comparison(&mut self) -> UdmResult<FilterExpression> {
+        let column = match self.peek() {
+            Some(Token::Word(word)) if !is_reserved(word) => word.to_lowercase(),
+            _ => return Err(self.unexpected("a column")),
+        };
+        self.position += 1;
+        let negated = self.eat_keyword("not");
+        let (operation, values) = if self.eat_keyword("in") {
+            let operation = if negated {
+                Operation::NotIn
+            } else {
+                Operation::In
+            };
+            (operation, self.parse_list()?)
+        } else if self.eat_keyword("like") {
+            let operation = if negated {
+                Operation::NotLike
+            } else {
+                Operation::Like
+            };
+            (operation, vec![self.parse_value()?])
+        } else if !negated && self.eat_keyword("is") {
+            let operation = if self.eat_keyword("not") {
+                Operation::NotIs
+            } else {
+                Operation::Is
+            };
+            if !self.eat_keyword("null") {
+                return Err(self.unexpected("NULL"));
+            }
+            (operation, Vec::new())
+        } else if negated {
+            return Err(self.unexpected("IN or LIKE"));
+        } else {
+            match self.peek() {
+                Some(Token::Op(operation)) => {
+                    let operation = *operation;
+                    self.position += 1;
+                    (operation, vec![self.parse_value()?])
+                }
+                _ => return Err(self.unexpected("an operator")),
+            }
+        };
+        Ok(FilterExpression {
+            node: Some(Node::Condition(FetchData {
+                column,
+                operation: operation.into(),
+                values,
+            })),
+        })
+    }
+    fn parse_list(&mut self) -> UdmResult<Vec<String>> {
+        self.expect(Token::LParen, "(")?;
+        let mut values = vec![self.parse_value()?];
+        while self.peek() == Some(&Token::Comma) {
+            self.position += 1;
+            values.push(self.parse_value()?);
+        }
+        self.expect(Token::RParen, ")")?;
+        Ok(values)
+    }
+    fn parse_value(&mut self) -> UdmResult<String> {
+        match self.peek() {
+            Some(Token::Quoted(value)) => {
+                let value = value.clone();
+                self.position += 1;
+                Ok(value)
+            }
+            Some(Token::Word(word)) if !is_reserved(word) => {
+                let value = word.clone();
+                self.position += 1;
+                Ok(value)
+            }
+            _ => Err(self.unexpected("a value")),
+        }
+    }
+}
+
+// A single expression is kept as is rather than wrapped in a group of one
+fn group(
+    mut expressions: Vec<FilterExpression>,
+    node: fn(FilterGroup) -> Node,
+) -> FilterExpression {
+    if expressions.len() == 1 {
+        return expressions.remove(0);
+    }
+    FilterExpression {
+        node: Some(node(FilterGroup { expressions })),
+    }
+}
+
+// Keywords need quoting to be used as values
+fn is_reserved(word: &str) -> bool {
+    ["and", "or", "not", "in", "like", "is", "null"]
+        .iter()
+        .any(|keyword| word.eq_ignore_ascii_case(keyword))
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word(word) => word.clone(),
+        Token::Quoted(value) => format!("'{}'", value),
+        Token::Op(operation) => operation.to_str().to_string(),
+        Token::LParen => "(".to_string(),
+        Token::RParen => ")".to_string(),
+        Token::Comma => ",".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(column: &str, operation: Operation, values: &[&str]) -> FilterExpression {
+        FilterExpression {
+            node: Some(Node::Condition(FetchData {
+                column: column.to_string(),
+                operation: operation.into(),
+                values: values.iter().map(|value| value.to_string()).collect(),
+            })),
+        }
+    }
+    fn all(expressions: Vec<FilterExpression>) -> FilterExpression {
+        FilterExpression {
+            node: Some(Node::All(FilterGroup { expressions })),
+        }
+    }
+    fn any(expressions: Vec<FilterExpression>) -> FilterExpression {
+        FilterExpression {
+            node: Some(Node::Any(FilterGroup { expressions })),
+        }
+    }
+    fn not(expression: FilterExpression) -> FilterExpression {
+        FilterExpression {
+            node: Some(Node::Not(Box::new(expression))),
+        }
+    }
+
+    #[test]
+    fn test_comparisons() {
+        assert_eq!(
+            parse("recipe_id>=2").unwrap(),
+            condition("recipe_id", Operation::GreaterThanOrEqual, &["2"])
+        );
+        assert_eq!(
+            parse("name != 'Old Fashioned'").unwrap(),
+            condition("name", Operation::NotEqual, &["Old Fashioned"])
+        );
+        assert_eq!(
+            parse(r#"name LIKE "it\"s%""#).unwrap(),
+            condition("name", Operation::Like, &["it\"s%"])
+        );
+    }
+
+    #[test]
+    fn test_comma_is_and() {
+        assert_eq!(
+            parse("fr_id=1,regulator_type=tap").unwrap(),
+            all(vec![
+                condition("fr_id", Operation::Equal, &["1"]),
+                condition("regulator_type", Operation::Equal, &["tap"]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_and_binds_tighter_than_or() {
+        assert_eq!(
+            parse("a=1 or b=2 and c=3").unwrap(),
+            any(vec![
+                condition("a", Operation::Equal, &["1"]),
+                all(vec![
+                    condition("b", Operation::Equal, &["2"]),
+                    condition("c", Operation::Equal, &["3"]),
+                ]),
+            ])
+        );
+        assert_eq!(
+            parse("(a=1 OR b=2) AND NOT c=3").unwrap(),
+            all(vec![
+                any(vec![
+                    condition("a", Operation::Equal, &["1"]),
+                    condition("b", Operation::Equal, &["2"]),
+                ]),
+                not(condition("c", Operation::Equal, &["3"])),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_in_lists_and_null_checks() {
+        assert_eq!(
+            parse("recipe_id in (1, 2,'3')").unwrap(),
+            condition("recipe_id", Operation::In, &["1", "2", "3"])
+        );
+        assert_eq!(
+            parse("name NOT IN ('a b')").unwrap(),
+            condition("name", Operation::NotIn, &["a b"])
+        );
+        assert_eq!(
+            parse("description is null").unwrap(),
+            condition("description", Operation::Is, &[])
+        );
+        assert_eq!(
+            parse("description IS NOT NULL").unwrap(),
+            condition("description", Operation::NotIs, &[])
+        );
+    }
+
+    #[test]
+    fn test_errors_point_at_the_problem() {
+        for (input, message) in [
+            ("", "The filter is empty"),
+            ("name = 'open", "Unterminated string starting at position 7"),
+            (
+                "(a=1",
+                "Expected ) at position 4, found the end of the filter",
+            ),
+            (
+                "a=1 b=2",
+                "Expected the end of the filter at position 4, found b",
+            ),
+            ("a in ()", "Expected a value at position 6, found )"),
+            ("a is 1", "Expected NULL at position 5, found 1"),
+            ("a ! 1", "Expected != at position 2"),
+            ("a = and", "Expected a value at position 4, found and"),
+        ] {
+            match parse(input) {
+                Err(UdmError::InvalidInput(error)) => assert_eq!(error, message, "{}", input),
+                other => panic!("{} parsed as {:?}", input, other),
+            }
+        }
+    }
+}
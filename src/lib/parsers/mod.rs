@@ -4,6 +4,7 @@ use serde::Deserialize;
 use std::fmt::Debug;
 use std::sync::Arc;
 
+pub mod filter;
 pub mod settings;
 
 pub trait UdmConfig: for<'a> Deserialize<'a> + Debug + Default {}
@@ -1,5 +1,6 @@
 use crate::parsers::UdmConfig;
 use serde::Deserialize;
+use tokio_postgres::config::SslMode;
 use tokio_postgres::Config;
 
 #[derive(Default, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -30,6 +31,8 @@ pub struct DaemonConfigurer {
     pub postgres: Option<PostgresConfigurer>,
     pub sqlite: Option<SqliteConfigurer>,
     pub log_file_path: String,
+    #[serde(default)]
+    pub hardware: HardwareConfigurer,
 }
 
 impl Default for DaemonConfigurer {
@@ -38,6 +41,7 @@ impl Default for DaemonConfigurer {
             postgres: Some(PostgresConfigurer::default()),
             sqlite: None,
             log_file_path: "/var/log/udm/udm_daemon".to_string(),
+            hardware: HardwareConfigurer::default(),
         }
     }
 }
@@ -55,6 +59,36 @@ impl DaemonConfigurer {
     }
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GpioDriverType {
+    // Records pin changes without touching hardware
+    #[default]
+    Mock,
+    // Linux gpiochip character device
+    Chardev,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HardwareConfigurer {
+    #[serde(default)]
+    pub driver: GpioDriverType,
+    #[serde(default = "default_gpio_chip_path")]
+    pub chip_path: String,
+    #[serde(default)]
+    pub active_low: bool,
+}
+impl Default for HardwareConfigurer {
+    fn default() -> Self {
+        Self {
+            driver: GpioDriverType::default(),
+            chip_path: default_gpio_chip_path(),
+            active_low: false,
+        }
+    }
+}
+impl UdmConfig for HardwareConfigurer {}
+
 #[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct SqliteConfigurer {
     #[serde(default = "default_daemon_db_path")]
@@ -67,7 +101,30 @@ impl Default for SqliteConfigurer {
         }
     }
 }
+impl SqliteConfigurer {
+    pub fn new(db_path: impl Into<String>) -> Self {
+        Self {
+            db_path: db_path.into(),
+        }
+    }
+}
 impl UdmConfig for SqliteConfigurer {}
+// Same meaning as libpq's sslmode
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PostgresSslMode {
+    #[default]
+    Disable,
+    // Use TLS when the server offers it
+    Prefer,
+    // Always use TLS, the certificate is only checked when ssl_root_cert is set
+    Require,
+    // Check the certificate against ssl_root_cert but not the host name
+    VerifyCa,
+    // Check the certificate and that it was issued for the host
+    VerifyFull,
+}
+
 #[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct PostgresConfigurer {
     #[serde(default = "PostgresConfigurer::set_user_default")]
@@ -84,6 +141,25 @@ pub struct PostgresConfigurer {
     application_name: Option<String>,
     #[serde(default)]
     options: Option<String>,
+    // Connections kept open in the pool, opened at startup and topped up by the
+    // health probe
+    #[serde(default = "PostgresConfigurer::set_default_pool_min_size")]
+    pub(crate) pool_min_size: usize,
+    #[serde(default = "PostgresConfigurer::set_default_pool_max_size")]
+    pub(crate) pool_max_size: usize,
+    // How long a request waits for a free connection before failing
+    #[serde(default = "PostgresConfigurer::set_default_checkout_timeout_ms")]
+    pub(crate) checkout_timeout_ms: u64,
+    #[serde(default)]
+    pub(crate) ssl_mode: PostgresSslMode,
+    // PEM bundle the server certificate is checked against
+    #[serde(default)]
+    pub(crate) ssl_root_cert: Option<String>,
+    // PEM client certificate and key, for servers that require one
+    #[serde(default)]
+    pub(crate) ssl_cert: Option<String>,
+    #[serde(default)]
+    pub(crate) ssl_key: Option<String>,
 }
 impl Default for PostgresConfigurer {
     fn default() -> Self {
@@ -95,6 +171,13 @@ impl Default for PostgresConfigurer {
             host: Self::set_default_host(),
             application_name: None,
             options: None,
+            pool_min_size: Self::set_default_pool_min_size(),
+            pool_max_size: Self::set_default_pool_max_size(),
+            checkout_timeout_ms: Self::set_default_checkout_timeout_ms(),
+            ssl_mode: PostgresSslMode::default(),
+            ssl_root_cert: None,
+            ssl_cert: None,
+            ssl_key: None,
         }
     }
 }
@@ -120,6 +203,15 @@ impl PostgresConfigurer {
     fn set_default_host() -> String {
         String::from("localhost")
     }
+    fn set_default_pool_min_size() -> usize {
+        2
+    }
+    fn set_default_pool_max_size() -> usize {
+        16
+    }
+    fn set_default_checkout_timeout_ms() -> u64 {
+        5000
+    }
 }
 #[allow(clippy::from_over_into)]
 impl Into<Config> for PostgresConfigurer {
@@ -132,6 +224,11 @@ impl Into<Config> for PostgresConfigurer {
             .host(self.host.as_str())
             .application_name(self.application_name.unwrap_or_default().as_str())
             .options(self.options.unwrap_or_default().as_str())
+            .ssl_mode(match self.ssl_mode {
+                PostgresSslMode::Disable => SslMode::Disable,
+                PostgresSslMode::Prefer => SslMode::Prefer,
+                _ => SslMode::Require,
+            })
             .to_owned()
     }
 }
@@ -146,3 +243,7 @@ fn default_daemon_db_path() -> String {
 fn default_udm_port() -> i64 {
     19211
 }
+
+fn default_gpio_chip_path() -> String {
+    String::from("/dev/gpiochip0")
+}
@@ -0,0 +1,238 @@
+// Decides which recipes can be poured with what is loaded right now.
+// An ingredient is available when it is active and, for fluids, connected to
+// a calibrated regulator whose bottle holds it and enough for the whole recipe
+use crate::pour::connected_regulator;
+use crate::rpc_types::fhs_types::Bottle;
+use crate::rpc_types::fhs_types::FlowCalibration;
+use crate::rpc_types::recipe_types::DrinkSize;
+use crate::rpc_types::recipe_types::Ingredient;
+use crate::rpc_types::recipe_types::IngredientType;
+use crate::rpc_types::recipe_types::Recipe;
+use crate::rpc_types::service_types::MissingIngredient;
+use crate::rpc_types::service_types::MissingReason;
+use itertools::Itertools;
+use std::collections::HashMap;
+
+pub struct Stock {
+    // Keyed by fr_id
+    pub calibrations: HashMap<i32, FlowCalibration>,
+    pub bottles: HashMap<i32, Bottle>,
+}
+
+impl Stock {
+    /// Every ingredient of `recipe` that keeps it from being poured at `size`.
+    /// `ingredients` are keyed by instruction id
+    pub fn missing_ingredients(
+        &self,
+        recipe: &Recipe,
+        size: DrinkSize,
+        ingredients: &HashMap<i32, Vec<Ingredient>>,
+    ) -> Vec<MissingIngredient> {
+        let recipe_size = DrinkSize::try_from(recipe.size).unwrap_or(DrinkSize::Unspecified);
+        let scale = recipe_size.scale_to(size);
+        let used = recipe
+            .instructions
+            .values()
+            .flat_map(|instruction| ingredients.get(&instruction.id).into_iter().flatten())
+            .collect_vec();
+        // Ingredients sharing a regulator draw from the same bottle
+        let mut required_ml: HashMap<i32, f32> = HashMap::new();
+        for ingredient in &used {
+            if let Some(fr_id) = Self::fluid_regulator(ingredient) {
+                *required_ml.entry(fr_id).or_default() += ingredient.amount * scale;
+            }
+        }
+        used.into_iter()
+            .filter_map(|ingredient| {
+                let reasons = self.missing_reasons(ingredient, &required_ml);
+                (!reasons.is_empty()).then(|| MissingIngredient {
+                    ingredient_id: ingredient.id,
+                    name: ingredient.name.clone(),
+                    reasons: reasons.into_iter().map(Into::into).collect(),
+                })
+            })
+            .collect()
+    }
+    fn missing_reasons(
+        &self,
+        ingredient: &Ingredient,
+        required_ml: &HashMap<i32, f32>,
+    ) -> Vec<MissingReason> {
+        let mut reasons = Vec::new();
+        if !ingredient.is_active {
+            reasons.push(MissingReason::Inactive);
+        }
+        if ingredient.ingredient_type() != IngredientType::Fluid {
+            return reasons;
+        }
+        let Some(fr_id) = Self::fluid_regulator(ingredient) else {
+            reasons.push(MissingReason::NoRegulator);
+            return reasons;
+        };
+        if !self.calibrations.contains_key(&fr_id) {
+            reasons.push(MissingReason::NotCalibrated);
+        }
+        // Regulators without a bottle loaded are not tracked
+        let Some(bottle) = self.bottles.get(&fr_id) else {
+            return reasons;
+        };
+        // The pour refuses a bottle loaded with something else
+        if bottle
+            .ingredient_id
+            .is_some_and(|loaded| loaded != ingredient.id)
+        {
+            reasons.push(MissingReason::WrongBottle);
+        }
+        if let Some(required) = required_ml.get(&fr_id) {
+            if bottle.remaining_ml < *required {
+                reasons.push(MissingReason::OutOfStock);
+            }
+        }
+        reasons
+    }
+    fn fluid_regulator(ingredient: &Ingredient) -> Option<i32> {
+        if ingredient.ingredient_type() != IngredientType::Fluid {
+            return None;
+        }
+        connected_regulator(ingredient)?.fr_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pour::PourPlan;
+    use crate::rpc_types::fhs_types::FluidRegulator;
+    use crate::rpc_types::recipe_types::Instruction;
+
+    fn ingredient(id: i32, fr_id: Option<i32>, amount: f32) -> Ingredient {
+        Ingredient {
+            id,
+            name: format!("ingredient {}", id),
+            is_active: true,
+            amount,
+            ingredient_type: IngredientType::Fluid.into(),
+            regulator: fr_id.map(|fr_id| FluidRegulator {
+                fr_id: Some(fr_id),
+                gpio_pin: Some(fr_id + 10),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+    fn recipe() -> Recipe {
+        let instruction = Instruction {
+            id: 10,
+            ..Default::default()
+        };
+        Recipe {
+            id: 1,
+            size: DrinkSize::Small.into(),
+            instructions: HashMap::from([(1, instruction)]),
+            ..Default::default()
+        }
+    }
+    fn stock(remaining_ml: f32) -> Stock {
+        let bottle = Bottle {
+            fr_id: 1,
+            ingredient_id: None,
+            capacity_ml: 750.0,
+            remaining_ml,
+        };
+        Stock {
+            calibrations: HashMap::from([(1, FlowCalibration::default())]),
+            bottles: HashMap::from([(1, bottle)]),
+        }
+    }
+    fn reasons(missing: &[MissingIngredient]) -> Vec<(i32, Vec<MissingReason>)> {
+        missing
+            .iter()
+            .map(|ingredient| {
+                let reasons = ingredient
+                    .reasons
+                    .iter()
+                    .map(|reason| MissingReason::try_from(*reason).unwrap())
+                    .collect();
+                (ingredient.ingredient_id, reasons)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_available_recipe_has_nothing_missing() {
+        let ingredients = HashMap::from([(10, vec![ingredient(1, Some(1), 30.0)])]);
+        let missing = stock(30.0).missing_ingredients(&recipe(), DrinkSize::Small, &ingredients);
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn test_missing_reasons() {
+        let mut inactive = ingredient(2, Some(1), 10.0);
+        inactive.is_active = false;
+        let ingredients = HashMap::from([(
+            10,
+            vec![
+                ingredient(1, Some(1), 30.0),
+                inactive,
+                ingredient(3, None, 5.0),
+                ingredient(4, Some(2), 5.0),
+            ],
+        )]);
+        let missing = stock(35.0).missing_ingredients(&recipe(), DrinkSize::Small, &ingredients);
+        assert_eq!(
+            reasons(&missing),
+            vec![
+                // Together they need 40ml from regulator 1
+                (1, vec![MissingReason::OutOfStock]),
+                (2, vec![MissingReason::Inactive, MissingReason::OutOfStock]),
+                (3, vec![MissingReason::NoRegulator]),
+                (4, vec![MissingReason::NotCalibrated]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_regulator_without_pin_is_missing() {
+        let mut unwired = ingredient(1, Some(1), 30.0);
+        if let Some(regulator) = unwired.regulator.as_mut() {
+            regulator.gpio_pin = None;
+        }
+        let ingredients = HashMap::from([(10, vec![unwired])]);
+        let missing = stock(30.0).missing_ingredients(&recipe(), DrinkSize::Small, &ingredients);
+        assert_eq!(
+            reasons(&missing),
+            vec![(1, vec![MissingReason::NoRegulator])]
+        );
+        // The pour agrees
+        let calibrations = HashMap::from([(1, FlowCalibration::default())]);
+        assert!(PourPlan::build(&recipe(), DrinkSize::Small, &ingredients, &calibrations).is_err());
+    }
+
+    #[test]
+    fn test_bottle_of_another_ingredient_is_missing() {
+        let ingredients = HashMap::from([(10, vec![ingredient(1, Some(1), 30.0)])]);
+        let mut stock = stock(30.0);
+        stock.bottles.get_mut(&1).unwrap().ingredient_id = Some(1);
+        assert!(stock
+            .missing_ingredients(&recipe(), DrinkSize::Small, &ingredients)
+            .is_empty());
+        stock.bottles.get_mut(&1).unwrap().ingredient_id = Some(2);
+        let missing = stock.missing_ingredients(&recipe(), DrinkSize::Small, &ingredients);
+        assert_eq!(
+            reasons(&missing),
+            vec![(1, vec![MissingReason::WrongBottle])]
+        );
+    }
+
+    #[test]
+    fn test_stock_is_checked_at_requested_size() {
+        let ingredients = HashMap::from([(10, vec![ingredient(1, Some(1), 100.0)])]);
+        let stock = stock(150.0);
+        assert!(stock
+            .missing_ingredients(&recipe(), DrinkSize::Small, &ingredients)
+            .is_empty());
+        assert!(!stock
+            .missing_ingredients(&recipe(), DrinkSize::Pint, &ingredients)
+            .is_empty());
+    }
+}
@@ -0,0 +1,178 @@
+// Keeps the events of running and recently finished pours so a client
+// that starts watching late can replay what it missed and then follow along
+use crate::pour::PourId;
+use crate::rpc_types::service_types::PourEvent;
+use crate::rpc_types::service_types::PourEventType;
+use futures::stream;
+use futures::Stream;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+const EVENT_CAPACITY: usize = 64;
+const FINISHED_POURS_KEPT: usize = 32;
+
+impl PourEvent {
+    pub fn new(pour_id: PourId, event_type: PourEventType) -> Self {
+        Self {
+            pour_id,
+            event_type: event_type.into(),
+            ..Default::default()
+        }
+    }
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self.event_type(),
+            PourEventType::Completed | PourEventType::Failed
+        )
+    }
+}
+
+struct PourChannel {
+    history: Vec<PourEvent>,
+    sender: broadcast::Sender<PourEvent>,
+    finished: bool,
+}
+
+#[derive(Default)]
+struct Pours {
+    channels: HashMap<PourId, PourChannel>,
+    finished: VecDeque<PourId>,
+}
+
+#[derive(Default)]
+pub struct PourRegistry {
+    pours: Mutex<Pours>,
+}
+
+pub struct PourSubscription {
+    history: Vec<PourEvent>,
+    // None once the pour has finished, the history is everything there is
+    receiver: Option<broadcast::Receiver<PourEvent>>,
+}
+
+impl PourRegistry {
+    pub fn register(&self, pour_id: PourId) {
+        let Ok(mut pours) = self.pours.lock() else {
+            return;
+        };
+        pours
+            .channels
+            .entry(pour_id)
+            .or_insert_with(|| PourChannel {
+                history: Vec::new(),
+                sender: broadcast::channel(EVENT_CAPACITY).0,
+                finished: false,
+            });
+    }
+    pub fn publish(&self, event: PourEvent) {
+        let Ok(mut pours) = self.pours.lock() else {
+            return;
+        };
+        let pour_id = event.pour_id;
+        let Some(channel) = pours.channels.get_mut(&pour_id) else {
+            tracing::warn!("Dropping event for unregistered pour {}", pour_id);
+            return;
+        };
+        let terminal = event.is_terminal();
+        // Progress ticks are only useful live, the closed event has the total
+        if event.event_type() != PourEventType::Dispensing {
+            channel.history.push(event.clone());
+        }
+        // No receivers is fine, nobody is watching
+        let _ = channel.sender.send(event);
+        if terminal && !channel.finished {
+            channel.finished = true;
+            pours.finished.push_back(pour_id);
+            while pours.finished.len() > FINISHED_POURS_KEPT {
+                if let Some(expired) = pours.finished.pop_front() {
+                    pours.channels.remove(&expired);
+                }
+            }
+        }
+    }
+    pub fn subscribe(&self, pour_id: PourId) -> Option<PourSubscription> {
+        let pours = self.pours.lock().ok()?;
+        let channel = pours.channels.get(&pour_id)?;
+        // Taken under the same lock as publish so no event is missed or repeated
+        Some(PourSubscription {
+            history: channel.history.clone(),
+            receiver: (!channel.finished).then(|| channel.sender.subscribe()),
+        })
+    }
+}
+
+impl PourSubscription {
+    pub fn into_stream(self) -> impl Stream<Item = PourEvent> + Send + 'static {
+        let live = stream::unfold(self.receiver, |receiver| async move {
+            let mut receiver = receiver?;
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if event.is_terminal() => return Some((event, None)),
+                    Ok(event) => return Some((event, Some(receiver))),
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Pour watcher fell behind, skipped {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        stream::iter(self.history).chain(live)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_late_subscriber_replays_history() {
+        let registry = PourRegistry::default();
+        registry.register(1);
+        registry.publish(PourEvent::new(1, PourEventType::Started));
+        let watching = registry.subscribe(1).unwrap();
+        registry.publish(PourEvent::new(1, PourEventType::Dispensing));
+        registry.publish(PourEvent::new(1, PourEventType::Completed));
+        let events = watching
+            .into_stream()
+            .map(|event| event.event_type())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                PourEventType::Started,
+                PourEventType::Dispensing,
+                PourEventType::Completed
+            ]
+        );
+
+        // Finished pours replay without the progress ticks and end the stream
+        let events = registry
+            .subscribe(1)
+            .unwrap()
+            .into_stream()
+            .map(|event| event.event_type())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            vec![PourEventType::Started, PourEventType::Completed]
+        );
+        assert!(registry.subscribe(2).is_none());
+    }
+
+    #[test]
+    fn test_finished_pours_expire() {
+        let registry = PourRegistry::default();
+        for pour_id in 0..(FINISHED_POURS_KEPT as i64 + 1) {
+            registry.register(pour_id);
+            registry.publish(PourEvent::new(pour_id, PourEventType::Failed));
+        }
+        assert!(registry.subscribe(0).is_none());
+        assert!(registry.subscribe(1).is_some());
+    }
+}
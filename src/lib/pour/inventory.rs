@@ -0,0 +1,278 @@
+// Bottle levels are checked and drawn down in one transaction before a pour
+// starts, whatever the pour did not get to is put back once it ends.
+// Regulators without a bottle loaded are not tracked
+use crate::db::executor::GenQueries;
+use crate::db::BottleSchema;
+use crate::db::DbConnection;
+use crate::error::UdmError;
+use crate::pour::PourPlan;
+use crate::rpc_types::fhs_types::Bottle;
+use crate::UdmResult;
+use async_trait::async_trait;
+use itertools::Itertools;
+use sea_query::Expr;
+use sea_query::Query;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[async_trait]
+pub trait PourLedger: Send + Sync {
+    // Draws everything the plan needs from the loaded bottles before anything
+    // is poured, all or nothing. Returns what was drawn keyed by fr_id
+    async fn reserve(&self, plan: &PourPlan) -> UdmResult<BTreeMap<i32, f32>>;
+    // Puts back what was drawn but never poured, e.g. after an emergency stop
+    async fn refund(&self, fr_id: i32, unpoured_ml: f32) -> UdmResult<()>;
+}
+
+impl PourPlan {
+    // Total volume the plan pours from each regulator, keyed by fr_id
+    pub fn required_ml(&self) -> BTreeMap<i32, f32> {
+        let mut required = BTreeMap::new();
+        for dispense in self.steps.iter().flat_map(|step| &step.dispenses) {
+            if let Some(fr_id) = dispense.regulator.fr_id {
+                *required.entry(fr_id).or_insert(0.0) += dispense.amount_ml;
+            }
+        }
+        required
+    }
+}
+
+/// Refuses the plan when any loaded bottle holds another ingredient or does
+/// not hold enough for it
+pub fn check_stock(plan: &PourPlan, bottles: &HashMap<i32, Bottle>) -> UdmResult<()> {
+    let mismatched = plan
+        .steps
+        .iter()
+        .flat_map(|step| &step.dispenses)
+        .filter_map(|dispense| {
+            let fr_id = dispense.regulator.fr_id?;
+            let loaded = bottles.get(&fr_id)?.ingredient_id?;
+            (loaded != dispense.ingredient_id).then(|| {
+                format!(
+                    "fluid regulator {} holds ingredient {} instead of {}",
+                    fr_id, loaded, dispense.ingredient_id
+                )
+            })
+        })
+        .unique();
+    let short = plan
+        .required_ml()
+        .into_iter()
+        .filter_map(|(fr_id, required_ml)| {
+            let bottle = bottles.get(&fr_id)?;
+            (bottle.remaining_ml < required_ml).then(|| {
+                format!(
+                    "fluid regulator {} needs {:.1}ml but has {:.1}ml left",
+                    fr_id, required_ml, bottle.remaining_ml
+                )
+            })
+        });
+    let short = mismatched.chain(short).collect::<Vec<String>>();
+    if short.is_empty() {
+        return Ok(());
+    }
+    Err(UdmError::InvalidInput(format!(
+        "Not enough left to pour recipe {}: {}",
+        plan.recipe_id,
+        short.join(", ")
+    )))
+}
+
+pub struct BottleLedger {
+    connection: Arc<dyn DbConnection>,
+}
+
+impl BottleLedger {
+    pub fn new(connection: Arc<dyn DbConnection>) -> Self {
+        Self { connection }
+    }
+}
+
+#[async_trait]
+impl PourLedger for BottleLedger {
+    async fn reserve(&self, plan: &PourPlan) -> UdmResult<BTreeMap<i32, f32>> {
+        let required = plan.required_ml();
+        if required.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+        let mut transaction = self.connection.begin().await?;
+        let query = Bottle::gen_select_query_on_fields(
+            BottleSchema::Table,
+            vec![Expr::col(BottleSchema::FrId).is_in(required.keys().copied().collect_vec())],
+        );
+        let bottles = transaction
+            .select(query)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let bottle =
+                    Bottle::try_from(row).map_err(|e| UdmError::ApiFailure(e.to_string()))?;
+                Ok((bottle.fr_id, bottle))
+            })
+            .collect::<UdmResult<HashMap<i32, Bottle>>>()?;
+        check_stock(plan, &bottles)?;
+        let mut drawn = BTreeMap::new();
+        for (fr_id, required_ml) in required {
+            if !bottles.contains_key(&fr_id) {
+                continue;
+            }
+            // Only matches while enough is left, so two pours can never both
+            // draw the last of a bottle
+            let query = Query::update()
+                .table(BottleSchema::Table)
+                .value(
+                    BottleSchema::RemainingMl,
+                    Expr::col(BottleSchema::RemainingMl).sub(required_ml),
+                )
+                .and_where(Expr::col(BottleSchema::FrId).eq(fr_id))
+                .and_where(Expr::col(BottleSchema::RemainingMl).gte(required_ml))
+                .returning(Query::returning().column(BottleSchema::FrId))
+                .to_owned();
+            transaction.update(query).await?;
+            drawn.insert(fr_id, required_ml);
+        }
+        transaction.commit().await?;
+        tracing::info!("Drew {:?}ml from the loaded bottles", drawn);
+        Ok(drawn)
+    }
+    async fn refund(&self, fr_id: i32, unpoured_ml: f32) -> UdmResult<()> {
+        tracing::info!(
+            "Putting {:.1}ml back into fluid regulator {}",
+            unpoured_ml,
+            fr_id
+        );
+        let query = Query::update()
+            .table(BottleSchema::Table)
+            .value(
+                BottleSchema::RemainingMl,
+                Expr::col(BottleSchema::RemainingMl).add(unpoured_ml),
+            )
+            .and_where(Expr::col(BottleSchema::FrId).eq(fr_id))
+            .returning(Query::returning().column(BottleSchema::FrId))
+            .to_owned();
+        self.connection.update(query).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pour::Dispense;
+    use crate::pour::PourStep;
+    use crate::rpc_types::fhs_types::FlowCalibration;
+    use crate::rpc_types::fhs_types::FluidRegulator;
+    use crate::rpc_types::recipe_types::DrinkSize;
+    use crate::rpc_types::recipe_types::Instruction;
+
+    fn dispense(fr_id: i32, amount_ml: f32) -> Dispense {
+        Dispense {
+            ingredient_id: fr_id,
+            ingredient_name: format!("ingredient {}", fr_id),
+            regulator: FluidRegulator {
+                fr_id: Some(fr_id),
+                gpio_pin: Some(fr_id + 10),
+                regulator_type: None,
+            },
+            calibration: FlowCalibration::default(),
+            amount_ml,
+        }
+    }
+    fn plan() -> PourPlan {
+        let step = |position, dispenses| PourStep {
+            position,
+            instruction: Instruction::default(),
+            dispenses,
+        };
+        PourPlan {
+            recipe_id: 1,
+            size: DrinkSize::Small,
+            steps: vec![
+                step(1, vec![dispense(1, 30.0), dispense(2, 60.0)]),
+                step(2, vec![dispense(1, 15.0)]),
+            ],
+        }
+    }
+    fn bottle(fr_id: i32, remaining_ml: f32) -> (i32, Bottle) {
+        let bottle = Bottle {
+            fr_id,
+            ingredient_id: None,
+            capacity_ml: 750.0,
+            remaining_ml,
+        };
+        (fr_id, bottle)
+    }
+
+    #[test]
+    fn test_required_ml_sums_per_regulator() {
+        let required = plan().required_ml();
+        assert_eq!(required, BTreeMap::from([(1, 45.0), (2, 60.0)]));
+    }
+
+    #[test]
+    fn test_check_stock_matches_ingredient() {
+        let plan = plan();
+        let (fr_id, mut loaded) = bottle(1, 500.0);
+        loaded.ingredient_id = Some(1);
+        assert!(check_stock(&plan, &HashMap::from([(fr_id, loaded.clone())])).is_ok());
+        loaded.ingredient_id = Some(2);
+        let err = check_stock(&plan, &HashMap::from([(fr_id, loaded)])).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("fluid regulator 1 holds ingredient 2 instead of 1"));
+    }
+
+    #[test]
+    fn test_check_stock() {
+        let plan = plan();
+        assert!(check_stock(&plan, &HashMap::from([bottle(1, 45.0), bottle(2, 60.0)])).is_ok());
+        // Untracked regulators are not checked
+        assert!(check_stock(&plan, &HashMap::from([bottle(2, 100.0)])).is_ok());
+        let err = check_stock(&plan, &HashMap::from([bottle(1, 40.0)])).unwrap_err();
+        assert!(err.to_string().contains("fluid regulator 1 needs 45.0ml"));
+    }
+
+    #[tokio::test]
+    async fn test_bottle_ledger_reserves_and_refunds() {
+        use crate::db::migrations::Migrator;
+        use crate::db::sqlite::conn::OpenSqliteConnection;
+        use crate::parsers::settings::SqliteConfigurer;
+
+        let conn = OpenSqliteConnection::new(SqliteConfigurer::new(":memory:")).await;
+        Migrator::new(&conn).up(None).await.unwrap();
+        let conn: Arc<dyn DbConnection> = Arc::new(conn);
+        for fr_id in [1, 2] {
+            let regulator = FluidRegulator {
+                fr_id: None,
+                gpio_pin: Some(fr_id + 10),
+                regulator_type: Some(1),
+            };
+            assert_eq!(
+                conn.insert(regulator.gen_insert_query()).await.unwrap(),
+                fr_id
+            );
+        }
+        conn.insert(bottle(1, 100.0).1.gen_insert_query())
+            .await
+            .unwrap();
+        let remaining = |conn: Arc<dyn DbConnection>| async move {
+            let query = Bottle::gen_select_query_on_fields(BottleSchema::Table, Vec::new());
+            let row = conn.select(query).await.unwrap().remove(0);
+            Bottle::try_from(row).unwrap().remaining_ml
+        };
+
+        let ledger = BottleLedger::new(conn.clone());
+        // Regulator 2 has no bottle and is not drawn from
+        let drawn = ledger.reserve(&plan()).await.unwrap();
+        assert_eq!(drawn, BTreeMap::from([(1, 45.0)]));
+        assert_eq!(remaining(conn.clone()).await, 55.0);
+        ledger.reserve(&plan()).await.unwrap();
+        assert_eq!(remaining(conn.clone()).await, 10.0);
+        // Nothing is drawn once the bottle is short
+        assert!(ledger.reserve(&plan()).await.is_err());
+        assert_eq!(remaining(conn.clone()).await, 10.0);
+
+        ledger.refund(1, 15.0).await.unwrap();
+        assert_eq!(remaining(conn).await, 25.0);
+    }
+}
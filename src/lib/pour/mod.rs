@@ -0,0 +1,802 @@
+// Turns a Recipe into a sequence of regulator actions and runs them.
+// Resolving the data is done by the server, this only needs the hydrated structs
+pub mod availability;
+pub mod events;
+pub mod inventory;
+pub mod queue;
+
+use crate::error::UdmError;
+use crate::gpio;
+use crate::gpio::GpioDriver;
+use crate::pour::events::PourRegistry;
+use crate::pour::inventory::PourLedger;
+use crate::rpc_types::fhs_types::FlowCalibration;
+use crate::rpc_types::fhs_types::FluidRegulator;
+use crate::rpc_types::recipe_types::DrinkSize;
+use crate::rpc_types::recipe_types::Ingredient;
+use crate::rpc_types::recipe_types::IngredientType;
+use crate::rpc_types::recipe_types::Instruction;
+use crate::rpc_types::recipe_types::Recipe;
+use crate::rpc_types::service_types::PourEvent;
+use crate::rpc_types::service_types::PourEventType;
+use crate::rpc_types::service_types::PourStepInfo;
+use crate::UdmResult;
+use itertools::Itertools;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::sync::watch;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+// Rounding left over from summing the dispenses is not refunded
+const REFUND_THRESHOLD_ML: f32 = 0.01;
+
+pub type PourId = i64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dispense {
+    pub ingredient_id: i32,
+    pub ingredient_name: String,
+    pub regulator: FluidRegulator,
+    pub calibration: FlowCalibration,
+    pub amount_ml: f32,
+}
+
+impl Dispense {
+    pub fn gpio_pin(&self) -> UdmResult<u32> {
+        gpio::to_pin(self.regulator.gpio_pin.unwrap_or(-1))
+    }
+    pub fn open_duration(&self) -> UdmResult<Duration> {
+        self.calibration.duration_for_volume(self.amount_ml)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PourStep {
+    pub position: i32,
+    pub instruction: Instruction,
+    pub dispenses: Vec<Dispense>,
+}
+
+impl PourStep {
+    // Steps without anything to dispense are done by hand (garnish, shake, ...)
+    pub fn is_manual(&self) -> bool {
+        self.dispenses.is_empty()
+    }
+    pub fn info(&self) -> PourStepInfo {
+        PourStepInfo {
+            position: self.position,
+            instruction_id: self.instruction.id,
+            instruction_name: self.instruction.instruction_name.clone(),
+            manual: self.is_manual(),
+        }
+    }
+    fn event(&self, pour_id: PourId, event_type: PourEventType) -> PourEvent {
+        let mut event = PourEvent::new(pour_id, event_type);
+        event.position = Some(self.position);
+        event.instruction_id = Some(self.instruction.id);
+        event
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PourPlan {
+    pub recipe_id: i32,
+    pub size: DrinkSize,
+    pub steps: Vec<PourStep>,
+}
+
+// The regulator an ingredient pours from, only one with an id and a pin can
+// be driven. Availability goes by the same rule so it agrees with the plan
+pub(crate) fn connected_regulator(ingredient: &Ingredient) -> Option<&FluidRegulator> {
+    ingredient
+        .regulator
+        .as_ref()
+        .filter(|fr| fr.fr_id.is_some() && fr.gpio_pin.is_some())
+}
+
+impl PourPlan {
+    /// Builds the plan from a hydrated recipe, the ingredients of each
+    /// instruction keyed by instruction id and the calibrations keyed by fr_id
+    pub fn build(
+        recipe: &Recipe,
+        size: DrinkSize,
+        ingredients: &HashMap<i32, Vec<Ingredient>>,
+        calibrations: &HashMap<i32, FlowCalibration>,
+    ) -> UdmResult<Self> {
+        let recipe_size = DrinkSize::try_from(recipe.size).unwrap_or(DrinkSize::Unspecified);
+        let scale = recipe_size.scale_to(size);
+        let mut steps = Vec::new();
+        for (position, instruction) in recipe.instructions.iter().sorted_by_key(|(pos, _)| **pos) {
+            let mut dispenses = Vec::new();
+            for ingredient in ingredients.get(&instruction.id).into_iter().flatten() {
+                if ingredient.ingredient_type() != IngredientType::Fluid {
+                    continue;
+                }
+                let regulator = connected_regulator(ingredient).cloned().ok_or_else(|| {
+                    UdmError::InvalidInput(format!(
+                        "Ingredient {} is not connected to a fluid regulator",
+                        ingredient.name
+                    ))
+                })?;
+                let fr_id = regulator.fr_id.unwrap_or_default();
+                let calibration = calibrations.get(&fr_id).cloned().ok_or_else(|| {
+                    UdmError::InvalidInput(format!(
+                        "Fluid regulator {} has not been calibrated",
+                        fr_id
+                    ))
+                })?;
+                dispenses.push(Dispense {
+                    ingredient_id: ingredient.id,
+                    ingredient_name: ingredient.name.clone(),
+                    regulator,
+                    calibration,
+                    amount_ml: ingredient.amount * scale,
+                });
+            }
+            steps.push(PourStep {
+                position: *position,
+                instruction: instruction.clone(),
+                dispenses,
+            });
+        }
+        if steps.is_empty() {
+            return Err(UdmError::InvalidInput(format!(
+                "Recipe {} has no instructions to pour",
+                recipe.id
+            )));
+        }
+        Ok(Self {
+            recipe_id: recipe.id,
+            size,
+            steps,
+        })
+    }
+}
+
+pub struct Dispenser {
+    driver: Arc<dyn GpioDriver>,
+    next_pour_id: AtomicI64,
+    // The regulators are shared, only one pour can run at a time
+    hardware: Mutex<()>,
+    // Some(reason) while latched by an emergency stop
+    halt: watch::Sender<Option<String>>,
+    active_pours: StdMutex<HashSet<PourId>>,
+    events: PourRegistry,
+    ledger: Option<Arc<dyn PourLedger>>,
+}
+
+// Closes the pin when dropped, so a cancelled or failed pour
+// can never leave a regulator open
+struct OpenRegulator<'a> {
+    driver: &'a dyn GpioDriver,
+    pin: u32,
+}
+
+impl Drop for OpenRegulator<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.driver.close(self.pin) {
+            tracing::error!("Failed to close pin {}: {}", self.pin, e);
+        }
+    }
+}
+
+struct ActivePour<'a> {
+    dispenser: &'a Dispenser,
+    pour_id: PourId,
+}
+
+impl Drop for ActivePour<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut active) = self.dispenser.active_pours.lock() {
+            active.remove(&self.pour_id);
+        }
+    }
+}
+
+impl Dispenser {
+    pub fn new(driver: Arc<dyn GpioDriver>) -> Self {
+        // Seed from the clock so pour ids do not repeat between daemon restarts
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as i64)
+            .unwrap_or_default();
+        Self {
+            driver,
+            next_pour_id: AtomicI64::new(seed),
+            hardware: Mutex::new(()),
+            halt: watch::Sender::new(None),
+            active_pours: StdMutex::new(HashSet::new()),
+            events: PourRegistry::default(),
+            ledger: None,
+        }
+    }
+    // Records what every regulator poured, used to keep the bottle levels
+    pub fn with_ledger(mut self, ledger: Arc<dyn PourLedger>) -> Self {
+        self.ledger = Some(ledger);
+        self
+    }
+    pub fn next_pour_id(&self) -> PourId {
+        self.next_pour_id.fetch_add(1, Ordering::SeqCst)
+    }
+    pub fn halt_reason(&self) -> Option<String> {
+        self.halt.borrow().clone()
+    }
+    pub fn is_halted(&self) -> bool {
+        self.halt.borrow().is_some()
+    }
+    pub fn active_pours(&self) -> Vec<PourId> {
+        self.active_pours
+            .lock()
+            .map(|active| active.iter().copied().sorted().collect())
+            .unwrap_or_default()
+    }
+    /// Latches the dispenser, cancels every running pour and closes every pin
+    /// the driver has used. Returns the pours that were cancelled
+    pub fn emergency_stop(&self, reason: String) -> UdmResult<Vec<PourId>> {
+        tracing::warn!("Emergency stop: {}", &reason);
+        let cancelled = self.active_pours();
+        self.halt.send_replace(Some(reason));
+        self.close_pins(&self.used_pins())?;
+        Ok(cancelled)
+    }
+    pub fn used_pins(&self) -> Vec<u32> {
+        self.driver.used_pins().into_iter().sorted().collect()
+    }
+    pub fn close_pins(&self, pins: &[u32]) -> UdmResult<()> {
+        let mut failed = Vec::new();
+        for pin in pins {
+            if let Err(e) = self.driver.close(*pin) {
+                tracing::error!("Failed to close pin {}: {}", pin, e);
+                failed.push(*pin);
+            }
+        }
+        if !failed.is_empty() {
+            return Err(UdmError::HardwareError(format!(
+                "Failed to close pins {:?}",
+                failed
+            )));
+        }
+        Ok(())
+    }
+    pub fn resume(&self) {
+        if let Some(reason) = self.halt.send_replace(None) {
+            tracing::info!("Resuming after emergency stop: {}", reason);
+        }
+    }
+    pub async fn wait_until_running(&self) {
+        let mut halt = self.halt.subscribe();
+        let _ = halt.wait_for(|reason| reason.is_none()).await;
+    }
+    fn ensure_running(&self) -> UdmResult<()> {
+        match self.halt_reason() {
+            Some(reason) => Err(UdmError::HardwareError(format!(
+                "Dispenser is halted: {}",
+                reason
+            ))),
+            None => Ok(()),
+        }
+    }
+    pub fn events(&self) -> &PourRegistry {
+        &self.events
+    }
+    // Runs the pour in the background so the caller can return the id
+    // right away and clients follow along with WatchPour
+    pub fn start(self: &Arc<Self>, plan: PourPlan) -> PourId {
+        let pour_id = self.next_pour_id();
+        self.events.register(pour_id);
+        let dispenser = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = dispenser.pour(pour_id, &plan).await {
+                tracing::error!("Pour {} failed: {}", pour_id, e);
+            }
+        });
+        pour_id
+    }
+    pub async fn pour(&self, pour_id: PourId, plan: &PourPlan) -> UdmResult<()> {
+        self.events.register(pour_id);
+        let mut dispensed_ml = 0.0;
+        let result = self.run_pour(pour_id, plan, &mut dispensed_ml).await;
+        let mut event = match &result {
+            Ok(_) => PourEvent::new(pour_id, PourEventType::Completed),
+            Err(e) => {
+                let mut failed = PourEvent::new(pour_id, PourEventType::Failed);
+                failed.message = e.to_string();
+                failed
+            }
+        };
+        event.dispensed_ml = dispensed_ml;
+        self.events.publish(event);
+        result
+    }
+    async fn run_pour(
+        &self,
+        pour_id: PourId,
+        plan: &PourPlan,
+        dispensed_ml: &mut f32,
+    ) -> UdmResult<()> {
+        self.ensure_running()?;
+        let _hardware = self.hardware.lock().await;
+        // Could have been stopped while waiting on another pour
+        self.ensure_running()?;
+        let _active = self.track(pour_id);
+        // What is left of it goes back into the bottles once the pour ends
+        let mut unpoured = match &self.ledger {
+            Some(ledger) => ledger.reserve(plan).await?,
+            None => BTreeMap::new(),
+        };
+        let result = self
+            .run_steps(pour_id, plan, dispensed_ml, &mut unpoured)
+            .await;
+        self.refund(unpoured).await;
+        result
+    }
+    async fn run_steps(
+        &self,
+        pour_id: PourId,
+        plan: &PourPlan,
+        dispensed_ml: &mut f32,
+        unpoured: &mut BTreeMap<i32, f32>,
+    ) -> UdmResult<()> {
+        tracing::info!("Pour {} started for recipe {}", pour_id, plan.recipe_id);
+        let mut started = PourEvent::new(pour_id, PourEventType::Started);
+        started.steps = plan.steps.iter().map(PourStep::info).collect();
+        self.events.publish(started);
+        for step in &plan.steps {
+            self.ensure_running()?;
+            tracing::info!(
+                "Pour {} step {}: {}",
+                pour_id,
+                step.position,
+                step.instruction.instruction_name
+            );
+            let mut step_started = step.event(pour_id, PourEventType::StepStarted);
+            step_started.message = step.instruction.instruction_name.clone();
+            step_started.dispensed_ml = *dispensed_ml;
+            self.events.publish(step_started);
+            if step.is_manual() {
+                tracing::info!(
+                    "Pour {} step {} is done by hand: {}",
+                    pour_id,
+                    step.position,
+                    step.instruction.instruction_detail
+                );
+                let mut manual = step.event(pour_id, PourEventType::ManualStep);
+                manual.message = step.instruction.instruction_detail.clone();
+                manual.dispensed_ml = *dispensed_ml;
+                self.events.publish(manual);
+                continue;
+            }
+            for dispense in &step.dispenses {
+                self.dispense(pour_id, step, dispense, dispensed_ml, unpoured)
+                    .await?;
+            }
+        }
+        tracing::info!("Pour {} completed", pour_id);
+        Ok(())
+    }
+    // Holds a single regulator open so the poured volume can be measured
+    pub async fn test_pour(&self, regulator: &FluidRegulator, duration: Duration) -> UdmResult<()> {
+        let pin = gpio::to_pin(regulator.gpio_pin.unwrap_or(-1))?;
+        self.ensure_running()?;
+        let _hardware = self.hardware.lock().await;
+        self.ensure_running()?;
+        tracing::info!("Test pour on pin {} for {:?}", pin, duration);
+        self.hold_open(pin, duration, |_| {}).await
+    }
+    async fn dispense(
+        &self,
+        pour_id: PourId,
+        step: &PourStep,
+        dispense: &Dispense,
+        dispensed_ml: &mut f32,
+        unpoured: &mut BTreeMap<i32, f32>,
+    ) -> UdmResult<()> {
+        let pin = dispense.gpio_pin()?;
+        let duration = dispense.open_duration()?;
+        tracing::info!(
+            "Pour {}: opening pin {} for {:?} to dispense {}ml of {}",
+            pour_id,
+            pin,
+            duration,
+            dispense.amount_ml,
+            dispense.ingredient_name
+        );
+        let before_ml = *dispensed_ml;
+        let event = |event_type, dispensed_ml| {
+            let mut event = step.event(pour_id, event_type);
+            event.fr_id = dispense.regulator.fr_id;
+            event.ingredient_id = Some(dispense.ingredient_id);
+            event.message = dispense.ingredient_name.clone();
+            event.dispensed_ml = dispensed_ml;
+            event
+        };
+        self.events
+            .publish(event(PourEventType::RegulatorOpened, before_ml));
+        let mut poured_ml = 0.0;
+        let result = self
+            .hold_open(pin, duration, |elapsed| {
+                // Estimated from the calibrated rate, there is no flow sensor
+                let fraction = (elapsed.as_secs_f32() / duration.as_secs_f32()).min(1.0);
+                poured_ml = dispense.amount_ml * fraction;
+                self.events
+                    .publish(event(PourEventType::Dispensing, before_ml + poured_ml));
+            })
+            .await;
+        if result.is_ok() {
+            poured_ml = dispense.amount_ml;
+        }
+        *dispensed_ml = before_ml + poured_ml;
+        if let Some(left) = dispense
+            .regulator
+            .fr_id
+            .and_then(|fr_id| unpoured.get_mut(&fr_id))
+        {
+            *left -= poured_ml;
+        }
+        self.events
+            .publish(event(PourEventType::RegulatorClosed, *dispensed_ml));
+        tracing::info!("Pour {}: closed pin {}", pour_id, pin);
+        result
+    }
+    async fn refund(&self, unpoured: BTreeMap<i32, f32>) {
+        let Some(ledger) = &self.ledger else {
+            return;
+        };
+        for (fr_id, unpoured_ml) in unpoured {
+            if unpoured_ml < REFUND_THRESHOLD_ML {
+                continue;
+            }
+            if let Err(e) = ledger.refund(fr_id, unpoured_ml).await {
+                tracing::error!(
+                    "Failed to put {}ml back into regulator {}: {}",
+                    unpoured_ml,
+                    fr_id,
+                    e
+                );
+            }
+        }
+    }
+    async fn hold_open(
+        &self,
+        pin: u32,
+        duration: Duration,
+        mut on_progress: impl FnMut(Duration),
+    ) -> UdmResult<()> {
+        let mut halt = self.halt.subscribe();
+        self.driver.open(pin)?;
+        let _open = OpenRegulator {
+            driver: self.driver.as_ref(),
+            pin,
+        };
+        let opened = Instant::now();
+        let closing = tokio::time::sleep(duration);
+        tokio::pin!(closing);
+        let mut progress = tokio::time::interval_at(opened + PROGRESS_INTERVAL, PROGRESS_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = &mut closing => return Ok(()),
+                _ = halt.wait_for(|reason| reason.is_some()) => {
+                    // Report how far it got before the pin closes
+                    on_progress(opened.elapsed());
+                    return Err(UdmError::HardwareError(format!(
+                        "Pin {} was closed by an emergency stop",
+                        pin
+                    )));
+                }
+                _ = progress.tick() => on_progress(opened.elapsed()),
+            }
+        }
+    }
+    fn track(&self, pour_id: PourId) -> ActivePour<'_> {
+        if let Ok(mut active) = self.active_pours.lock() {
+            active.insert(pour_id);
+        }
+        ActivePour {
+            dispenser: self,
+            pour_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpio::mock::MockGpioDriver;
+    use crate::gpio::mock::PinEvent;
+    use crate::gpio::mock::PinState;
+    use crate::rpc_types::fhs_types::RegulatorType;
+    use futures::StreamExt;
+
+    fn instruction(id: i32) -> Instruction {
+        Instruction {
+            id,
+            instruction_detail: format!("detail {}", id),
+            instruction_name: format!("step {}", id),
+        }
+    }
+    fn ingredient(id: i32, fr_id: Option<i32>, amount: f32) -> Ingredient {
+        Ingredient {
+            id,
+            name: format!("ingredient {}", id),
+            amount,
+            ingredient_type: IngredientType::Fluid.into(),
+            regulator: fr_id.map(|fr_id| FluidRegulator {
+                fr_id: Some(fr_id),
+                gpio_pin: Some(fr_id + 10),
+                regulator_type: Some(RegulatorType::Pump.into()),
+            }),
+            ..Default::default()
+        }
+    }
+    fn calibrations() -> HashMap<i32, FlowCalibration> {
+        (1..=2)
+            .map(|fr_id| {
+                let calibration = FlowCalibration {
+                    fr_id,
+                    ml_per_second: 10.0,
+                    startup_lag_ms: 0,
+                    drip_compensation_ml: 0.0,
+                };
+                (fr_id, calibration)
+            })
+            .collect()
+    }
+    fn recipe() -> Recipe {
+        Recipe {
+            id: 1,
+            name: "test".to_string(),
+            size: DrinkSize::Small.into(),
+            instructions: HashMap::from([(2, instruction(20)), (1, instruction(10))]),
+            user_input: false,
+            description: "".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_build_orders_steps_by_position() {
+        let ingredients = HashMap::from([
+            (10, vec![ingredient(1, Some(1), 30.0)]),
+            (20, vec![ingredient(2, Some(2), 60.0)]),
+        ]);
+        let plan = PourPlan::build(
+            &recipe(),
+            DrinkSize::Unspecified,
+            &ingredients,
+            &calibrations(),
+        )
+        .unwrap();
+        let positions: Vec<i32> = plan.steps.iter().map(|step| step.position).collect();
+        assert_eq!(positions, vec![1, 2]);
+        assert_eq!(plan.steps[0].dispenses[0].ingredient_id, 1);
+        assert_eq!(plan.steps[1].dispenses[0].amount_ml, 60.0);
+    }
+
+    #[test]
+    fn test_build_scales_to_size() {
+        let ingredients = HashMap::from([(10, vec![ingredient(1, Some(1), 100.0)])]);
+        let plan =
+            PourPlan::build(&recipe(), DrinkSize::Pint, &ingredients, &calibrations()).unwrap();
+        let expected = 100.0 * 473.0 / 236.0;
+        assert!((plan.steps[0].dispenses[0].amount_ml - expected).abs() < 0.001);
+        assert!(plan.steps[1].is_manual());
+    }
+
+    #[test]
+    fn test_build_requires_regulator() {
+        let ingredients = HashMap::from([(10, vec![ingredient(1, None, 30.0)])]);
+        let plan = PourPlan::build(&recipe(), DrinkSize::Small, &ingredients, &calibrations());
+        assert_eq!(
+            plan.unwrap_err().to_string(),
+            "Invalid Input Ingredient ingredient 1 is not connected to a fluid regulator"
+        )
+    }
+
+    #[test]
+    fn test_build_requires_calibration() {
+        let ingredients = HashMap::from([(10, vec![ingredient(1, Some(1), 30.0)])]);
+        let plan = PourPlan::build(&recipe(), DrinkSize::Small, &ingredients, &HashMap::new());
+        assert_eq!(
+            plan.unwrap_err().to_string(),
+            "Invalid Input Fluid regulator 1 has not been calibrated"
+        )
+    }
+
+    #[test]
+    fn test_build_skips_eatables() {
+        let mut garnish = ingredient(3, None, 1.0);
+        garnish.ingredient_type = IngredientType::Eatables.into();
+        let ingredients = HashMap::from([(10, vec![garnish])]);
+        let plan =
+            PourPlan::build(&recipe(), DrinkSize::Small, &ingredients, &calibrations()).unwrap();
+        assert!(plan.steps.iter().all(|step| step.is_manual()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pour_drives_pins_in_order() {
+        let ingredients = HashMap::from([
+            (10, vec![ingredient(1, Some(1), 30.0)]),
+            (20, vec![ingredient(2, Some(2), 60.0)]),
+        ]);
+        let plan =
+            PourPlan::build(&recipe(), DrinkSize::Small, &ingredients, &calibrations()).unwrap();
+        let driver = Arc::new(MockGpioDriver::new());
+        let dispenser = Dispenser::new(driver.clone());
+        let start = tokio::time::Instant::now();
+        dispenser
+            .pour(dispenser.next_pour_id(), &plan)
+            .await
+            .unwrap();
+        let pin_event = |pin, state| PinEvent { pin, state };
+        assert_eq!(
+            driver.events(),
+            vec![
+                pin_event(11, PinState::Open),
+                pin_event(11, PinState::Closed),
+                pin_event(12, PinState::Open),
+                pin_event(12, PinState::Closed),
+            ]
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(9));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pour_publishes_events() {
+        let ingredients = HashMap::from([(10, vec![ingredient(1, Some(1), 30.0)])]);
+        let plan =
+            PourPlan::build(&recipe(), DrinkSize::Small, &ingredients, &calibrations()).unwrap();
+        let dispenser = Arc::new(Dispenser::new(Arc::new(MockGpioDriver::new())));
+        let pour_id = dispenser.start(plan);
+        let events = dispenser
+            .events()
+            .subscribe(pour_id)
+            .unwrap()
+            .into_stream()
+            .collect::<Vec<_>>()
+            .await;
+        // Ticks depend on timing, the rest is fixed
+        let progress = events
+            .iter()
+            .filter(|event| event.event_type() == PourEventType::Dispensing)
+            .map(|event| event.dispensed_ml)
+            .collect_vec();
+        assert!(!progress.is_empty());
+        assert!(progress.windows(2).all(|pair| pair[0] <= pair[1]));
+        let events = events
+            .into_iter()
+            .filter(|event| event.event_type() != PourEventType::Dispensing)
+            .collect_vec();
+        let kinds = events.iter().map(|event| event.event_type()).collect_vec();
+        assert_eq!(
+            kinds,
+            vec![
+                PourEventType::Started,
+                PourEventType::StepStarted,
+                PourEventType::RegulatorOpened,
+                PourEventType::RegulatorClosed,
+                PourEventType::StepStarted,
+                PourEventType::ManualStep,
+                PourEventType::Completed,
+            ]
+        );
+        let positions = events[0]
+            .steps
+            .iter()
+            .map(|step| step.position)
+            .collect_vec();
+        assert_eq!(positions, vec![1, 2]);
+        assert!(events[0].steps[1].manual);
+        assert_eq!(events[3].fr_id, Some(1));
+        assert_eq!(events[4].instruction_id, Some(20));
+        assert_eq!(events[6].dispensed_ml, 30.0);
+    }
+
+    #[derive(Default)]
+    struct RecordingLedger {
+        refunds: StdMutex<Vec<(i32, f32)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl PourLedger for RecordingLedger {
+        async fn reserve(&self, plan: &PourPlan) -> UdmResult<BTreeMap<i32, f32>> {
+            Ok(plan.required_ml())
+        }
+        async fn refund(&self, fr_id: i32, unpoured_ml: f32) -> UdmResult<()> {
+            self.refunds.lock().unwrap().push((fr_id, unpoured_ml));
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_completed_pour_refunds_nothing() {
+        let ingredients = HashMap::from([
+            (10, vec![ingredient(1, Some(1), 30.0)]),
+            (20, vec![ingredient(2, Some(2), 60.0)]),
+        ]);
+        let plan =
+            PourPlan::build(&recipe(), DrinkSize::Small, &ingredients, &calibrations()).unwrap();
+        let ledger = Arc::new(RecordingLedger::default());
+        let dispenser = Dispenser::new(Arc::new(MockGpioDriver::new())).with_ledger(ledger.clone());
+        dispenser.pour(1, &plan).await.unwrap();
+        assert!(ledger.refunds.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stopped_pour_refunds_the_rest() {
+        let ingredients = HashMap::from([
+            (10, vec![ingredient(1, Some(1), 100.0)]),
+            (20, vec![ingredient(2, Some(2), 60.0)]),
+        ]);
+        let plan =
+            PourPlan::build(&recipe(), DrinkSize::Small, &ingredients, &calibrations()).unwrap();
+        let ledger = Arc::new(RecordingLedger::default());
+        let dispenser =
+            Arc::new(Dispenser::new(Arc::new(MockGpioDriver::new())).with_ledger(ledger.clone()));
+        let pouring = {
+            let dispenser = dispenser.clone();
+            tokio::spawn(async move { dispenser.pour(1, &plan).await })
+        };
+        // Half of the first regulator at 10ml/s
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        dispenser.emergency_stop("test".to_string()).unwrap();
+        assert!(pouring.await.unwrap().is_err());
+        let refunds = ledger.refunds.lock().unwrap().clone();
+        assert_eq!(refunds.len(), 2);
+        assert_eq!(refunds[0].0, 1);
+        assert!((refunds[0].1 - 50.0).abs() < 1.0);
+        assert_eq!(refunds[1], (2, 60.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_emergency_stop_cancels_pour() {
+        let ingredients = HashMap::from([(10, vec![ingredient(1, Some(1), 100.0)])]);
+        let plan =
+            PourPlan::build(&recipe(), DrinkSize::Small, &ingredients, &calibrations()).unwrap();
+        let driver = Arc::new(MockGpioDriver::new());
+        let dispenser = Arc::new(Dispenser::new(driver.clone()));
+        let pouring = {
+            let dispenser = dispenser.clone();
+            tokio::spawn(async move { dispenser.pour(7, &plan).await })
+        };
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(driver.state(11), PinState::Open);
+        assert_eq!(dispenser.active_pours(), vec![7]);
+
+        let cancelled = dispenser.emergency_stop("test".to_string()).unwrap();
+        assert_eq!(cancelled, vec![7]);
+        assert_eq!(dispenser.used_pins(), vec![11]);
+        assert!(pouring.await.unwrap().is_err());
+        assert_eq!(driver.state(11), PinState::Closed);
+        assert!(dispenser.active_pours().is_empty());
+
+        // Latched until resumed
+        let ingredients = HashMap::from([(10, vec![ingredient(1, Some(1), 10.0)])]);
+        let plan =
+            PourPlan::build(&recipe(), DrinkSize::Small, &ingredients, &calibrations()).unwrap();
+        assert!(dispenser.pour(8, &plan).await.is_err());
+        dispenser.resume();
+        assert!(dispenser.pour(9, &plan).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dropped_pour_closes_regulator() {
+        let ingredients = HashMap::from([(10, vec![ingredient(1, Some(1), 100.0)])]);
+        let plan =
+            PourPlan::build(&recipe(), DrinkSize::Small, &ingredients, &calibrations()).unwrap();
+        let driver = Arc::new(MockGpioDriver::new());
+        let dispenser = Dispenser::new(driver.clone());
+        let pouring = tokio::time::timeout(Duration::from_secs(1), dispenser.pour(1, &plan)).await;
+        assert!(pouring.is_err());
+        assert_eq!(driver.state(11), PinState::Closed);
+        assert!(dispenser.active_pours().is_empty());
+    }
+}
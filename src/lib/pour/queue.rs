@@ -0,0 +1,109 @@
+// Ordering rules for queued drink orders. The worker always pours the
+// queued order with the lowest position, priority only decides where a new
+// order is placed and ReorderQueue can move orders anywhere
+use crate::error::UdmError;
+use crate::rpc_types::service_types::DrinkOrder;
+use crate::rpc_types::service_types::OrderStatus;
+use crate::UdmResult;
+use itertools::Itertools;
+use std::collections::HashSet;
+
+impl DrinkOrder {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.status(),
+            OrderStatus::Completed | OrderStatus::Failed | OrderStatus::Cancelled
+        )
+    }
+}
+
+// New orders go behind every queued order of the same or a higher priority
+pub fn insertion_index(queued: &[DrinkOrder], priority: i32) -> usize {
+    queued
+        .iter()
+        .rposition(|order| order.priority >= priority)
+        .map_or(0, |index| index + 1)
+}
+
+/// Puts the orders in `order_ids` first, in that order, followed by the rest
+/// of `queued` in their current order. `queued` must be sorted by position
+pub fn reorder(queued: Vec<DrinkOrder>, order_ids: &[i32]) -> UdmResult<Vec<DrinkOrder>> {
+    if !order_ids.iter().all_unique() {
+        return Err(UdmError::InvalidInput(
+            "An order can only be listed once".to_string(),
+        ));
+    }
+    let known: HashSet<i32> = queued.iter().filter_map(|order| order.order_id).collect();
+    if let Some(missing) = order_ids.iter().find(|id| !known.contains(id)) {
+        return Err(UdmError::InvalidInput(format!(
+            "Order {} is not queued",
+            missing
+        )));
+    }
+    let (mut moved, rest): (Vec<DrinkOrder>, Vec<DrinkOrder>) = queued
+        .into_iter()
+        .partition(|order| order_ids.contains(&order.order_id.unwrap_or_default()));
+    moved.sort_by_key(|order| order_ids.iter().position(|id| Some(*id) == order.order_id));
+    Ok(moved.into_iter().chain(rest).collect())
+}
+
+// Returns the orders whose position changed after numbering them in sequence
+pub fn renumber(orders: &mut [DrinkOrder]) -> Vec<DrinkOrder> {
+    let mut changed = Vec::new();
+    for (position, order) in orders.iter_mut().enumerate() {
+        if order.position != position as i32 {
+            order.position = position as i32;
+            changed.push(order.clone());
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(order_id: i32, priority: i32, position: i32) -> DrinkOrder {
+        DrinkOrder {
+            order_id: Some(order_id),
+            priority,
+            position,
+            status: OrderStatus::Queued.into(),
+            ..Default::default()
+        }
+    }
+    fn ids(orders: &[DrinkOrder]) -> Vec<i32> {
+        orders.iter().filter_map(|order| order.order_id).collect()
+    }
+
+    #[test]
+    fn test_insertion_index_respects_priority() {
+        let queued = vec![order(1, 5, 0), order(2, 5, 1), order(3, 0, 2)];
+        assert_eq!(insertion_index(&queued, 5), 2);
+        assert_eq!(insertion_index(&queued, 10), 0);
+        assert_eq!(insertion_index(&queued, 0), 3);
+        assert_eq!(insertion_index(&[], 0), 0);
+    }
+
+    #[test]
+    fn test_reorder_moves_listed_orders_first() {
+        let queued = vec![
+            order(1, 0, 0),
+            order(2, 0, 1),
+            order(3, 0, 2),
+            order(4, 0, 3),
+        ];
+        let mut reordered = reorder(queued, &[3, 1]).unwrap();
+        assert_eq!(ids(&reordered), vec![3, 1, 2, 4]);
+        let changed = renumber(&mut reordered);
+        assert_eq!(ids(&changed), vec![3, 1, 2]);
+        assert_eq!(reordered[3].position, 3);
+    }
+
+    #[test]
+    fn test_reorder_rejects_unknown_and_duplicates() {
+        let queued = vec![order(1, 0, 0), order(2, 0, 1)];
+        assert!(reorder(queued.clone(), &[9]).is_err());
+        assert!(reorder(queued, &[1, 1]).is_err());
+    }
+}
@@ -1,21 +1,29 @@
 use crate::db::executor::GenQueries;
+use crate::db::row::DbRow;
+use crate::db::BottleSchema;
+use crate::db::FlowCalibrationSchema;
 use crate::db::FluidRegulationSchema;
+use crate::db::SqlTransactionsFactory;
 use crate::error::UdmError;
 use crate::rpc_types::FieldValidation;
 use crate::rpc_types::MultipleValues;
 use crate::UdmResult;
 use anyhow::Error as AnyError;
 use async_trait::async_trait;
-use postgres::row::Row;
 use sea_query::DeleteStatement;
 use sea_query::Expr;
 use sea_query::InsertStatement;
+use sea_query::OnConflict;
 use sea_query::Query;
 use sea_query::UpdateStatement;
 use std::fmt::Display;
+use std::time::Duration;
 
 tonic::include_proto!("fhs_types");
 
+// Longest a test pour may hold a regulator open
+pub const MAX_CALIBRATION_MS: i32 = 60_000;
+
 #[async_trait]
 impl GenQueries for FluidRegulator {
     fn gen_insert_query(&self) -> InsertStatement {
@@ -69,14 +77,239 @@ impl FieldValidation for FluidRegulator {
         Ok(())
     }
 }
-impl TryFrom<Row> for FluidRegulator {
+impl TryFrom<DbRow> for FluidRegulator {
+    type Error = AnyError;
+
+    fn try_from(value: DbRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            fr_id: value.try_get(FluidRegulationSchema::FrId.column_to_str())?,
+            regulator_type: value.try_get(FluidRegulationSchema::RegulatorType.column_to_str())?,
+            gpio_pin: value.try_get(FluidRegulationSchema::GpioPin.column_to_str())?,
+        })
+    }
+}
+
+impl FlowCalibration {
+    /// Derives the flow rate from a test pour that held the regulator open
+    /// for `open_for` and produced `measured_ml`
+    pub fn from_measurement(
+        fr_id: i32,
+        open_for: Duration,
+        measured_ml: f32,
+        startup_lag: Duration,
+        drip_compensation_ml: f32,
+    ) -> UdmResult<Self> {
+        if open_for <= startup_lag {
+            return Err(UdmError::InvalidInput(String::from(
+                "The test pour has to be longer than the startup lag",
+            )));
+        }
+        if !measured_ml.is_finite() || !drip_compensation_ml.is_finite() {
+            return Err(UdmError::InvalidInput(String::from(
+                "The measured volume and drip compensation have to be numbers",
+            )));
+        }
+        // A negative compensation would make every pour larger than asked for
+        if drip_compensation_ml < 0.0 {
+            return Err(UdmError::InvalidInput(String::from(
+                "The drip compensation can not be negative",
+            )));
+        }
+        let flowing_ml = measured_ml - drip_compensation_ml;
+        if flowing_ml <= 0.0 {
+            return Err(UdmError::InvalidInput(String::from(
+                "The measured volume has to be larger than the drip compensation",
+            )));
+        }
+        let ml_per_second = flowing_ml / (open_for - startup_lag).as_secs_f32();
+        if !ml_per_second.is_finite() || ml_per_second <= 0.0 {
+            return Err(UdmError::InvalidInput(format!(
+                "The measurement gives an invalid flow rate of {}ml/s",
+                ml_per_second
+            )));
+        }
+        Ok(Self {
+            fr_id,
+            ml_per_second,
+            startup_lag_ms: startup_lag.as_millis() as i32,
+            drip_compensation_ml,
+        })
+    }
+    /// How long the regulator has to stay open to dispense `volume_ml`
+    pub fn duration_for_volume(&self, volume_ml: f32) -> UdmResult<Duration> {
+        if !self.ml_per_second.is_finite() || self.ml_per_second <= 0.0 {
+            return Err(UdmError::InvalidInput(format!(
+                "Regulator {} has an invalid flow rate of {}ml/s",
+                self.fr_id, self.ml_per_second
+            )));
+        }
+        let flowing_ml = (volume_ml - self.drip_compensation_ml).max(0.0);
+        let lag = Duration::from_millis(self.startup_lag_ms.max(0) as u64);
+        let flowing =
+            Duration::try_from_secs_f32(flowing_ml / self.ml_per_second).map_err(|e| {
+                UdmError::InvalidInput(format!(
+                    "Can not pour {}ml from regulator {}: {}",
+                    volume_ml, self.fr_id, e
+                ))
+            })?;
+        Ok(lag + flowing)
+    }
+}
+
+#[async_trait]
+impl GenQueries for FlowCalibration {
+    // A regulator only has one calibration, recalibrating replaces it
+    fn gen_insert_query(&self) -> InsertStatement {
+        Query::insert()
+            .into_table(FlowCalibrationSchema::Table)
+            .columns([
+                FlowCalibrationSchema::FrId,
+                FlowCalibrationSchema::MlPerSecond,
+                FlowCalibrationSchema::StartupLagMs,
+                FlowCalibrationSchema::DripCompensationMl,
+            ])
+            .values_panic([
+                self.fr_id.into(),
+                self.ml_per_second.into(),
+                self.startup_lag_ms.into(),
+                self.drip_compensation_ml.into(),
+            ])
+            .on_conflict(
+                OnConflict::column(FlowCalibrationSchema::FrId)
+                    .update_columns([
+                        FlowCalibrationSchema::MlPerSecond,
+                        FlowCalibrationSchema::StartupLagMs,
+                        FlowCalibrationSchema::DripCompensationMl,
+                    ])
+                    .to_owned(),
+            )
+            .returning(Query::returning().column(FlowCalibrationSchema::FrId))
+            .to_owned()
+    }
+    fn gen_remove_query(id: i32) -> DeleteStatement {
+        Query::delete()
+            .from_table(FlowCalibrationSchema::Table)
+            .and_where(Expr::col(FlowCalibrationSchema::FrId).eq(id))
+            .to_owned()
+    }
+    fn gen_update_query(&self) -> UpdateStatement {
+        Query::update()
+            .table(FlowCalibrationSchema::Table)
+            .values([
+                (
+                    FlowCalibrationSchema::MlPerSecond,
+                    self.ml_per_second.into(),
+                ),
+                (
+                    FlowCalibrationSchema::StartupLagMs,
+                    self.startup_lag_ms.into(),
+                ),
+                (
+                    FlowCalibrationSchema::DripCompensationMl,
+                    self.drip_compensation_ml.into(),
+                ),
+            ])
+            .and_where(Expr::col(FlowCalibrationSchema::FrId).eq(self.fr_id))
+            .returning(Query::returning().column(FlowCalibrationSchema::FrId))
+            .to_owned()
+    }
+}
+
+impl TryFrom<DbRow> for FlowCalibration {
     type Error = AnyError;
 
-    fn try_from(value: Row) -> Result<Self, Self::Error> {
+    fn try_from(value: DbRow) -> Result<Self, Self::Error> {
         Ok(Self {
-            fr_id: value.try_get(0)?,
-            regulator_type: value.try_get(1)?,
-            gpio_pin: value.try_get(2)?,
+            fr_id: value.try_get(FlowCalibrationSchema::FrId.column_to_str())?,
+            ml_per_second: value.try_get(FlowCalibrationSchema::MlPerSecond.column_to_str())?,
+            startup_lag_ms: value.try_get(FlowCalibrationSchema::StartupLagMs.column_to_str())?,
+            drip_compensation_ml: value
+                .try_get(FlowCalibrationSchema::DripCompensationMl.column_to_str())?,
+        })
+    }
+}
+
+impl Bottle {
+    pub fn validate(&self) -> UdmResult<()> {
+        if !self.capacity_ml.is_finite() || !self.remaining_ml.is_finite() {
+            return Err(UdmError::InvalidInput(
+                "Bottle capacity and remaining volume have to be numbers".to_string(),
+            ));
+        }
+        if self.capacity_ml <= 0.0 {
+            return Err(UdmError::InvalidInput(
+                "Bottle capacity must be greater than 0".to_string(),
+            ));
+        }
+        if self.remaining_ml < 0.0 || self.remaining_ml > self.capacity_ml {
+            return Err(UdmError::InvalidInput(format!(
+                "Remaining volume must be between 0 and {}ml",
+                self.capacity_ml
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl GenQueries for Bottle {
+    // A regulator holds one bottle, setting it again replaces it
+    fn gen_insert_query(&self) -> InsertStatement {
+        Query::insert()
+            .into_table(BottleSchema::Table)
+            .columns([
+                BottleSchema::FrId,
+                BottleSchema::IngredientId,
+                BottleSchema::CapacityMl,
+                BottleSchema::RemainingMl,
+            ])
+            .values_panic([
+                self.fr_id.into(),
+                self.ingredient_id.into(),
+                self.capacity_ml.into(),
+                self.remaining_ml.into(),
+            ])
+            .on_conflict(
+                OnConflict::column(BottleSchema::FrId)
+                    .update_columns([
+                        BottleSchema::IngredientId,
+                        BottleSchema::CapacityMl,
+                        BottleSchema::RemainingMl,
+                    ])
+                    .to_owned(),
+            )
+            .returning(Query::returning().column(BottleSchema::FrId))
+            .to_owned()
+    }
+    fn gen_remove_query(id: i32) -> DeleteStatement {
+        Query::delete()
+            .from_table(BottleSchema::Table)
+            .and_where(Expr::col(BottleSchema::FrId).eq(id))
+            .to_owned()
+    }
+    fn gen_update_query(&self) -> UpdateStatement {
+        Query::update()
+            .table(BottleSchema::Table)
+            .values([
+                (BottleSchema::IngredientId, self.ingredient_id.into()),
+                (BottleSchema::CapacityMl, self.capacity_ml.into()),
+                (BottleSchema::RemainingMl, self.remaining_ml.into()),
+            ])
+            .and_where(Expr::col(BottleSchema::FrId).eq(self.fr_id))
+            .returning(Query::returning().column(BottleSchema::FrId))
+            .to_owned()
+    }
+}
+
+impl TryFrom<DbRow> for Bottle {
+    type Error = AnyError;
+
+    fn try_from(value: DbRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            fr_id: value.try_get(BottleSchema::FrId.column_to_str())?,
+            ingredient_id: value.try_get(BottleSchema::IngredientId.column_to_str())?,
+            capacity_ml: value.try_get(BottleSchema::CapacityMl.column_to_str())?,
+            remaining_ml: value.try_get(BottleSchema::RemainingMl.column_to_str())?,
         })
     }
 }
@@ -101,8 +334,86 @@ impl Display for RegulatorType {
 mod tests {
     use super::*;
 
+    use crate::db::migrations::Migrator;
+    use crate::db::sqlite::conn::OpenSqliteConnection;
+    use crate::db::DbConnection;
+    use crate::parsers::settings::SqliteConfigurer;
+    use crate::rpc_types::recipe_types::Ingredient;
+    use sea_query::Iden;
     use sea_query::PostgresQueryBuilder;
 
+    async fn open() -> OpenSqliteConnection {
+        let settings = SqliteConfigurer {
+            db_path: ":memory:".to_string(),
+        };
+        let conn = OpenSqliteConnection::new(settings).await;
+        Migrator::new(&conn).up(None).await.unwrap();
+        conn
+    }
+    // Reads back the only row of the table
+    async fn read_back<T, S>(conn: &OpenSqliteConnection, table: S) -> T
+    where
+        T: GenQueries + TryFrom<DbRow, Error = AnyError>,
+        S: Iden + 'static,
+    {
+        let query = T::gen_select_query_on_fields(table, vec![]);
+        T::try_from(conn.select(query).await.unwrap().remove(0)).unwrap()
+    }
+    async fn insert_regulator(conn: &OpenSqliteConnection) -> FluidRegulator {
+        let mut regulator = FluidRegulator {
+            fr_id: None,
+            gpio_pin: Some(17),
+            regulator_type: Some(RegulatorType::Tap.into()),
+        };
+        regulator.fr_id = Some(conn.insert(regulator.gen_insert_query()).await.unwrap());
+        regulator
+    }
+
+    // Every field holds a value no other column does, reading a column by
+    // the wrong name fails the comparison
+    #[tokio::test]
+    async fn test_fluid_regulator_round_trip() {
+        let conn = open().await;
+        let regulator = insert_regulator(&conn).await;
+        let read: FluidRegulator = read_back(&conn, FluidRegulationSchema::Table).await;
+        assert_eq!(read, regulator);
+    }
+
+    #[tokio::test]
+    async fn test_flow_calibration_round_trip() {
+        let conn = open().await;
+        let fr_id = insert_regulator(&conn).await.fr_id.unwrap();
+        let calibration = FlowCalibration {
+            fr_id,
+            ml_per_second: 12.5,
+            startup_lag_ms: 250,
+            drip_compensation_ml: 1.5,
+        };
+        conn.insert(calibration.gen_insert_query()).await.unwrap();
+        let read: FlowCalibration = read_back(&conn, FlowCalibrationSchema::Table).await;
+        assert_eq!(read, calibration);
+    }
+
+    #[tokio::test]
+    async fn test_bottle_round_trip() {
+        let conn = open().await;
+        let fr_id = insert_regulator(&conn).await.fr_id.unwrap();
+        let ingredient = Ingredient {
+            name: "Tequila".to_string(),
+            ..Default::default()
+        };
+        let ingredient_id = conn.insert(ingredient.gen_insert_query()).await.unwrap();
+        let bottle = Bottle {
+            fr_id,
+            ingredient_id: Some(ingredient_id),
+            capacity_ml: 750.0,
+            remaining_ml: 500.0,
+        };
+        conn.insert(bottle.gen_insert_query()).await.unwrap();
+        let read: Bottle = read_back(&conn, BottleSchema::Table).await;
+        assert_eq!(read, bottle);
+    }
+
     #[test]
     fn test_gen_insert_query() {
         let fr = FluidRegulator {
@@ -134,4 +445,131 @@ mod tests {
         let expected_query = r#"UPDATE "FluidRegulation" SET "gpio_pin" = 23, "regulator_type" = 3 WHERE "fr_id" = 1 RETURNING "fr_id""#.to_string();
         assert_eq!(query, expected_query)
     }
+
+    #[test]
+    fn test_calibration_from_measurement() {
+        let calibration = FlowCalibration::from_measurement(
+            1,
+            Duration::from_millis(5500),
+            52.0,
+            Duration::from_millis(500),
+            2.0,
+        )
+        .unwrap();
+        assert_eq!(calibration.ml_per_second, 10.0);
+        assert_eq!(calibration.startup_lag_ms, 500);
+    }
+
+    #[test]
+    fn test_calibration_rejects_short_pour() {
+        let calibration = FlowCalibration::from_measurement(
+            1,
+            Duration::from_millis(200),
+            10.0,
+            Duration::from_millis(500),
+            0.0,
+        );
+        assert!(calibration.is_err())
+    }
+
+    #[test]
+    fn test_calibration_rejects_invalid_measurements() {
+        let measure = |measured_ml: f32| {
+            FlowCalibration::from_measurement(
+                1,
+                Duration::from_millis(5500),
+                measured_ml,
+                Duration::from_millis(500),
+                0.0,
+            )
+        };
+        assert!(measure(f32::NAN).is_err());
+        assert!(measure(f32::INFINITY).is_err());
+        assert!(measure(-1.0).is_err());
+    }
+
+    #[test]
+    fn test_calibration_rejects_negative_drip_compensation() {
+        let measure = |drip_compensation_ml: f32| {
+            FlowCalibration::from_measurement(
+                1,
+                Duration::from_millis(5500),
+                100.0,
+                Duration::from_millis(500),
+                drip_compensation_ml,
+            )
+        };
+        assert!(measure(0.0).is_ok());
+        assert!(measure(2.0).is_ok());
+        let error = measure(-2.0).unwrap_err();
+        assert!(matches!(error, UdmError::InvalidInput(_)));
+    }
+
+    #[test]
+    fn test_calibration_duration_does_not_overflow() {
+        let calibration = FlowCalibration {
+            fr_id: 1,
+            ml_per_second: 1e-30,
+            startup_lag_ms: 0,
+            drip_compensation_ml: 0.0,
+        };
+        assert!(calibration.duration_for_volume(45.0).is_err());
+        let calibration = FlowCalibration {
+            ml_per_second: f32::NAN,
+            ..calibration
+        };
+        assert!(calibration.duration_for_volume(45.0).is_err());
+    }
+
+    #[test]
+    fn test_calibration_duration_for_volume() {
+        let calibration = FlowCalibration {
+            fr_id: 1,
+            ml_per_second: 20.0,
+            startup_lag_ms: 250,
+            drip_compensation_ml: 5.0,
+        };
+        assert_eq!(
+            calibration.duration_for_volume(45.0).unwrap(),
+            Duration::from_millis(2250)
+        );
+        // Smaller than the drip, only wait for the lag
+        assert_eq!(
+            calibration.duration_for_volume(3.0).unwrap(),
+            Duration::from_millis(250)
+        );
+    }
+
+    #[test]
+    fn test_calibration_gen_insert_query() {
+        let calibration = FlowCalibration {
+            fr_id: 1,
+            ml_per_second: 20.0,
+            startup_lag_ms: 250,
+            drip_compensation_ml: 5.0,
+        };
+        let query = calibration
+            .gen_insert_query()
+            .to_string(PostgresQueryBuilder);
+        let expected_query = r#"INSERT INTO "FlowCalibration" ("fr_id", "ml_per_second", "startup_lag_ms", "drip_compensation_ml") VALUES (1, 20, 250, 5) ON CONFLICT ("fr_id") DO UPDATE SET "ml_per_second" = "excluded"."ml_per_second", "startup_lag_ms" = "excluded"."startup_lag_ms", "drip_compensation_ml" = "excluded"."drip_compensation_ml" RETURNING "fr_id""#;
+        assert_eq!(query, expected_query)
+    }
+
+    #[test]
+    fn test_bottle_validate() {
+        let bottle = |capacity_ml, remaining_ml| Bottle {
+            fr_id: 1,
+            ingredient_id: None,
+            capacity_ml,
+            remaining_ml,
+        };
+        assert!(bottle(750.0, 750.0).validate().is_ok());
+        assert!(bottle(750.0, 0.0).validate().is_ok());
+        assert!(bottle(0.0, 0.0).validate().is_err());
+        assert!(bottle(750.0, 751.0).validate().is_err());
+        assert!(bottle(750.0, -1.0).validate().is_err());
+        assert!(bottle(f32::NAN, 0.0).validate().is_err());
+        assert!(bottle(750.0, f32::NAN).validate().is_err());
+        assert!(bottle(f32::INFINITY, 750.0).validate().is_err());
+    }
 }
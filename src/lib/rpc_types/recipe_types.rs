@@ -4,11 +4,15 @@ use std::collections::HashMap;
 use std::fmt::Display;
 
 use crate::db::executor::GenQueries;
+use crate::db::row::DbRow;
+use crate::db::DrinkOrderSchema;
 use crate::db::IngredientSchema;
 use crate::db::InstructionSchema;
 use crate::db::InstructionToRecipeSchema;
 use crate::db::RecipeSchema;
+use crate::db::SqlTransactionsFactory;
 use crate::error::UdmError;
+use crate::rpc_types::service_types::DrinkOrder;
 use crate::rpc_types::service_types::InstructionToRecipeMetadata;
 use crate::rpc_types::FieldValidation;
 use crate::rpc_types::FluidRegulator;
@@ -16,7 +20,6 @@ use crate::rpc_types::MultipleValues;
 use crate::UdmResult;
 use anyhow::Error as AnyError;
 use async_trait::async_trait;
-use postgres::row::Row;
 use sea_query::DeleteStatement;
 use sea_query::Expr;
 use sea_query::InsertStatement;
@@ -42,24 +45,114 @@ impl FieldValidation for Instruction {
         Ok(())
     }
 }
+// The most of one ingredient a single drink can hold
+const MAX_INGREDIENT_AMOUNT_ML: f32 = 1000.0;
+
 impl FieldValidation for Ingredient {
     fn validate_all_fields(&self) -> UdmResult<()> {
-        todo!();
+        if self.id == 0 {
+            return Err(UdmError::InvalidInput(String::from(
+                "`Ingredient ID is not set`",
+            )));
+        }
+        self.validate_without_id_fields()
     }
 
     fn validate_without_id_fields(&self) -> UdmResult<()> {
-        todo!()
+        if self.name.trim().is_empty() {
+            return Err(UdmError::InvalidInput(String::from(
+                "`Ingredient name is required`",
+            )));
+        }
+        let ingredient_type = IngredientType::try_from(self.ingredient_type)
+            .ok()
+            .filter(|ingredient_type| *ingredient_type != IngredientType::Unspecified)
+            .ok_or_else(|| {
+                UdmError::InvalidInput(format!(
+                    "`Ingredient type must be one of {:?}`",
+                    specified(IngredientType::get_possible_values())
+                ))
+            })?;
+        // Eatables like a garnish may not be measured
+        let amount_is_valid = match ingredient_type {
+            IngredientType::Fluid => self.amount > 0.0 && self.amount <= MAX_INGREDIENT_AMOUNT_ML,
+            _ => (0.0..=MAX_INGREDIENT_AMOUNT_ML).contains(&self.amount),
+        };
+        if !amount_is_valid {
+            return Err(UdmError::InvalidInput(format!(
+                "`Amount {} is out of range for {}, the most is {} ml`",
+                self.amount,
+                ingredient_type.as_str_name(),
+                MAX_INGREDIENT_AMOUNT_ML
+            )));
+        }
+        if self
+            .regulator
+            .as_ref()
+            .is_some_and(|regulator| regulator.fr_id.is_none())
+        {
+            return Err(UdmError::InvalidInput(String::from(
+                "`The fluid regulator needs an ID`",
+            )));
+        }
+        if self
+            .instruction
+            .as_ref()
+            .is_some_and(|instruction| instruction.id == 0)
+        {
+            return Err(UdmError::InvalidInput(String::from(
+                "`The instruction needs an ID`",
+            )));
+        }
+        Ok(())
     }
 }
 impl FieldValidation for Recipe {
     fn validate_all_fields(&self) -> UdmResult<()> {
-        todo!()
+        if self.id == 0 {
+            return Err(UdmError::InvalidInput(String::from(
+                "`Recipe ID is not set`",
+            )));
+        }
+        self.validate_without_id_fields()
     }
 
     fn validate_without_id_fields(&self) -> UdmResult<()> {
-        todo!()
+        if self.name.trim().is_empty() {
+            return Err(UdmError::InvalidInput(String::from(
+                "`Recipe name is required`",
+            )));
+        }
+        if DrinkSize::try_from(self.size).map_or(true, |size| size == DrinkSize::Unspecified) {
+            return Err(UdmError::InvalidInput(format!(
+                "`Drink size must be one of {:?}`",
+                specified(DrinkSize::get_possible_values())
+            )));
+        }
+        for (position, instruction) in &self.instructions {
+            if *position < 1 {
+                return Err(UdmError::InvalidInput(format!(
+                    "`Instruction positions start at 1, got {}`",
+                    position
+                )));
+            }
+            if instruction.id == 0 {
+                return Err(UdmError::InvalidInput(format!(
+                    "`The instruction at position {} needs an ID`",
+                    position
+                )));
+            }
+        }
+        Ok(())
     }
 }
+// Every enum name but the *_UNSPECIFIED default
+fn specified(names: Vec<&'static str>) -> Vec<&'static str> {
+    names
+        .into_iter()
+        .filter(|name| !name.ends_with("_UNSPECIFIED"))
+        .collect()
+}
 #[async_trait]
 impl GenQueries for Instruction {
     fn gen_insert_query(&self) -> InsertStatement {
@@ -71,7 +164,7 @@ impl GenQueries for Instruction {
             ])
             .values_panic([
                 self.instruction_name.clone().into(),
-                self.instruction_name.clone().into(),
+                self.instruction_detail.clone().into(),
             ])
             .returning(Query::returning().column(InstructionSchema::InstructionId))
             .to_owned()
@@ -100,47 +193,45 @@ impl GenQueries for Instruction {
             .to_owned()
     }
 }
-impl TryFrom<Row> for Instruction {
+impl TryFrom<DbRow> for Instruction {
     type Error = AnyError;
 
-    fn try_from(value: Row) -> Result<Self, Self::Error> {
+    fn try_from(value: DbRow) -> Result<Self, Self::Error> {
         Ok(Self {
-            id: value.try_get(0)?,
-            instruction_detail: value.try_get(1)?,
-            instruction_name: value.try_get(2)?,
+            id: value.try_get(InstructionSchema::InstructionId.column_to_str())?,
+            instruction_detail: value
+                .try_get(InstructionSchema::InstructionDetail.column_to_str())?,
+            instruction_name: value.try_get(InstructionSchema::InstructionName.column_to_str())?,
         })
     }
 }
-impl TryFrom<Row> for Ingredient {
+impl TryFrom<DbRow> for Ingredient {
     type Error = AnyError;
 
-    fn try_from(value: Row) -> Result<Self, Self::Error> {
+    fn try_from(value: DbRow) -> Result<Self, Self::Error> {
         Ok(Self {
-            id: value.try_get(0)?,
-            name: value.try_get(1)?,
-            is_alcoholic: value.try_get(2)?,
-            description: value.try_get(3)?,
-            is_active: value.try_get(4)?,
-            amount: value.try_get(5)?,
-            ingredient_type: value.try_get(6)?,
-            regulator: {
-                value.try_get(7).map_or(None, |f| {
-                    Some(FluidRegulator {
-                        fr_id: f,
-                        gpio_pin: None,
-                        regulator_type: None,
-                    })
-                })
-            },
-            instruction: {
-                value.try_get(8).map_or(None, |id: Option<i32>| {
-                    Some(Instruction {
-                        id: id.unwrap_or_default(),
-                        instruction_detail: "".to_string(),
-                        instruction_name: "".to_string(),
-                    })
-                })
-            },
+            id: value.try_get(IngredientSchema::IngredientId.column_to_str())?,
+            name: value.try_get(IngredientSchema::Name.column_to_str())?,
+            is_alcoholic: value.try_get(IngredientSchema::Alcoholic.column_to_str())?,
+            description: value.try_get(IngredientSchema::Description.column_to_str())?,
+            is_active: value.try_get(IngredientSchema::IsActive.column_to_str())?,
+            amount: value.try_get(IngredientSchema::Amount.column_to_str())?,
+            ingredient_type: value.try_get(IngredientSchema::IngredientType.column_to_str())?,
+            // Only the keys are read, callers collect the rest when they need it
+            regulator: value
+                .try_get::<_, Option<i32>>(IngredientSchema::FrId.column_to_str())?
+                .map(|fr_id| FluidRegulator {
+                    fr_id: Some(fr_id),
+                    gpio_pin: None,
+                    regulator_type: None,
+                }),
+            instruction: value
+                .try_get::<_, Option<i32>>(IngredientSchema::InstructionId.column_to_str())?
+                .map(|id| Instruction {
+                    id,
+                    instruction_detail: "".to_string(),
+                    instruction_name: "".to_string(),
+                }),
         })
     }
 }
@@ -234,16 +325,16 @@ impl GenQueries for Ingredient {
             .to_owned()
     }
 }
-impl TryFrom<Row> for Recipe {
+impl TryFrom<DbRow> for Recipe {
     type Error = AnyError;
 
-    fn try_from(value: Row) -> Result<Self, Self::Error> {
+    fn try_from(value: DbRow) -> Result<Self, Self::Error> {
         Ok(Self {
-            id: value.try_get(0)?,
-            name: value.try_get(1)?,
-            user_input: value.try_get(2)?,
-            size: value.try_get(3)?,
-            description: value.try_get(4)?,
+            id: value.try_get(RecipeSchema::RecipeId.column_to_str())?,
+            name: value.try_get(RecipeSchema::Name.column_to_str())?,
+            user_input: value.try_get(RecipeSchema::UserInput.column_to_str())?,
+            size: value.try_get(RecipeSchema::DrinkSize.column_to_str())?,
+            description: value.try_get(RecipeSchema::Description.column_to_str())?,
             instructions: HashMap::new(),
         })
     }
@@ -268,6 +359,28 @@ impl Display for DrinkSize {
     }
 }
 
+impl DrinkSize {
+    /// Nominal volume of the glass in ml
+    pub fn volume_ml(&self) -> Option<f32> {
+        match self {
+            DrinkSize::Unspecified => None,
+            DrinkSize::Small => Some(236.0),
+            DrinkSize::Medium => Some(355.0),
+            DrinkSize::Pint => Some(473.0),
+            DrinkSize::Large => Some(591.0),
+            DrinkSize::ExtraLarge => Some(710.0),
+        }
+    }
+    /// How much the amounts of a recipe written for `self` have to be
+    /// scaled to fill `target`. Unspecified sizes are poured as written.
+    pub fn scale_to(&self, target: DrinkSize) -> f32 {
+        match (self.volume_ml(), target.volume_ml()) {
+            (Some(base), Some(wanted)) => wanted / base,
+            _ => 1.0,
+        }
+    }
+}
+
 #[async_trait]
 impl GenQueries for Recipe {
     fn gen_insert_query(&self) -> InsertStatement {
@@ -311,15 +424,17 @@ impl GenQueries for Recipe {
             .to_owned()
     }
 }
-impl TryFrom<Row> for InstructionToRecipeMetadata {
+impl TryFrom<DbRow> for InstructionToRecipeMetadata {
     type Error = AnyError;
 
-    fn try_from(value: Row) -> Result<Self, Self::Error> {
+    fn try_from(value: DbRow) -> Result<Self, Self::Error> {
         Ok(Self {
-            id: value.try_get(0)?,
-            recipe_id: value.try_get(1)?,
-            instruction_id: value.try_get(2)?,
-            instruction_order: value.try_get(3)?,
+            id: value.try_get(InstructionToRecipeSchema::Id.column_to_str())?,
+            recipe_id: value.try_get(InstructionToRecipeSchema::RecipeId.column_to_str())?,
+            instruction_id: value
+                .try_get(InstructionToRecipeSchema::InstructionId.column_to_str())?,
+            instruction_order: value
+                .try_get(InstructionToRecipeSchema::InstructionOrder.column_to_str())?,
         })
     }
 }
@@ -378,3 +493,355 @@ impl GenQueries for InstructionToRecipeMetadata {
             .to_owned()
     }
 }
+impl TryFrom<DbRow> for DrinkOrder {
+    type Error = AnyError;
+
+    fn try_from(value: DbRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            order_id: value.try_get(DrinkOrderSchema::OrderId.column_to_str())?,
+            recipe_id: value.try_get(DrinkOrderSchema::RecipeId.column_to_str())?,
+            size: value.try_get(DrinkOrderSchema::DrinkSize.column_to_str())?,
+            requester: value.try_get(DrinkOrderSchema::Requester.column_to_str())?,
+            priority: value.try_get(DrinkOrderSchema::Priority.column_to_str())?,
+            position: value.try_get(DrinkOrderSchema::Position.column_to_str())?,
+            status: value.try_get(DrinkOrderSchema::Status.column_to_str())?,
+            pour_id: value.try_get(DrinkOrderSchema::PourId.column_to_str())?,
+            message: value.try_get(DrinkOrderSchema::Message.column_to_str())?,
+        })
+    }
+}
+#[async_trait]
+impl GenQueries for DrinkOrder {
+    fn gen_insert_query(&self) -> InsertStatement {
+        let columns = vec![
+            DrinkOrderSchema::RecipeId,
+            DrinkOrderSchema::DrinkSize,
+            DrinkOrderSchema::Requester,
+            DrinkOrderSchema::Priority,
+            DrinkOrderSchema::Position,
+            DrinkOrderSchema::Status,
+            DrinkOrderSchema::PourId,
+            DrinkOrderSchema::Message,
+        ];
+        let values = vec![
+            self.recipe_id.into(),
+            self.size.into(),
+            self.requester.clone().into(),
+            self.priority.into(),
+            self.position.into(),
+            self.status.into(),
+            self.pour_id.into(),
+            self.message.clone().into(),
+        ];
+        Query::insert()
+            .into_table(DrinkOrderSchema::Table)
+            .columns(columns)
+            .values_panic(values)
+            .returning(Query::returning().column(DrinkOrderSchema::OrderId))
+            .to_owned()
+    }
+    fn gen_remove_query(id: i32) -> DeleteStatement {
+        Query::delete()
+            .from_table(DrinkOrderSchema::Table)
+            .and_where(Expr::col(DrinkOrderSchema::OrderId).eq(id))
+            .to_owned()
+    }
+    fn gen_update_query(&self) -> UpdateStatement {
+        let values = vec![
+            (DrinkOrderSchema::Priority, self.priority.into()),
+            (DrinkOrderSchema::Position, self.position.into()),
+            (DrinkOrderSchema::Status, self.status.into()),
+            (DrinkOrderSchema::PourId, self.pour_id.into()),
+            (DrinkOrderSchema::Message, self.message.clone().into()),
+        ];
+        Query::update()
+            .table(DrinkOrderSchema::Table)
+            .values(values)
+            .and_where(Expr::col(DrinkOrderSchema::OrderId).eq(self.order_id))
+            .returning(Query::returning().column(DrinkOrderSchema::OrderId))
+            .to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::db::migrations::Migrator;
+    use crate::db::sqlite::conn::OpenSqliteConnection;
+    use crate::db::DbConnection;
+    use crate::parsers::settings::SqliteConfigurer;
+    use crate::rpc_types::fhs_types::RegulatorType;
+    use crate::rpc_types::service_types::OrderStatus;
+    use sea_query::Iden;
+
+    async fn open() -> OpenSqliteConnection {
+        let settings = SqliteConfigurer {
+            db_path: ":memory:".to_string(),
+        };
+        let conn = OpenSqliteConnection::new(settings).await;
+        Migrator::new(&conn).up(None).await.unwrap();
+        conn
+    }
+    // Reads back the only row of the table
+    async fn read_back<T, S>(conn: &OpenSqliteConnection, table: S) -> T
+    where
+        T: GenQueries + TryFrom<DbRow, Error = AnyError>,
+        S: Iden + 'static,
+    {
+        let query = T::gen_select_query_on_fields(table, vec![]);
+        T::try_from(conn.select(query).await.unwrap().remove(0)).unwrap()
+    }
+    async fn insert_instruction(conn: &OpenSqliteConnection) -> Instruction {
+        let mut instruction = Instruction {
+            id: 0,
+            instruction_detail: "Shake it for ten seconds".to_string(),
+            instruction_name: "Shake".to_string(),
+        };
+        instruction.id = conn.insert(instruction.gen_insert_query()).await.unwrap();
+        instruction
+    }
+    async fn insert_recipe(conn: &OpenSqliteConnection) -> Recipe {
+        let mut recipe = Recipe {
+            name: "Margarita".to_string(),
+            size: DrinkSize::Pint.into(),
+            user_input: true,
+            description: "Lime and tequila".to_string(),
+            ..Default::default()
+        };
+        recipe.id = conn.insert(recipe.gen_insert_query()).await.unwrap();
+        recipe
+    }
+
+    // Every field holds a value no other column does, reading a column by
+    // the wrong name fails the comparison
+    #[tokio::test]
+    async fn test_instruction_round_trip() {
+        let conn = open().await;
+        let instruction = insert_instruction(&conn).await;
+        let read: Instruction = read_back(&conn, InstructionSchema::Table).await;
+        assert_eq!(read, instruction);
+    }
+
+    #[tokio::test]
+    async fn test_ingredient_round_trip() {
+        let conn = open().await;
+        let instruction = insert_instruction(&conn).await;
+        let mut regulator = FluidRegulator {
+            fr_id: None,
+            gpio_pin: Some(17),
+            regulator_type: Some(RegulatorType::Pump.into()),
+        };
+        regulator.fr_id = Some(conn.insert(regulator.gen_insert_query()).await.unwrap());
+        let mut ingredient = Ingredient {
+            id: 0,
+            name: "Tequila".to_string(),
+            is_active: false,
+            is_alcoholic: true,
+            regulator: Some(regulator.clone()),
+            amount: 30.5,
+            description: "Blanco".to_string(),
+            ingredient_type: IngredientType::Eatables.into(),
+            instruction: Some(instruction.clone()),
+        };
+        ingredient.id = conn.insert(ingredient.gen_insert_query()).await.unwrap();
+        let read: Ingredient = read_back(&conn, IngredientSchema::Table).await;
+        // Only the keys of the regulator and instruction are stored
+        assert_eq!(
+            read,
+            Ingredient {
+                regulator: Some(FluidRegulator {
+                    fr_id: regulator.fr_id,
+                    gpio_pin: None,
+                    regulator_type: None,
+                }),
+                instruction: Some(Instruction {
+                    id: instruction.id,
+                    instruction_detail: "".to_string(),
+                    instruction_name: "".to_string(),
+                }),
+                ..ingredient.clone()
+            }
+        );
+
+        conn.delete(Ingredient::gen_remove_query(ingredient.id))
+            .await
+            .unwrap();
+        let detached = Ingredient {
+            regulator: None,
+            instruction: None,
+            ..ingredient
+        };
+        let id = conn.insert(detached.gen_insert_query()).await.unwrap();
+        let read: Ingredient = read_back(&conn, IngredientSchema::Table).await;
+        assert_eq!(read, Ingredient { id, ..detached });
+    }
+
+    #[tokio::test]
+    async fn test_recipe_round_trip() {
+        let conn = open().await;
+        let recipe = insert_recipe(&conn).await;
+        let read: Recipe = read_back(&conn, RecipeSchema::Table).await;
+        assert_eq!(read, recipe);
+    }
+
+    #[tokio::test]
+    async fn test_instruction_to_recipe_round_trip() {
+        let conn = open().await;
+        let instruction = insert_instruction(&conn).await;
+        let recipe = insert_recipe(&conn).await;
+        let mut metadata = InstructionToRecipeMetadata {
+            id: None,
+            recipe_id: recipe.id,
+            instruction_id: instruction.id,
+            instruction_order: 7,
+        };
+        metadata.id = Some(conn.insert(metadata.gen_insert_query()).await.unwrap());
+        let read: InstructionToRecipeMetadata =
+            read_back(&conn, InstructionToRecipeSchema::Table).await;
+        assert_eq!(read, metadata);
+    }
+
+    #[tokio::test]
+    async fn test_drink_order_round_trip() {
+        let conn = open().await;
+        let recipe = insert_recipe(&conn).await;
+        let mut order = DrinkOrder {
+            order_id: None,
+            recipe_id: recipe.id,
+            size: DrinkSize::Large.into(),
+            requester: "table 4".to_string(),
+            priority: 9,
+            position: 11,
+            status: OrderStatus::Pouring.into(),
+            pour_id: Some(1234),
+            message: "halfway".to_string(),
+        };
+        order.order_id = Some(conn.insert(order.gen_insert_query()).await.unwrap());
+        let read: DrinkOrder = read_back(&conn, DrinkOrderSchema::Table).await;
+        assert_eq!(read, order);
+    }
+
+    #[test]
+    fn test_ingredient_validation() {
+        let valid = Ingredient {
+            id: 3,
+            name: "Lime juice".to_string(),
+            amount: 30.0,
+            ingredient_type: IngredientType::Fluid.into(),
+            regulator: Some(FluidRegulator {
+                fr_id: Some(1),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(valid.validate_all_fields().is_ok());
+        let garnish = Ingredient {
+            amount: 0.0,
+            ingredient_type: IngredientType::Eatables.into(),
+            ..valid.clone()
+        };
+        assert!(garnish.validate_all_fields().is_ok());
+        for invalid in [
+            Ingredient {
+                id: 0,
+                ..valid.clone()
+            },
+            Ingredient {
+                name: " ".to_string(),
+                ..valid.clone()
+            },
+            Ingredient {
+                ingredient_type: IngredientType::Unspecified.into(),
+                ..valid.clone()
+            },
+            Ingredient {
+                ingredient_type: 42,
+                ..valid.clone()
+            },
+            Ingredient {
+                amount: 0.0,
+                ..valid.clone()
+            },
+            Ingredient {
+                amount: MAX_INGREDIENT_AMOUNT_ML + 1.0,
+                ..valid.clone()
+            },
+            Ingredient {
+                amount: f32::NAN,
+                ..valid.clone()
+            },
+            Ingredient {
+                regulator: Some(FluidRegulator::default()),
+                ..valid.clone()
+            },
+            Ingredient {
+                instruction: Some(Instruction::default()),
+                ..valid.clone()
+            },
+        ] {
+            assert!(invalid.validate_all_fields().is_err(), "{:?}", invalid);
+        }
+        assert!(Ingredient { id: 0, ..valid }
+            .validate_without_id_fields()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_recipe_validation() {
+        let valid = Recipe {
+            id: 1,
+            name: "Gimlet".to_string(),
+            size: DrinkSize::Small.into(),
+            instructions: HashMap::from([(
+                1,
+                Instruction {
+                    id: 4,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        assert!(valid.validate_all_fields().is_ok());
+        for invalid in [
+            Recipe {
+                id: 0,
+                ..valid.clone()
+            },
+            Recipe {
+                name: String::new(),
+                ..valid.clone()
+            },
+            Recipe {
+                size: DrinkSize::Unspecified.into(),
+                ..valid.clone()
+            },
+            Recipe {
+                instructions: HashMap::from([(
+                    0,
+                    Instruction {
+                        id: 4,
+                        ..Default::default()
+                    },
+                )]),
+                ..valid.clone()
+            },
+            Recipe {
+                instructions: HashMap::from([(1, Instruction::default())]),
+                ..valid.clone()
+            },
+        ] {
+            assert!(invalid.validate_all_fields().is_err(), "{:?}", invalid);
+        }
+        let error = Recipe {
+            size: DrinkSize::Unspecified.into(),
+            ..valid
+        }
+        .validate_without_id_fields()
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid Input `Drink size must be one of [\"DRINK_SIZE_SMALL\", \"DRINK_SIZE_MEDIUM\", \"DRINK_SIZE_PINT\", \"DRINK_SIZE_LARGE\", \"DRINK_SIZE_EXTRA_LARGE\"]`"
+        );
+    }
+}
@@ -1,12 +1,30 @@
 use crate::db::executor::GenQueries;
+use crate::db::executor::Page;
+use crate::db::health::MonitoredConnection;
+use crate::db::row::DbRow;
+use crate::db::BottleSchema;
 use crate::db::DbConnection;
 use crate::db::DbMetaData;
+use crate::db::DbTransaction;
+use crate::db::DrinkOrderSchema;
+use crate::db::FlowCalibrationSchema;
 use crate::db::FluidRegulationSchema;
 use crate::db::IngredientSchema;
 use crate::db::InstructionSchema;
 use crate::db::InstructionToRecipeSchema;
 use crate::db::RecipeSchema;
+use crate::error::UdmError;
+use crate::gpio;
+use crate::pour::availability::Stock;
+use crate::pour::inventory;
+use crate::pour::queue;
+use crate::pour::Dispenser;
+use crate::pour::PourPlan;
+use crate::rpc_types::fhs_types::Bottle;
+use crate::rpc_types::fhs_types::FlowCalibration;
 use crate::rpc_types::fhs_types::FluidRegulator;
+use crate::rpc_types::fhs_types::MAX_CALIBRATION_MS;
+use crate::rpc_types::recipe_types::DrinkSize;
 use crate::rpc_types::recipe_types::Ingredient;
 use crate::rpc_types::recipe_types::Instruction;
 use crate::rpc_types::server::udm_service_server::UdmService;
@@ -21,6 +39,11 @@ use crate::rpc_types::service_types::AddRecipeInstOrderRequest;
 use crate::rpc_types::service_types::AddRecipeInstOrderResponse;
 use crate::rpc_types::service_types::AddRecipeRequest;
 use crate::rpc_types::service_types::AddRecipeResponse;
+use crate::rpc_types::service_types::CalibrateRegulatorRequest;
+use crate::rpc_types::service_types::CalibrateRegulatorResponse;
+use crate::rpc_types::service_types::CancelOrderRequest;
+use crate::rpc_types::service_types::CollectAvailableRecipesRequest;
+use crate::rpc_types::service_types::CollectAvailableRecipesResponse;
 use crate::rpc_types::service_types::CollectExpressions;
 use crate::rpc_types::service_types::CollectFluidRegulatorsRequest;
 use crate::rpc_types::service_types::CollectFluidRegulatorsResponse;
@@ -28,14 +51,38 @@ use crate::rpc_types::service_types::CollectIngredientRequest;
 use crate::rpc_types::service_types::CollectIngredientResponse;
 use crate::rpc_types::service_types::CollectInstructionRequest;
 use crate::rpc_types::service_types::CollectInstructionResponse;
+use crate::rpc_types::service_types::CollectPage;
 use crate::rpc_types::service_types::CollectRecipeInstOrderRequest;
 use crate::rpc_types::service_types::CollectRecipeInstOrderResponse;
 use crate::rpc_types::service_types::CollectRecipeRequest;
 use crate::rpc_types::service_types::CollectRecipeResponse;
+use crate::rpc_types::service_types::DaemonStatusRequest;
+use crate::rpc_types::service_types::DaemonStatusResponse;
+use crate::rpc_types::service_types::DrinkOrder;
+use crate::rpc_types::service_types::EmergencyStopRequest;
+use crate::rpc_types::service_types::EmergencyStopResponse;
+use crate::rpc_types::service_types::EnqueueDrinkRequest;
+use crate::rpc_types::service_types::EnqueueDrinkResponse;
 use crate::rpc_types::service_types::FetchData;
 use crate::rpc_types::service_types::GenericEmpty;
 use crate::rpc_types::service_types::GenericRemovalResponse;
+use crate::rpc_types::service_types::GetFluidRegulatorRequest;
+use crate::rpc_types::service_types::GetFluidRegulatorResponse;
+use crate::rpc_types::service_types::GetHealthRequest;
+use crate::rpc_types::service_types::GetHealthResponse;
+use crate::rpc_types::service_types::GetIngredientRequest;
+use crate::rpc_types::service_types::GetIngredientResponse;
+use crate::rpc_types::service_types::GetInstructionRequest;
+use crate::rpc_types::service_types::GetInstructionResponse;
+use crate::rpc_types::service_types::GetInventoryRequest;
+use crate::rpc_types::service_types::GetInventoryResponse;
+use crate::rpc_types::service_types::GetRecipeRequest;
+use crate::rpc_types::service_types::GetRecipeResponse;
 use crate::rpc_types::service_types::InstructionToRecipeMetadata;
+use crate::rpc_types::service_types::ListQueueRequest;
+use crate::rpc_types::service_types::ListQueueResponse;
+use crate::rpc_types::service_types::MakeDrinkRequest;
+use crate::rpc_types::service_types::MakeDrinkResponse;
 use crate::rpc_types::service_types::ModifyFluidRegulatorRequest;
 use crate::rpc_types::service_types::ModifyFluidRegulatorResponse;
 use crate::rpc_types::service_types::ModifyIngredientRequest;
@@ -45,45 +92,91 @@ use crate::rpc_types::service_types::ModifyInstructionResponse;
 use crate::rpc_types::service_types::ModifyRecipeRequest;
 use crate::rpc_types::service_types::ModifyRecipeResponse;
 use crate::rpc_types::service_types::Operation;
+use crate::rpc_types::service_types::OrderStatus;
+use crate::rpc_types::service_types::PourEvent;
 use crate::rpc_types::service_types::RecipeInstructionOrder;
 use crate::rpc_types::service_types::RemoveFluidRegulatorRequest;
 use crate::rpc_types::service_types::RemoveIngredientRequest;
 use crate::rpc_types::service_types::RemoveInstructionRequest;
 use crate::rpc_types::service_types::RemoveRecipeInstOrderRequest;
 use crate::rpc_types::service_types::RemoveRecipeRequest;
+use crate::rpc_types::service_types::ReorderQueueRequest;
 use crate::rpc_types::service_types::ResetRequest;
 use crate::rpc_types::service_types::ResetResponse;
+use crate::rpc_types::service_types::ResetType;
+use crate::rpc_types::service_types::ResumeRequest;
 use crate::rpc_types::service_types::ServiceResponse;
+use crate::rpc_types::service_types::SetBottleLevelRequest;
+use crate::rpc_types::service_types::SetBottleLevelResponse;
+use crate::rpc_types::service_types::UnavailableRecipe;
 use crate::rpc_types::service_types::UpdateRecipeInstOrderRequest;
+use crate::rpc_types::service_types::WatchPourRequest;
+use crate::rpc_types::FieldValidation;
 use crate::rpc_types::Recipe;
 use crate::UdmResult;
 use anyhow::Result;
-use futures::stream;
+use futures::future;
+use futures::future::BoxFuture;
 use futures::stream::StreamExt;
+use futures::Stream;
 use itertools::Itertools;
-use sea_query::PostgresQueryBuilder;
+use sea_query::Expr;
+use sea_query::Iden;
+use sea_query::IntoIden;
+use sea_query::Query;
+use sea_query::SelectStatement;
+use sea_query::SimpleExpr;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::sync::Notify;
+use tonic::body::BoxBody;
 use tonic::transport::Server;
+use tonic::Code;
 use tonic::IntoRequest;
 use tonic::Request;
 use tonic::Response;
 use tonic::Status;
+use tower::Layer;
+use tower::Service;
 
 tonic::include_proto!("server");
 
+const QUEUE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
 pub struct DaemonServerContext {
-    pub connection: Box<dyn DbConnection>,
+    pub connection: Arc<dyn DbConnection>,
+    pub health: Arc<MonitoredConnection>,
     pub addr: SocketAddr,
     pub metadata: DbMetaData,
+    pub dispenser: Arc<Dispenser>,
+    // Wakes the queue worker when an order is added
+    order_ready: Notify,
+    // Held while the queue positions are read and rewritten
+    queue_lock: Mutex<()>,
 }
 
 impl DaemonServerContext {
-    pub fn new(connection: Box<dyn DbConnection>, addr: SocketAddr, metadata: DbMetaData) -> Self {
+    pub fn new(
+        connection: Arc<MonitoredConnection>,
+        addr: SocketAddr,
+        metadata: DbMetaData,
+        dispenser: Arc<Dispenser>,
+    ) -> Self {
         Self {
-            connection,
+            connection: Arc::clone(&connection) as Arc<dyn DbConnection>,
+            health: connection,
             addr,
             metadata,
+            dispenser,
+            order_ready: Notify::new(),
+            queue_lock: Mutex::new(()),
         }
     }
 }
@@ -98,17 +191,18 @@ impl UdmService for DaemonServerContext {
             .into_inner()
             .fluid
             .ok_or_else(|| Status::cancelled("Invalid request to add fluid regulator"))?;
-        let query = fr.gen_insert_query().to_string(PostgresQueryBuilder);
+        let query = fr.gen_insert_query();
         let input_result = self.connection.insert(query).await;
         match input_result {
             Ok(fr_id) => {
                 let fr_response = AddFluidRegulatorResponse { fr_id }.to_response();
                 Ok(fr_response)
             }
-            Err(e) => Err(Status::data_loss(format!(
-                "Failed to insert into database: {}",
-                e
-            ))),
+            Err(e) => Err(db_status(
+                Code::DataLoss,
+                "Failed to insert into database",
+                e,
+            )),
         }
     }
     async fn remove_fluid_regulator(
@@ -117,14 +211,18 @@ impl UdmService for DaemonServerContext {
     ) -> Result<Response<GenericRemovalResponse>, Status> {
         tracing::debug!("Got Request {request:?}");
         let fr_id = request.into_inner().fr_id;
-        let query = FluidRegulator::gen_remove_query(fr_id).to_string(PostgresQueryBuilder);
+        let query = FluidRegulator::gen_remove_query(fr_id);
         let delete_result = self.connection.delete(query).await;
         match delete_result {
             Ok(_) => {
                 let remove_response = GenericRemovalResponse {}.to_response();
                 Ok(remove_response)
             }
-            Err(e) => Err(Status::aborted(e.to_string())),
+            Err(e) => Err(db_status(
+                Code::Aborted,
+                "Failed to delete from the database",
+                e,
+            )),
         }
     }
     async fn update_fluid_regulator(
@@ -136,17 +234,18 @@ impl UdmService for DaemonServerContext {
             .into_inner()
             .fluid
             .ok_or_else(|| Status::cancelled("Invalid request to remove fluid regulator"))?;
-        let query = fr.gen_update_query().to_string(PostgresQueryBuilder);
+        let query = fr.gen_update_query();
         let result = self.connection.update(query).await;
         match result {
             Ok(fr_id) => {
                 let fr_response = ModifyFluidRegulatorResponse { fr_id }.to_response();
                 Ok(fr_response)
             }
-            Err(e) => Err(Status::data_loss(format!(
-                "Failed to update into database: {}",
-                e
-            ))),
+            Err(e) => Err(db_status(
+                Code::DataLoss,
+                "Failed to update into database",
+                e,
+            )),
         }
     }
     async fn collect_fluid_regulators(
@@ -154,32 +253,62 @@ impl UdmService for DaemonServerContext {
         request: Request<CollectFluidRegulatorsRequest>,
     ) -> Result<Response<CollectFluidRegulatorsResponse>, Status> {
         tracing::debug!("Got {:?}", request);
-        let exprs = request
-            .into_inner()
-            .get_expressions()
-            .map_err(|e| Status::cancelled(e.to_string()))?;
-        let query = FluidRegulator::gen_select_query_on_fields(FluidRegulationSchema::Table, exprs)
-            .to_string(PostgresQueryBuilder);
+        let request = request.into_inner();
+        let condition = request
+            .get_condition()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let page = request
+            .get_page()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let count =
+            FluidRegulator::gen_count_query(FluidRegulationSchema::Table, condition.clone());
+        let query =
+            FluidRegulator::gen_paged_select_query(FluidRegulationSchema::Table, condition, &page);
         let results = self.connection.select(query).await;
         match results {
             Ok(results) => {
                 let frs: Vec<FluidRegulator> = results
                     .into_iter()
-                    .map(|row| FluidRegulator::try_from(row).unwrap())
-                    .collect_vec();
+                    .map(FluidRegulator::try_from)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| Status::internal(format!("Failed to read regulators: {}", e)))?;
+                let total_count = self.count_rows(&page, frs.len(), count).await?;
                 tracing::info!("Successfully collected fluid regulators");
                 tracing::debug!("Collected data {:?}", frs);
-                Ok(CollectFluidRegulatorsResponse { fluids: frs }.to_response())
+                Ok(CollectFluidRegulatorsResponse {
+                    next_page_token: page.next_page_token(frs.len(), total_count),
+                    fluids: frs,
+                    total_count,
+                }
+                .to_response())
             }
             Err(e) => {
                 tracing::error!("There was an error collecting {}", e.to_string());
-                Err(Status::cancelled(format!(
-                    "Failed to query the database: {}",
-                    e
-                )))
+                Err(db_status(
+                    Code::Cancelled,
+                    "Failed to query the database",
+                    e,
+                ))
             }
         }
     }
+    async fn get_fluid_regulator(
+        &self,
+        request: Request<GetFluidRegulatorRequest>,
+    ) -> Result<Response<GetFluidRegulatorResponse>, Status> {
+        tracing::debug!("Got {:?}", request);
+        let fr_id = request.into_inner().fr_id;
+        let fluid = self
+            .select_one(
+                FluidRegulationSchema::Table,
+                Expr::col(FluidRegulationSchema::FrId).eq(fr_id),
+            )
+            .await?
+            .ok_or_else(|| {
+                Status::not_found(format!("Fluid regulator {} does not exist", fr_id))
+            })?;
+        Ok(GetFluidRegulatorResponse { fluid: Some(fluid) }.to_response())
+    }
     async fn add_recipe(
         &self,
         request: Request<AddRecipeRequest>,
@@ -190,8 +319,13 @@ impl UdmService for DaemonServerContext {
             .clone()
             .recipe
             .ok_or_else(|| Status::cancelled("Invalid request to add recipe"))?;
-        let query = recipe.gen_insert_query().to_string(PostgresQueryBuilder);
-        let response = self.connection.insert(query).await;
+        recipe
+            .validate_without_id_fields()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        self.check_recipe_references(&recipe).await?;
+        let query = recipe.gen_insert_query();
+        let mut transaction = self.begin().await?;
+        let response = transaction.insert(query).await;
         match response {
             Ok(recipe_id) => {
                 // Insert instruction order into db
@@ -202,22 +336,24 @@ impl UdmService for DaemonServerContext {
                         instruction_id: instruction.id,
                         instruction_order: position,
                     };
-                    let order_query = order.gen_insert_query().to_string(PostgresQueryBuilder);
-                    self.connection.insert(order_query).await.map_err(|e| {
-                        let message = format!("Failed to query the database: {}", e);
-                        tracing::error!(message);
-                        Status::cancelled(message)
+                    let order_query = order.gen_insert_query();
+                    transaction.insert(order_query).await.map_err(|e| {
+                        let status = db_status(Code::Cancelled, "Failed to query the database", e);
+                        tracing::error!("{}", status.message());
+                        status
                     })?;
                 }
+                Self::commit(transaction).await?;
                 let response = AddRecipeResponse { recipe_id }.to_response();
                 Ok(response)
             }
             Err(e) => {
-                format!("Failed to insert into database: {}", e);
-                Err(Status::data_loss(format!(
-                    "Failed to insert into database: {}",
-                    e
-                )))
+                tracing::error!("Failed to insert into database: {}", e);
+                Err(db_status(
+                    Code::DataLoss,
+                    "Failed to insert into database",
+                    e,
+                ))
             }
         }
     }
@@ -227,14 +363,18 @@ impl UdmService for DaemonServerContext {
     ) -> Result<Response<GenericRemovalResponse>, Status> {
         tracing::debug!("Got Request {request:?}");
         let recipe_id = request.get_ref().recipe_id;
-        let query = FluidRegulator::gen_remove_query(recipe_id).to_string(PostgresQueryBuilder);
+        let query = FluidRegulator::gen_remove_query(recipe_id);
         let delete_result = self.connection.delete(query).await;
         match delete_result {
             Ok(_) => {
                 let remove_response = GenericRemovalResponse {}.to_response();
                 Ok(remove_response)
             }
-            Err(e) => Err(Status::aborted(e.to_string())),
+            Err(e) => Err(db_status(
+                Code::Aborted,
+                "Failed to delete from the database",
+                e,
+            )),
         }
     }
     async fn update_recipe(
@@ -247,8 +387,13 @@ impl UdmService for DaemonServerContext {
             .clone()
             .recipe
             .ok_or_else(|| Status::cancelled("Invalid request to add recipe"))?;
-        let query = recipe.gen_update_query().to_string(PostgresQueryBuilder);
-        let response = self.connection.insert(query).await;
+        recipe
+            .validate_all_fields()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        self.check_recipe_references(&recipe).await?;
+        let query = recipe.gen_update_query();
+        let mut transaction = self.begin().await?;
+        let response = transaction.update(query).await;
         match response {
             Ok(recipe_id) => {
                 // Insert instruction order into db
@@ -259,18 +404,20 @@ impl UdmService for DaemonServerContext {
                         instruction_id: instruction.id,
                         instruction_order: position,
                     };
-                    let order_query = order.gen_insert_query().to_string(PostgresQueryBuilder);
-                    self.connection.update(order_query).await.map_err(|e| {
-                        Status::cancelled(format!("Failed to query the database: {}", e))
+                    let order_query = order.gen_insert_query();
+                    transaction.insert(order_query).await.map_err(|e| {
+                        db_status(Code::Cancelled, "Failed to query the database", e)
                     })?;
                 }
+                Self::commit(transaction).await?;
                 let response = ModifyRecipeResponse { recipe_id }.to_response();
                 Ok(response)
             }
-            Err(e) => Err(Status::data_loss(format!(
-                "Failed to update into database: {}",
-                e
-            ))),
+            Err(e) => Err(db_status(
+                Code::DataLoss,
+                "Failed to update into database",
+                e,
+            )),
         }
     }
 
@@ -279,53 +426,117 @@ impl UdmService for DaemonServerContext {
         request: Request<CollectRecipeRequest>,
     ) -> Result<Response<CollectRecipeResponse>, Status> {
         tracing::debug!("Got {:?}", request);
-        let exprs = request
-            .get_ref()
-            .get_expressions()
-            .map_err(|e| Status::cancelled(e.to_string()))?;
-        let query = Recipe::gen_select_query_on_fields(RecipeSchema::Table, exprs)
-            .to_string(PostgresQueryBuilder);
+        let request = request.into_inner();
+        let condition = request
+            .get_condition()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let page = request
+            .get_page()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let count = Recipe::gen_count_query(RecipeSchema::Table, condition.clone());
+        let query = Recipe::gen_paged_select_query(RecipeSchema::Table, condition, &page);
         let results = self.connection.select(query).await;
         match results {
             Ok(results) => {
                 let recipes: Vec<Recipe> = results
                     .into_iter()
-                    .map(|row| Recipe::try_from(row).unwrap())
-                    .collect_vec();
-                let rebuilt_data: Vec<Recipe> = stream::iter(recipes)
-                    .then(|mut recipe| async {
-                        // Collection instruction
-                        let sorted = self
-                            .parse_and_collect_instructions_to_recipe_by_recipe_id(recipe.id)
-                            .await;
-                        for instruct in sorted {
-                            let instr = self
-                                .parse_and_collect_instruction(instruct.instruction_id)
-                                .await;
-                            if let Some(ins) = instr {
-                                recipe.instructions.insert(instruct.instruction_order, ins);
-                            }
-                        }
-                        recipe
-                    })
-                    .collect()
-                    .await;
+                    .map(Recipe::try_from)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| Status::internal(format!("Failed to read recipes: {}", e)))?;
+                let rebuilt_data = self.hydrate_recipes(recipes).await?;
+                let total_count = self.count_rows(&page, rebuilt_data.len(), count).await?;
                 tracing::info!("Successfully collected instructions");
                 tracing::debug!("Collected data {:?}", rebuilt_data);
                 Ok(CollectRecipeResponse {
+                    next_page_token: page.next_page_token(rebuilt_data.len(), total_count),
                     recipes: rebuilt_data,
+                    total_count,
                 }
                 .to_response())
             }
             Err(e) => {
                 tracing::error!("There was an error collecting {}", e.to_string());
-                Err(Status::cancelled(format!(
-                    "Failed to query the database: {}",
-                    e
-                )))
+                Err(db_status(
+                    Code::Cancelled,
+                    "Failed to query the database",
+                    e,
+                ))
             }
         }
     }
+    async fn get_recipe(
+        &self,
+        request: Request<GetRecipeRequest>,
+    ) -> Result<Response<GetRecipeResponse>, Status> {
+        tracing::debug!("Got {:?}", request);
+        let recipe_id = request.into_inner().recipe_id;
+        let recipe = self
+            .select_one(
+                RecipeSchema::Table,
+                Expr::col(RecipeSchema::RecipeId).eq(recipe_id),
+            )
+            .await?
+            .ok_or_else(|| Status::not_found(format!("Recipe {} does not exist", recipe_id)))?;
+        let recipe = self.hydrate_recipes(vec![recipe]).await?.pop();
+        Ok(GetRecipeResponse { recipe }.to_response())
+    }
+    async fn collect_available_recipes(
+        &self,
+        request: Request<CollectAvailableRecipesRequest>,
+    ) -> Result<Response<CollectAvailableRecipesResponse>, Status> {
+        tracing::debug!("Got {:?}", request);
+        let size = DrinkSize::try_from(request.into_inner().size)
+            .map_err(|_| Status::invalid_argument("Invalid drink size"))?;
+        let recipes = self
+            .collect_recipe(
+                CollectRecipeRequest {
+                    expressions: Vec::new(),
+                    ..Default::default()
+                }
+                .into_request(),
+            )
+            .await?
+            .into_inner()
+            .recipes;
+        let ingredients = self.collect_ingredients_by_instruction().await?;
+        let stock = Stock {
+            calibrations: self
+                .collect_flow_calibrations()
+                .await?
+                .into_iter()
+                .map(|calibration| (calibration.fr_id, calibration))
+                .collect(),
+            bottles: self
+                .collect_bottles(&[])
+                .await?
+                .into_iter()
+                .map(|bottle| (bottle.fr_id, bottle))
+                .collect(),
+        };
+        let mut available = Vec::new();
+        let mut unavailable = Vec::new();
+        for recipe in recipes {
+            let missing = stock.missing_ingredients(&recipe, size, &ingredients);
+            if missing.is_empty() && !recipe.instructions.is_empty() {
+                available.push(recipe);
+            } else {
+                unavailable.push(UnavailableRecipe {
+                    recipe: Some(recipe),
+                    missing,
+                });
+            }
+        }
+        tracing::info!(
+            "{} recipes available, {} unavailable",
+            available.len(),
+            unavailable.len()
+        );
+        Ok(CollectAvailableRecipesResponse {
+            recipes: available,
+            unavailable,
+        }
+        .to_response())
+    }
     async fn add_instruction(
         &self,
         request: Request<AddInstructionRequest>,
@@ -335,9 +546,7 @@ impl UdmService for DaemonServerContext {
             .into_inner()
             .instruction
             .ok_or_else(|| Status::cancelled("Invalid request to add fluid regulator"))?;
-        let query = instruction
-            .gen_insert_query()
-            .to_string(PostgresQueryBuilder);
+        let query = instruction.gen_insert_query();
         let input_result = self.connection.insert(query).await;
         match input_result {
             Ok(instruction_id) => {
@@ -345,10 +554,11 @@ impl UdmService for DaemonServerContext {
                     AddInstructionResponse { instruction_id }.to_response();
                 Ok(instruction_response)
             }
-            Err(e) => Err(Status::data_loss(format!(
-                "Failed to insert into database: {}",
-                e
-            ))),
+            Err(e) => Err(db_status(
+                Code::DataLoss,
+                "Failed to insert into database",
+                e,
+            )),
         }
     }
     async fn remove_instruction(
@@ -357,14 +567,18 @@ impl UdmService for DaemonServerContext {
     ) -> Result<Response<GenericRemovalResponse>, Status> {
         tracing::debug!("Got Request {request:?}");
         let instruction_id = request.into_inner().instruction_id;
-        let query = Instruction::gen_remove_query(instruction_id).to_string(PostgresQueryBuilder);
+        let query = Instruction::gen_remove_query(instruction_id);
         let delete_result = self.connection.delete(query).await;
         match delete_result {
             Ok(_) => {
                 let remove_response = GenericRemovalResponse {}.to_response();
                 Ok(remove_response)
             }
-            Err(e) => Err(Status::aborted(e.to_string())),
+            Err(e) => Err(db_status(
+                Code::Aborted,
+                "Failed to delete from the database",
+                e,
+            )),
         }
     }
     async fn collect_instructions(
@@ -372,32 +586,63 @@ impl UdmService for DaemonServerContext {
         request: Request<CollectInstructionRequest>,
     ) -> Result<Response<CollectInstructionResponse>, Status> {
         tracing::debug!("Got {:?}", request);
-        let exprs = request
-            .into_inner()
-            .get_expressions()
-            .map_err(|e| Status::cancelled(e.to_string()))?;
-        let query = Instruction::gen_select_query_on_fields(InstructionSchema::Table, exprs)
-            .to_string(PostgresQueryBuilder);
+        let request = request.into_inner();
+        let condition = request
+            .get_condition()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let page = request
+            .get_page()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let count = Instruction::gen_count_query(InstructionSchema::Table, condition.clone());
+        let query = Instruction::gen_paged_select_query(InstructionSchema::Table, condition, &page);
         let results = self.connection.select(query).await;
         match results {
             Ok(results) => {
                 let instructions: Vec<Instruction> = results
                     .into_iter()
-                    .map(|row| Instruction::try_from(row).unwrap())
-                    .collect_vec();
+                    .map(Instruction::try_from)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| Status::internal(format!("Failed to read instructions: {}", e)))?;
+                let total_count = self.count_rows(&page, instructions.len(), count).await?;
                 tracing::info!("Successfully collected instructions");
                 tracing::debug!("Collected data {:?}", instructions);
-                Ok(CollectInstructionResponse { instructions }.to_response())
+                Ok(CollectInstructionResponse {
+                    next_page_token: page.next_page_token(instructions.len(), total_count),
+                    instructions,
+                    total_count,
+                }
+                .to_response())
             }
             Err(e) => {
                 tracing::error!("There was an error collecting {}", e.to_string());
-                Err(Status::cancelled(format!(
-                    "Failed to query the database: {}",
-                    e
-                )))
+                Err(db_status(
+                    Code::Cancelled,
+                    "Failed to query the database",
+                    e,
+                ))
             }
         }
     }
+    async fn get_instruction(
+        &self,
+        request: Request<GetInstructionRequest>,
+    ) -> Result<Response<GetInstructionResponse>, Status> {
+        tracing::debug!("Got {:?}", request);
+        let instruction_id = request.into_inner().instruction_id;
+        let instruction = self
+            .select_one(
+                InstructionSchema::Table,
+                Expr::col(InstructionSchema::InstructionId).eq(instruction_id),
+            )
+            .await?
+            .ok_or_else(|| {
+                Status::not_found(format!("Instruction {} does not exist", instruction_id))
+            })?;
+        Ok(GetInstructionResponse {
+            instruction: Some(instruction),
+        }
+        .to_response())
+    }
     async fn update_instruction(
         &self,
         request: Request<ModifyInstructionRequest>,
@@ -407,19 +652,18 @@ impl UdmService for DaemonServerContext {
             .into_inner()
             .instruction
             .ok_or_else(|| Status::cancelled("Invalid request to remove instruction"))?;
-        let query = instruction
-            .gen_update_query()
-            .to_string(PostgresQueryBuilder);
+        let query = instruction.gen_update_query();
         let result = self.connection.update(query).await;
         match result {
             Ok(id) => {
                 let response = ModifyInstructionResponse { instruction_id: id }.to_response();
                 Ok(response)
             }
-            Err(e) => Err(Status::data_loss(format!(
-                "Failed to update into database: {}",
-                e
-            ))),
+            Err(e) => Err(db_status(
+                Code::DataLoss,
+                "Failed to update into database",
+                e,
+            )),
         }
     }
     async fn add_ingredient(
@@ -431,9 +675,11 @@ impl UdmService for DaemonServerContext {
             .into_inner()
             .ingredient
             .ok_or_else(|| Status::cancelled("Invalid request to add ingredient"))?;
-        let query = ingredient
-            .gen_insert_query()
-            .to_string(PostgresQueryBuilder);
+        ingredient
+            .validate_without_id_fields()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        self.check_ingredient_references(&ingredient).await?;
+        let query = ingredient.gen_insert_query();
         let input_result = self.connection.insert(query).await;
         match input_result {
             Ok(ingredient_id) => {
@@ -441,10 +687,11 @@ impl UdmService for DaemonServerContext {
                     AddIngredientResponse { ingredient_id }.to_response();
                 Ok(ingredient_response)
             }
-            Err(e) => Err(Status::data_loss(format!(
-                "Failed to insert into database: {}",
-                e
-            ))),
+            Err(e) => Err(db_status(
+                Code::DataLoss,
+                "Failed to insert into database",
+                e,
+            )),
         }
     }
     async fn remove_ingredient(
@@ -453,14 +700,18 @@ impl UdmService for DaemonServerContext {
     ) -> Result<Response<GenericRemovalResponse>, Status> {
         tracing::debug!("Got Request {request:?}");
         let ingredient_id = request.into_inner().ingredient_id;
-        let query = Ingredient::gen_remove_query(ingredient_id).to_string(PostgresQueryBuilder);
+        let query = Ingredient::gen_remove_query(ingredient_id);
         let delete_result = self.connection.delete(query).await;
         match delete_result {
             Ok(_) => {
                 let remove_response = GenericRemovalResponse {}.to_response();
                 Ok(remove_response)
             }
-            Err(e) => Err(Status::aborted(e.to_string())),
+            Err(e) => Err(db_status(
+                Code::Aborted,
+                "Failed to delete from the database",
+                e,
+            )),
         }
     }
     async fn update_ingredient(
@@ -473,94 +724,120 @@ impl UdmService for DaemonServerContext {
             .clone()
             .ingredient
             .ok_or_else(|| Status::cancelled("Invalid request to remove instruction"))?;
-        let query = ingredient
-            .gen_update_query()
-            .to_string(PostgresQueryBuilder);
-        let ingredient_update_result = self.connection.update(query).await;
-        match ingredient_update_result {
-            Ok(ingredient_id) => {
-                if request.get_ref().update_fr {
-                    if let Some(fr) = ingredient.regulator {
-                        let request = ModifyFluidRegulatorRequest { fluid: Some(fr) };
-                        let _ = self.update_fluid_regulator(request.into_request()).await?;
-                    }
-                }
-                if request.get_ref().update_instruction {
-                    if let Some(instruction) = ingredient.instruction {
-                        let request = ModifyInstructionRequest {
-                            instruction: Some(instruction),
-                        };
-                        let _ = self.update_instruction(request.into_request()).await?;
-                    }
-                }
-
-                Ok(ModifyIngredientResponse { ingredient_id }.to_response())
-            }
-            Err(e) => Err(Status::data_loss(format!(
-                "Failed to update into database: {}",
-                e
-            ))),
+        ingredient
+            .validate_all_fields()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        self.check_ingredient_references(&ingredient).await?;
+        let request = request.into_inner();
+        // The ingredient, its regulator and its instruction change together
+        let failed = |e| db_status(Code::DataLoss, "Failed to update into database", e);
+        let mut transaction = self.begin().await?;
+        let ingredient_id = transaction
+            .update(ingredient.gen_update_query())
+            .await
+            .map_err(failed)?;
+        if let Some(fr) = ingredient.regulator.filter(|_| request.update_fr) {
+            transaction
+                .update(fr.gen_update_query())
+                .await
+                .map_err(failed)?;
+        }
+        if let Some(instruction) = ingredient
+            .instruction
+            .filter(|_| request.update_instruction)
+        {
+            transaction
+                .update(instruction.gen_update_query())
+                .await
+                .map_err(failed)?;
         }
+        Self::commit(transaction).await?;
+        Ok(ModifyIngredientResponse { ingredient_id }.to_response())
     }
     async fn collect_ingredients(
         &self,
         request: Request<CollectIngredientRequest>,
     ) -> Result<Response<CollectIngredientResponse>, Status> {
         tracing::debug!("Got {:?}", request);
-        let exprs = request
-            .into_inner()
-            .get_expressions()
-            .map_err(|e| Status::cancelled(e.to_string()))?;
-        let query = Ingredient::gen_select_query_on_fields(IngredientSchema::Table, exprs)
-            .to_string(PostgresQueryBuilder);
+        let request = request.into_inner();
+        let condition = request
+            .get_condition()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let page = request
+            .get_page()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let count = Ingredient::gen_count_query(IngredientSchema::Table, condition.clone());
+        let query = Ingredient::gen_paged_select_query(IngredientSchema::Table, condition, &page);
         let results = self.connection.select(query).await;
         match results {
             Ok(results) => {
                 let ingredients: Vec<Ingredient> = results
                     .into_iter()
-                    .map(|row| Ingredient::try_from(row).unwrap())
-                    .collect_vec();
-                // TDOO(TheFinalJoke): Refactor this to run on single query
-                // TODO(TheFinalJoke): Refactor this so we are not sending another request
-                let rebuilt_data = stream::iter(ingredients)
-                    .then(|mut ingredient| async {
-                        if let Some(fr_id) = ingredient.regulator.as_ref().and_then(|fr| fr.fr_id) {
-                            ingredient.regulator =
-                                self.parse_and_collect_fluid_regulator(fr_id).await;
-                        }
-                        if let Some(instruction_id) = ingredient
-                            .instruction
-                            .as_ref()
-                            .map(|instruction| instruction.id)
-                        {
-                            ingredient.instruction =
-                                self.parse_and_collect_instruction(instruction_id).await;
-                        }
-                        ingredient
-                    })
-                    .collect()
-                    .await;
+                    .map(Ingredient::try_from)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| Status::internal(format!("Failed to read ingredients: {}", e)))?;
+                let rebuilt_data = self.hydrate_ingredients(ingredients).await?;
+                let total_count = self.count_rows(&page, rebuilt_data.len(), count).await?;
                 tracing::info!("Successfully collected fluid regulators");
                 tracing::debug!("Collected data {:?}", rebuilt_data);
                 Ok(CollectIngredientResponse {
+                    next_page_token: page.next_page_token(rebuilt_data.len(), total_count),
                     ingredients: rebuilt_data,
+                    total_count,
                 }
                 .to_response())
             }
             Err(e) => {
                 tracing::error!("There was an error collecting {}", e.to_string());
-                Err(Status::cancelled(format!(
-                    "Failed to query the database: {}",
-                    e
-                )))
+                Err(db_status(
+                    Code::Cancelled,
+                    "Failed to query the database",
+                    e,
+                ))
             }
         }
     }
+    async fn get_ingredient(
+        &self,
+        request: Request<GetIngredientRequest>,
+    ) -> Result<Response<GetIngredientResponse>, Status> {
+        tracing::debug!("Got {:?}", request);
+        let ingredient_id = request.into_inner().ingredient_id;
+        let ingredient = self
+            .select_one(
+                IngredientSchema::Table,
+                Expr::col(IngredientSchema::IngredientId).eq(ingredient_id),
+            )
+            .await?
+            .ok_or_else(|| {
+                Status::not_found(format!("Ingredient {} does not exist", ingredient_id))
+            })?;
+        let ingredient = self.hydrate_ingredients(vec![ingredient]).await?.pop();
+        Ok(GetIngredientResponse { ingredient }.to_response())
+    }
     async fn reset_db(
         &self,
         request: Request<ResetRequest>,
     ) -> Result<Response<ResetResponse>, Status> {
         tracing::info!("Got request {request:?}");
+        let request = request.into_inner();
+        let entity = request.entity();
+        if let Some(statements) = entity.reset_statements() {
+            let mut transaction = self.begin().await?;
+            for statement in statements {
+                transaction.delete(statement).await.map_err(|e| {
+                    db_status(Code::Cancelled, &format!("Failed to reset {}", entity), e)
+                })?;
+            }
+            Self::commit(transaction).await?;
+            tracing::info!("Successfully reset {}", entity);
+            return Ok(ResetResponse {}.to_response());
+        }
+        if request.reset_type() != ResetType::All {
+            return Err(Status::invalid_argument(
+                "Either an entity or RESET_TYPE_ALL is required",
+            ));
+        }
         let dropped_result = self.connection.truncate_schema().await;
         tracing::info!("the dropped Result {:?}", &dropped_result);
         match dropped_result {
@@ -568,7 +845,7 @@ impl UdmService for DaemonServerContext {
                 tracing::info!("Successfully dropped rows");
                 Ok(ResetResponse {}.to_response())
             }
-            Err(err) => Err(Status::cancelled(format!("Failed to drop rows: {}", err))),
+            Err(err) => Err(db_status(Code::Cancelled, "Failed to drop rows", err)),
         }
     }
     async fn update_recipe_instruction_order(
@@ -585,19 +862,23 @@ impl UdmService for DaemonServerContext {
             .collect_vec();
         let filtered_requests: HashSet<InstructionToRecipeMetadata> =
             order_requests.into_iter().flatten().collect();
+        let mut transaction = self.begin().await?;
         for req in filtered_requests {
-            let query = req.gen_update_query().to_string(PostgresQueryBuilder);
-            let result = self.connection.update(query).await;
+            let query = req.gen_update_query();
+            let result = transaction.update(query).await;
             match result {
                 Ok(id) => tracing::info!("Updated Recipe to Instruction Collection {}", id),
                 Err(e) => {
                     tracing::error!("Error while updating the Recipe to Instruction {}", e);
-                    return Err(Status::cancelled(
-                        "Error while updating the Recipe to Instruction {e:?}",
+                    return Err(db_status(
+                        Code::Cancelled,
+                        "Error while updating the Recipe to Instruction",
+                        e,
                     ));
                 }
             }
         }
+        Self::commit(transaction).await?;
         Ok(GenericEmpty {}.to_response())
     }
     async fn add_recipe_instruction_order(
@@ -606,24 +887,21 @@ impl UdmService for DaemonServerContext {
     ) -> Result<Response<AddRecipeInstOrderResponse>, Status> {
         tracing::debug!("Got Request {request:?}");
         let recipe_orders = request.into_inner().recipe_orders;
-        let ids = stream::iter(recipe_orders)
-            .filter_map(|orders| async { InstructionToRecipeMetadata::try_from(orders).ok() })
-            .then(|order| async move {
-                let query = order.gen_insert_query().to_string(PostgresQueryBuilder);
-                match self.connection.insert(query).await {
-                    Ok(id) => {
-                        tracing::info!("Successfully inserted into db {}", id);
-                        Some(id)
-                    }
-                    Err(e) => {
-                        tracing::error!("Error inserting into db {e:?}");
-                        None
-                    }
-                }
-            })
-            .filter_map(|id| async move { id })
-            .collect()
-            .await;
+        let mut transaction = self.begin().await?;
+        let mut ids = Vec::new();
+        for order in recipe_orders
+            .into_iter()
+            .filter_map(|orders| InstructionToRecipeMetadata::try_from(orders).ok())
+        {
+            let query = order.gen_insert_query();
+            let id = transaction.insert(query).await.map_err(|e| {
+                tracing::error!("Error inserting into db {e:?}");
+                db_status(Code::Cancelled, "Failed to insert into database", e)
+            })?;
+            tracing::info!("Successfully inserted into db {}", id);
+            ids.push(id);
+        }
+        Self::commit(transaction).await?;
         Ok(AddRecipeInstOrderResponse { ids }.to_response())
     }
 
@@ -632,39 +910,57 @@ impl UdmService for DaemonServerContext {
         request: Request<CollectRecipeInstOrderRequest>,
     ) -> Result<Response<CollectRecipeInstOrderResponse>, Status> {
         tracing::debug!("Got request {request:?}");
-        let exprs = request
-            .into_inner()
-            .get_expressions()
-            .map_err(|e| Status::cancelled(e.to_string()))?;
-        let query = InstructionToRecipeMetadata::gen_select_query_on_fields(
+        let request = request.into_inner();
+        let condition = request
+            .get_condition()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let page = request
+            .get_page()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let count = InstructionToRecipeMetadata::gen_count_query(
             InstructionToRecipeSchema::Table,
-            exprs,
-        )
-        .to_string(PostgresQueryBuilder);
+            condition.clone(),
+        );
+        let query = InstructionToRecipeMetadata::gen_paged_select_query(
+            InstructionToRecipeSchema::Table,
+            condition,
+            &page,
+        );
         let results = self.connection.select(query).await;
         match results {
             Ok(results) => {
                 let recipe_instrs: Vec<InstructionToRecipeMetadata> = results
                     .into_iter()
-                    .map(|row| InstructionToRecipeMetadata::try_from(row).unwrap())
-                    .collect_vec();
+                    .map(InstructionToRecipeMetadata::try_from)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| {
+                        Status::internal(format!("Failed to read recipe instructions: {}", e))
+                    })?;
                 tracing::info!("Successfully collected Instructions to Recipe");
                 tracing::debug!("Collected data {:?}", recipe_instrs);
                 let recipe_to_instructions: Vec<RecipeInstructionOrder> = recipe_instrs
                     .into_iter()
-                    .map(|orders| orders.try_into().ok().unwrap())
-                    .collect_vec();
+                    .map(RecipeInstructionOrder::try_from)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| Status::internal(format!("Failed to read positions: {}", e)))?;
+                let total_count = self
+                    .count_rows(&page, recipe_to_instructions.len(), count)
+                    .await?;
                 Ok(CollectRecipeInstOrderResponse {
+                    next_page_token: page
+                        .next_page_token(recipe_to_instructions.len(), total_count),
                     recipe_to_instructions,
+                    total_count,
                 }
                 .to_response())
             }
             Err(e) => {
                 tracing::error!("There was an error collecting {}", e.to_string());
-                Err(Status::cancelled(format!(
-                    "Failed to query the database: {}",
-                    e
-                )))
+                Err(db_status(
+                    Code::Cancelled,
+                    "Failed to query the database",
+                    e,
+                ))
             }
         }
     }
@@ -674,123 +970,943 @@ impl UdmService for DaemonServerContext {
     ) -> Result<Response<GenericRemovalResponse>, Status> {
         tracing::debug!("Got Request {request:?}");
         let recipe_inst_id = request.into_inner().id;
-        let query = InstructionToRecipeMetadata::gen_remove_query(recipe_inst_id)
-            .to_string(PostgresQueryBuilder);
+        let query = InstructionToRecipeMetadata::gen_remove_query(recipe_inst_id);
         let delete_result = self.connection.delete(query).await;
         match delete_result {
             Ok(_) => {
                 let remove_response = GenericRemovalResponse {}.to_response();
                 Ok(remove_response)
             }
-            Err(e) => Err(Status::aborted(e.to_string())),
+            Err(e) => Err(db_status(
+                Code::Aborted,
+                "Failed to delete from the database",
+                e,
+            )),
+        }
+    }
+    async fn make_drink(
+        &self,
+        request: Request<MakeDrinkRequest>,
+    ) -> Result<Response<MakeDrinkResponse>, Status> {
+        tracing::debug!("Got Request {request:?}");
+        if let Some(status) = self.halted_status() {
+            return Err(status);
+        }
+        let req = request.into_inner();
+        let size = DrinkSize::try_from(req.size)
+            .map_err(|_| Status::invalid_argument("Invalid drink size"))?;
+        let plan = self.build_pour_plan(req.recipe_id, size).await?;
+        let pour_id = self.dispenser.start(plan);
+        tracing::info!("Started pour {} for recipe {}", pour_id, req.recipe_id);
+        Ok(MakeDrinkResponse { pour_id }.to_response())
+    }
+    async fn calibrate_regulator(
+        &self,
+        request: Request<CalibrateRegulatorRequest>,
+    ) -> Result<Response<CalibrateRegulatorResponse>, Status> {
+        tracing::debug!("Got Request {request:?}");
+        if let Some(status) = self.halted_status() {
+            return Err(status);
+        }
+        let req = request.into_inner();
+        let regulator = self
+            .parse_and_collect_fluid_regulator(req.fr_id)
+            .await?
+            .ok_or_else(|| {
+                Status::not_found(format!("Fluid regulator {} does not exist", req.fr_id))
+            })?;
+        if !(1..=MAX_CALIBRATION_MS).contains(&req.duration_ms) {
+            return Err(Status::invalid_argument(format!(
+                "The test pour has to last between 1 and {}ms",
+                MAX_CALIBRATION_MS
+            )));
+        }
+        let open_for = Duration::from_millis(req.duration_ms as u64);
+        let Some(measured_ml) = req.measured_ml else {
+            // First half, pour so the user can measure what came out
+            self.dispenser
+                .test_pour(&regulator, open_for)
+                .await
+                .map_err(|e| Status::aborted(format!("Test pour failed: {}", e)))?;
+            return Ok(CalibrateRegulatorResponse { calibration: None }.to_response());
+        };
+        let calibration = FlowCalibration::from_measurement(
+            req.fr_id,
+            open_for,
+            measured_ml,
+            Duration::from_millis(req.startup_lag_ms.max(0) as u64),
+            req.drip_compensation_ml,
+        )
+        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let query = calibration.gen_insert_query();
+        match self.connection.insert(query).await {
+            Ok(fr_id) => {
+                tracing::info!("Calibrated fluid regulator {}: {:?}", fr_id, &calibration);
+                Ok(CalibrateRegulatorResponse {
+                    calibration: Some(calibration),
+                }
+                .to_response())
+            }
+            Err(e) => Err(db_status(
+                Code::DataLoss,
+                "Failed to insert into database",
+                e,
+            )),
+        }
+    }
+    async fn emergency_stop(
+        &self,
+        request: Request<EmergencyStopRequest>,
+    ) -> Result<Response<EmergencyStopResponse>, Status> {
+        tracing::debug!("Got Request {request:?}");
+        let req = request.into_inner();
+        let reason = if req.reason.is_empty() {
+            "Emergency stop requested".to_string()
+        } else {
+            req.reason
+        };
+        // Latch before anything can wait on the database
+        let stopped = self.dispenser.emergency_stop(reason);
+        let mut closed_pins = self.dispenser.used_pins();
+        // Regulators that were configured but never used are closed as well,
+        // as far as the database can tell
+        let (configured, regulator_error) = match self.collect_regulator_pins().await {
+            Ok(pins) => (pins, String::new()),
+            Err(status) => {
+                tracing::error!("Failed to read the regulators: {}", status.message());
+                (Vec::new(), status.message().to_string())
+            }
+        };
+        let configured = configured
+            .into_iter()
+            .filter(|pin| !closed_pins.contains(pin))
+            .collect_vec();
+        let closed = self.dispenser.close_pins(&configured);
+        closed_pins.extend(configured);
+        match stopped.and_then(|cancelled_pours| closed.map(|_| cancelled_pours)) {
+            Ok(cancelled_pours) => Ok(EmergencyStopResponse {
+                cancelled_pours,
+                closed_pins: closed_pins.into_iter().map(|pin| pin as i32).collect(),
+                regulator_error,
+            }
+            .to_response()),
+            Err(e) => Err(Status::internal(format!(
+                "Dispenser is halted but not every pin closed: {}",
+                e
+            ))),
         }
     }
+    async fn resume(
+        &self,
+        request: Request<ResumeRequest>,
+    ) -> Result<Response<GenericEmpty>, Status> {
+        tracing::debug!("Got Request {request:?}");
+        self.dispenser.resume();
+        Ok(GenericEmpty {}.to_response())
+    }
+    type WatchPourStream = Pin<Box<dyn Stream<Item = Result<PourEvent, Status>> + Send>>;
+    async fn watch_pour(
+        &self,
+        request: Request<WatchPourRequest>,
+    ) -> Result<Response<Self::WatchPourStream>, Status> {
+        tracing::debug!("Got Request {request:?}");
+        let pour_id = request.into_inner().pour_id;
+        let subscription = self
+            .dispenser
+            .events()
+            .subscribe(pour_id)
+            .ok_or_else(|| Status::not_found(format!("Pour {} does not exist", pour_id)))?;
+        Ok(Response::new(Box::pin(subscription.into_stream().map(Ok))))
+    }
+    async fn enqueue_drink(
+        &self,
+        request: Request<EnqueueDrinkRequest>,
+    ) -> Result<Response<EnqueueDrinkResponse>, Status> {
+        tracing::debug!("Got Request {request:?}");
+        let req = request.into_inner();
+        let size = DrinkSize::try_from(req.size)
+            .map_err(|_| Status::invalid_argument("Invalid drink size"))?;
+        if req.requester.trim().is_empty() {
+            return Err(Status::invalid_argument("A requester name is required"));
+        }
+        // Catch orders that can never be poured before they wait in line
+        self.build_pour_plan(req.recipe_id, size).await?;
+        let _queue = self.queue_lock.lock().await;
+        let mut queued = self.collect_orders(&[OrderStatus::Queued]).await?;
+        let index = queue::insertion_index(&queued, req.priority);
+        let mut order = DrinkOrder {
+            order_id: None,
+            recipe_id: req.recipe_id,
+            size: size.into(),
+            requester: req.requester,
+            priority: req.priority,
+            position: index as i32,
+            status: OrderStatus::Queued.into(),
+            pour_id: None,
+            message: String::new(),
+        };
+        queued.insert(index, order.clone());
+        let mut transaction = self.begin().await?;
+        for moved in queue::renumber(&mut queued)
+            .iter()
+            .filter(|moved| moved.order_id.is_some())
+        {
+            transaction
+                .update(moved.gen_update_query())
+                .await
+                .map_err(|e| db_status(Code::DataLoss, "Failed to update order", e))?;
+        }
+        let query = order.gen_insert_query();
+        match transaction.insert(query).await {
+            Ok(order_id) => {
+                Self::commit(transaction).await?;
+                order.order_id = Some(order_id);
+                tracing::info!("Queued order {} for {}", order_id, &order.requester);
+                self.order_ready.notify_one();
+                Ok(EnqueueDrinkResponse { order: Some(order) }.to_response())
+            }
+            Err(e) => Err(db_status(
+                Code::DataLoss,
+                "Failed to insert into database",
+                e,
+            )),
+        }
+    }
+    async fn list_queue(
+        &self,
+        request: Request<ListQueueRequest>,
+    ) -> Result<Response<ListQueueResponse>, Status> {
+        tracing::debug!("Got Request {request:?}");
+        let orders = if request.into_inner().include_finished {
+            self.collect_orders(&[]).await?
+        } else {
+            self.collect_orders(&[OrderStatus::Queued, OrderStatus::Pouring])
+                .await?
+        };
+        Ok(ListQueueResponse { orders }.to_response())
+    }
+    async fn cancel_order(
+        &self,
+        request: Request<CancelOrderRequest>,
+    ) -> Result<Response<GenericEmpty>, Status> {
+        tracing::debug!("Got Request {request:?}");
+        let order_id = request.into_inner().order_id;
+        let _queue = self.queue_lock.lock().await;
+        let mut order = self
+            .collect_orders(&[])
+            .await?
+            .into_iter()
+            .find(|order| order.order_id == Some(order_id))
+            .ok_or_else(|| Status::not_found(format!("Order {} does not exist", order_id)))?;
+        match order.status() {
+            OrderStatus::Queued => {}
+            OrderStatus::Pouring => {
+                return Err(Status::failed_precondition(format!(
+                    "Order {} is already pouring, use an emergency stop instead",
+                    order_id
+                )))
+            }
+            _ => {
+                return Err(Status::failed_precondition(format!(
+                    "Order {} has already finished",
+                    order_id
+                )))
+            }
+        }
+        order.set_status(OrderStatus::Cancelled);
+        self.update_order(&order).await?;
+        tracing::info!("Cancelled order {}", order_id);
+        Ok(GenericEmpty {}.to_response())
+    }
+    async fn reorder_queue(
+        &self,
+        request: Request<ReorderQueueRequest>,
+    ) -> Result<Response<ListQueueResponse>, Status> {
+        tracing::debug!("Got Request {request:?}");
+        let order_ids = request.into_inner().order_ids;
+        let _queue = self.queue_lock.lock().await;
+        let queued = self.collect_orders(&[OrderStatus::Queued]).await?;
+        let mut reordered = queue::reorder(queued, &order_ids)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let mut transaction = self.begin().await?;
+        for moved in queue::renumber(&mut reordered) {
+            transaction
+                .update(moved.gen_update_query())
+                .await
+                .map_err(|e| db_status(Code::DataLoss, "Failed to update order", e))?;
+        }
+        Self::commit(transaction).await?;
+        let orders = self
+            .collect_orders(&[OrderStatus::Queued, OrderStatus::Pouring])
+            .await?;
+        Ok(ListQueueResponse { orders }.to_response())
+    }
+    async fn set_bottle_level(
+        &self,
+        request: Request<SetBottleLevelRequest>,
+    ) -> Result<Response<SetBottleLevelResponse>, Status> {
+        tracing::debug!("Got Request {request:?}");
+        let req = request.into_inner();
+        if self
+            .parse_and_collect_fluid_regulator(req.fr_id)
+            .await?
+            .is_none()
+        {
+            return Err(Status::not_found(format!(
+                "Fluid regulator {} does not exist",
+                req.fr_id
+            )));
+        }
+        let current = self.collect_bottles(&[req.fr_id]).await?.into_iter().next();
+        let capacity_ml = req
+            .capacity_ml
+            .or(current.as_ref().map(|bottle| bottle.capacity_ml))
+            .ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "No bottle is loaded on fluid regulator {}, a capacity is required",
+                    req.fr_id
+                ))
+            })?;
+        let bottle = Bottle {
+            fr_id: req.fr_id,
+            ingredient_id: req
+                .ingredient_id
+                .or(current.and_then(|bottle| bottle.ingredient_id)),
+            capacity_ml,
+            remaining_ml: req.remaining_ml.unwrap_or(capacity_ml),
+        };
+        bottle
+            .validate()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if let Some(ingredient_id) = req.ingredient_id {
+            self.ensure_exists(
+                IngredientSchema::Table,
+                IngredientSchema::IngredientId,
+                vec![ingredient_id],
+            )
+            .await?;
+        }
+        let query = bottle.gen_insert_query();
+        match self.connection.insert(query).await {
+            Ok(fr_id) => {
+                tracing::info!("Set bottle on fluid regulator {}: {:?}", fr_id, &bottle);
+                Ok(SetBottleLevelResponse {
+                    bottle: Some(bottle),
+                }
+                .to_response())
+            }
+            Err(e) => Err(db_status(
+                Code::DataLoss,
+                "Failed to insert into database",
+                e,
+            )),
+        }
+    }
+    async fn get_inventory(
+        &self,
+        request: Request<GetInventoryRequest>,
+    ) -> Result<Response<GetInventoryResponse>, Status> {
+        tracing::debug!("Got Request {request:?}");
+        let bottles = self.collect_bottles(&request.into_inner().fr_ids).await?;
+        Ok(GetInventoryResponse { bottles }.to_response())
+    }
+    async fn get_daemon_status(
+        &self,
+        request: Request<DaemonStatusRequest>,
+    ) -> Result<Response<DaemonStatusResponse>, Status> {
+        tracing::debug!("Got Request {request:?}");
+        let halt_reason = self.dispenser.halt_reason();
+        Ok(DaemonStatusResponse {
+            halted: halt_reason.is_some(),
+            halt_reason: halt_reason.unwrap_or_default(),
+            active_pours: self.dispenser.active_pours(),
+        }
+        .to_response())
+    }
+    async fn get_health(
+        &self,
+        request: Request<GetHealthRequest>,
+    ) -> Result<Response<GetHealthResponse>, Status> {
+        tracing::debug!("Got Request {request:?}");
+        let health = self.health.health();
+        Ok(GetHealthResponse {
+            database_available: health.available,
+            database_message: health.message,
+            failed_probes: health.failed_probes,
+            last_checked: health.last_checked,
+            unavailable_since: health.unavailable_since,
+        }
+        .to_response())
+    }
 }
 
 impl DaemonServerContext {
-    async fn parse_and_collect_fluid_regulator(&self, fr_id: i32) -> Option<FluidRegulator> {
-        let req = CollectFluidRegulatorsRequest {
-            expressions: vec![FetchData {
-                column: "fr_id".to_string(),
-                operation: Operation::Equal.into(),
-                values: fr_id.to_string(),
-            }],
-        };
-        match self.collect_fluid_regulators(req.into_request()).await {
-            Ok(response) => response.into_inner().fluids.first().cloned(),
+    /// Pours queued orders one at a time until the daemon exits
+    pub async fn run_order_queue(self: Arc<Self>) {
+        self.fail_interrupted_orders().await;
+        loop {
+            // Orders wait in the queue while the dispenser is stopped
+            self.dispenser.wait_until_running().await;
+            match self.take_next_order().await {
+                Ok(Some((order, plan))) => self.pour_order(order, plan).await,
+                Ok(None) => self.order_ready.notified().await,
+                Err(e) => {
+                    tracing::error!("Failed to read the order queue: {}", e);
+                    tokio::time::sleep(QUEUE_RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+    async fn take_next_order(&self) -> Result<Option<(DrinkOrder, PourPlan)>, Status> {
+        let _queue = self.queue_lock.lock().await;
+        let queued = self.collect_orders(&[OrderStatus::Queued]).await?;
+        for mut order in queued {
+            let size = DrinkSize::try_from(order.size).unwrap_or(DrinkSize::Unspecified);
+            match self.build_pour_plan(order.recipe_id, size).await {
+                Ok(plan) => {
+                    order.set_status(OrderStatus::Pouring);
+                    order.pour_id = Some(self.dispenser.next_pour_id());
+                    self.update_order(&order).await?;
+                    return Ok(Some((order, plan)));
+                }
+                Err(status) if !Self::is_order_failure(&status) => return Err(status),
+                Err(status) => {
+                    // Something changed since it was queued, skip it
+                    tracing::error!("Order {:?} can not be poured: {}", order.order_id, status);
+                    order.set_status(OrderStatus::Failed);
+                    order.message = status.message().to_string();
+                    self.update_order(&order).await?;
+                }
+            }
+        }
+        Ok(None)
+    }
+    async fn pour_order(&self, mut order: DrinkOrder, plan: PourPlan) {
+        let pour_id = order.pour_id.unwrap_or_default();
+        tracing::info!(
+            "Pouring order {:?} for {} as pour {}",
+            order.order_id,
+            &order.requester,
+            pour_id
+        );
+        match self.dispenser.pour(pour_id, &plan).await {
+            Ok(_) => order.set_status(OrderStatus::Completed),
             Err(e) => {
-                tracing::error!("Error Occured: {}", e);
-                None
+                tracing::error!("Order {:?} failed: {}", order.order_id, e);
+                order.set_status(OrderStatus::Failed);
+                order.message = e.to_string();
             }
         }
+        if let Err(e) = self.update_order(&order).await {
+            tracing::error!("Failed to record order {:?}: {}", order.order_id, e);
+        }
     }
-    async fn parse_and_collect_instruction(&self, instruction_id: i32) -> Option<Instruction> {
-        let req = CollectInstructionRequest {
-            expressions: vec![FetchData {
-                column: "instruction_id".to_string(),
-                operation: Operation::Equal.into(),
-                values: instruction_id.to_string(),
-            }],
-        };
-        match self.collect_instructions(req.into_request()).await {
-            Ok(response) => response.into_inner().instructions.first().cloned(),
+    // An order that was pouring when the daemon stopped can not be resumed
+    async fn fail_interrupted_orders(&self) {
+        let interrupted = match self.collect_orders(&[OrderStatus::Pouring]).await {
+            Ok(interrupted) => interrupted,
             Err(e) => {
-                tracing::error!("Error Occured: {}", e);
-                None
+                tracing::error!("Failed to read the order queue: {}", e);
+                return;
+            }
+        };
+        for mut order in interrupted {
+            order.set_status(OrderStatus::Failed);
+            order.message = "Interrupted by a daemon restart".to_string();
+            if let Err(e) = self.update_order(&order).await {
+                tracing::error!("Failed to record order {:?}: {}", order.order_id, e);
             }
         }
     }
-    async fn parse_and_collect_instructions_to_recipe_by_recipe_id(
+    // Raw rows, the regulator and instruction only carry their ids
+    async fn collect_ingredients_by_instruction(
         &self,
-        recipe_id: i32,
-    ) -> Vec<InstructionToRecipeMetadata> {
-        // Collection instruction
-        let fetch_data = vec![FetchData {
-            column: "recipe_id".to_string(),
-            operation: Operation::Equal.into(),
-            values: recipe_id.to_string(),
+    ) -> Result<HashMap<i32, Vec<Ingredient>>, Status> {
+        let query = Ingredient::gen_select_query_on_fields(IngredientSchema::Table, Vec::new());
+        let rows = self
+            .connection
+            .select(query)
+            .await
+            .map_err(|e| db_status(Code::Cancelled, "Failed to query the database", e))?;
+        let mut collected = rows
+            .into_iter()
+            .map(Ingredient::try_from)
+            .collect::<Result<Vec<Ingredient>, _>>()
+            .map_err(|e| Status::internal(format!("Failed to read ingredients: {}", e)))?;
+        self.attach_regulators(&mut collected).await?;
+        let mut ingredients: HashMap<i32, Vec<Ingredient>> = HashMap::new();
+        for ingredient in collected {
+            if let Some(instruction_id) = ingredient.instruction.as_ref().map(|i| i.id) {
+                ingredients
+                    .entry(instruction_id)
+                    .or_default()
+                    .push(ingredient);
+            }
         }
-        .to_simple_expr(RecipeSchema::RecipeId)
-        .unwrap()];
-        let data_query = InstructionToRecipeMetadata::gen_select_query_on_fields(
-            InstructionToRecipeSchema::Table,
-            fetch_data,
+        Ok(ingredients)
+    }
+    async fn collect_flow_calibrations(&self) -> Result<Vec<FlowCalibration>, Status> {
+        let query =
+            FlowCalibration::gen_select_query_on_fields(FlowCalibrationSchema::Table, Vec::new());
+        let rows = self
+            .connection
+            .select(query)
+            .await
+            .map_err(|e| db_status(Code::Cancelled, "Failed to query the database", e))?;
+        rows.into_iter()
+            .map(FlowCalibration::try_from)
+            .collect::<Result<Vec<FlowCalibration>, _>>()
+            .map_err(|e| Status::internal(format!("Failed to read calibrations: {}", e)))
+    }
+    // Empty fr_ids collects every bottle
+    async fn collect_bottles(&self, fr_ids: &[i32]) -> Result<Vec<Bottle>, Status> {
+        let wheres = if fr_ids.is_empty() {
+            Vec::new()
+        } else {
+            vec![Expr::col(BottleSchema::FrId).is_in(fr_ids.iter().copied())]
+        };
+        let query = Bottle::gen_select_query_on_fields(BottleSchema::Table, wheres);
+        let rows = self
+            .connection
+            .select(query)
+            .await
+            .map_err(|e| db_status(Code::Cancelled, "Failed to query the database", e))?;
+        let mut bottles = rows
+            .into_iter()
+            .map(Bottle::try_from)
+            .collect::<Result<Vec<Bottle>, _>>()
+            .map_err(|e| Status::internal(format!("Failed to read bottles: {}", e)))?;
+        bottles.sort_by_key(|bottle| bottle.fr_id);
+        Ok(bottles)
+    }
+    // Empty statuses collects every order
+    async fn collect_orders(&self, statuses: &[OrderStatus]) -> Result<Vec<DrinkOrder>, Status> {
+        let wheres = if statuses.is_empty() {
+            Vec::new()
+        } else {
+            vec![Expr::col(DrinkOrderSchema::Status)
+                .is_in(statuses.iter().map(|status| *status as i32))]
+        };
+        let query = DrinkOrder::gen_select_query_on_fields(DrinkOrderSchema::Table, wheres);
+        let rows = self
+            .connection
+            .select(query)
+            .await
+            .map_err(|e| db_status(Code::Cancelled, "Failed to query the database", e))?;
+        let mut orders = rows
+            .into_iter()
+            .map(DrinkOrder::try_from)
+            .collect::<Result<Vec<DrinkOrder>, _>>()
+            .map_err(|e| Status::internal(format!("Failed to read orders: {}", e)))?;
+        orders.sort_by_key(|order| (order.is_finished(), order.position, order.order_id));
+        Ok(orders)
+    }
+    // Statements on the connection wait while the transaction is open, use
+    // only the transaction until it is committed
+    async fn begin(&self) -> Result<Box<dyn DbTransaction + '_>, Status> {
+        self.connection
+            .begin()
+            .await
+            .map_err(|e| db_status(Code::Cancelled, "Failed to start a transaction", e))
+    }
+    // Without a limit or token the page already holds every matching row
+    async fn count_rows(
+        &self,
+        page: &Page,
+        returned: usize,
+        query: SelectStatement,
+    ) -> Result<i64, Status> {
+        if page.limit.is_none() && page.offset == 0 {
+            return Ok(returned as i64);
+        }
+        let rows = self
+            .connection
+            .select(query)
+            .await
+            .map_err(|e| db_status(Code::Cancelled, "Failed to count rows", e))?;
+        rows.first()
+            .map_or(Ok(0), |row| row.try_get(0))
+            .map_err(|e| Status::internal(format!("Failed to count rows: {}", e)))
+    }
+    // Referenced rows are looked up before anything is written so a missing
+    // one is reported to the caller instead of failing a foreign key
+    async fn ensure_exists<T: Iden + 'static>(
+        &self,
+        table: T,
+        key: T,
+        ids: Vec<i32>,
+    ) -> Result<(), Status> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let name = Iden::to_string(&table);
+        let key = key.into_iden();
+        let query = Query::select()
+            .column(key.clone())
+            .from(table)
+            .and_where(Expr::col(key).is_in(ids.clone()))
+            .to_owned();
+        let rows = self
+            .connection
+            .select(query)
+            .await
+            .map_err(|e| db_status(Code::Cancelled, "Failed to query the database", e))?;
+        let found = rows
+            .iter()
+            .map(|row| row.try_get(0))
+            .collect::<UdmResult<HashSet<i32>>>()
+            .map_err(|e| Status::internal(format!("Failed to read {}: {}", name, e)))?;
+        match ids.into_iter().find(|id| !found.contains(id)) {
+            Some(id) => Err(Status::invalid_argument(format!(
+                "{} {} does not exist",
+                name, id
+            ))),
+            None => Ok(()),
+        }
+    }
+    async fn check_ingredient_references(&self, ingredient: &Ingredient) -> Result<(), Status> {
+        if let Some(fr_id) = ingredient.regulator.as_ref().and_then(|fr| fr.fr_id) {
+            self.ensure_exists(
+                FluidRegulationSchema::Table,
+                FluidRegulationSchema::FrId,
+                vec![fr_id],
+            )
+            .await?;
+        }
+        if let Some(instruction) = &ingredient.instruction {
+            self.ensure_exists(
+                InstructionSchema::Table,
+                InstructionSchema::InstructionId,
+                vec![instruction.id],
+            )
+            .await?;
+        }
+        Ok(())
+    }
+    async fn check_recipe_references(&self, recipe: &Recipe) -> Result<(), Status> {
+        let ids = recipe
+            .instructions
+            .values()
+            .map(|instruction| instruction.id)
+            .collect();
+        self.ensure_exists(
+            InstructionSchema::Table,
+            InstructionSchema::InstructionId,
+            ids,
         )
-        .to_string(PostgresQueryBuilder);
-        let ordered_data = self.connection.select(data_query).await;
-        match ordered_data {
-            Ok(data) => {
-                let meta: Vec<_> = data
-                    .into_iter()
-                    .map(|row| InstructionToRecipeMetadata::try_from(row).unwrap())
-                    .collect();
-                let sorted: Vec<_> = meta
-                    .into_iter()
-                    .sorted_by_key(|row| row.instruction_order)
-                    .collect();
-                sorted
+        .await
+    }
+    // The first row matching `filter`, None when there is none
+    async fn select_one<T, I>(&self, table: I, filter: SimpleExpr) -> Result<Option<T>, Status>
+    where
+        T: GenQueries + TryFrom<DbRow, Error = anyhow::Error>,
+        I: Iden + 'static,
+    {
+        let query = T::gen_select_query_on_fields(table, vec![filter]);
+        let rows = self
+            .connection
+            .select(query)
+            .await
+            .map_err(|e| db_status(Code::Cancelled, "Failed to query the database", e))?;
+        rows.into_iter()
+            .next()
+            .map(T::try_from)
+            .transpose()
+            .map_err(|e| Status::internal(format!("Failed to read the row: {}", e)))
+    }
+    // Every row whose `column` is one of `ids`
+    async fn select_in<T, I, C>(&self, table: I, column: C, ids: &[i32]) -> Result<Vec<T>, Status>
+    where
+        T: GenQueries + TryFrom<DbRow, Error = anyhow::Error>,
+        I: Iden + 'static,
+        C: Iden + 'static,
+    {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let query =
+            T::gen_select_query_on_fields(table, vec![Expr::col(column).is_in(ids.to_vec())]);
+        let rows = self
+            .connection
+            .select(query)
+            .await
+            .map_err(|e| db_status(Code::Cancelled, "Failed to query the database", e))?;
+        rows.into_iter()
+            .map(T::try_from)
+            .collect::<Result<Vec<T>, _>>()
+            .map_err(|e| Status::internal(format!("Failed to read the rows: {}", e)))
+    }
+    // Fills in the instructions of the recipes by their position. Runs two
+    // queries however many recipes there are
+    async fn hydrate_recipes(&self, mut recipes: Vec<Recipe>) -> Result<Vec<Recipe>, Status> {
+        let recipe_ids = recipes
+            .iter()
+            .map(|recipe| recipe.id)
+            .unique()
+            .collect_vec();
+        let positions: Vec<InstructionToRecipeMetadata> = self
+            .select_in(
+                InstructionToRecipeSchema::Table,
+                InstructionToRecipeSchema::RecipeId,
+                &recipe_ids,
+            )
+            .await?;
+        let instruction_ids = positions
+            .iter()
+            .map(|position| position.instruction_id)
+            .unique()
+            .collect_vec();
+        let instructions: HashMap<i32, Instruction> = self
+            .select_in(
+                InstructionSchema::Table,
+                InstructionSchema::InstructionId,
+                &instruction_ids,
+            )
+            .await?
+            .into_iter()
+            .map(|instruction: Instruction| (instruction.id, instruction))
+            .collect();
+        let mut by_recipe: HashMap<i32, Vec<InstructionToRecipeMetadata>> = HashMap::new();
+        for position in positions {
+            by_recipe
+                .entry(position.recipe_id)
+                .or_default()
+                .push(position);
+        }
+        for recipe in recipes.iter_mut() {
+            for position in by_recipe.get(&recipe.id).into_iter().flatten() {
+                if let Some(instruction) = instructions.get(&position.instruction_id) {
+                    recipe
+                        .instructions
+                        .insert(position.instruction_order, instruction.clone());
+                }
             }
-            Err(err) => {
-                tracing::error!("{}", err.to_string());
-                Vec::new()
+        }
+        Ok(recipes)
+    }
+    // Rows only carry the id of the regulator, every one is replaced with the
+    // stored regulator in a single query
+    async fn attach_regulators(&self, ingredients: &mut [Ingredient]) -> Result<(), Status> {
+        let fr_ids = ingredients
+            .iter()
+            .filter_map(|ingredient| ingredient.regulator.as_ref()?.fr_id)
+            .unique()
+            .collect_vec();
+        let regulators: HashMap<i32, FluidRegulator> = self
+            .select_in(
+                FluidRegulationSchema::Table,
+                FluidRegulationSchema::FrId,
+                &fr_ids,
+            )
+            .await?
+            .into_iter()
+            .filter_map(|fr: FluidRegulator| Some((fr.fr_id?, fr)))
+            .collect();
+        for ingredient in ingredients.iter_mut() {
+            if let Some(fr_id) = ingredient.regulator.as_ref().and_then(|fr| fr.fr_id) {
+                ingredient.regulator = regulators.get(&fr_id).cloned();
+            }
+        }
+        Ok(())
+    }
+    // Rows only carry the ids of the regulator and instruction, both are
+    // replaced with what is stored. Runs two queries however many ingredients
+    // there are
+    async fn hydrate_ingredients(
+        &self,
+        mut ingredients: Vec<Ingredient>,
+    ) -> Result<Vec<Ingredient>, Status> {
+        self.attach_regulators(&mut ingredients).await?;
+        let instruction_ids = ingredients
+            .iter()
+            .filter_map(|ingredient| Some(ingredient.instruction.as_ref()?.id))
+            .unique()
+            .collect_vec();
+        let instructions: HashMap<i32, Instruction> = self
+            .select_in(
+                InstructionSchema::Table,
+                InstructionSchema::InstructionId,
+                &instruction_ids,
+            )
+            .await?
+            .into_iter()
+            .map(|instruction: Instruction| (instruction.id, instruction))
+            .collect();
+        for ingredient in ingredients.iter_mut() {
+            if let Some(instruction_id) = ingredient.instruction.as_ref().map(|i| i.id) {
+                ingredient.instruction = instructions.get(&instruction_id).cloned();
             }
         }
+        Ok(ingredients)
+    }
+    async fn commit(transaction: Box<dyn DbTransaction + '_>) -> Result<(), Status> {
+        transaction
+            .commit()
+            .await
+            .map_err(|e| db_status(Code::DataLoss, "Failed to commit to the database", e))
+    }
+    async fn update_order(&self, order: &DrinkOrder) -> Result<(), Status> {
+        let query = order.gen_update_query();
+        self.connection
+            .update(query)
+            .await
+            .map(|_| ())
+            .map_err(|e| db_status(Code::DataLoss, "Failed to update order", e))
+    }
+    fn halted_status(&self) -> Option<Status> {
+        self.dispenser.halt_reason().map(|reason| {
+            Status::failed_precondition(format!(
+                "Dispenser is halted ({}), resume it first",
+                reason
+            ))
+        })
+    }
+    // The pins of every configured regulator
+    async fn collect_regulator_pins(&self) -> Result<Vec<u32>, Status> {
+        let query =
+            FluidRegulator::gen_select_query_on_fields(FluidRegulationSchema::Table, Vec::new());
+        let rows = self
+            .connection
+            .select(query)
+            .await
+            .map_err(|e| db_status(Code::Cancelled, "Failed to query the database", e))?;
+        let regulators = rows
+            .into_iter()
+            .map(FluidRegulator::try_from)
+            .collect::<Result<Vec<FluidRegulator>, _>>()
+            .map_err(|e| Status::internal(format!("Failed to read regulators: {}", e)))?;
+        Ok(regulators
+            .iter()
+            .filter_map(|fr| fr.gpio_pin)
+            .filter_map(|pin| gpio::to_pin(pin).ok())
+            .unique()
+            .collect())
+    }
+    // Errors that come from the order itself rather than from reading it
+    fn is_order_failure(status: &Status) -> bool {
+        matches!(
+            status.code(),
+            Code::NotFound | Code::InvalidArgument | Code::FailedPrecondition
+        )
+    }
+    async fn build_pour_plan(&self, recipe_id: i32, size: DrinkSize) -> Result<PourPlan, Status> {
+        let recipe = self
+            .parse_and_collect_recipe(recipe_id)
+            .await?
+            .ok_or_else(|| Status::not_found(format!("Recipe {} does not exist", recipe_id)))?;
+        let instruction_ids = recipe
+            .instructions
+            .values()
+            .map(|instruction| instruction.id)
+            .unique()
+            .collect_vec();
+        let collected = self
+            .select_in(
+                IngredientSchema::Table,
+                IngredientSchema::InstructionId,
+                &instruction_ids,
+            )
+            .await?;
+        let collected = self.hydrate_ingredients(collected).await?;
+        let fr_ids = collected
+            .iter()
+            .filter_map(|ingredient| ingredient.regulator.as_ref()?.fr_id)
+            .unique()
+            .collect_vec();
+        let calibrations: HashMap<i32, FlowCalibration> = self
+            .select_in(
+                FlowCalibrationSchema::Table,
+                FlowCalibrationSchema::FrId,
+                &fr_ids,
+            )
+            .await?
+            .into_iter()
+            .map(|calibration: FlowCalibration| (calibration.fr_id, calibration))
+            .collect();
+        let mut ingredients: HashMap<i32, Vec<Ingredient>> = HashMap::new();
+        for ingredient in collected {
+            if let Some(instruction_id) = ingredient.instruction.as_ref().map(|i| i.id) {
+                ingredients
+                    .entry(instruction_id)
+                    .or_default()
+                    .push(ingredient);
+            }
+        }
+        let plan = PourPlan::build(&recipe, size, &ingredients, &calibrations)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        let fr_ids = plan.required_ml().into_keys().collect_vec();
+        let bottles = self
+            .collect_bottles(&fr_ids)
+            .await?
+            .into_iter()
+            .map(|bottle| (bottle.fr_id, bottle))
+            .collect();
+        inventory::check_stock(&plan, &bottles)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+        tracing::debug!("Built pour plan {:?}", plan);
+        Ok(plan)
+    }
+    async fn parse_and_collect_recipe(&self, recipe_id: i32) -> Result<Option<Recipe>, Status> {
+        let recipe = self
+            .select_one(
+                RecipeSchema::Table,
+                Expr::col(RecipeSchema::RecipeId).eq(recipe_id),
+            )
+            .await?;
+        Ok(self
+            .hydrate_recipes(recipe.into_iter().collect())
+            .await?
+            .pop())
+    }
+    async fn parse_and_collect_fluid_regulator(
+        &self,
+        fr_id: i32,
+    ) -> Result<Option<FluidRegulator>, Status> {
+        self.select_one(
+            FluidRegulationSchema::Table,
+            Expr::col(FluidRegulationSchema::FrId).eq(fr_id),
+        )
+        .await
     }
     // Built this but do not need it anymore, but might be useful later
     #[allow(dead_code)]
     async fn parse_and_collect_instructions_to_recipe_by_id(
         &self,
         ids: Vec<i32>,
-    ) -> Vec<InstructionToRecipeMetadata> {
+    ) -> Result<Vec<InstructionToRecipeMetadata>, Status> {
         // Collection instruction
         let fetch_data = vec![FetchData {
             column: "id".to_string(),
             operation: Operation::In.into(),
-            values: format!("{:?}", ids),
+            values: ids.iter().map(|id| id.to_string()).collect(),
         }
         .to_simple_expr(InstructionToRecipeSchema::Id)
-        .unwrap()];
+        .map_err(|e| Status::invalid_argument(e.to_string()))?];
         let data_query = InstructionToRecipeMetadata::gen_select_query_on_fields(
             InstructionToRecipeSchema::Table,
             fetch_data,
-        )
-        .to_string(PostgresQueryBuilder);
+        );
         let ordered_data = self.connection.select(data_query).await;
         match ordered_data {
             Ok(data) => {
                 let meta: Vec<_> = data
                     .into_iter()
-                    .map(|row| InstructionToRecipeMetadata::try_from(row).unwrap())
-                    .collect();
+                    .map(InstructionToRecipeMetadata::try_from)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| Status::internal(format!("Failed to read positions: {}", e)))?;
                 let sorted: Vec<_> = meta
                     .into_iter()
                     .sorted_by_key(|row| row.instruction_order)
                     .collect();
-                sorted
+                Ok(sorted)
             }
             Err(err) => {
                 tracing::error!("{}", err.to_string());
-                Vec::new()
+                Err(db_status(
+                    Code::Cancelled,
+                    "Failed to query the database",
+                    err,
+                ))
             }
         }
     }
@@ -799,8 +1915,988 @@ impl DaemonServerContext {
 pub async fn start_server(
     service: UdmServiceServer<DaemonServerContext>,
     addr: SocketAddr,
+    health: Arc<MonitoredConnection>,
 ) -> UdmResult<()> {
     tracing::info!("Running Udm Service on {:?}", &addr);
-    let _ = Server::builder().add_service(service).serve(addr).await;
+    let _ = Server::builder()
+        .layer(RequireDatabaseLayer { health })
+        .add_service(service)
+        .serve(addr)
+        .await;
     Ok(())
 }
+
+// Rpcs that keep working while the database is down, so the dispenser can
+// still be stopped and its state read
+const DATABASE_FREE_METHODS: [&str; 5] = [
+    "/server.UdmService/GetHealth",
+    "/server.UdmService/GetDaemonStatus",
+    "/server.UdmService/EmergencyStop",
+    "/server.UdmService/Resume",
+    "/server.UdmService/WatchPour",
+];
+
+// Turns every other rpc away with UNAVAILABLE while the database is down
+#[derive(Clone)]
+struct RequireDatabaseLayer {
+    health: Arc<MonitoredConnection>,
+}
+
+impl<S> Layer<S> for RequireDatabaseLayer {
+    type Service = RequireDatabase<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireDatabase {
+            inner,
+            health: Arc::clone(&self.health),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct RequireDatabase<S> {
+    inner: S,
+    health: Arc<MonitoredConnection>,
+}
+
+impl<S, B> Service<http::Request<B>> for RequireDatabase<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let path = request.uri().path();
+        if !self.health.is_available() && !DATABASE_FREE_METHODS.contains(&path) {
+            tracing::warn!("Rejecting {} while the database is down", path);
+            let status = Status::unavailable(format!(
+                "Database is unavailable: {}",
+                self.health.health().message
+            ));
+            return Box::pin(future::ready(Ok(status.to_http())));
+        }
+        Box::pin(self.inner.call(request))
+    }
+}
+
+// Every handler maps its database errors through here. A lost database is
+// UNAVAILABLE so clients retry later instead of giving up on the request
+fn db_status(code: Code, context: &str, error: UdmError) -> Status {
+    let message = format!("{}: {}", context, error);
+    match error {
+        UdmError::DatabaseUnavailable(_) => Status::unavailable(message),
+        _ => Status::new(code, message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations::Migrator;
+    use crate::db::sqlite::conn::OpenSqliteConnection;
+    use crate::db::DatabaseTransactionsFactory;
+    use crate::db::DbType;
+    use crate::db::SchemaStatement;
+    use crate::gpio::mock::MockGpioDriver;
+    use crate::gpio::mock::PinState;
+    use crate::gpio::GpioDriver;
+    use crate::parsers::settings::SqliteConfigurer;
+    use crate::rpc_types::recipe_types::IngredientType;
+    use crate::rpc_types::service_types::MissingReason;
+    use async_trait::async_trait;
+    use sea_query::DeleteStatement;
+    use sea_query::InsertStatement;
+    use sea_query::Table;
+    use sea_query::TableStatement;
+    use sea_query::UpdateStatement;
+    use std::net::IpAddr;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    // Counts the selects run outside of transactions
+    struct CountingConnection {
+        connection: OpenSqliteConnection,
+        selects: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl DbConnection for CountingConnection {
+        async fn insert(&self, stmt: InsertStatement) -> UdmResult<i32> {
+            self.connection.insert(stmt).await
+        }
+        async fn delete(&self, stmt: DeleteStatement) -> UdmResult<()> {
+            self.connection.delete(stmt).await
+        }
+        async fn update(&self, stmt: UpdateStatement) -> UdmResult<i32> {
+            self.connection.update(stmt).await
+        }
+        async fn select(&self, stmt: SelectStatement) -> UdmResult<Vec<DbRow>> {
+            self.selects.fetch_add(1, Ordering::SeqCst);
+            self.connection.select(stmt).await
+        }
+        async fn begin<'a>(&'a self) -> UdmResult<Box<dyn DbTransaction + 'a>> {
+            self.connection.begin().await
+        }
+        async fn ping(&self) -> UdmResult<()> {
+            self.connection.ping().await
+        }
+    }
+
+    #[async_trait]
+    impl DatabaseTransactionsFactory for CountingConnection {
+        async fn collect_all_current_tables(&mut self) -> UdmResult<Vec<String>> {
+            self.connection.collect_all_current_tables().await
+        }
+        async fn execute_schema(&self, statements: Vec<SchemaStatement>) -> UdmResult<()> {
+            self.connection.execute_schema(statements).await
+        }
+        async fn truncate_schema(&self) -> UdmResult<()> {
+            self.connection.truncate_schema().await
+        }
+    }
+
+    async fn server() -> DaemonServerContext {
+        server_with_driver(Arc::new(MockGpioDriver::new())).await
+    }
+    async fn server_with_driver(driver: Arc<MockGpioDriver>) -> DaemonServerContext {
+        let settings = SqliteConfigurer {
+            db_path: ":memory:".to_string(),
+        };
+        server_on(OpenSqliteConnection::new(settings).await, driver).await
+    }
+    async fn server_on(
+        conn: OpenSqliteConnection,
+        driver: Arc<MockGpioDriver>,
+    ) -> DaemonServerContext {
+        Migrator::new(&conn).up(None).await.unwrap();
+        let settings = conn.settings.clone();
+        DaemonServerContext::new(
+            Arc::new(MonitoredConnection::new(Box::new(conn))),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+            DbMetaData::new(Arc::new(DbType::Sqlite(settings))),
+            Arc::new(Dispenser::new(driver)),
+        )
+    }
+    async fn counting_server() -> (DaemonServerContext, Arc<AtomicUsize>) {
+        let settings = SqliteConfigurer {
+            db_path: ":memory:".to_string(),
+        };
+        let connection = OpenSqliteConnection::new(settings.clone()).await;
+        Migrator::new(&connection).up(None).await.unwrap();
+        let selects = Arc::new(AtomicUsize::new(0));
+        let conn = CountingConnection {
+            connection,
+            selects: selects.clone(),
+        };
+        let server = DaemonServerContext::new(
+            Arc::new(MonitoredConnection::new(Box::new(conn))),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+            DbMetaData::new(Arc::new(DbType::Sqlite(settings))),
+            Arc::new(Dispenser::new(Arc::new(MockGpioDriver::new()))),
+        );
+        (server, selects)
+    }
+    fn lime(fr_id: Option<i32>) -> Ingredient {
+        Ingredient {
+            name: "Lime juice".to_string(),
+            amount: 30.0,
+            ingredient_type: IngredientType::Fluid.into(),
+            regulator: fr_id.map(|fr_id| FluidRegulator {
+                fr_id: Some(fr_id),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_add_ingredient_checks_references() {
+        let server = server().await;
+        let add = |ingredient: Ingredient| {
+            Request::new(AddIngredientRequest {
+                ingredient: Some(ingredient),
+            })
+        };
+        let status = server.add_ingredient(add(lime(Some(7)))).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "FluidRegulation 7 does not exist");
+
+        let status = server
+            .add_ingredient(add(Ingredient {
+                amount: -1.0,
+                ..lime(None)
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let fr_id = server
+            .add_fluid_regulator(Request::new(AddFluidRegulatorRequest {
+                fluid: Some(FluidRegulator {
+                    fr_id: None,
+                    gpio_pin: Some(4),
+                    regulator_type: Some(1),
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .fr_id;
+        assert!(server.add_ingredient(add(lime(Some(fr_id)))).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_add_recipe_checks_instructions() {
+        let server = server().await;
+        let instruction_id = server
+            .add_instruction(Request::new(AddInstructionRequest {
+                instruction: Some(Instruction {
+                    instruction_name: "Shake".to_string(),
+                    instruction_detail: "Shake with ice".to_string(),
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .instruction_id;
+        let recipe = |ids: &[i32]| Recipe {
+            name: "Gimlet".to_string(),
+            size: DrinkSize::Small.into(),
+            instructions: ids
+                .iter()
+                .zip(1..)
+                .map(|(id, position)| {
+                    (
+                        position,
+                        Instruction {
+                            id: *id,
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let status = server
+            .add_recipe(Request::new(AddRecipeRequest {
+                recipe: Some(recipe(&[instruction_id, instruction_id + 1])),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            format!("Instruction {} does not exist", instruction_id + 1)
+        );
+        assert!(server
+            .add_recipe(Request::new(AddRecipeRequest {
+                recipe: Some(recipe(&[instruction_id])),
+            }))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_get_missing_ids_are_not_found() {
+        let server = server().await;
+        let status = server
+            .get_recipe(Request::new(GetRecipeRequest { recipe_id: 3 }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        assert_eq!(status.message(), "Recipe 3 does not exist");
+        let status = server
+            .get_ingredient(Request::new(GetIngredientRequest { ingredient_id: 3 }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let status = server
+            .get_instruction(Request::new(GetInstructionRequest { instruction_id: 3 }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let status = server
+            .get_fluid_regulator(Request::new(GetFluidRegulatorRequest { fr_id: 3 }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_get_ingredient_is_hydrated() {
+        let server = server().await;
+        let regulator = FluidRegulator {
+            fr_id: None,
+            gpio_pin: Some(4),
+            regulator_type: Some(1),
+        };
+        let fr_id = server
+            .add_fluid_regulator(Request::new(AddFluidRegulatorRequest {
+                fluid: Some(regulator.clone()),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .fr_id;
+        let instruction_id = server
+            .add_instruction(Request::new(AddInstructionRequest {
+                instruction: Some(Instruction {
+                    instruction_name: "Shake".to_string(),
+                    instruction_detail: "Shake with ice".to_string(),
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .instruction_id;
+        let ingredient_id = server
+            .add_ingredient(Request::new(AddIngredientRequest {
+                ingredient: Some(Ingredient {
+                    instruction: Some(Instruction {
+                        id: instruction_id,
+                        ..Default::default()
+                    }),
+                    ..lime(Some(fr_id))
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .ingredient_id;
+
+        let ingredient = server
+            .get_ingredient(Request::new(GetIngredientRequest { ingredient_id }))
+            .await
+            .unwrap()
+            .into_inner()
+            .ingredient
+            .unwrap();
+        assert_eq!(ingredient.name, "Lime juice");
+        assert_eq!(
+            ingredient.regulator,
+            Some(FluidRegulator {
+                fr_id: Some(fr_id),
+                ..regulator
+            })
+        );
+        let instruction = ingredient.instruction.unwrap();
+        assert_eq!(instruction.id, instruction_id);
+        assert_eq!(instruction.instruction_name, "Shake");
+        assert_eq!(instruction.instruction_detail, "Shake with ice");
+    }
+
+    #[tokio::test]
+    async fn test_collect_recipe_hydrates_every_recipe() {
+        let server = server().await;
+        let mut ids = Vec::new();
+        for name in ["Shake", "Strain", "Garnish"] {
+            let id = server
+                .add_instruction(Request::new(AddInstructionRequest {
+                    instruction: Some(Instruction {
+                        instruction_name: name.to_string(),
+                        ..Default::default()
+                    }),
+                }))
+                .await
+                .unwrap()
+                .into_inner()
+                .instruction_id;
+            ids.push(id);
+        }
+        let recipe = |name: &str, ids: &[i32]| Recipe {
+            name: name.to_string(),
+            description: format!("A {}", name),
+            size: DrinkSize::Small.into(),
+            instructions: ids
+                .iter()
+                .zip(1..)
+                .map(|(id, position)| {
+                    (
+                        position,
+                        Instruction {
+                            id: *id,
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        };
+        for recipe in [
+            recipe("Gimlet", &[ids[0], ids[1]]),
+            recipe("Daiquiri", &[ids[0], ids[1], ids[2]]),
+            recipe("Neat", &[]),
+        ] {
+            server
+                .add_recipe(Request::new(AddRecipeRequest {
+                    recipe: Some(recipe),
+                }))
+                .await
+                .unwrap();
+        }
+
+        let recipes = server
+            .collect_recipe(Request::new(CollectRecipeRequest::default()))
+            .await
+            .unwrap()
+            .into_inner()
+            .recipes;
+        let steps = |recipe: &Recipe| {
+            recipe
+                .instructions
+                .iter()
+                .sorted_by_key(|(position, _)| **position)
+                .map(|(_, instruction)| instruction.instruction_name.clone())
+                .collect_vec()
+        };
+        assert_eq!(recipes.len(), 3);
+        assert_eq!(steps(&recipes[0]), ["Shake", "Strain"]);
+        assert_eq!(steps(&recipes[1]), ["Shake", "Strain", "Garnish"]);
+        assert!(recipes[2].instructions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_make_drink_fails_when_ingredients_can_not_be_read() {
+        let server = server().await;
+        let instruction_id = server
+            .add_instruction(Request::new(AddInstructionRequest {
+                instruction: Some(Instruction {
+                    instruction_name: "Shake".to_string(),
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .instruction_id;
+        let recipe_id = server
+            .add_recipe(Request::new(AddRecipeRequest {
+                recipe: Some(Recipe {
+                    name: "Gimlet".to_string(),
+                    size: DrinkSize::Small.into(),
+                    instructions: HashMap::from([(
+                        1,
+                        Instruction {
+                            id: instruction_id,
+                            ..Default::default()
+                        },
+                    )]),
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .recipe_id;
+        let drop = Table::drop().table(IngredientSchema::Table).to_owned();
+        server
+            .health
+            .execute_schema(vec![SchemaStatement::Table(Box::new(
+                TableStatement::Drop(drop),
+            ))])
+            .await
+            .unwrap();
+
+        let status = server
+            .make_drink(Request::new(MakeDrinkRequest {
+                recipe_id,
+                size: DrinkSize::Small.into(),
+            }))
+            .await
+            .unwrap_err();
+        assert!(!DaemonServerContext::is_order_failure(&status));
+    }
+
+    #[tokio::test]
+    async fn test_calibrate_regulator_bounds_the_test_pour() {
+        let server = server().await;
+        let fr_id = server
+            .add_fluid_regulator(Request::new(AddFluidRegulatorRequest {
+                fluid: Some(FluidRegulator {
+                    fr_id: None,
+                    gpio_pin: Some(4),
+                    regulator_type: Some(1),
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .fr_id;
+        for duration_ms in [0, -5, MAX_CALIBRATION_MS + 1] {
+            let status = server
+                .calibrate_regulator(Request::new(CalibrateRegulatorRequest {
+                    fr_id,
+                    duration_ms,
+                    ..Default::default()
+                }))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        }
+        let status = server
+            .calibrate_regulator(Request::new(CalibrateRegulatorRequest {
+                fr_id,
+                duration_ms: 1000,
+                measured_ml: Some(f32::NAN),
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_emergency_stop_without_regulators() {
+        let driver = Arc::new(MockGpioDriver::new());
+        let server = server_with_driver(driver.clone()).await;
+        driver.open(5).unwrap();
+        let drop = Table::drop().table(FluidRegulationSchema::Table).to_owned();
+        server
+            .health
+            .execute_schema(vec![SchemaStatement::Table(Box::new(
+                TableStatement::Drop(drop),
+            ))])
+            .await
+            .unwrap();
+
+        let stopped = server
+            .emergency_stop(Request::new(EmergencyStopRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(server.dispenser.is_halted());
+        assert_eq!(stopped.closed_pins, vec![5]);
+        assert!(!stopped.regulator_error.is_empty());
+        assert_eq!(driver.state(5), PinState::Closed);
+    }
+
+    #[test]
+    fn test_lost_database_is_unavailable() {
+        let lost = UdmError::DatabaseUnavailable("closed".to_string());
+        let status = db_status(Code::DataLoss, "Failed to insert into database", lost);
+        assert_eq!(status.code(), Code::Unavailable);
+        let failed = UdmError::ApiFailure("syntax error".to_string());
+        let status = db_status(Code::DataLoss, "Failed to insert into database", failed);
+        assert_eq!(status.code(), Code::DataLoss);
+        assert!(status
+            .message()
+            .starts_with("Failed to insert into database: "));
+    }
+
+    #[tokio::test]
+    async fn test_hydration_runs_a_fixed_number_of_queries() {
+        for count in [1, 20] {
+            let (server, selects) = counting_server().await;
+            let mut instructions = HashMap::new();
+            for position in 1..=2 {
+                let id = server
+                    .add_instruction(Request::new(AddInstructionRequest {
+                        instruction: Some(Instruction {
+                            instruction_name: format!("Step {}", position),
+                            ..Default::default()
+                        }),
+                    }))
+                    .await
+                    .unwrap()
+                    .into_inner()
+                    .instruction_id;
+                instructions.insert(
+                    position,
+                    Instruction {
+                        id,
+                        ..Default::default()
+                    },
+                );
+            }
+            for recipe in 0..count {
+                server
+                    .add_recipe(Request::new(AddRecipeRequest {
+                        recipe: Some(Recipe {
+                            name: format!("Recipe {}", recipe),
+                            description: format!("Recipe number {}", recipe),
+                            size: DrinkSize::Small.into(),
+                            instructions: instructions.clone(),
+                            ..Default::default()
+                        }),
+                    }))
+                    .await
+                    .unwrap();
+            }
+
+            selects.store(0, Ordering::SeqCst);
+            let recipes = server
+                .collect_recipe(Request::new(CollectRecipeRequest::default()))
+                .await
+                .unwrap()
+                .into_inner()
+                .recipes;
+            assert_eq!(recipes.len(), count);
+            assert!(recipes.iter().all(|recipe| recipe.instructions.len() == 2));
+            // One for the page of recipes, two to hydrate all of them
+            assert_eq!(selects.load(Ordering::SeqCst), 3);
+
+            let bare = recipes
+                .iter()
+                .map(|recipe| Recipe {
+                    instructions: HashMap::new(),
+                    ..recipe.clone()
+                })
+                .collect_vec();
+            selects.store(0, Ordering::SeqCst);
+            assert_eq!(server.hydrate_recipes(bare).await.unwrap(), recipes);
+            assert_eq!(selects.load(Ordering::SeqCst), 2);
+
+            let fr_id = server
+                .add_fluid_regulator(Request::new(AddFluidRegulatorRequest {
+                    fluid: Some(FluidRegulator {
+                        fr_id: None,
+                        gpio_pin: Some(4),
+                        regulator_type: Some(1),
+                    }),
+                }))
+                .await
+                .unwrap()
+                .into_inner()
+                .fr_id;
+            server
+                .calibrate_regulator(Request::new(CalibrateRegulatorRequest {
+                    fr_id,
+                    duration_ms: 1000,
+                    measured_ml: Some(20.0),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+            for ingredient in 0..count {
+                server
+                    .add_ingredient(Request::new(AddIngredientRequest {
+                        ingredient: Some(Ingredient {
+                            name: format!("Ingredient {}", ingredient),
+                            amount: 1.0,
+                            is_active: true,
+                            instruction: Some(instructions[&1].clone()),
+                            ..lime(Some(fr_id))
+                        }),
+                    }))
+                    .await
+                    .unwrap();
+            }
+
+            selects.store(0, Ordering::SeqCst);
+            let ingredients = server
+                .collect_ingredients(Request::new(CollectIngredientRequest::default()))
+                .await
+                .unwrap()
+                .into_inner()
+                .ingredients;
+            assert_eq!(ingredients.len(), count);
+            assert!(ingredients.iter().all(|ingredient| {
+                ingredient.regulator.as_ref().unwrap().gpio_pin == Some(4)
+                    && ingredient.instruction.as_ref().unwrap().instruction_name == "Step 1"
+            }));
+            // One for the page, one for the regulators and one for the instructions
+            assert_eq!(selects.load(Ordering::SeqCst), 3);
+
+            selects.store(0, Ordering::SeqCst);
+            let plan = server
+                .build_pour_plan(recipes[0].id, DrinkSize::Small)
+                .await
+                .unwrap();
+            assert_eq!(
+                plan.steps
+                    .iter()
+                    .map(|step| step.dispenses.len())
+                    .sum::<usize>(),
+                count
+            );
+            // The recipe and its instructions, the ingredients with their
+            // regulators and instructions, then calibrations and bottles
+            assert_eq!(selects.load(Ordering::SeqCst), 8);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collect_available_recipes_reads_stored_regulators() {
+        let server = server().await;
+        let fr_id = server
+            .add_fluid_regulator(Request::new(AddFluidRegulatorRequest {
+                fluid: Some(FluidRegulator {
+                    fr_id: None,
+                    gpio_pin: Some(4),
+                    regulator_type: Some(1),
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .fr_id;
+        let instruction = Instruction {
+            id: server
+                .add_instruction(Request::new(AddInstructionRequest {
+                    instruction: Some(Instruction {
+                        instruction_name: "Pour".to_string(),
+                        ..Default::default()
+                    }),
+                }))
+                .await
+                .unwrap()
+                .into_inner()
+                .instruction_id,
+            ..Default::default()
+        };
+        let ingredient_id = server
+            .add_ingredient(Request::new(AddIngredientRequest {
+                ingredient: Some(Ingredient {
+                    is_active: true,
+                    instruction: Some(instruction.clone()),
+                    ..lime(Some(fr_id))
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .ingredient_id;
+        server
+            .add_recipe(Request::new(AddRecipeRequest {
+                recipe: Some(Recipe {
+                    name: "Gimlet".to_string(),
+                    description: "Lime and gin".to_string(),
+                    size: DrinkSize::Small.into(),
+                    instructions: HashMap::from([(1, instruction)]),
+                    ..Default::default()
+                }),
+            }))
+            .await
+            .unwrap();
+        server
+            .calibrate_regulator(Request::new(CalibrateRegulatorRequest {
+                fr_id,
+                duration_ms: 1000,
+                measured_ml: Some(20.0),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        let set_bottle = |ingredient_id: i32| {
+            Request::new(SetBottleLevelRequest {
+                fr_id,
+                remaining_ml: Some(500.0),
+                capacity_ml: Some(750.0),
+                ingredient_id: Some(ingredient_id),
+            })
+        };
+        server
+            .set_bottle_level(set_bottle(ingredient_id))
+            .await
+            .unwrap();
+        let collect = || {
+            Request::new(CollectAvailableRecipesRequest {
+                size: DrinkSize::Small.into(),
+            })
+        };
+
+        let response = server
+            .collect_available_recipes(collect())
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.unavailable.is_empty());
+        assert_eq!(response.recipes.len(), 1);
+        assert_eq!(response.recipes[0].name, "Gimlet");
+
+        let other_id = server
+            .add_ingredient(Request::new(AddIngredientRequest {
+                ingredient: Some(Ingredient {
+                    name: "Gin".to_string(),
+                    ..lime(None)
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .ingredient_id;
+        server.set_bottle_level(set_bottle(other_id)).await.unwrap();
+        let response = server
+            .collect_available_recipes(collect())
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.recipes.is_empty());
+        let missing = &response.unavailable[0].missing;
+        assert_eq!(missing[0].ingredient_id, ingredient_id);
+        assert_eq!(
+            missing[0].reasons,
+            vec![i32::from(MissingReason::WrongBottle)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_ingredient_is_all_or_nothing() {
+        let conn = OpenSqliteConnection::new(SqliteConfigurer {
+            db_path: ":memory:".to_string(),
+        })
+        .await;
+        let raw = conn.connection.clone();
+        let server = server_on(conn, Arc::new(MockGpioDriver::new())).await;
+        let fr_id = server
+            .add_fluid_regulator(Request::new(AddFluidRegulatorRequest {
+                fluid: Some(FluidRegulator {
+                    fr_id: None,
+                    gpio_pin: Some(4),
+                    regulator_type: Some(1),
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .fr_id;
+        let instruction = Instruction {
+            id: server
+                .add_instruction(Request::new(AddInstructionRequest {
+                    instruction: Some(Instruction {
+                        instruction_name: "Pour".to_string(),
+                        ..Default::default()
+                    }),
+                }))
+                .await
+                .unwrap()
+                .into_inner()
+                .instruction_id,
+            instruction_name: "Pour".to_string(),
+            ..Default::default()
+        };
+        let ingredient = Ingredient {
+            instruction: Some(instruction.clone()),
+            ..lime(Some(fr_id))
+        };
+        let ingredient_id = server
+            .add_ingredient(Request::new(AddIngredientRequest {
+                ingredient: Some(ingredient.clone()),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .ingredient_id;
+        // Any update of an instruction fails from here on
+        raw.call_unwrap(|conn| {
+            conn.execute_batch(
+                "CREATE TRIGGER lock_instruction BEFORE UPDATE ON Instruction \
+                 BEGIN SELECT RAISE(ABORT, 'instruction is locked'); END",
+            )
+        })
+        .await
+        .unwrap();
+
+        let changed = Ingredient {
+            id: ingredient_id,
+            name: "Lemon juice".to_string(),
+            regulator: Some(FluidRegulator {
+                fr_id: Some(fr_id),
+                gpio_pin: Some(5),
+                regulator_type: Some(1),
+            }),
+            instruction: Some(Instruction {
+                instruction_name: "Squeeze".to_string(),
+                ..instruction
+            }),
+            ..ingredient
+        };
+        let status = server
+            .update_ingredient(Request::new(ModifyIngredientRequest {
+                ingredient: Some(changed),
+                update_fr: true,
+                update_instruction: true,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::DataLoss);
+
+        let stored = server
+            .get_ingredient(Request::new(GetIngredientRequest { ingredient_id }))
+            .await
+            .unwrap()
+            .into_inner()
+            .ingredient
+            .unwrap();
+        assert_eq!(stored.name, "Lime juice");
+        assert_eq!(stored.regulator.unwrap().gpio_pin, Some(4));
+        assert_eq!(stored.instruction.unwrap().instruction_name, "Pour");
+    }
+
+    #[tokio::test]
+    async fn test_set_bottle_level_validates_the_bottle() {
+        let server = server().await;
+        let fr_id = server
+            .add_fluid_regulator(Request::new(AddFluidRegulatorRequest {
+                fluid: Some(FluidRegulator {
+                    fr_id: None,
+                    gpio_pin: Some(4),
+                    regulator_type: Some(1),
+                }),
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .fr_id;
+        let set_bottle = |capacity_ml: f32, ingredient_id: Option<i32>| {
+            Request::new(SetBottleLevelRequest {
+                fr_id,
+                remaining_ml: None,
+                capacity_ml: Some(capacity_ml),
+                ingredient_id,
+            })
+        };
+        let status = server
+            .set_bottle_level(set_bottle(f32::NAN, None))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let status = server
+            .set_bottle_level(set_bottle(750.0, Some(7)))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "Ingredient 7 does not exist");
+        assert!(server.collect_bottles(&[fr_id]).await.unwrap().is_empty());
+        server
+            .set_bottle_level(set_bottle(750.0, None))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_collect_reports_rows_it_can_not_read() {
+        let conn = OpenSqliteConnection::new(SqliteConfigurer {
+            db_path: ":memory:".to_string(),
+        })
+        .await;
+        let raw = conn.connection.clone();
+        let server = server_on(conn, Arc::new(MockGpioDriver::new())).await;
+        // Sqlite stores whatever it is given
+        raw.call_unwrap(|conn| {
+            conn.execute_batch(
+                "INSERT INTO FluidRegulation (regulator_type, gpio_pin) VALUES (1, 'four')",
+            )
+        })
+        .await
+        .unwrap();
+
+        let status = server
+            .collect_fluid_regulators(Request::new(CollectFluidRegulatorsRequest::default()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Internal);
+        assert!(status.message().starts_with("Failed to read regulators: "));
+    }
+}
@@ -1,14 +1,28 @@
+use crate::db::executor::Page;
+use crate::db::BottleSchema;
+use crate::db::ColumnType;
+use crate::db::DrinkOrderSchema;
+use crate::db::FlowCalibrationSchema;
 use crate::db::FluidRegulationSchema;
 use crate::db::IngredientSchema;
 use crate::db::InstructionSchema;
 use crate::db::InstructionToRecipeSchema;
 use crate::db::RecipeSchema;
+use crate::db::SqlTransactionsFactory;
 use crate::error::UdmError;
+use crate::rpc_types::MultipleValues;
 use crate::UdmResult;
 use anyhow::Error as AnyError;
-use regex::Regex;
+use sea_query::Condition;
+use sea_query::DeleteStatement;
 use sea_query::Expr;
+use sea_query::Iden;
+use sea_query::IntoIden;
+use sea_query::Order;
+use sea_query::Query;
 use sea_query::SimpleExpr;
+use sea_query::Value;
+use std::fmt::Display;
 use tonic::Response;
 use tracing::debug;
 tonic::include_proto!("service_types");
@@ -24,12 +38,16 @@ pub trait ServiceResponse: Clone {
 }
 
 pub trait CollectExpressions {
-    fn get_expressions(&self) -> UdmResult<Vec<SimpleExpr>>;
+    fn get_condition(&self) -> UdmResult<Condition>;
+}
+pub trait CollectPage {
+    fn get_page(&self) -> UdmResult<Page>;
 }
 impl ServiceRequest for AddFluidRegulatorRequest {}
 impl ServiceRequest for ModifyFluidRegulatorRequest {}
 impl ServiceRequest for RemoveFluidRegulatorRequest {}
 impl ServiceRequest for CollectFluidRegulatorsRequest {}
+impl ServiceRequest for GetFluidRegulatorRequest {}
 impl ServiceRequest for AddRecipeRequest {}
 impl ServiceRequest for GetRecipeRequest {}
 impl ServiceRequest for ModifyRecipeRequest {}
@@ -45,14 +63,29 @@ impl ServiceRequest for ModifyIngredientRequest {}
 impl ServiceRequest for ResetRequest {}
 impl ServiceRequest for CollectInstructionRequest {}
 impl ServiceRequest for CollectRecipeRequest {}
+impl ServiceRequest for CollectAvailableRecipesRequest {}
 impl ServiceRequest for UpdateRecipeInstOrderRequest {}
 impl ServiceRequest for AddRecipeInstOrderRequest {}
 impl ServiceRequest for CollectRecipeInstOrderRequest {}
 impl ServiceRequest for RemoveRecipeInstOrderRequest {}
+impl ServiceRequest for MakeDrinkRequest {}
+impl ServiceRequest for CalibrateRegulatorRequest {}
+impl ServiceRequest for EmergencyStopRequest {}
+impl ServiceRequest for ResumeRequest {}
+impl ServiceRequest for DaemonStatusRequest {}
+impl ServiceRequest for GetHealthRequest {}
+impl ServiceRequest for WatchPourRequest {}
+impl ServiceRequest for EnqueueDrinkRequest {}
+impl ServiceRequest for ListQueueRequest {}
+impl ServiceRequest for CancelOrderRequest {}
+impl ServiceRequest for ReorderQueueRequest {}
+impl ServiceRequest for SetBottleLevelRequest {}
+impl ServiceRequest for GetInventoryRequest {}
 
 impl ServiceResponse for AddFluidRegulatorResponse {}
 impl ServiceResponse for ModifyFluidRegulatorResponse {}
 impl ServiceResponse for CollectFluidRegulatorsResponse {}
+impl ServiceResponse for GetFluidRegulatorResponse {}
 impl ServiceResponse for AddRecipeResponse {}
 impl ServiceResponse for GetRecipeResponse {}
 impl ServiceResponse for ModifyRecipeResponse {}
@@ -67,116 +100,333 @@ impl ServiceResponse for ResetResponse {}
 impl ServiceResponse for GenericRemovalResponse {}
 impl ServiceResponse for CollectIngredientResponse {}
 impl ServiceResponse for CollectRecipeResponse {}
+impl ServiceResponse for CollectAvailableRecipesResponse {}
 impl ServiceResponse for AddRecipeInstOrderResponse {}
 impl ServiceResponse for CollectRecipeInstOrderResponse {}
 impl ServiceResponse for GenericEmpty {}
+impl ServiceResponse for MakeDrinkResponse {}
+impl ServiceResponse for CalibrateRegulatorResponse {}
+impl ServiceResponse for EmergencyStopResponse {}
+impl ServiceResponse for DaemonStatusResponse {}
+impl ServiceResponse for GetHealthResponse {}
+impl ServiceResponse for EnqueueDrinkResponse {}
+impl ServiceResponse for ListQueueResponse {}
+impl ServiceResponse for SetBottleLevelResponse {}
+impl ServiceResponse for GetInventoryResponse {}
 
-impl FetchData {
-    pub fn to_fetch_data_vec(user_input: &str) -> UdmResult<Vec<FetchData>> {
-        let capture_regex: &str = r"(?P<field>[a-z_\s]+)(?P<operation>=|!=|in|!in|<|<=|>=|>|like|!like|is|!is)(?P<value>[a-zA-Z_\d\\s]+)(?:,|$)";
-        let reg = Regex::new(capture_regex)?;
-        let mut fetch_vec = Vec::new();
-        for captures in reg.captures_iter(user_input) {
-            fetch_vec.push(FetchData {
-                column: captures["field"].to_owned(),
-                operation: Operation::to_operation(&captures["operation"])
-                    .unwrap()
-                    .into(),
-                values: captures["value"]
-                    .split(',')
-                    .map(|val| val.to_string())
-                    .collect(),
-            })
+impl MultipleValues for EntityType {
+    fn get_possible_values() -> Vec<&'static str> {
+        [
+            EntityType::Recipe.as_str_name(),
+            EntityType::Fluid.as_str_name(),
+            EntityType::Instruction.as_str_name(),
+            EntityType::Ingredient.as_str_name(),
+        ]
+        .to_vec()
+    }
+}
+impl Display for EntityType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl EntityType {
+    /// Deletes every row of the entity along with the rows that only make
+    /// sense next to it, dependents go first. Columns that merely point at the
+    /// entity are nulled by their foreign keys. None for Unspecified
+    pub fn reset_statements(&self) -> Option<Vec<DeleteStatement>> {
+        match self {
+            EntityType::Unspecified => None,
+            EntityType::Recipe => Some(vec![
+                Self::delete_all(DrinkOrderSchema::Table),
+                Self::delete_all(InstructionToRecipeSchema::Table),
+                Self::delete_all(RecipeSchema::Table),
+            ]),
+            EntityType::Fluid => Some(vec![
+                Self::delete_all(FlowCalibrationSchema::Table),
+                Self::delete_all(BottleSchema::Table),
+                Self::delete_all(FluidRegulationSchema::Table),
+            ]),
+            EntityType::Instruction => Some(vec![
+                Self::delete_all(InstructionToRecipeSchema::Table),
+                Self::delete_all(InstructionSchema::Table),
+            ]),
+            EntityType::Ingredient => Some(vec![Self::delete_all(IngredientSchema::Table)]),
         }
-        debug!("User input data: {:?}", &fetch_vec);
-        Ok(fetch_vec)
     }
-    pub fn to_simple_expr<T: sea_query::Iden + 'static>(&self, column: T) -> UdmResult<SimpleExpr> {
-        let vals = self.values.to_owned();
-        match Operation::try_from(self.operation)
-            .map_err(|_| UdmError::InvalidInput("Could not parse the operation".to_string()))?
+    // Truncate would skip the foreign key actions on postgres
+    fn delete_all<T: Iden + 'static>(table: T) -> DeleteStatement {
+        Query::delete().from_table(table).to_owned()
+    }
+}
+
+impl FetchData {
+    pub fn to_simple_expr<T>(&self, column: T) -> UdmResult<SimpleExpr>
+    where
+        T: Iden + SqlTransactionsFactory + 'static,
+    {
+        let column_type = column
+            .column_type()
+            .ok_or_else(|| UdmError::InvalidInput(format!("Can't filter on {}", self.column)))?;
+        let operation = Operation::try_from(self.operation)
+            .map_err(|_| UdmError::InvalidInput("Could not parse the operation".to_string()))?;
+        if matches!(operation, Operation::Like | Operation::NotLike)
+            && !matches!(column_type, ColumnType::Text)
         {
+            return Err(UdmError::InvalidInput(format!(
+                "{} only works on text columns, {} is not one",
+                operation.to_str(),
+                self.column
+            )));
+        }
+        let values = self
+            .values
+            .iter()
+            .map(|value| column_type.to_value(&self.column, value))
+            .collect::<UdmResult<Vec<Value>>>()?;
+        match operation {
             Operation::Unspecified => {
                 Err(UdmError::ApiFailure("Operation not specified".to_string()))
             }
-            Operation::Equal => Ok(Expr::col(column).eq(vals)),
-            Operation::NotEqual => Ok(Expr::col(column).ne(vals)),
-            Operation::In => Ok(Expr::col(column).is_in(vec![vals])),
-            Operation::NotIn => Ok(Expr::col(column).is_not_in(vec![vals])),
-            Operation::GreaterThan => Ok(Expr::col(column).gt(vals)),
-            Operation::GreaterThanOrEqual => Ok(Expr::col(column).gte(vals)),
-            Operation::LessThanOrEqual => Ok(Expr::col(column).lte(vals)),
-            Operation::LessThan => Ok(Expr::col(column).lt(vals)),
-            Operation::Like => Ok(Expr::col(column).like(vals)),
-            Operation::NotLike => Ok(Expr::col(column).not_like(vals)),
-            Operation::Is => Ok(Expr::col(column).is(vals)),
-            Operation::NotIs => Ok(Expr::col(column).is_not(vals)),
+            Operation::Equal => Ok(Expr::col(column).eq(self.value(values)?)),
+            Operation::NotEqual => Ok(Expr::col(column).ne(self.value(values)?)),
+            Operation::In => Ok(Expr::col(column).is_in(values)),
+            Operation::NotIn => Ok(Expr::col(column).is_not_in(values)),
+            Operation::GreaterThan => Ok(Expr::col(column).gt(self.value(values)?)),
+            Operation::GreaterThanOrEqual => Ok(Expr::col(column).gte(self.value(values)?)),
+            Operation::LessThanOrEqual => Ok(Expr::col(column).lte(self.value(values)?)),
+            Operation::LessThan => Ok(Expr::col(column).lt(self.value(values)?)),
+            Operation::Like => Ok(Expr::col(column).like(self.value(self.values.clone())?)),
+            Operation::NotLike => Ok(Expr::col(column).not_like(self.value(self.values.clone())?)),
+            Operation::Is if values.is_empty() => Ok(Expr::col(column).is_null()),
+            Operation::NotIs if values.is_empty() => Ok(Expr::col(column).is_not_null()),
+            Operation::Is => Ok(Expr::col(column).is(self.value(values)?)),
+            Operation::NotIs => Ok(Expr::col(column).is_not(self.value(values)?)),
+        }
+    }
+    // Every operation but IN and NOT IN compares against exactly one value
+    fn value<V>(&self, mut values: Vec<V>) -> UdmResult<V> {
+        if values.len() != 1 {
+            return Err(UdmError::InvalidInput(format!(
+                "{} expects a single value, got {}",
+                self.column,
+                values.len()
+            )));
         }
+        Ok(values.remove(0))
     }
 }
-impl CollectExpressions for CollectFluidRegulatorsRequest {
-    fn get_expressions(&self) -> UdmResult<Vec<SimpleExpr>> {
-        let mut exprs = Vec::new();
-        for expr in &self.expressions {
-            let cloned_data = expr.column.clone();
-            let col = FluidRegulationSchema::try_from(cloned_data)?;
-            let simple_expr = expr.to_simple_expr(col)?;
-            debug!("Got simple expr: {:?}", simple_expr);
-            exprs.push(simple_expr)
+impl FilterExpression {
+    pub fn to_condition<T>(&self, table: &T) -> UdmResult<Condition>
+    where
+        T: Iden + PartialEq + TryFrom<String, Error = UdmError> + SqlTransactionsFactory + 'static,
+    {
+        match &self.node {
+            None => Err(UdmError::InvalidInput(
+                "Filter expression is empty".to_string(),
+            )),
+            Some(filter_expression::Node::Condition(data)) => {
+                let column = filter_column(table, &data.column)?;
+                Ok(Condition::all().add(data.to_simple_expr(column)?))
+            }
+            Some(filter_expression::Node::All(group)) => group
+                .expressions
+                .iter()
+                .try_fold(Condition::all(), |condition, expr| {
+                    Ok(condition.add(expr.to_condition(table)?))
+                }),
+            Some(filter_expression::Node::Any(group)) => group
+                .expressions
+                .iter()
+                .try_fold(Condition::any(), |condition, expr| {
+                    Ok(condition.add(expr.to_condition(table)?))
+                }),
+            Some(filter_expression::Node::Not(expr)) => Ok(expr.to_condition(table)?.not()),
         }
-        Ok(exprs)
+    }
+}
+fn filter_column<T>(table: &T, column: &str) -> UdmResult<T>
+where
+    T: PartialEq + TryFrom<String, Error = UdmError>,
+{
+    let found = T::try_from(column.to_string())?;
+    if found == *table {
+        return Err(UdmError::InvalidInput(format!(
+            "Can't filter on {}",
+            column
+        )));
+    }
+    Ok(found)
+}
+// The expressions and the filter are ANDed together
+fn parse_condition<T>(
+    table: T,
+    expressions: &[FetchData],
+    filter: Option<&FilterExpression>,
+) -> UdmResult<Condition>
+where
+    T: Iden + PartialEq + TryFrom<String, Error = UdmError> + SqlTransactionsFactory + 'static,
+{
+    let mut condition = Condition::all();
+    for expr in expressions {
+        let column = filter_column(&table, &expr.column)?;
+        condition = condition.add(expr.to_simple_expr(column)?);
+    }
+    if let Some(filter) = filter {
+        condition = condition.add(filter.to_condition(&table)?);
+    }
+    debug!("Got condition: {:?}", condition);
+    Ok(condition)
+}
+impl CollectExpressions for CollectFluidRegulatorsRequest {
+    fn get_condition(&self) -> UdmResult<Condition> {
+        parse_condition(
+            FluidRegulationSchema::Table,
+            &self.expressions,
+            self.filter.as_ref(),
+        )
     }
 }
 impl CollectExpressions for CollectInstructionRequest {
-    fn get_expressions(&self) -> UdmResult<Vec<SimpleExpr>> {
-        let mut exprs = Vec::new();
-        for expr in &self.expressions {
-            let cloned_data = expr.column.clone();
-            let col = InstructionSchema::try_from(cloned_data)?;
-            let simple_expr = expr.to_simple_expr(col)?;
-            debug!("Got simple expr: {:?}", simple_expr);
-            exprs.push(simple_expr)
-        }
-        Ok(exprs)
+    fn get_condition(&self) -> UdmResult<Condition> {
+        parse_condition(
+            InstructionSchema::Table,
+            &self.expressions,
+            self.filter.as_ref(),
+        )
     }
 }
 impl CollectExpressions for CollectIngredientRequest {
-    fn get_expressions(&self) -> UdmResult<Vec<SimpleExpr>> {
-        let mut exprs = Vec::new();
-        for expr in &self.expressions {
-            let cloned_data = expr.column.clone();
-            let col = IngredientSchema::try_from(cloned_data)?;
-            let simple_expr = expr.to_simple_expr(col)?;
-            debug!("Got simple expr: {:?}", simple_expr);
-            exprs.push(simple_expr)
-        }
-        Ok(exprs)
+    fn get_condition(&self) -> UdmResult<Condition> {
+        parse_condition(
+            IngredientSchema::Table,
+            &self.expressions,
+            self.filter.as_ref(),
+        )
     }
 }
 impl CollectExpressions for CollectRecipeRequest {
-    fn get_expressions(&self) -> UdmResult<Vec<SimpleExpr>> {
-        let mut exprs = Vec::new();
-        for expr in &self.expressions {
-            let cloned_data = expr.column.clone();
-            let col = RecipeSchema::try_from(cloned_data)?;
-            let simple_expr = expr.to_simple_expr(col)?;
-            debug!("Got simple expr: {:?}", simple_expr);
-            exprs.push(simple_expr)
-        }
-        Ok(exprs)
+    fn get_condition(&self) -> UdmResult<Condition> {
+        parse_condition(RecipeSchema::Table, &self.expressions, self.filter.as_ref())
     }
 }
 impl CollectExpressions for CollectRecipeInstOrderRequest {
-    fn get_expressions(&self) -> UdmResult<Vec<SimpleExpr>> {
-        let mut exprs = Vec::new();
-        for expr in &self.expressions {
-            let cloned_data = expr.column.clone();
-            let col = InstructionToRecipeSchema::try_from(cloned_data)?;
-            let simple_expr = expr.to_simple_expr(col)?;
-            debug!("Got simple expr: {:?}", simple_expr);
-            exprs.push(simple_expr)
+    fn get_condition(&self) -> UdmResult<Condition> {
+        parse_condition(
+            InstructionToRecipeSchema::Table,
+            &self.expressions,
+            self.filter.as_ref(),
+        )
+    }
+}
+impl SortDirection {
+    pub fn to_order(&self) -> Order {
+        match self {
+            SortDirection::Descending => Order::Desc,
+            SortDirection::Unspecified | SortDirection::Ascending => Order::Asc,
+        }
+    }
+}
+// The primary key always ends the ordering so rows with the same order_by
+// value keep their place between pages
+fn parse_page<T>(
+    table: T,
+    key: T,
+    order_by: &str,
+    direction: SortDirection,
+    limit: u32,
+    page_token: &str,
+) -> UdmResult<Page>
+where
+    T: Iden + PartialEq + TryFrom<String, Error = UdmError> + 'static,
+{
+    let mut columns = Vec::new();
+    if !order_by.is_empty() {
+        let column = T::try_from(order_by.to_string())?;
+        if column == table {
+            return Err(UdmError::InvalidInput(format!(
+                "Can't sort on {}",
+                order_by
+            )));
+        }
+        if column != key {
+            columns.push(column.into_iden());
         }
-        Ok(exprs)
+    }
+    columns.push(key.into_iden());
+    let offset = if page_token.is_empty() {
+        0
+    } else {
+        page_token
+            .parse::<u64>()
+            .map_err(|_| UdmError::InvalidInput(format!("Invalid page token {}", page_token)))?
+    };
+    Ok(Page {
+        order_by: columns,
+        order: direction.to_order(),
+        limit: (limit > 0).then_some(limit as u64),
+        offset,
+    })
+}
+impl CollectPage for CollectFluidRegulatorsRequest {
+    fn get_page(&self) -> UdmResult<Page> {
+        parse_page(
+            FluidRegulationSchema::Table,
+            FluidRegulationSchema::FrId,
+            &self.order_by,
+            self.direction(),
+            self.limit,
+            &self.page_token,
+        )
+    }
+}
+impl CollectPage for CollectInstructionRequest {
+    fn get_page(&self) -> UdmResult<Page> {
+        parse_page(
+            InstructionSchema::Table,
+            InstructionSchema::InstructionId,
+            &self.order_by,
+            self.direction(),
+            self.limit,
+            &self.page_token,
+        )
+    }
+}
+impl CollectPage for CollectIngredientRequest {
+    fn get_page(&self) -> UdmResult<Page> {
+        parse_page(
+            IngredientSchema::Table,
+            IngredientSchema::IngredientId,
+            &self.order_by,
+            self.direction(),
+            self.limit,
+            &self.page_token,
+        )
+    }
+}
+impl CollectPage for CollectRecipeRequest {
+    fn get_page(&self) -> UdmResult<Page> {
+        parse_page(
+            RecipeSchema::Table,
+            RecipeSchema::RecipeId,
+            &self.order_by,
+            self.direction(),
+            self.limit,
+            &self.page_token,
+        )
+    }
+}
+impl CollectPage for CollectRecipeInstOrderRequest {
+    fn get_page(&self) -> UdmResult<Page> {
+        parse_page(
+            InstructionToRecipeSchema::Table,
+            InstructionToRecipeSchema::Id,
+            &self.order_by,
+            self.direction(),
+            self.limit,
+            &self.page_token,
+        )
     }
 }
 impl Operation {
@@ -204,10 +454,10 @@ impl Operation {
             Operation::NotEqual => "!=",
             Operation::In => "IN",
             Operation::NotIn => "NOT IN",
-            Operation::GreaterThan => "<",
-            Operation::GreaterThanOrEqual => "<=",
-            Operation::LessThanOrEqual => ">=",
-            Operation::LessThan => ">",
+            Operation::GreaterThan => ">",
+            Operation::GreaterThanOrEqual => ">=",
+            Operation::LessThanOrEqual => "<=",
+            Operation::LessThan => "<",
             Operation::Like => "LIKE",
             Operation::NotLike => "NOT LIKE",
             Operation::Is => "IS",
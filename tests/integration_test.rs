@@ -27,6 +27,51 @@ fn fluid_regulation_table_alter() {
     );
 }
 
+#[test]
+fn flow_calibration_table_create() {
+    let query = [
+        r#"CREATE TABLE IF NOT EXISTS "FlowCalibration""#,
+        r#"( "fr_id" integer NOT NULL PRIMARY KEY, "ml_per_second" real NOT NULL,"#,
+        r#""startup_lag_ms" integer NOT NULL DEFAULT 0, "drip_compensation_ml" real NOT NULL DEFAULT 0,"#,
+        r#"FOREIGN KEY ("fr_id") REFERENCES "FluidRegulation" ("fr_id") ON DELETE CASCADE ON UPDATE CASCADE )"#,
+    ]
+    .join(" ");
+    assert_eq!(
+        db::FlowCalibrationSchema::create_table(SqliteQueryBuilder).to_string(),
+        query
+    );
+}
+
+#[test]
+fn bottle_table_create() {
+    let query = [
+        r#"CREATE TABLE IF NOT EXISTS "Bottle""#,
+        r#"( "fr_id" integer NOT NULL PRIMARY KEY, "ingredient_id" integer, "capacity_ml" real NOT NULL, "remaining_ml" real NOT NULL,"#,
+        r#"FOREIGN KEY ("fr_id") REFERENCES "FluidRegulation" ("fr_id") ON DELETE CASCADE ON UPDATE CASCADE,"#,
+        r#"FOREIGN KEY ("ingredient_id") REFERENCES "Ingredient" ("ingredient_id") ON DELETE SET NULL ON UPDATE SET NULL )"#,
+    ]
+    .join(" ");
+    assert_eq!(
+        db::BottleSchema::create_table(SqliteQueryBuilder).to_string(),
+        query
+    );
+}
+
+#[test]
+fn drink_order_table_create() {
+    let query = [
+        r#"CREATE TABLE IF NOT EXISTS "DrinkOrder""#,
+        r#"( "order_id" integer NOT NULL PRIMARY KEY AUTOINCREMENT, "recipe_id" integer NOT NULL, "drink_size" integer NOT NULL DEFAULT 0,"#,
+        r#""requester" text NOT NULL, "priority" integer NOT NULL DEFAULT 0, "position" integer NOT NULL, "status" integer NOT NULL, "pour_id" bigint, "message" text NOT NULL DEFAULT '',"#,
+        r#"FOREIGN KEY ("recipe_id") REFERENCES "Recipe" ("recipe_id") ON DELETE CASCADE ON UPDATE CASCADE )"#,
+    ]
+    .join(" ");
+    assert_eq!(
+        db::DrinkOrderSchema::create_table(SqliteQueryBuilder).to_string(),
+        query
+    );
+}
+
 #[test]
 fn ingredient_table_create() {
     let query = [